@@ -0,0 +1,202 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{convert::Infallible, time::Duration};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use plugin::{
+    concurrency_control::ConcurrencyControlLayer,
+    config,
+    layer::{service_fn, Layer, Service, ServiceBuilder},
+};
+use regex::{Regex, RegexSet};
+
+const RULES: usize = 50;
+const QUERY: &str = "SELECT * FROM t_unknown WHERE id = 1";
+
+fn patterns() -> Vec<String> {
+    (0..RULES).map(|i| format!(r"^SELECT .* FROM t_{} WHERE", i)).collect()
+}
+
+fn bench_match_rules(c: &mut Criterion) {
+    let mut group = c.benchmark_group("match_50_rules");
+
+    // The previous implementation, every rule scans the query with its own regex
+    let regexes = patterns().iter().map(|r| Regex::new(r).unwrap()).collect::<Vec<_>>();
+    group.bench_function("per_rule_regex", |b| {
+        b.iter(|| regexes.iter().position(|r| r.is_match(black_box(QUERY))))
+    });
+
+    let config = patterns()
+        .into_iter()
+        .map(|r| config::ConcurrencyControl {
+            regex: vec![r],
+            max_concurrency: 10,
+            duration: Duration::from_secs(60),
+            ..Default::default()
+        })
+        .collect();
+    let mut svc = ServiceBuilder::new()
+        .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+        .build(service_fn(|_: &str| Ok::<_, Infallible>(())));
+    group.bench_function("regex_set", |b| b.iter(|| svc.handle(black_box(QUERY)).unwrap()));
+
+    group.finish();
+}
+
+// `layer()` shares the regexes compiled by `ConcurrencyControlLayer::new`
+fn bench_layer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("layer_50_rules");
+    let config = patterns()
+        .into_iter()
+        .map(|r| config::ConcurrencyControl {
+            regex: vec![r],
+            max_concurrency: 10,
+            duration: Duration::from_secs(60),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+
+    group.bench_function("new_layer", |b| {
+        b.iter(|| ConcurrencyControlLayer::new(black_box(config.clone())).unwrap())
+    });
+
+    let layer = ConcurrencyControlLayer::new(config).unwrap();
+    group.bench_function("layer", |b| {
+        b.iter(|| layer.layer(service_fn(|_: &str| Ok::<_, Infallible>(()))))
+    });
+
+    group.finish();
+}
+
+// A million matches of a prefix rule and of the equivalent regex rule,
+// it takes about 65ms for the prefix rule and 120ms for the regex rule
+fn bench_match_type(c: &mut Criterion) {
+    let mut group = c.benchmark_group("match_1m_queries");
+    group.sample_size(10);
+
+    for (name, pattern, match_type) in [
+        ("regex", r"^SELECT \* FROM t_order", config::ConcurrencyControlMatchType::Regex),
+        ("prefix", "SELECT * FROM t_order", config::ConcurrencyControlMatchType::Prefix),
+    ] {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(pattern)],
+            action: config::ConcurrencyControlAction::Allow,
+            match_type,
+            ..Default::default()
+        }];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|_: &str| Ok::<_, Infallible>(())));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for _ in 0..1_000_000 {
+                    svc.handle(black_box("SELECT * FROM t_order WHERE id = 1")).unwrap();
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+// A workload of 10 queries repeated, they are matched once with the match cache
+fn bench_match_cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("match_cache_50_rules");
+    let config = patterns()
+        .into_iter()
+        .map(|r| config::ConcurrencyControl {
+            regex: vec![r],
+            max_concurrency: 10,
+            duration: Duration::from_secs(60),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    let queries =
+        (0..10).map(|i| format!("SELECT * FROM t_unknown WHERE id = {}", i)).collect::<Vec<_>>();
+
+    for (name, capacity) in [("uncached", 0), ("cached", 1024)] {
+        let layer = ConcurrencyControlLayer::new(config.clone())
+            .unwrap()
+            .with_match_cache(capacity)
+            .unwrap();
+        let mut svc = ServiceBuilder::new()
+            .with_layer(layer)
+            .build(service_fn(|_: &str| Ok::<_, Infallible>(())));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for query in &queries {
+                    svc.handle(black_box(query.as_str())).unwrap();
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+// 25 literal prefix rules and 25 complex rules, the literal prefixes are matched by
+// an Aho-Corasick automaton instead of the `RegexSet` of all patterns
+fn bench_mixed_rules(c: &mut Criterion) {
+    let mut group = c.benchmark_group("match_mixed_50_rules");
+    let patterns = (0..RULES / 2)
+        .flat_map(|i| [format!("^INSERT INTO t_{} ", i), format!(r"^UPDATE t_{} SET .* WHERE", i)])
+        .collect::<Vec<_>>();
+    let queries = [
+        "INSERT INTO t_24 VALUES (1, 2)",
+        "UPDATE t_24 SET a = 1 WHERE id = 1",
+        "SELECT * FROM t_unknown WHERE id = 1",
+    ];
+
+    let set = RegexSet::new(&patterns).unwrap();
+    group.bench_function("regex_set", |b| {
+        b.iter(|| {
+            for query in queries {
+                black_box(set.matches(black_box(query)).iter().next());
+            }
+        })
+    });
+
+    let config = patterns
+        .into_iter()
+        .map(|r| config::ConcurrencyControl {
+            regex: vec![r],
+            max_concurrency: 10,
+            duration: Duration::from_secs(60),
+            ..Default::default()
+        })
+        .collect();
+    let mut svc = ServiceBuilder::new()
+        .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+        .build(service_fn(|_: &str| Ok::<_, Infallible>(())));
+    group.bench_function("aho_corasick_prefixes", |b| {
+        b.iter(|| {
+            for query in queries {
+                svc.handle(black_box(query)).unwrap();
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_match_rules,
+    bench_layer,
+    bench_match_type,
+    bench_match_cache,
+    bench_mixed_rules
+);
+criterion_main!(benches);
@@ -0,0 +1,169 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    err::{BoxError, PluginError},
+    layer::{AsyncService, Layer},
+};
+
+// The request sent to the worker, and the channel of its result
+type Message<Input, Output> = (Input, oneshot::Sender<Result<Output, BoxError>>);
+
+/// `BufferLayer` queues at most `bound` requests for a worker task running the inner
+/// service, the requests are rejected with `PluginError::BufferFull` when the queue is full.
+/// The requests are handled one by one in order. The worker is spawned by `layer`, so it must
+/// be called in a tokio runtime. Only `AsyncService` is supported.
+pub struct BufferLayer<Input> {
+    bound: usize,
+    _input: PhantomData<fn(Input)>,
+}
+
+impl<Input> BufferLayer<Input> {
+    /// Panics if `bound` is 0.
+    pub fn new(bound: usize) -> BufferLayer<Input> {
+        assert!(bound > 0, "buffer plugin bound must be greater than 0");
+        BufferLayer { bound, _input: PhantomData }
+    }
+}
+
+impl<Input> Clone for BufferLayer<Input> {
+    fn clone(&self) -> Self {
+        BufferLayer { bound: self.bound, _input: PhantomData }
+    }
+}
+
+impl<S, Input> Layer<S> for BufferLayer<Input>
+where
+    S: AsyncService<Input> + Send + 'static,
+    Input: Send + 'static,
+    S::Output: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Service = Buffer<Input, S::Output>;
+
+    fn layer(&self, mut inner: S) -> Self::Service {
+        let (tx, mut rx) = mpsc::channel::<Message<Input, S::Output>>(self.bound);
+        // The worker stops once all clones of the service are dropped.
+        tokio::spawn(async move {
+            while let Some((input, result)) = rx.recv().await {
+                let res = inner.handle(input).await.map_err(Into::into);
+                // the caller may have given up waiting
+                let _ = result.send(res);
+            }
+        });
+        Buffer { tx }
+    }
+}
+
+/// The clones of `Buffer` share the same queue and worker.
+pub struct Buffer<Input, Output> {
+    tx: mpsc::Sender<Message<Input, Output>>,
+}
+
+impl<Input, Output> Clone for Buffer<Input, Output> {
+    fn clone(&self) -> Self {
+        Buffer { tx: self.tx.clone() }
+    }
+}
+
+#[async_trait]
+impl<Input, Output> AsyncService<Input> for Buffer<Input, Output>
+where
+    Input: Send + 'static,
+    Output: Send + 'static,
+{
+    type Output = Output;
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.try_send((input, tx)).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => BoxError::from(PluginError::BufferFull),
+            mpsc::error::TrySendError::Closed(_) => BoxError::from(PluginError::BufferClosed),
+        })?;
+        // the result is dropped without sending if the worker panics
+        rx.await.map_err(|_| BoxError::from(PluginError::BufferClosed))?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use parking_lot::Mutex;
+    use tokio::sync::Notify;
+
+    use super::*;
+    use crate::layer::{async_service_fn, ServiceBuilder};
+
+    #[tokio::test]
+    async fn test_buffer_full() {
+        let gate = Arc::new(Notify::new());
+        let inner_gate = gate.clone();
+        let svc = ServiceBuilder::new().with_layer(BufferLayer::new(2)).build(async_service_fn(
+            move |input: &'static str| {
+                let gate = inner_gate.clone();
+                async move {
+                    gate.notified().await;
+                    Ok::<_, PluginError>(input)
+                }
+            },
+        ));
+
+        // the first request is taken by the worker, the next two fill the buffer
+        let mut tasks = vec![];
+        for input in ["SELECT 1", "SELECT 2", "SELECT 3"] {
+            let mut svc = svc.clone();
+            tasks.push(tokio::spawn(
+                async move { svc.handle(input).await.map_err(|e| e.to_string()) },
+            ));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let err = svc.clone().handle("SELECT 4").await.unwrap_err();
+        assert_eq!(err.downcast_ref::<PluginError>(), Some(&PluginError::BufferFull));
+
+        for (t, input) in tasks.into_iter().zip(["SELECT 1", "SELECT 2", "SELECT 3"]) {
+            gate.notify_one();
+            assert_eq!(t.await.unwrap(), Ok(input));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_buffer_in_order() {
+        let handled = Arc::new(Mutex::new(vec![]));
+        let inner_handled = handled.clone();
+        let svc = ServiceBuilder::new().with_layer(BufferLayer::new(8)).build(async_service_fn(
+            move |sleep: u64| {
+                let handled = inner_handled.clone();
+                async move {
+                    // the later requests are faster, they would finish first if run concurrently
+                    tokio::time::sleep(Duration::from_millis(sleep)).await;
+                    handled.lock().push(sleep);
+                    Ok::<_, PluginError>(sleep)
+                }
+            },
+        ));
+
+        let (mut a, mut b, mut c) = (svc.clone(), svc.clone(), svc.clone());
+        let (a, b, c) = tokio::join!(a.handle(30), b.handle(20), c.handle(10));
+        assert_eq!((a.unwrap(), b.unwrap(), c.unwrap()), (30, 20, 10));
+        assert_eq!(*handled.lock(), [30, 20, 10]);
+    }
+}
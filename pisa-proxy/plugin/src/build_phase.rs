@@ -17,6 +17,7 @@ use crate::{
     concurrency_control::{ConcurrencyControl, ConcurrencyControlLayer},
     config,
     err::PluginError,
+    firewall::{Firewall, FirewallLayer},
     layer::*,
 };
 
@@ -30,23 +31,49 @@ fn circuit_break_phase(_input: String) -> Result<(), PluginError> {
     Ok(())
 }
 
+/// firewall service, some logic may be added in the future, eg: metrics...
+fn firewall_phase(_input: String) -> Result<(), PluginError> {
+    Ok(())
+}
+
+/// The inner service of each plugin of the phase
+pub type PhaseFn = ServiceFn<fn(String) -> Result<(), PluginError>>;
+
 #[derive(Clone)]
 pub struct PluginPhase {
-    pub concurrency_control: ConcurrencyControl<ServiceFn<fn(String) -> Result<(), PluginError>>>,
-    pub circuit_break: CircuitBreak<ServiceFn<fn(String) -> Result<(), PluginError>>>,
+    pub concurrency_control: ConcurrencyControl<PhaseFn>,
+    pub circuit_break: CircuitBreak<PhaseFn>,
+    pub firewall: Firewall<PhaseFn>,
+    // The kill switch shared by the plugins of the phase
+    bypass: Bypass,
 }
 
 impl PluginPhase {
-    pub fn new(config: config::Plugin) -> PluginPhase {
-        let concurrency_control = ServiceBuilder::new()
-            .with_layer(ConcurrencyControlLayer::with_opt(config.concurrency_control))
+    pub fn new(config: config::Plugin) -> Result<PluginPhase, PluginError> {
+        // the builders share the kill switch of `builder`
+        let builder = ServiceBuilder::new();
+        let concurrency_control = builder
+            .clone()
+            .with_bypassable_layer(ConcurrencyControlLayer::with_opt(config.concurrency_control)?)
             // issue https://users.rust-lang.org/t/puzzling-expected-fn-pointer-found-fn-item/46423/4
             .build(service_fn(concurrency_control_phase as fn(String) -> Result<(), PluginError>));
 
-        let circuit_break = ServiceBuilder::new()
-            .with_layer(CircuitBreakLayer::with_opt(config.circuit_break))
+        let circuit_break = builder
+            .clone()
+            .with_bypassable_layer(CircuitBreakLayer::with_opt(config.circuit_break))
             .build(service_fn(circuit_break_phase as fn(String) -> Result<(), PluginError>));
 
-        PluginPhase { concurrency_control, circuit_break }
+        let firewall = builder
+            .clone()
+            .with_bypassable_layer(FirewallLayer::with_opt(config.firewall)?)
+            .build(service_fn(firewall_phase as fn(String) -> Result<(), PluginError>));
+
+        Ok(PluginPhase { concurrency_control, circuit_break, firewall, bypass: builder.bypass() })
+    }
+
+    /// Take all plugins of the phase out of the path at once, eg: during an incident,
+    /// the clones of the phase are bypassed too
+    pub fn set_bypass(&self, bypass: bool) {
+        self.bypass.set(bypass)
     }
 }
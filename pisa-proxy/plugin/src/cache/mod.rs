@@ -0,0 +1,255 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    any::Any,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use lru::LruCache;
+use parking_lot::Mutex;
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+    config,
+    err::{BoxError, PluginError},
+    layer::{AsyncService, Layer, Service},
+};
+
+/// `CacheLayer` serves the cached output of the cacheable queries without calling
+/// the inner service. Only the successful outputs are cached.
+#[derive(Clone)]
+pub struct CacheLayer {
+    config: config::Cache,
+    regex: Regex,
+}
+
+impl CacheLayer {
+    pub fn new(config: config::Cache) -> Result<CacheLayer, PluginError> {
+        let regex = RegexBuilder::new(&config.regex)
+            .case_insensitive(config.case_insensitive)
+            .build()
+            .map_err(|e| PluginError::InvalidCacheRegex {
+                regex: config.regex.clone(),
+                source: e,
+            })?;
+        Ok(CacheLayer { config, regex })
+    }
+}
+
+impl<S> Layer<S> for CacheLayer {
+    type Service = Cache<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Cache {
+            inner,
+            regex: self.regex.clone(),
+            ttl: self.config.ttl,
+            entries: Arc::new(Mutex::new(LruCache::new(self.config.capacity))),
+        }
+    }
+}
+
+/// The clones of `Cache` share the same entries.
+#[derive(Clone)]
+pub struct Cache<S> {
+    inner: S,
+    regex: Regex,
+    ttl: Duration,
+    entries: Arc<Mutex<LruCache<String, Entry>>>,
+}
+
+// The time it expires and the output of the query,
+// the output type is only known by the `Service` impl
+type Entry = (Instant, Box<dyn Any + Send>);
+
+impl<S> Cache<S> {
+    // Return the cached output of `query`, the expired entry is removed
+    fn get<O: Clone + 'static>(&self, query: &str) -> Option<O> {
+        let mut entries = self.entries.lock();
+        match entries.get(query) {
+            Some((expire_at, out)) if *expire_at > Instant::now() => {
+                return out.downcast_ref::<O>().cloned()
+            }
+            Some(_) => {
+                entries.pop(query);
+            }
+            None => {}
+        }
+        None
+    }
+
+    fn put<O: Clone + Send + 'static>(&self, query: &str, out: &O) {
+        let entry: Entry = (Instant::now() + self.ttl, Box::new(out.clone()));
+        self.entries.lock().put(query.to_string(), entry);
+    }
+
+    /// Return the number of the cached entries, including the expired ones not removed yet
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<S, Input> Service<Input> for Cache<S>
+where
+    S: Service<Input>,
+    S::Output: Clone + Send + 'static,
+    Input: AsRef<str>,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        if !self.regex.is_match(input.as_ref()) {
+            return self.inner.handle(input).map_err(Into::into);
+        }
+        if let Some(out) = self.get(input.as_ref()) {
+            return Ok(out);
+        }
+
+        let query = input.as_ref().to_string();
+        let out = self.inner.handle(input).map_err(Into::into)?;
+        self.put(&query, &out);
+        Ok(out)
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.inner.poll_ready()
+    }
+}
+
+#[async_trait]
+impl<S, Input> AsyncService<Input> for Cache<S>
+where
+    S: AsyncService<Input> + Send,
+    S::Output: Clone + Send + 'static,
+    Input: AsRef<str> + Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        if !self.regex.is_match(input.as_ref()) {
+            return self.inner.handle(input).await.map_err(Into::into);
+        }
+        if let Some(out) = self.get(input.as_ref()) {
+            return Ok(out);
+        }
+
+        let query = input.as_ref().to_string();
+        let out = self.inner.handle(input).await.map_err(Into::into)?;
+        self.put(&query, &out);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread::sleep,
+    };
+
+    use super::*;
+    use crate::layer::{async_service_fn, service_fn, ServiceBuilder};
+
+    fn config(capacity: usize) -> config::Cache {
+        config::Cache {
+            regex: String::from(r"^SELECT"),
+            ttl: Duration::from_millis(100),
+            capacity,
+            case_insensitive: false,
+        }
+    }
+
+    // The service returns the number of calls
+    fn counting_service() -> impl FnMut(&str) -> Result<usize, PluginError> {
+        let calls = AtomicUsize::new(0);
+        move |_| Ok(calls.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    #[test]
+    fn test_cache_hit_and_miss() {
+        let mut svc = ServiceBuilder::new()
+            .with_layer(CacheLayer::new(config(10)).unwrap())
+            .build(service_fn(counting_service()));
+
+        assert_eq!(svc.handle("SELECT 1").unwrap(), 1);
+        assert_eq!(svc.handle("SELECT 1").unwrap(), 1);
+        // a different query misses
+        assert_eq!(svc.handle("SELECT 2").unwrap(), 2);
+        // the queries not cacheable are not cached
+        assert_eq!(svc.handle("UPDATE t SET a = 1").unwrap(), 3);
+        assert_eq!(svc.handle("UPDATE t SET a = 1").unwrap(), 4);
+        assert_eq!(svc.len(), 2);
+
+        // the errors are not cached
+        let mut svc = ServiceBuilder::new()
+            .with_layer(CacheLayer::new(config(10)).unwrap())
+            .build(service_fn(|_: &str| Err::<String, _>(PluginError::Unknown)));
+        assert!(svc.handle("SELECT 1").is_err());
+        assert!(svc.is_empty());
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let mut svc = ServiceBuilder::new()
+            .with_layer(CacheLayer::new(config(10)).unwrap())
+            .build(service_fn(counting_service()));
+
+        assert_eq!(svc.handle("SELECT 1").unwrap(), 1);
+        sleep(Duration::from_millis(150));
+        assert_eq!(svc.handle("SELECT 1").unwrap(), 2);
+        assert_eq!(svc.handle("SELECT 1").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let mut svc = ServiceBuilder::new()
+            .with_layer(CacheLayer::new(config(2)).unwrap())
+            .build(service_fn(counting_service()));
+
+        assert_eq!(svc.handle("SELECT 1").unwrap(), 1);
+        assert_eq!(svc.handle("SELECT 2").unwrap(), 2);
+        // `SELECT 1` is used recently, so `SELECT 2` is evicted
+        assert_eq!(svc.handle("SELECT 1").unwrap(), 1);
+        assert_eq!(svc.handle("SELECT 3").unwrap(), 3);
+        assert_eq!(svc.len(), 2);
+        assert_eq!(svc.handle("SELECT 1").unwrap(), 1);
+        assert_eq!(svc.handle("SELECT 2").unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_cache_async() {
+        let mut svc = ServiceBuilder::new().with_layer(CacheLayer::new(config(10)).unwrap()).build(
+            async_service_fn(|input: &'static str| async move {
+                Ok::<_, PluginError>(input.to_lowercase())
+            }),
+        );
+        assert_eq!(svc.handle("SELECT A").await.unwrap(), "select a");
+        assert_eq!(svc.len(), 1);
+        assert!(matches!(
+            CacheLayer::new(config::Cache { regex: String::from("("), ..config(1) }),
+            Err(PluginError::InvalidCacheRegex { .. })
+        ));
+    }
+}
@@ -17,12 +17,13 @@ use regex::{Regex, RegexBuilder};
 use crate::{
     config,
     err::{BoxError, PluginError},
-    layer::{Layer, Service},
+    layer::{Bypass, Bypassable, Layer, Service},
 };
 
 #[derive(Clone)]
 pub struct CircuitBreakLayer {
     config: Option<Vec<config::CircuitBreak>>,
+    bypass: Bypass,
 }
 
 #[derive(Clone)]
@@ -37,11 +38,11 @@ pub struct CircuitBreakInstance {
 
 impl CircuitBreakLayer {
     pub fn new(config: Vec<config::CircuitBreak>) -> CircuitBreakLayer {
-        CircuitBreakLayer { config: Some(config) }
+        CircuitBreakLayer { config: Some(config), bypass: Bypass::default() }
     }
 
     pub fn with_opt(config: Option<Vec<config::CircuitBreak>>) -> CircuitBreakLayer {
-        CircuitBreakLayer { config }
+        CircuitBreakLayer { config, bypass: Bypass::default() }
     }
 
     fn create_instances(&self) -> Option<Vec<CircuitBreakInstance>> {
@@ -62,12 +63,18 @@ impl CircuitBreakLayer {
     }
 }
 
+impl Bypassable for CircuitBreakLayer {
+    fn with_bypass(self, bypass: Bypass) -> Self {
+        CircuitBreakLayer { bypass, ..self }
+    }
+}
+
 impl<S> Layer<S> for CircuitBreakLayer {
     type Service = CircuitBreak<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
         let instances = self.create_instances();
-        CircuitBreak { inner, instances }
+        CircuitBreak { inner, instances, bypass: self.bypass.clone() }
     }
 }
 
@@ -75,6 +82,7 @@ impl<S> Layer<S> for CircuitBreakLayer {
 pub struct CircuitBreak<S> {
     inner: S,
     instances: Option<Vec<CircuitBreakInstance>>,
+    bypass: Bypass,
 }
 
 impl<S> CircuitBreak<S> {
@@ -102,13 +110,17 @@ where
     type Error = BoxError;
 
     fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
-        let is_allow = self.is_allow(input.as_ref());
+        let is_allow = self.bypass.is_set() || self.is_allow(input.as_ref());
         if is_allow {
             return self.inner.handle(input).map_err(Into::into);
         }
 
         Err(Box::new(PluginError::CircuitBreakPluginReject))
     }
+
+    fn poll_ready(&mut self) -> bool {
+        self.inner.poll_ready()
+    }
 }
 
 #[cfg(test)]
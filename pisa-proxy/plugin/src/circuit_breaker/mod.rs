@@ -0,0 +1,328 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU8, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+
+use crate::{
+    config,
+    err::{BoxError, PluginError},
+    layer::{AsyncService, Bypass, Bypassable, Layer, Service},
+};
+
+const CLOSED: u8 = 0;
+const OPEN: u8 = 1;
+const HALF_OPEN: u8 = 2;
+
+/// The state of `CircuitBreaker`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitState {
+    // The requests pass through, the consecutive failures are counted
+    Closed,
+    // The requests are rejected until `cool_down` has elapsed
+    Open,
+    // A single probe request is passing through, the others are rejected
+    HalfOpen,
+}
+
+/// `CircuitBreakerLayer` stops calling the inner service after it fails
+/// `failure_threshold` times in a row.
+#[derive(Clone)]
+pub struct CircuitBreakerLayer {
+    config: config::CircuitBreaker,
+    bypass: Bypass,
+}
+
+impl CircuitBreakerLayer {
+    pub fn new(config: config::CircuitBreaker) -> CircuitBreakerLayer {
+        CircuitBreakerLayer { config, bypass: Bypass::default() }
+    }
+}
+
+impl Bypassable for CircuitBreakerLayer {
+    fn with_bypass(self, bypass: Bypass) -> Self {
+        CircuitBreakerLayer { bypass, ..self }
+    }
+}
+
+impl<S> Layer<S> for CircuitBreakerLayer {
+    type Service = CircuitBreaker<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CircuitBreaker {
+            inner,
+            state: Arc::new(BreakerState {
+                state: AtomicU8::new(CLOSED),
+                failures: AtomicU32::new(0),
+                opened_at: Mutex::new(Instant::now()),
+                failure_threshold: self.config.failure_threshold,
+                cool_down: self.config.cool_down,
+            }),
+            bypass: self.bypass.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    state: AtomicU8,
+    // The consecutive failures in `Closed` state
+    failures: AtomicU32,
+    opened_at: Mutex<Instant>,
+    failure_threshold: u32,
+    cool_down: Duration,
+}
+
+impl BreakerState {
+    fn open(&self) {
+        *self.opened_at.lock() = Instant::now();
+        self.failures.store(0, Ordering::Relaxed);
+        self.state.store(OPEN, Ordering::Release);
+    }
+
+    // Return whether the request is the probe of `HalfOpen` state,
+    // return `CircuitOpen` error if the request is rejected.
+    fn try_enter(&self) -> Result<bool, PluginError> {
+        match self.state.load(Ordering::Acquire) {
+            CLOSED => Ok(false),
+            OPEN if self.opened_at.lock().elapsed() >= self.cool_down => {
+                // Only the request which switches the state can be the probe
+                self.state
+                    .compare_exchange(OPEN, HALF_OPEN, Ordering::AcqRel, Ordering::Acquire)
+                    .map(|_| true)
+                    .map_err(|_| PluginError::CircuitOpen)
+            }
+            _ => Err(PluginError::CircuitOpen),
+        }
+    }
+
+    fn on_result(&self, probe: bool, is_ok: bool) {
+        match (probe, is_ok) {
+            (true, true) => {
+                self.failures.store(0, Ordering::Relaxed);
+                self.state.store(CLOSED, Ordering::Release);
+            }
+            (true, false) => self.open(),
+            (false, true) => self.failures.store(0, Ordering::Relaxed),
+            (false, false) => {
+                let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures >= self.failure_threshold
+                    && self.state.load(Ordering::Acquire) == CLOSED
+                {
+                    self.open()
+                }
+            }
+        }
+    }
+}
+
+// Count the call as failed if it is dropped before finishing, eg: the inner service
+// panicked or the future was cancelled, so the `HalfOpen` state is never stuck.
+struct CallGuard {
+    state: Arc<BreakerState>,
+    probe: bool,
+    finished: bool,
+}
+
+impl CallGuard {
+    fn finish(mut self, is_ok: bool) {
+        self.finished = true;
+        self.state.on_result(self.probe, is_ok);
+    }
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.state.on_result(self.probe, false);
+        }
+    }
+}
+
+/// The clones of `CircuitBreaker` share the same state.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker<S> {
+    inner: S,
+    state: Arc<BreakerState>,
+    bypass: Bypass,
+}
+
+impl<S> CircuitBreaker<S> {
+    /// Return the current state
+    pub fn state(&self) -> CircuitState {
+        match self.state.state.load(Ordering::Acquire) {
+            CLOSED => CircuitState::Closed,
+            OPEN => CircuitState::Open,
+            _ => CircuitState::HalfOpen,
+        }
+    }
+
+    fn enter(&self) -> Result<CallGuard, PluginError> {
+        let probe = self.state.try_enter()?;
+        Ok(CallGuard { state: self.state.clone(), probe, finished: false })
+    }
+}
+
+impl<S, Input> Service<Input> for CircuitBreaker<S>
+where
+    S: Service<Input>,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        // The results are not counted while bypassed
+        if self.bypass.is_set() {
+            return self.inner.handle(input).map_err(Into::into);
+        }
+        let guard = self.enter().map_err(BoxError::from)?;
+        let res = self.inner.handle(input);
+        guard.finish(res.is_ok());
+        res.map_err(Into::into)
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.inner.poll_ready()
+    }
+}
+
+#[async_trait]
+impl<S, Input> AsyncService<Input> for CircuitBreaker<S>
+where
+    S: AsyncService<Input> + Send,
+    Input: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        if self.bypass.is_set() {
+            return self.inner.handle(input).await.map_err(Into::into);
+        }
+        let guard = self.enter().map_err(BoxError::from)?;
+        let res = self.inner.handle(input).await;
+        guard.finish(res.is_ok());
+        res.map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::sleep;
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::layer::{async_service_fn, service_fn, ServiceBuilder};
+
+    fn config() -> config::CircuitBreaker {
+        config::CircuitBreaker { failure_threshold: 3, cool_down: Duration::from_millis(100) }
+    }
+
+    fn test_service(is_ok: bool) -> Result<(), PluginError> {
+        if is_ok {
+            return Ok(());
+        }
+        Err(PluginError::Unknown)
+    }
+
+    fn is_circuit_open(err: BoxError) -> bool {
+        err.downcast_ref::<PluginError>() == Some(&PluginError::CircuitOpen)
+    }
+
+    #[test]
+    fn test_circuit_breaker_open() {
+        let mut svc = ServiceBuilder::new()
+            .with_layer(CircuitBreakerLayer::new(config()))
+            .build(service_fn(test_service));
+
+        // a success resets the consecutive failures
+        assert!(svc.handle(false).is_err());
+        assert!(svc.handle(false).is_err());
+        assert!(svc.handle(true).is_ok());
+        assert!(svc.handle(false).is_err());
+        assert!(svc.handle(false).is_err());
+        assert_eq!(svc.state(), CircuitState::Closed);
+
+        assert!(!is_circuit_open(svc.handle(false).unwrap_err()));
+        assert_eq!(svc.state(), CircuitState::Open);
+        // the inner service is not called while open
+        assert!(is_circuit_open(svc.handle(true).unwrap_err()));
+    }
+
+    #[test]
+    fn test_circuit_breaker_half_open() {
+        let mut svc = ServiceBuilder::new()
+            .with_layer(CircuitBreakerLayer::new(config()))
+            .build(service_fn(test_service));
+        for _ in 0..3 {
+            assert!(svc.handle(false).is_err());
+        }
+        assert_eq!(svc.state(), CircuitState::Open);
+
+        // a failed probe opens it again
+        sleep(Duration::from_millis(150));
+        assert!(!is_circuit_open(svc.handle(false).unwrap_err()));
+        assert_eq!(svc.state(), CircuitState::Open);
+        assert!(is_circuit_open(svc.handle(true).unwrap_err()));
+
+        // a successful probe closes it
+        sleep(Duration::from_millis(150));
+        assert!(svc.handle(true).is_ok());
+        assert_eq!(svc.state(), CircuitState::Closed);
+        assert!(svc.handle(false).is_err());
+        assert_eq!(svc.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_single_probe() {
+        let mut svc = ServiceBuilder::new().with_layer(CircuitBreakerLayer::new(config())).build(
+            async_service_fn(|rx: Option<oneshot::Receiver<()>>| async move {
+                match rx {
+                    Some(rx) => rx.await.map_err(|_| PluginError::Unknown),
+                    None => Err(PluginError::Unknown),
+                }
+            }),
+        );
+        for _ in 0..3 {
+            assert!(svc.handle(None).await.is_err());
+        }
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let (tx, rx) = oneshot::channel();
+        let mut probe_svc = svc.clone();
+        let probe = tokio::spawn(async move { probe_svc.handle(Some(rx)).await.is_ok() });
+        while svc.state() != CircuitState::HalfOpen {
+            tokio::task::yield_now().await;
+        }
+
+        // the other requests are rejected while the probe is in flight
+        let (_tx, rx) = oneshot::channel();
+        assert!(is_circuit_open(svc.handle(Some(rx)).await.unwrap_err()));
+
+        tx.send(()).unwrap();
+        assert!(probe.await.unwrap());
+        assert_eq!(svc.state(), CircuitState::Closed);
+    }
+}
@@ -0,0 +1,303 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// The result of `AdmitAlgorithm::try_admit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdmitResult {
+    Admitted,
+    Rejected,
+}
+
+/// The algorithm admitting the matched requests of a rule. The fixed window, the sliding
+/// window and the token bucket of the config are built-in algorithms, and an algorithm can
+/// replace the configured one by `ConcurrencyControlLayer::with_algorithm`. `weight` is the
+/// weight of the request, see `config::ConcurrencyControl::weight`.
+///
+/// The fixed-window rules of the config are admitted by their permits rather than by
+/// `FixedWindowAlgorithm`, the permits are held by the guard until the request completes,
+/// and they can be awaited, borrowed by a pool and overdrawn.
+pub trait AdmitAlgorithm: Send {
+    fn try_admit(&mut self, now: Instant, weight: u32) -> AdmitResult;
+
+    /// Undo the last admission of `weight`, the request is rejected by another matched rule.
+    /// The admission is kept if it can not be rolled back.
+    fn rollback(&mut self, _weight: u32) {}
+
+    /// Change the limit of the algorithm, eg: by `ConcurrencyControl::resize_rule` or
+    /// when the algorithm is kept by `reload`
+    fn set_limit(&mut self, _limit: usize) {}
+
+    /// Return the weights the algorithm would admit at `now`, None if it is unknown
+    fn available(&self, _now: Instant) -> Option<f64> {
+        None
+    }
+
+    /// Return the start of the current window at `now`, None if there is no window
+    fn window_started(&self, _now: Instant) -> Option<Instant> {
+        None
+    }
+
+    /// Return the time until a request may be admitted after `now`, None if it is unknown
+    fn retry_after(&self, _now: Instant) -> Option<Duration> {
+        None
+    }
+}
+
+/// The fixed window admitting the weights of `max` requests per `duration`, the window
+/// starts with the first request after the previous one elapsed. The weights are counted
+/// when admitted, they are not returned when the requests complete.
+#[derive(Debug, Clone)]
+pub struct FixedWindowAlgorithm {
+    max: usize,
+    duration: Duration,
+    started: Option<Instant>,
+    // The weights admitted in the current window
+    admitted: usize,
+}
+
+impl FixedWindowAlgorithm {
+    pub fn new(max: usize, duration: Duration) -> Self {
+        FixedWindowAlgorithm { max, duration, started: None, admitted: 0 }
+    }
+
+    // Return the start of the window at `now`, None if it has elapsed
+    fn current(&self, now: Instant) -> Option<Instant> {
+        self.started.filter(|at| now.saturating_duration_since(*at) < self.duration)
+    }
+}
+
+impl AdmitAlgorithm for FixedWindowAlgorithm {
+    fn try_admit(&mut self, now: Instant, weight: u32) -> AdmitResult {
+        if self.current(now).is_none() {
+            self.started = Some(now);
+            self.admitted = 0;
+        }
+        if self.admitted + weight as usize > self.max {
+            return AdmitResult::Rejected;
+        }
+        self.admitted += weight as usize;
+        AdmitResult::Admitted
+    }
+
+    fn rollback(&mut self, weight: u32) {
+        self.admitted = self.admitted.saturating_sub(weight as usize);
+    }
+
+    fn set_limit(&mut self, limit: usize) {
+        self.max = limit;
+    }
+
+    fn available(&self, now: Instant) -> Option<f64> {
+        let admitted = self.current(now).map_or(0, |_| self.admitted);
+        Some(self.max.saturating_sub(admitted) as f64)
+    }
+
+    fn window_started(&self, now: Instant) -> Option<Instant> {
+        self.current(now)
+    }
+
+    fn retry_after(&self, now: Instant) -> Option<Duration> {
+        let started = self.current(now).filter(|_| self.admitted >= self.max)?;
+        Some(self.duration.saturating_sub(now.saturating_duration_since(started)))
+    }
+}
+
+/// The sliding window admitting `max` requests in the trailing `duration`.
+/// Each request is counted once, the weight only works with fixed window.
+#[derive(Debug, Clone)]
+pub struct SlidingWindowAlgorithm {
+    max: usize,
+    duration: Duration,
+    // The admitted time of the last `max` requests
+    admitted_at: VecDeque<Instant>,
+}
+
+impl SlidingWindowAlgorithm {
+    pub fn new(max: usize, duration: Duration) -> Self {
+        SlidingWindowAlgorithm { max, duration, admitted_at: VecDeque::with_capacity(max) }
+    }
+}
+
+impl AdmitAlgorithm for SlidingWindowAlgorithm {
+    fn try_admit(&mut self, now: Instant, _weight: u32) -> AdmitResult {
+        if self.admitted_at.len() >= self.max {
+            match self.admitted_at.front() {
+                Some(at) if now.saturating_duration_since(*at) >= self.duration => {
+                    self.admitted_at.pop_front();
+                }
+                _ => return AdmitResult::Rejected,
+            }
+        }
+        self.admitted_at.push_back(now);
+        AdmitResult::Admitted
+    }
+
+    fn rollback(&mut self, _weight: u32) {
+        self.admitted_at.pop_back();
+    }
+
+    fn set_limit(&mut self, limit: usize) {
+        self.max = limit;
+        while self.admitted_at.len() > limit {
+            self.admitted_at.pop_front();
+        }
+    }
+
+    fn available(&self, now: Instant) -> Option<f64> {
+        let admitted = self
+            .admitted_at
+            .iter()
+            .filter(|at| now.saturating_duration_since(**at) < self.duration)
+            .count();
+        Some(self.max.saturating_sub(admitted) as f64)
+    }
+
+    fn window_started(&self, now: Instant) -> Option<Instant> {
+        self.admitted_at
+            .iter()
+            .find(|at| now.saturating_duration_since(**at) < self.duration)
+            .copied()
+    }
+}
+
+/// The token bucket holding up to `capacity` tokens, refilled by `refill_per_sec`.
+/// It is full when created, each request consumes one token, the weight only works
+/// with fixed window.
+#[derive(Debug, Clone)]
+pub struct TokenBucketAlgorithm {
+    capacity: u32,
+    refill_per_sec: f64,
+    // The remaining tokens and the last refill time
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucketAlgorithm {
+    pub fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        TokenBucketAlgorithm {
+            capacity,
+            refill_per_sec,
+            tokens: capacity as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        (self.tokens + elapsed * self.refill_per_sec).min(self.capacity as f64)
+    }
+}
+
+impl AdmitAlgorithm for TokenBucketAlgorithm {
+    // Refill the tokens lazily by the elapsed time, then consume one token
+    fn try_admit(&mut self, now: Instant, _weight: u32) -> AdmitResult {
+        self.tokens = self.tokens_at(now);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return AdmitResult::Rejected;
+        }
+        self.tokens -= 1.0;
+        AdmitResult::Admitted
+    }
+
+    fn rollback(&mut self, _weight: u32) {
+        self.tokens += 1.0;
+    }
+
+    fn available(&self, now: Instant) -> Option<f64> {
+        Some(self.tokens_at(now))
+    }
+
+    fn retry_after(&self, now: Instant) -> Option<Duration> {
+        let missing = 1.0 - self.tokens_at(now);
+        (missing > 0.0 && self.refill_per_sec > 0.0)
+            .then(|| Duration::from_secs_f64(missing / self.refill_per_sec))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fixed_window_algorithm() {
+        let mut algorithm = FixedWindowAlgorithm::new(3, Duration::from_secs(1));
+        let now = Instant::now();
+        assert_eq!(algorithm.try_admit(now, 2), AdmitResult::Admitted);
+        let later = now + Duration::from_millis(500);
+        assert_eq!(algorithm.try_admit(later, 2), AdmitResult::Rejected);
+        assert_eq!(algorithm.try_admit(later, 1), AdmitResult::Admitted);
+        assert_eq!(algorithm.available(later), Some(0.0));
+        assert_eq!(algorithm.window_started(later), Some(now));
+        assert_eq!(algorithm.retry_after(later), Some(Duration::from_millis(500)));
+        algorithm.rollback(1);
+        assert_eq!(algorithm.available(later), Some(1.0));
+
+        // the elapsed window is reset by the next request
+        let next = now + Duration::from_secs(1);
+        assert_eq!(algorithm.available(next), Some(3.0));
+        assert_eq!(algorithm.window_started(next), None);
+        assert_eq!(algorithm.try_admit(next, 3), AdmitResult::Admitted);
+        assert_eq!(algorithm.window_started(next), Some(next));
+
+        algorithm.set_limit(4);
+        assert_eq!(algorithm.available(next), Some(1.0));
+    }
+
+    #[test]
+    fn test_sliding_window_algorithm() {
+        let mut algorithm = SlidingWindowAlgorithm::new(2, Duration::from_secs(1));
+        let now = Instant::now();
+        assert_eq!(algorithm.try_admit(now, 1), AdmitResult::Admitted);
+        let later = now + Duration::from_millis(500);
+        assert_eq!(algorithm.try_admit(later, 1), AdmitResult::Admitted);
+        assert_eq!(algorithm.try_admit(later, 1), AdmitResult::Rejected);
+        assert_eq!(algorithm.available(later), Some(0.0));
+        assert_eq!(algorithm.window_started(later), Some(now));
+
+        // the first request leaves the window
+        let next = now + Duration::from_secs(1);
+        assert_eq!(algorithm.available(next), Some(1.0));
+        assert_eq!(algorithm.try_admit(next, 1), AdmitResult::Admitted);
+        algorithm.rollback(1);
+        assert_eq!(algorithm.try_admit(next, 1), AdmitResult::Admitted);
+        assert_eq!(algorithm.try_admit(next, 1), AdmitResult::Rejected);
+
+        algorithm.set_limit(1);
+        assert_eq!(algorithm.available(next), Some(0.0));
+    }
+
+    #[test]
+    fn test_token_bucket_algorithm() {
+        let mut algorithm = TokenBucketAlgorithm::new(2, 10.0);
+        let now = Instant::now();
+        assert_eq!(algorithm.try_admit(now, 1), AdmitResult::Admitted);
+        assert_eq!(algorithm.try_admit(now, 1), AdmitResult::Admitted);
+        assert_eq!(algorithm.try_admit(now, 1), AdmitResult::Rejected);
+        assert_eq!(algorithm.retry_after(now), Some(Duration::from_millis(100)));
+
+        // a token is refilled in 100ms, the tokens are capped by the capacity
+        let later = now + Duration::from_millis(100);
+        assert_eq!(algorithm.try_admit(later, 1), AdmitResult::Admitted);
+        assert_eq!(algorithm.available(later + Duration::from_secs(1)), Some(2.0));
+        algorithm.rollback(1);
+        assert_eq!(algorithm.available(later), Some(1.0));
+        assert_eq!(algorithm.retry_after(later), None);
+    }
+}
@@ -13,25 +13,195 @@
 // limitations under the License.
 
 use std::{
-    sync::Arc,
-    time::{Duration, Instant},
+    cell::RefCell,
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use aho_corasick::AhoCorasick;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use hdrhistogram::Histogram;
+use ipnet::IpNet;
+use lru::LruCache;
 use parking_lot::Mutex;
-use regex::Regex;
-use tokio::sync::Semaphore;
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::StdRng,
+    Rng, SeedableRng,
+};
+use regex::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    runtime::Handle,
+    sync::{AcquireError, Notify, OwnedSemaphorePermit, Semaphore, TryAcquireError},
+    task::JoinHandle,
+    time::MissedTickBehavior,
+};
 
+pub mod algorithm;
+pub mod store;
+
+use self::{
+    algorithm::{
+        AdmitAlgorithm, AdmitResult, FixedWindowAlgorithm, SlidingWindowAlgorithm,
+        TokenBucketAlgorithm,
+    },
+    store::ConcurrencyControlStore,
+};
 use crate::{
     config,
+    deadline::{stack_deadline, Deadlined},
     err::{BoxError, PluginError},
-    layer::{Layer, Service},
+    layer::{AsyncService, Bypass, Bypassable, Layer, Service},
+    sql::{classify, extract_limit, normalize, split_statements, StatementKind},
 };
 
 #[derive(Clone)]
 pub struct ConcurrencyControlLayer {
     config: Option<Vec<config::ConcurrencyControl>>,
+    options: BuildOptions,
+    // The rules compiled from `config` once, `layer()` only creates their fresh state
+    compiled: Arc<CompiledRules>,
+}
+
+/// The options of building the instances besides the rules, they are kept by `reload`
+#[derive(Debug, Clone, Default)]
+struct BuildOptions {
+    // The fallback rule applied to the queries matching no rule
+    default: Option<config::ConcurrencyControl>,
+    // The seed of the jitter added to the window durations, it is random if not set
+    jitter_seed: Option<u64>,
+    // The seed of selecting the rules of `WeightedOneOf`, it is random if not set
+    selection_seed: Option<u64>,
+    // The capacity of the backend set by `ConcurrencyControlLayer::set_capacity`,
+    // it is shared by all services built from the layer.
+    capacity: Arc<AtomicUsize>,
+    // The hook called with every decision
+    decision_logger: Option<DecisionLogger>,
+    // The kill switch of the builder, no rule is evaluated when it is set
+    bypass: Bypass,
+    // The capacity of the cache of the matched rules, `MATCH_CACHE_CAPACITY` if not set
+    match_cache: Option<usize>,
+    // The store of the rules with a remote backend, set by `ConcurrencyControlLayer::with_store`
+    store: Option<Arc<dyn ConcurrencyControlStore>>,
+    // The algorithms of the rules by index, set by `ConcurrencyControlLayer::with_algorithm`
+    algorithms: HashMap<usize, AlgorithmFactory>,
+    // The background eviction of the idle clients, set by `with_client_eviction`
+    client_eviction: Option<ClientEviction>,
+    // The rules matched by a combined regex, set by `with_named_ruleset`
+    named_ruleset: Option<NamedRuleset>,
+}
+
+/// The rules of `ConcurrencyControlLayer::with_named_ruleset`, `regex` is the alternation
+/// of the patterns of the rules, each in a capture group named by the rule
+#[derive(Debug, Clone)]
+struct NamedRuleset {
+    regex: Regex,
+    rules: Vec<(String, config::ConcurrencyControl)>,
+}
+
+/// The idle clients of the `PerClient` rules are evicted every `sweep_interval`
+/// by a task spawned for each service
+#[derive(Debug, Clone, Copy)]
+struct ClientEviction {
+    sweep_interval: Duration,
+    idle_ttl: Duration,
+}
+
+impl BuildOptions {
+    fn match_cache_capacity(&self) -> usize {
+        self.match_cache.unwrap_or(MATCH_CACHE_CAPACITY)
+    }
+}
+
+// The default number of queries whose matched rules are cached
+const MATCH_CACHE_CAPACITY: usize = 1024;
+// The longer queries are not cached, so the cache stays small
+const MATCH_CACHE_MAX_QUERY_BYTES: usize = 1024;
+
+type DecisionHook = dyn Fn(&ConcurrencyControlDecision, &ConcurrencyControlRules) + Send + Sync;
+
+/// The factory of the algorithm of a rule, each service built from the layer admits
+/// by its own algorithm
+#[derive(Clone)]
+struct AlgorithmFactory(Arc<dyn Fn() -> Box<dyn AdmitAlgorithm> + Send + Sync>);
+
+impl std::fmt::Debug for AlgorithmFactory {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("AlgorithmFactory")
+    }
+}
+
+/// The algorithm of a rule and its factory, a fresh instance creates a new algorithm
+#[derive(Clone)]
+struct RuleAlgorithm {
+    factory: AlgorithmFactory,
+    algorithm: Arc<Mutex<Box<dyn AdmitAlgorithm>>>,
+    // Whether the algorithm is set by `ConcurrencyControlLayer::with_algorithm`
+    custom: bool,
+}
+
+impl RuleAlgorithm {
+    fn new(factory: AlgorithmFactory, custom: bool) -> Self {
+        let algorithm = Arc::new(Mutex::new((factory.0)()));
+        RuleAlgorithm { factory, algorithm, custom }
+    }
+
+    // The built-in algorithm of the config
+    fn builtin(
+        algorithm: &config::ConcurrencyControlAlgorithm,
+        max_concurrency: usize,
+        duration: Duration,
+    ) -> Self {
+        let factory = match *algorithm {
+            config::ConcurrencyControlAlgorithm::FixedWindow => {
+                AlgorithmFactory(Arc::new(move || {
+                    Box::new(FixedWindowAlgorithm::new(max_concurrency, duration))
+                }))
+            }
+            config::ConcurrencyControlAlgorithm::SlidingWindow => {
+                AlgorithmFactory(Arc::new(move || {
+                    Box::new(SlidingWindowAlgorithm::new(max_concurrency, duration))
+                }))
+            }
+            config::ConcurrencyControlAlgorithm::TokenBucket { capacity, refill_per_sec } => {
+                AlgorithmFactory(Arc::new(move || {
+                    Box::new(TokenBucketAlgorithm::new(capacity, refill_per_sec))
+                }))
+            }
+        };
+        RuleAlgorithm::new(factory, false)
+    }
 }
 
+/// The hook set by `ConcurrencyControlLayer::with_decision_logger`
+#[derive(Clone)]
+struct DecisionLogger(Arc<DecisionHook>);
+
+impl std::fmt::Debug for DecisionLogger {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("DecisionLogger")
+    }
+}
+
+/// The compiled regexes of the rules, they are shared by the layered services
+#[derive(Debug)]
+struct CompiledRules {
+    instances: Vec<ConcurrencyControlInstance>,
+    matcher: Arc<ConcurrencyControlMatcher>,
+    // The stores of the rules by backend, they are reused when the rules are rebuilt
+    stores: Stores,
+}
+
+type Stores = HashMap<config::ConcurrencyControlBackend, Arc<dyn ConcurrencyControlStore>>;
+
 #[derive(Clone)]
 pub struct ConcurrencyControlConfig {
     pub regex: Vec<String>,
@@ -39,194 +209,6558 @@ pub struct ConcurrencyControlConfig {
     pub duration: Duration,
 }
 
-/// `Limit` instance
+impl From<ConcurrencyControlConfig> for config::ConcurrencyControl {
+    fn from(cfg: ConcurrencyControlConfig) -> Self {
+        config::ConcurrencyControl {
+            regex: cfg.regex,
+            max_concurrency: u32::try_from(cfg.max_concurrency).unwrap_or(u32::MAX),
+            duration: cfg.duration,
+            ..Default::default()
+        }
+    }
+}
+
+/// The input with the identity of client, the rules with `PerClient` scope
+/// limit each client separately by `client_id`.
 #[derive(Debug, Clone)]
-pub struct ConcurrencyControlInstance {
-    regex: Vec<Regex>,
-    max_concurrency: usize,
+pub struct ClientInput<I> {
+    pub client_id: String,
+    pub input: I,
+}
+
+/// The input with the address of the client connection, the rules with `source_cidr`
+/// only limit the requests from their network.
+#[derive(Debug, Clone)]
+pub struct SourceInput<I> {
+    pub source: SocketAddr,
+    pub input: I,
+}
+
+/// The priority of a request, see `PriorityInput`. It is unrelated to the `priority`
+/// of a rule, which orders the matched rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RequestPriority {
+    // The request may overdraw `high_priority_overdraft` permits of a used up fixed window,
+    // eg: the queries of the admin or the replication
+    High,
+    #[default]
+    Normal,
+}
+
+/// The input with the priority of the request
+#[derive(Debug, Clone)]
+pub struct PriorityInput<I> {
+    pub priority: RequestPriority,
+    pub input: I,
+}
+
+/// The permits of fixed window
+#[derive(Debug, Clone)]
+struct FixedWindow {
     semaphore: Arc<Semaphore>,
+    // The permits overdrawn by the `High` priority requests in flight
+    overdrawn: Arc<AtomicUsize>,
     // If the first match, the timing starts to take effect,
     // and duration `duration`
-    duration: Duration,
     start_at: Option<Instant>,
+    // Increased when the window is reset
+    generation: Arc<AtomicU64>,
+    // The permits granted in the current window and not released yet, the permits
+    // returned by `ConcurrencyControl::add_permits` are written off from them
+    outstanding: Arc<AtomicUsize>,
 }
 
-impl ConcurrencyControlLayer {
-    pub fn new(config: Vec<config::ConcurrencyControl>) -> ConcurrencyControlLayer {
-        ConcurrencyControlLayer { config: Some(config) }
-    }
-
-    pub fn with_opt(config: Option<Vec<config::ConcurrencyControl>>) -> ConcurrencyControlLayer {
-        ConcurrencyControlLayer { config }
-    }
-
-    fn create_instances(&self) -> Option<Vec<ConcurrencyControlInstance>> {
-        if let Some(config) = &self.config {
-            let mut instances = Vec::with_capacity(config.len());
-            for c in config {
-                // let regex = Regex::new(regex).unwrap();
-                let regex = c.regex.iter().map(|r| Regex::new(r).unwrap()).collect::<Vec<Regex>>();
-                let semaphore = Arc::new(Semaphore::new(c.max_concurrency as usize));
-                instances.push(ConcurrencyControlInstance {
-                    max_concurrency: c.max_concurrency as usize,
-                    regex,
-                    semaphore,
-                    duration: c.duration,
-                    start_at: None,
-                });
+/// The permit of fixed window, it is forgotten instead of released if the window
+/// has been reset since it was acquired, because the reset has restored the budget.
+/// Only the permits still outstanding are released, the others have been returned
+/// by `ConcurrencyControl::add_permits`, so the window never exceeds its limit.
+#[derive(Debug)]
+struct WindowPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    permits: usize,
+    semaphore: Arc<Semaphore>,
+    generation: Arc<AtomicU64>,
+    acquired_in: u64,
+    outstanding: Arc<AtomicUsize>,
+}
+
+impl Drop for WindowPermit {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            if self.generation.load(Ordering::Acquire) != self.acquired_in {
+                permit.forget();
+                return;
+            }
+            let outstanding = self
+                .outstanding
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                    Some(n.saturating_sub(self.permits))
+                })
+                .unwrap_or_default();
+            if outstanding < self.permits {
+                permit.forget();
+                self.semaphore.add_permits(outstanding);
             }
-            return Some(instances);
         }
-        None
     }
 }
 
-impl<S> Layer<S> for ConcurrencyControlLayer {
-    type Service = ConcurrencyControl<S>;
+impl FixedWindow {
+    fn new(max_concurrency: usize) -> Self {
+        FixedWindow {
+            semaphore: Arc::new(Semaphore::new(max_concurrency)),
+            overdrawn: Arc::default(),
+            start_at: None,
+            generation: Arc::new(AtomicU64::new(0)),
+            outstanding: Arc::default(),
+        }
+    }
 
-    fn layer(&self, inner: S) -> Self::Service {
-        let instances = self.create_instances();
-        let mut cc = ConcurrencyControl { inner, instances: None };
+    fn permit(&self, permit: OwnedSemaphorePermit, weight: u32) -> WindowPermit {
+        self.outstanding.fetch_add(weight as usize, Ordering::AcqRel);
+        WindowPermit {
+            permit: Some(permit),
+            permits: weight as usize,
+            semaphore: self.semaphore.clone(),
+            generation: self.generation.clone(),
+            acquired_in: self.generation.load(Ordering::Acquire),
+            outstanding: self.outstanding.clone(),
+        }
+    }
+
+    // Return a permit written off from the outstanding permits, it fails if the window
+    // has all permits of `max_concurrency` available
+    fn add_permit(&self, max_concurrency: usize) -> bool {
+        if self.semaphore.available_permits() >= max_concurrency {
+            return false;
+        }
+        let _ = self
+            .outstanding
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| Some(n.saturating_sub(1)));
+        self.semaphore.add_permits(1);
+        true
+    }
+
+    // Wait for `weight` permits, the queued waiters are served in FIFO order,
+    // and they keep waiting on the same semaphore across the window resets.
+    async fn acquire(&self, weight: u32) -> Result<WindowPermit, AcquireError> {
+        let permit = self.semaphore.clone().acquire_many_owned(weight).await?;
+        Ok(self.permit(permit, weight))
+    }
+
+    // Overdraw `weight` permits if fewer than `limit` permits are overdrawn in total,
+    // they are counted until the overdraft is dropped
+    fn overdraw(&self, weight: usize, limit: usize) -> Option<Overdraft> {
+        self.overdrawn
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n + weight <= limit).then_some(n + weight)
+            })
+            .ok()?;
+        Some(Overdraft { overdrawn: self.overdrawn.clone(), permits: weight })
+    }
+
+    // Whether the window was reset less than `cooldown` ago, the first window has no cooldown
+    fn cooling_down(&self, cooldown: Option<Duration>, now: Instant) -> bool {
+        match (cooldown, self.start_at) {
+            (Some(cooldown), Some(start_at)) => {
+                self.generation.load(Ordering::Acquire) > 0
+                    && now.saturating_duration_since(start_at) < cooldown
+            }
+            _ => false,
+        }
+    }
+
+    // Try to acquire `weight` permits, the window is reset first if it has elapsed.
+    // If the semaphore is acquired at the same time, the duration will be invalid.
+    // No permit is acquired in the `cooldown` after the reset.
+    fn try_acquire(
+        &mut self,
+        max_concurrency: usize,
+        duration: Duration,
+        weight: u32,
+        cooldown: Option<Duration>,
+    ) -> Result<WindowPermit, TryAcquireError> {
+        let now = Instant::now();
+        match self.start_at {
+            // first match, set start_at
+            None => self.start_at = Some(now),
+            // duration has invalid, enter next loop, restore the permits and reinit `start_at`
+            Some(start_at) if now.duration_since(start_at) >= duration => {
+                // the windows elapsed while idle are skipped, only the overflow of
+                // the current window is carried forward
+                let overflow = now
+                    .duration_since(start_at)
+                    .as_nanos()
+                    .checked_rem(duration.as_nanos())
+                    .unwrap_or_default();
+                self.start_at = Some(now - Duration::from_nanos(overflow as u64));
 
-        if let Some(instances) = instances {
-            cc.instances = Some(Arc::new(Mutex::new(instances)))
+                // The semaphore is never recreated, so the waiters are not dropped.
+                // The permits held by the requests in flight are forgotten when released.
+                self.generation.fetch_add(1, Ordering::AcqRel);
+                self.outstanding.store(0, Ordering::Release);
+                resize_semaphore(
+                    &self.semaphore,
+                    self.semaphore.available_permits(),
+                    max_concurrency,
+                );
+            }
+            _ => {}
+        }
+        if self.cooling_down(cooldown, now) {
+            return Err(TryAcquireError::NoPermits);
         }
 
-        cc
+        let permit = self.semaphore.clone().try_acquire_many_owned(weight)?;
+        Ok(self.permit(permit, weight))
     }
 }
 
+/// The fixed window of a client, it is evicted after being idle for `duration`
 #[derive(Debug, Clone)]
-pub struct ConcurrencyControl<S> {
-    inner: S,
-    instances: Option<Arc<Mutex<Vec<ConcurrencyControlInstance>>>>,
+struct ClientWindow {
+    window: FixedWindow,
+    last_seen: Instant,
 }
 
-impl<S> ConcurrencyControl<S> {
-    // If accquire success return true, otherwise return fasle
-    // If the semaphore is acquired at the same time, the duration will be invalid
-    fn is_allow(&mut self, input: &str) -> (Option<usize>, bool) {
-        if let Some(instances) = &self.instances {
-            let mut instances = instances.lock();
-            for (idx, c) in instances.iter_mut().enumerate() {
-                if !c.regex.iter().any(|r| r.is_match(input)) {
-                    continue;
+/// `Limit` instance
+#[derive(Clone)]
+pub struct ConcurrencyControlInstance {
+    // The rule as configured, the patterns set by `update_regex` are compiled from it
+    config: config::ConcurrencyControl,
+    regex: Vec<Regex>,
+    // The patterns of `Prefix` and `Exact` rules, they are not compiled into `regex`
+    literals: Vec<String>,
+    match_type: config::ConcurrencyControlMatchType,
+    enabled: bool,
+    // The rule installed by `ConcurrencyControlLayer::with_default`
+    fallback: bool,
+    // The group of the rule in the named ruleset, its patterns are matched by the
+    // combined regex instead of the `RegexSet`
+    group: Option<String>,
+    adaptive: Option<config::AdaptiveLimit>,
+    // The rejected and failed requests in the current window, used by adaptive limit
+    window_rejected: u64,
+    window_failed: u64,
+    max_concurrency: usize,
+    window: FixedWindow,
+    duration: Duration,
+    algorithm: config::ConcurrencyControlAlgorithm,
+    // The algorithm of the sliding window and token bucket rules,
+    // or the one set by `ConcurrencyControlLayer::with_algorithm`
+    admit: Option<RuleAlgorithm>,
+    acquire_timeout: Option<Duration>,
+    scope: config::ConcurrencyControlScope,
+    // The windows of each client, used by `PerClient` scope
+    clients: HashMap<String, ClientWindow>,
+    last_sweep: Instant,
+    // The idle time of the clients evicted by the background task, the clients are
+    // swept when they are looked up if not set
+    idle_ttl: Option<Duration>,
+    case_insensitive: bool,
+    match_normalized: bool,
+    split_multi_statement: bool,
+    // The limit in percent of the shared capacity, the capacity it was computed from,
+    // and the permits to remove from the shared window once they are released
+    percent: Option<u32>,
+    capacity: Arc<AtomicUsize>,
+    capacity_seen: usize,
+    shrink_debt: usize,
+    // The recent rejections and the end of the quarantine, used by `quarantine`
+    quarantine: Option<config::Quarantine>,
+    rejected_at: VecDeque<Instant>,
+    quarantined_until: Option<Instant>,
+    post_reset_cooldown: Option<Duration>,
+    // The warmup of the limit and the time the rule was built, it is kept on reload
+    warmup: Option<Duration>,
+    created_at: Instant,
+    soft_limit: Option<usize>,
+    source_cidr: Option<IpNet>,
+    // The keys seen in the current window and its start time, used by `key_group`
+    key_group: Option<usize>,
+    // The capture groups of the composite keys, each key has a window in `clients`
+    key_groups: Vec<usize>,
+    seen_keys: HashSet<String>,
+    keys_started: Option<Instant>,
+    action: config::ConcurrencyControlAction,
+    weight_regex: Option<Regex>,
+    except_regex: Option<Regex>,
+    weight: u32,
+    statement_kinds: Option<Vec<StatementKind>>,
+    mode: config::ConcurrencyControlMatchMode,
+    selection_weight: u32,
+    max_bytes: Option<usize>,
+    require_limit: Option<u64>,
+    dry_run: bool,
+    log_sample_rate: Option<f64>,
+    active_window: Option<config::ActiveWindow>,
+    // The pool of the rule, and the permits borrowed from the other rules of the pool
+    pool: Option<String>,
+    pool_max_borrow: Option<u32>,
+    borrowed: Arc<AtomicUsize>,
+    high_priority_overdraft: Option<u32>,
+    reject_message: Option<String>,
+    // The store counting the requests of the rule with a remote backend
+    store: Option<Arc<dyn ConcurrencyControlStore>>,
+}
+
+/// The admission of a rule, it is rolled back if another matched rule rejects the request
+#[derive(Debug)]
+enum Admission {
+    // The permit, whether the fixed window was reset by the request,
+    // and whether the window is used beyond the soft limit
+    Permit(WindowPermit, bool, bool),
+    // The request admitted by the algorithm of the rule, with its weight
+    Algorithm(u32),
+    // The new key added by the request, if the key has not been seen
+    Key(Option<String>),
+    // The permit borrowed from another rule of the pool
+    Borrowed(WindowPermit, Loan),
+    // The permits overdrawn by a `High` priority request
+    Overdraft(Overdraft),
+    // The request to be counted by the store once the instances are unlocked,
+    // it can not be rolled back once counted
+    Store(StoreCheck),
+}
+
+/// The request of a rule with a remote backend, counted by `store` under `key`
+#[derive(Debug)]
+struct StoreCheck {
+    store: Arc<dyn ConcurrencyControlStore>,
+    key: String,
+    limit: u64,
+    window: Duration,
+}
+
+/// The permits borrowed by a rule of a pool, they are no longer counted once dropped
+#[derive(Debug)]
+struct Loan {
+    borrowed: Arc<AtomicUsize>,
+    permits: usize,
+}
+
+impl Drop for Loan {
+    fn drop(&mut self) {
+        self.borrowed.fetch_sub(self.permits, Ordering::AcqRel);
+    }
+}
+
+/// The permits overdrawn from a used up fixed window, they are returned once dropped
+#[derive(Debug)]
+struct Overdraft {
+    overdrawn: Arc<AtomicUsize>,
+    permits: usize,
+}
+
+impl Drop for Overdraft {
+    fn drop(&mut self) {
+        self.overdrawn.fetch_sub(self.permits, Ordering::AcqRel);
+    }
+}
+
+impl ConcurrencyControlInstance {
+    // Return the window of `client`, the idle clients are evicted every `duration`
+    // unless they are evicted by the background task
+    fn client_window(&mut self, client: &str) -> &mut FixedWindow {
+        let now = Instant::now();
+        if self.idle_ttl.is_none() && now.duration_since(self.last_sweep) >= self.duration {
+            self.evict_idle_clients(now, self.duration);
+        }
+
+        let max_concurrency = self.max_concurrency;
+        let client = self.clients.entry(client.to_string()).or_insert_with(|| ClientWindow {
+            window: FixedWindow::new(max_concurrency),
+            last_seen: now,
+        });
+        client.last_seen = now;
+        &mut client.window
+    }
+
+    // Evict the clients idle for `ttl`, the clients which still hold permits are kept
+    fn evict_idle_clients(&mut self, now: Instant, ttl: Duration) {
+        let max_concurrency = self.max_concurrency;
+        self.clients.retain(|_, c| {
+            now.saturating_duration_since(c.last_seen) < ttl
+                || c.window.semaphore.available_permits() < max_concurrency
+                || c.window.overdrawn.load(Ordering::Acquire) > 0
+        });
+        self.last_sweep = now;
+    }
+
+    // Try to admit the request by the algorithm, return None if it is rejected
+    fn try_admit(&mut self, input: &str, client: Option<&str>) -> Result<Admission, RejectReason> {
+        let quarantine = match self.quarantine {
+            Some(quarantine) => quarantine,
+            None => return self.try_admit_rule(input, client),
+        };
+
+        let now = Instant::now();
+        if self.quarantined(now) {
+            return Err(RejectReason::Quarantined);
+        }
+        let res = self.try_admit_rule(input, client);
+        if res.is_err() {
+            self.count_rejection(quarantine, now);
+        }
+        res
+    }
+
+    // Count the rejections within the last `duration`, the rule is quarantined once
+    // `after_rejections` are counted
+    fn count_rejection(&mut self, quarantine: config::Quarantine, now: Instant) {
+        while self.rejected_at.front().is_some_and(|at| now.duration_since(*at) >= self.duration) {
+            self.rejected_at.pop_front();
+        }
+        self.rejected_at.push_back(now);
+        if self.rejected_at.len() >= quarantine.after_rejections {
+            self.quarantined_until = Some(now + quarantine.for_duration);
+            self.rejected_at.clear();
+        }
+    }
+
+    fn quarantined(&self, now: Instant) -> bool {
+        self.quarantined_until.is_some_and(|until| now < until)
+    }
+
+    fn try_admit_rule(
+        &mut self,
+        input: &str,
+        client: Option<&str>,
+    ) -> Result<Admission, RejectReason> {
+        // the algorithm set by `with_algorithm` replaces the backend and the key groups
+        if self.admit.as_ref().is_some_and(|a| a.custom) {
+            return self.try_admit_algorithm(input);
+        }
+        if let Some(store) = &self.store {
+            return Ok(self.store_check(store.clone(), client));
+        }
+        if let Some(group) = self.key_group {
+            return self.try_admit_key(input, group);
+        }
+        if self.admit.is_some() {
+            return self.try_admit_algorithm(input);
+        }
+
+        if self.adaptive.is_some() {
+            self.adapt();
+        }
+        if self.percent.is_some() {
+            self.sync_capacity();
+        }
+        if self.shrink_debt > 0 {
+            self.pay_shrink_debt();
+        }
+
+        let shared_generation = self.window.generation.load(Ordering::Acquire);
+        let (max_concurrency, duration, weight, cooldown) =
+            (self.max_concurrency, self.duration, self.weight(input), self.post_reset_cooldown);
+        let warmup_limit = self.warmup_limit(Instant::now());
+        let soft_limit = self.soft_limit;
+        let key = self.composite_key(input);
+        let window = match (&self.scope, client, key) {
+            (_, _, Some(key)) => self.client_window(&key),
+            (config::ConcurrencyControlScope::PerClient, Some(client), None) => {
+                self.client_window(client)
+            }
+            _ => &mut self.window,
+        };
+
+        let generation = window.generation.load(Ordering::Acquire);
+        let res = window
+            .try_acquire(max_concurrency, duration, weight, cooldown)
+            .map_err(|_| match window.cooling_down(cooldown, Instant::now()) {
+                true => RejectReason::CoolingDown,
+                false => RejectReason::PermitsExhausted,
+            })
+            .and_then(|permit| {
+                // the permit is released if the window is used beyond the warmup limit
+                let used = max_concurrency - window.semaphore.available_permits();
+                if warmup_limit.is_some_and(|limit| used > limit) {
+                    return Err(RejectReason::PermitsExhausted);
                 }
+                let reset = permit.acquired_in != generation;
+                Ok(Admission::Permit(permit, reset, soft_limit.is_some_and(|soft| used > soft)))
+            });
+        if res.is_err() {
+            self.window_rejected += 1;
+        }
+        // the reset resizes the semaphore and forgets the permits in flight
+        if self.window.generation.load(Ordering::Acquire) != shared_generation {
+            self.shrink_debt = 0;
+        }
+        res
+    }
 
-                if c.start_at.is_none() {
-                    // first match, set start_at
-                    c.start_at = Some(Instant::now());
-                    let permit = c.semaphore.clone().try_acquire_owned();
+    // Recompute the limit if the shared capacity has changed, the limit is `max_concurrency`
+    // until the capacity is set.
+    fn sync_capacity(&mut self) {
+        let capacity = self.capacity.load(Ordering::Acquire);
+        if let Some(percent) =
+            self.percent.filter(|_| capacity != 0 && capacity != self.capacity_seen)
+        {
+            self.capacity_seen = capacity;
+            self.resize((capacity * percent as usize / 100).max(1));
+        }
+    }
 
-                    if permit.is_err() {
-                        return (Some(idx), false);
-                    }
-                    permit.unwrap().forget();
-                    return (Some(idx), true);
-                } else {
-                    // duration has invalid, return true
-                    if c.start_at.unwrap().elapsed() > c.duration {
-                        // enter next loop, reinit `Semaphore` and `start_at`
-                        c.start_at = None;
-                        c.semaphore = Arc::new(Semaphore::new(c.max_concurrency));
-                        return (None, true);
-                    } else {
-                        let permit = c.clone().semaphore.try_acquire_owned();
-                        if permit.is_err() {
-                            return (Some(idx), false);
-                        }
-                        permit.unwrap().forget();
-                        return (Some(idx), true);
-                    }
+    // Change the limit without resetting the window. The extra permits are available
+    // at once, but the permits held by the requests in flight can not be removed,
+    // they are forgotten when released, so the window never exceeds the new limit.
+    fn resize(&mut self, limit: usize) {
+        let old = std::mem::replace(&mut self.max_concurrency, limit);
+        if limit > old {
+            let added = (limit - old).saturating_sub(self.shrink_debt);
+            self.shrink_debt = self.shrink_debt.saturating_sub(limit - old);
+            self.window.semaphore.add_permits(added);
+        } else {
+            self.shrink_debt += old - limit;
+        }
+        for client in self.clients.values() {
+            resize_semaphore(&client.window.semaphore, old, limit);
+        }
+        // the limit of the algorithm set by `with_algorithm` is its own
+        if let Some(admit) = self.admit.as_ref().filter(|a| !a.custom) {
+            admit.algorithm.lock().set_limit(limit);
+        }
+        self.pay_shrink_debt();
+    }
+
+    // Forget the released permits owed by shrinking the limit
+    fn pay_shrink_debt(&mut self) {
+        let n = self.shrink_debt.min(self.window.semaphore.available_permits());
+        if let Ok(permits) = self.window.semaphore.try_acquire_many(n as u32) {
+            permits.forget();
+            self.shrink_debt -= n;
+        }
+    }
+
+    // The request to be admitted by the store, the `PerClient` rules count each client
+    // by its key
+    fn store_check(
+        &self,
+        store: Arc<dyn ConcurrencyControlStore>,
+        client: Option<&str>,
+    ) -> Admission {
+        let mut key = self.patterns().join(",");
+        if let (config::ConcurrencyControlScope::PerClient, Some(client)) = (&self.scope, client) {
+            key = format!("{}:{}", key, client);
+        }
+        Admission::Store(StoreCheck {
+            store,
+            key,
+            limit: self.max_concurrency as u64,
+            window: self.duration,
+        })
+    }
+
+    // Admit the request if its key has been seen in the window, or fewer than
+    // `max_concurrency` keys have been seen. The requests without the key are admitted.
+    fn try_admit_key(&mut self, input: &str, group: usize) -> Result<Admission, RejectReason> {
+        let now = Instant::now();
+        match self.keys_started {
+            Some(at) if now.duration_since(at) < self.duration => {}
+            _ => {
+                self.keys_started = Some(now);
+                self.seen_keys.clear();
+            }
+        }
+
+        match self.key(input, group) {
+            Some(key) if !self.seen_keys.contains(&key) => {
+                if self.seen_keys.len() >= self.max_concurrency {
+                    return Err(RejectReason::DistinctKeysExhausted);
                 }
+                self.seen_keys.insert(key.clone());
+                Ok(Admission::Key(Some(key)))
             }
+            _ => Ok(Admission::Key(None)),
         }
+    }
 
-        (None, true)
+    // Admit the request by the algorithm of the rule
+    fn try_admit_algorithm(&self, input: &str) -> Result<Admission, RejectReason> {
+        let admit = self.admit.as_ref().expect("the rule has an algorithm");
+        let weight = self.weight(input);
+        match admit.algorithm.lock().try_admit(Instant::now(), weight) {
+            AdmitResult::Admitted => Ok(Admission::Algorithm(weight)),
+            AdmitResult::Rejected if admit.custom => Err(RejectReason::AlgorithmRejected),
+            AdmitResult::Rejected => match self.algorithm {
+                config::ConcurrencyControlAlgorithm::TokenBucket { .. } => {
+                    Err(RejectReason::TokensExhausted)
+                }
+                _ => Err(RejectReason::SlidingWindowFull),
+            },
+        }
     }
 
-    pub fn add_permits(&mut self, idx: usize) {
-        let instances = self.instances.as_mut().unwrap().lock();
-        instances[idx].semaphore.add_permits(1)
+    // Return the capture group `group` of the first matching regex
+    fn key(&self, input: &str, group: usize) -> Option<String> {
+        // the regexes of the rule are matched against the normalized query
+        let digest = self.match_normalized.then(|| normalize(input));
+        let input = digest.as_deref().unwrap_or(input);
+        let key = self.regex.iter().find_map(|r| r.captures(input)?.get(group))?;
+        Some(key.as_str().to_string())
     }
-}
 
-impl<S, Input> Service<Input> for ConcurrencyControl<S>
-where
-    S: Service<Input>,
-    Input: AsRef<str>,
-    S::Error: Into<BoxError>,
-{
-    type Output = (Option<usize>, S::Output);
-    type Error = BoxError;
+    // Return the limit during the warmup, it ramps linearly from 1 to `max_concurrency`.
+    // Return None once the warmup has finished.
+    fn warmup_limit(&self, now: Instant) -> Option<usize> {
+        let warmup = self.warmup?;
+        let elapsed = now.saturating_duration_since(self.created_at);
+        (elapsed < warmup).then(|| {
+            let ramp = (self.max_concurrency - 1) as f64 * elapsed.as_secs_f64();
+            1 + (ramp / warmup.as_secs_f64()) as usize
+        })
+    }
 
-    fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
-        let (idx, is_allow) = self.is_allow(input.as_ref());
-        if is_allow {
-            let res = self.inner.handle(input).map_err(Into::into);
-            match res {
-                Ok(out) => return Ok((idx, out)),
-                Err(e) => return Err(e),
+    // Lend `weight` permits of the shared window if they are spare
+    fn try_lend(&mut self, weight: u32) -> Option<WindowPermit> {
+        if self.algorithm != config::ConcurrencyControlAlgorithm::FixedWindow {
+            return None;
+        }
+        self.window
+            .try_acquire(self.max_concurrency, self.duration, weight, self.post_reset_cooldown)
+            .ok()
+    }
+
+    // Return the capture groups `key_groups` of the first matching regex joined into a key,
+    // return None if any group is not matched
+    fn composite_key(&self, input: &str) -> Option<String> {
+        if self.key_groups.is_empty() {
+            return None;
+        }
+        let digest = self.match_normalized.then(|| normalize(input));
+        let input = digest.as_deref().unwrap_or(input);
+        let captures = self.regex.iter().find_map(|r| r.captures(input))?;
+        let parts = self
+            .key_groups
+            .iter()
+            .map(|group| captures.get(*group).map(|m| m.as_str()))
+            .collect::<Option<Vec<_>>>()?;
+        // the unit separator does not appear in the queries
+        Some(parts.join("\u{1f}"))
+    }
+
+    // Adjust the limit before the elapsed shared window is reset by the request,
+    // the semaphore is resized to the new limit by the reset.
+    fn adapt(&mut self) {
+        let adaptive = match self.adaptive {
+            Some(adaptive)
+                if self.window.start_at.is_some_and(|at| at.elapsed() >= self.duration) =>
+            {
+                adaptive
+            }
+            _ => return,
+        };
+
+        let limit = self.max_concurrency as u32;
+        let limit = if self.window_failed > 0 {
+            limit / 2
+        } else if self.window_rejected == 0 {
+            limit.saturating_add(1)
+        } else {
+            limit
+        };
+        self.max_concurrency = limit.max(adaptive.min).min(adaptive.max) as usize;
+        self.window_rejected = 0;
+        self.window_failed = 0;
+    }
+
+    // Undo the admission, the permit of fixed window is released when dropped
+    fn rollback(&mut self, admission: Admission) {
+        match admission {
+            Admission::Permit(..)
+            | Admission::Key(None)
+            | Admission::Borrowed(..)
+            | Admission::Overdraft(..)
+            | Admission::Store(_) => {}
+            Admission::Algorithm(weight) => {
+                if let Some(admit) = &self.admit {
+                    admit.algorithm.lock().rollback(weight);
+                }
+            }
+            Admission::Key(Some(key)) => {
+                self.seen_keys.remove(&key);
             }
         }
+    }
 
-        Err(Box::new(PluginError::ConcurrencyControlPluginReject))
+    // Overdraw the shared window for a `High` priority request, the overdraft is
+    // shared by all clients of the rule
+    fn try_overdraw(&self, input: &str) -> Option<Admission> {
+        let limit = self.high_priority_overdraft? as usize;
+        if self.algorithm != config::ConcurrencyControlAlgorithm::FixedWindow
+            || self.key_group.is_some()
+        {
+            return None;
+        }
+        self.window.overdraw(self.weight(input) as usize, limit).map(Admission::Overdraft)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::{
-        thread::{self, sleep},
-        time::Duration,
-    };
+    // Return the permits consumed by `input`
+    fn weight(&self, input: &str) -> u32 {
+        match &self.weight_regex {
+            Some(r) if r.is_match(input) => self.weight,
+            _ => 1,
+        }
+    }
 
-    use super::ConcurrencyControlLayer;
-    use crate::{
-        config,
-        err::PluginError,
-        layer::{service_fn, Service, ServiceBuilder},
-    };
+    fn try_new(c: &config::ConcurrencyControl) -> Result<Self, PluginError> {
+        let build = |r: &String| {
+            let mut builder = RegexBuilder::new(r);
+            builder.case_insensitive(c.case_insensitive);
+            if let Some(limit) = c.regex_size_limit {
+                builder.size_limit(limit);
+            }
+            if let Some(limit) = c.dfa_size_limit {
+                builder.dfa_size_limit(limit);
+            }
+            builder.build().map_err(|e| match e {
+                regex::Error::CompiledTooBig(limit) => {
+                    PluginError::RegexTooComplex { regex: r.clone(), limit }
+                }
+                e => PluginError::InvalidConcurrencyControlRegex { regex: r.clone(), source: e },
+            })
+        };
+        let (regex, literals) = match c.match_type {
+            config::ConcurrencyControlMatchType::Regex => {
+                (c.regex.iter().map(build).collect::<Result<Vec<Regex>, PluginError>>()?, vec![])
+            }
+            _ => (vec![], c.regex.clone()),
+        };
+        let weight_regex = c.weight_regex.as_ref().map(build).transpose()?;
+        let except_regex = c.except_regex.as_ref().map(build).transpose()?;
+        if let Some(max_group) = c.key_group.into_iter().chain(c.key_groups.clone()).max() {
+            let errors: Vec<String> = regex
+                .iter()
+                .filter(|r| r.captures_len() <= max_group)
+                .map(|r| format!("regex {} has no capture group {}", r, max_group))
+                .collect();
+            if !errors.is_empty() {
+                return Err(PluginError::InvalidConcurrencyControlConfig { errors });
+            }
+        }
+        // the initial limit of adaptive limit is in the bounds
+        let max_concurrency = match c.adaptive {
+            Some(adaptive) => c.max_concurrency.max(adaptive.min).min(adaptive.max),
+            None => c.max_concurrency,
+        } as usize;
+        let mut instance = ConcurrencyControlInstance {
+            config: c.clone(),
+            max_concurrency,
+            regex,
+            literals,
+            match_type: c.match_type,
+            enabled: c.enabled,
+            match_normalized: c.match_normalized,
+            split_multi_statement: c.split_multi_statement,
+            key_group: c.key_group,
+            key_groups: c.key_groups.clone(),
+            quarantine: c.quarantine,
+            rejected_at: VecDeque::new(),
+            quarantined_until: None,
+            post_reset_cooldown: c.post_reset_cooldown,
+            warmup: c.warmup,
+            created_at: Instant::now(),
+            soft_limit: c.soft_limit.map(|limit| limit as usize),
+            source_cidr: c.source_cidr,
+            percent: c.max_concurrency_percent,
+            capacity: Arc::default(),
+            capacity_seen: 0,
+            shrink_debt: 0,
+            seen_keys: HashSet::new(),
+            keys_started: None,
+            fallback: false,
+            group: None,
+            adaptive: c.adaptive,
+            window_rejected: 0,
+            window_failed: 0,
+            window: FixedWindow::new(max_concurrency),
+            duration: c.duration,
+            algorithm: c.algorithm.clone(),
+            admit: None,
+            acquire_timeout: c.acquire_timeout,
+            case_insensitive: c.case_insensitive,
+            action: c.action.clone(),
+            weight_regex,
+            except_regex,
+            weight: c.weight.unwrap_or(1),
+            statement_kinds: c.statement_kinds.clone(),
+            mode: c.mode.clone(),
+            selection_weight: c.selection_weight.unwrap_or(1),
+            max_bytes: c.max_bytes,
+            require_limit: c.require_limit,
+            dry_run: c.dry_run,
+            log_sample_rate: c.log_sample_rate,
+            active_window: c.active_window,
+            pool: c.pool.clone(),
+            pool_max_borrow: c.pool_max_borrow,
+            borrowed: Arc::default(),
+            high_priority_overdraft: c.high_priority_overdraft,
+            reject_message: c.reject_message.clone(),
+            store: None,
+            scope: c.scope.clone(),
+            clients: HashMap::new(),
+            last_sweep: Instant::now(),
+            idle_ttl: None,
+        };
+        instance.admit = instance.builtin_admit();
+        Ok(instance)
+    }
 
-    fn test_service(input: &str) -> Result<String, PluginError> {
-        sleep(Duration::new(5, 0));
-        Ok(input.to_string())
+    // The built-in algorithm admitting the requests of the rule, None for the fixed window
+    // of the config, it is admitted by the permits held until the requests complete
+    fn builtin_admit(&self) -> Option<RuleAlgorithm> {
+        (self.algorithm != config::ConcurrencyControlAlgorithm::FixedWindow)
+            .then(|| RuleAlgorithm::builtin(&self.algorithm, self.max_concurrency, self.duration))
     }
 
-    #[test]
-    fn test_concurrency_control() {
-        let config = vec![config::ConcurrencyControl {
-            regex: vec![String::from(r"[A-Za-z]+$")],
-            max_concurrency: 3,
-            duration: Duration::new(50, 0),
-        }];
+    // Return the patterns of the rule as configured
+    fn patterns(&self) -> Vec<String> {
+        self.regex.iter().map(|r| r.as_str().to_string()).chain(self.literals.clone()).collect()
+    }
 
-        let svc = service_fn(test_service);
+    // Replace the patterns of the rule with `patterns`, they are validated and compiled as
+    // the configured rule. The rule is unchanged if any pattern is invalid.
+    fn set_patterns(&mut self, patterns: Vec<String>) -> Result<(), PluginError> {
+        if self.group.is_some() {
+            return Err(PluginError::InvalidConcurrencyControlConfig {
+                errors: vec![String::from("the regex of a named ruleset rule can not be updated")],
+            });
+        }
+        let config = config::ConcurrencyControl { regex: patterns, ..self.config.clone() };
+        config.validate()?;
+        let ConcurrencyControlInstance { regex, literals, .. } = Self::try_new(&config)?;
+        self.regex = regex;
+        self.literals = literals;
+        self.config = config;
+        Ok(())
+    }
 
-        let wrap_svc =
-            ServiceBuilder::new().with_layer(ConcurrencyControlLayer::new(config)).build(svc);
+    // Return a copy sharing the compiled regexes, with the state of a new instance
+    fn fresh(&self) -> Self {
+        let now = Instant::now();
+        ConcurrencyControlInstance {
+            window: FixedWindow::new(self.max_concurrency),
+            admit: self.admit.as_ref().map(|a| RuleAlgorithm::new(a.factory.clone(), a.custom)),
+            clients: HashMap::new(),
+            last_sweep: now,
+            seen_keys: HashSet::new(),
+            keys_started: None,
+            rejected_at: VecDeque::new(),
+            quarantined_until: None,
+            borrowed: Arc::default(),
+            created_at: now,
+            ..self.clone()
+        }
+    }
 
-        let mut tasks = vec![];
-        for _ in 0..5 {
-            let mut svc = wrap_svc.clone();
-            //sleep(Duration::new(3, 0));
-            tasks.push(thread::spawn(move || svc.handle("abc")))
+    /// Build an instance from `ConcurrencyControlConfig`, for the rules built programmatically.
+    pub fn from_config(cfg: &ConcurrencyControlConfig) -> Result<Self, PluginError> {
+        Self::try_new(&cfg.clone().into())
+    }
+
+    /// Return the current tokens of token bucket, it always returns 0 for other algorithms.
+    pub fn available_tokens(&self) -> f64 {
+        match (&self.algorithm, &self.admit) {
+            (config::ConcurrencyControlAlgorithm::TokenBucket { .. }, Some(admit)) => {
+                admit.algorithm.lock().available(Instant::now()).unwrap_or_default()
+            }
+            _ => 0.0,
         }
+    }
 
-        let mut count = 0;
-        for t in tasks {
-            let res = t.join();
-            println!("{:?}", res);
-            let res = res.unwrap();
-            match res {
-                Ok(_) => count += 1,
-                Err(e) => {
-                    let e = e.downcast::<PluginError>().unwrap();
-                    assert_eq!(*e, PluginError::ConcurrencyControlPluginReject);
+    // Copy the current state of the rule, the `PerClient` rules report the shared window
+    fn snapshot(&self) -> ConcurrencyControlRuleSnapshot {
+        let now = Instant::now();
+        let (available_permits, window_started) = self.available(now);
+        ConcurrencyControlRuleSnapshot {
+            regex: self.patterns(),
+            max_concurrency: self.max_concurrency,
+            available_permits,
+            window_started,
+            window_remaining: window_started
+                .map(|at| self.duration.saturating_sub(now.duration_since(at))),
+        }
+    }
+
+    // Return the state of the current fixed window, None if it has not started or has elapsed
+    fn export_state(&self, now: Instant) -> Option<ConcurrencyControlRuleState> {
+        if self.algorithm != config::ConcurrencyControlAlgorithm::FixedWindow
+            || self.scope != config::ConcurrencyControlScope::Global
+            || self.key_group.is_some()
+        {
+            return None;
+        }
+        let elapsed = now.duration_since(self.window.start_at?);
+        (elapsed < self.duration).then(|| ConcurrencyControlRuleState {
+            regex: self.patterns(),
+            elapsed,
+            consumed: self
+                .max_concurrency
+                .saturating_sub(self.window.semaphore.available_permits()),
+        })
+    }
+
+    // Start the window `state.elapsed` ago with `state.consumed` permits consumed.
+    // Nothing releases the consumed permits, they are restored when the window is reset.
+    fn import_state(&mut self, state: &ConcurrencyControlRuleState, now: Instant) {
+        let start_at = match now.checked_sub(state.elapsed) {
+            Some(start_at) if state.elapsed < self.duration => start_at,
+            _ => return,
+        };
+        self.window.start_at = Some(start_at);
+        let available = self.window.semaphore.available_permits();
+        resize_semaphore(
+            &self.window.semaphore,
+            available,
+            self.max_concurrency.saturating_sub(state.consumed),
+        );
+    }
+
+    // Return the available permits and the start of the current window at `now`
+    fn available(&self, now: Instant) -> (usize, Option<Instant>) {
+        match self.algorithm {
+            // the keys of the elapsed window are cleared by the next request
+            _ if self.key_group.is_some() => match self.keys_started {
+                Some(at) if now.duration_since(at) >= self.duration => (self.max_concurrency, None),
+                at => (self.max_concurrency.saturating_sub(self.seen_keys.len()), at),
+            },
+            _ if self.admit.is_some() => {
+                let algorithm = self.admit.as_ref().expect("checked above").algorithm.lock();
+                match algorithm.available(now) {
+                    Some(available) => (available as usize, algorithm.window_started(now)),
+                    None => (self.max_concurrency, None),
                 }
             }
+            config::ConcurrencyControlAlgorithm::FixedWindow => match self.window.start_at {
+                // the elapsed window is reset by the next request
+                Some(at) if now.duration_since(at) >= self.duration => (self.max_concurrency, None),
+                at => (self.window.semaphore.available_permits(), at),
+            },
+            _ => (self.max_concurrency, None),
         }
+    }
 
-        assert_eq!(count, 3)
+    // Return the time until the rule may admit a request after `now`, it is the rest of the
+    // window, or the time to refill one token of token bucket. Return None if the rule
+    // would admit a request now or the time is unknown.
+    fn retry_after(&self, now: Instant) -> Option<Duration> {
+        if let Some(until) = self.quarantined_until.filter(|until| *until > now) {
+            return Some(until - now);
+        }
+        if let Some(admit) = &self.admit {
+            let algorithm = admit.algorithm.lock();
+            if let Some(after) = algorithm.retry_after(now) {
+                return Some(after);
+            }
+            if matches!(self.algorithm, config::ConcurrencyControlAlgorithm::TokenBucket { .. }) {
+                return None;
+            }
+        }
+        let (_, window_started) = self.available(now);
+        if let Some(cooldown) = self.post_reset_cooldown {
+            if self.window.cooling_down(Some(cooldown), now) {
+                return window_started.map(|at| cooldown.saturating_sub(now.duration_since(at)));
+            }
+        }
+        window_started.map(|at| self.duration.saturating_sub(now.duration_since(at)))
+    }
+
+    // Whether the request would be admitted now, the state is not changed.
+    // The `PerClient` rules are checked against the shared window.
+    fn would_admit(&self, input: &str) -> bool {
+        let now = Instant::now();
+        if self.quarantined(now) || self.window.cooling_down(self.post_reset_cooldown, now) {
+            return false;
+        }
+        let (available, _) = self.available(now);
+        match self.key_group {
+            Some(group) => {
+                available > 0 || self.key(input, group).is_some_and(|k| self.seen_keys.contains(&k))
+            }
+            None if self.algorithm == config::ConcurrencyControlAlgorithm::FixedWindow => {
+                // the permits beyond the warmup limit are not available
+                let held = self.warmup_limit(now).map_or(0, |limit| self.max_concurrency - limit);
+                available.saturating_sub(held) >= self.weight(input) as usize
+            }
+            None => available > 0,
+        }
+    }
+}
+
+// The compiled regexes and the semaphores are noisy in the logs,
+// only the patterns and the state of the current window are shown.
+impl std::fmt::Debug for ConcurrencyControlInstance {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let snapshot = self.snapshot();
+        f.debug_struct("ConcurrencyControlInstance")
+            .field("regex", &snapshot.regex)
+            .field("algorithm", &self.algorithm)
+            .field("max_concurrency", &snapshot.max_concurrency)
+            .field("available_permits", &snapshot.available_permits)
+            .field("window_remaining", &snapshot.window_remaining)
+            .finish()
+    }
+}
+
+/// The result of evaluating a sample query by `ConcurrencyControlLayer::self_test`,
+/// the rule indexes are in the evaluated order, the same as `stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleResult {
+    pub sample: String,
+    pub matched_rules: Vec<usize>,
+    pub allowed: bool,
+    pub reason: Option<RejectReason>,
+}
+
+/// The evaluation of a request captured after `ConcurrencyControl::trace_next`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecisionTrace {
+    pub query: String,
+    // A step for each rule in the evaluated order
+    pub steps: Vec<TraceStep>,
+    pub allowed: bool,
+    pub reason: Option<RejectReason>,
+    pub rule_index: Option<usize>,
+}
+
+/// The state of a rule when the traced request was decided, the disabled rules are not
+/// checked, and `available_permits` is counted after the request has been admitted
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceStep {
+    pub rule_index: usize,
+    pub regex: Vec<String>,
+    pub checked: bool,
+    pub matched: bool,
+    pub available_permits: usize,
+}
+
+// The captured traces are kept up to this count, the old ones are dropped first
+const TRACE_CAPACITY: usize = 64;
+
+/// The requests to trace and the captured traces, shared by all clones of the service
+#[derive(Debug, Default)]
+struct Tracer {
+    pending: AtomicUsize,
+    traces: Mutex<VecDeque<DecisionTrace>>,
+}
+
+impl ConcurrencyControlLayer {
+    pub fn new(
+        config: Vec<config::ConcurrencyControl>,
+    ) -> Result<ConcurrencyControlLayer, PluginError> {
+        Self::with_opt(Some(config))
+    }
+
+    /// Create the layer from `ConcurrencyControlConfig`, for the rules built programmatically
+    /// instead of loaded from the config file.
+    pub fn from_concurrency_control_configs(
+        configs: Vec<ConcurrencyControlConfig>,
+    ) -> Result<ConcurrencyControlLayer, PluginError> {
+        Self::new(configs.into_iter().map(Into::into).collect())
+    }
+
+    pub fn with_opt(
+        config: Option<Vec<config::ConcurrencyControl>>,
+    ) -> Result<ConcurrencyControlLayer, PluginError> {
+        if let Some(config) = &config {
+            validate(config)?;
+        }
+
+        let options = BuildOptions::default();
+        let compiled = Arc::new(compile(config.as_deref(), &options, &Stores::new())?);
+        Ok(ConcurrencyControlLayer { config, options, compiled })
+    }
+
+    /// Install a fixed window rule evaluated last, it limits the queries matching no other
+    /// rule, so it never shadows the specific rules. The queries matching an `Allow` rule
+    /// are not limited by it.
+    pub fn with_default(
+        mut self,
+        max_concurrency: u32,
+        duration: Duration,
+    ) -> Result<ConcurrencyControlLayer, PluginError> {
+        let default =
+            config::ConcurrencyControl { max_concurrency, duration, ..Default::default() };
+        // the fallback rule has no regex, it is validated as a catch-all rule
+        let catch_all =
+            config::ConcurrencyControl { regex: vec![String::from(".*")], ..default.clone() };
+        if let Err(PluginError::InvalidConcurrencyControlConfig { errors }) = catch_all.validate() {
+            let errors = errors.into_iter().map(|e| format!("default rule: {}", e)).collect();
+            return Err(PluginError::InvalidConcurrencyControlConfig { errors });
+        }
+
+        self.options.default = Some(default);
+        self.compiled =
+            Arc::new(compile(self.config.as_deref(), &self.options, &self.compiled.stores)?);
+        Ok(self)
+    }
+
+    /// Install the rules matched by a single regex. It is the alternation of the patterns of
+    /// the rules, each in a capture group named by the rule, eg: `(?P<a>...)|(?P<b>...)`.
+    /// The group which participates in the leftmost match identifies the matched rule, which
+    /// may be faster than the `RegexSet` for a large number of rules. The named rules are
+    /// evaluated after the rules of the config, and they only support the `Regex` match type.
+    pub fn with_named_ruleset(
+        mut self,
+        rules: Vec<(String, config::ConcurrencyControl)>,
+    ) -> Result<ConcurrencyControlLayer, PluginError> {
+        let mut errors = vec![];
+        for (name, c) in &rules {
+            if let Err(PluginError::InvalidConcurrencyControlConfig { errors: e }) = c.validate() {
+                errors.extend(e.into_iter().map(|e| format!("rule {}: {}", name, e)));
+            }
+            if c.match_type != config::ConcurrencyControlMatchType::Regex || c.match_normalized {
+                errors.push(format!("rule {}: named rules only match the query by regex", name));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(PluginError::InvalidConcurrencyControlConfig { errors });
+        }
+
+        let pattern = rules
+            .iter()
+            .map(|(name, c)| {
+                let flags = if c.case_insensitive { "?i" } else { "?" };
+                let alternatives =
+                    c.regex.iter().map(|r| format!("({}:{})", flags, r)).collect::<Vec<_>>();
+                format!("(?P<{}>{})", name, alternatives.join("|"))
+            })
+            .collect::<Vec<_>>()
+            .join("|");
+        let regex = Regex::new(&pattern).map_err(|e| {
+            PluginError::InvalidConcurrencyControlRegex { regex: pattern, source: e }
+        })?;
+        self.options.named_ruleset = Some(NamedRuleset { regex, rules });
+        self.compiled =
+            Arc::new(compile(self.config.as_deref(), &self.options, &self.compiled.stores)?);
+        Ok(self)
+    }
+
+    /// Use `seed` to generate the jitter of the window durations, so the durations
+    /// are the same every time the rules are built.
+    pub fn with_jitter_seed(mut self, seed: u64) -> Result<ConcurrencyControlLayer, PluginError> {
+        self.options.jitter_seed = Some(seed);
+        self.compiled =
+            Arc::new(compile(self.config.as_deref(), &self.options, &self.compiled.stores)?);
+        Ok(self)
+    }
+
+    /// Evict the clients of the `PerClient` rules idle longer than `idle_ttl` by a task
+    /// sweeping every `sweep_interval`, instead of sweeping them when they are looked up.
+    /// The clients which still hold permits are kept. A task is spawned for each service
+    /// built from the layer and aborted once the service and its clones are dropped. The
+    /// clients of a service built outside a tokio runtime are evicted when looked up, as
+    /// without this option. The `sweep_interval` must not be zero.
+    pub fn with_client_eviction(
+        mut self,
+        sweep_interval: Duration,
+        idle_ttl: Duration,
+    ) -> Result<ConcurrencyControlLayer, PluginError> {
+        if sweep_interval.is_zero() {
+            return Err(PluginError::InvalidConcurrencyControlConfig {
+                errors: vec![String::from("client eviction sweep interval must not be zero")],
+            });
+        }
+        self.options.client_eviction = Some(ClientEviction { sweep_interval, idle_ttl });
+        self.compiled =
+            Arc::new(compile(self.config.as_deref(), &self.options, &self.compiled.stores)?);
+        Ok(self)
+    }
+
+    /// Use `seed` to select the rules of `WeightedOneOf` mode, so the selections are the
+    /// same every time the rules are built.
+    pub fn with_selection_seed(
+        mut self,
+        seed: u64,
+    ) -> Result<ConcurrencyControlLayer, PluginError> {
+        self.options.selection_seed = Some(seed);
+        self.compiled =
+            Arc::new(compile(self.config.as_deref(), &self.options, &self.compiled.stores)?);
+        Ok(self)
+    }
+
+    /// Call `logger` with the decision of every evaluated request, after the rules are
+    /// unlocked, so it may take its time. It replaces the logger set before.
+    pub fn with_decision_logger(
+        mut self,
+        logger: impl Fn(&ConcurrencyControlDecision) + Send + Sync + 'static,
+    ) -> ConcurrencyControlLayer {
+        self.options.decision_logger =
+            Some(DecisionLogger(Arc::new(move |decision, _| logger(decision))));
+        self
+    }
+
+    /// Write every decision as a JSON line by `write`, with the fields `ts` in milliseconds
+    /// since the unix epoch, `rule_index`, `regex`, `decision` and `retry_after_ms`.
+    pub fn with_json_decision_logger(
+        mut self,
+        write: impl Fn(&str) + Send + Sync + 'static,
+    ) -> ConcurrencyControlLayer {
+        let logger = move |decision: &ConcurrencyControlDecision,
+                           rules: &ConcurrencyControlRules| {
+            let idx = decision.rule_index;
+            let retry_after = idx.filter(|_| !decision.allowed).and_then(|i| rules.retry_after(i));
+            let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            let line = serde_json::json!({
+                "ts": ts.as_millis() as u64,
+                "rule_index": idx,
+                "regex": idx.and_then(|i| rules.counters.get(i)).map(|c| &c.regex),
+                "decision": if decision.allowed { "allowed" } else { "rejected" },
+                "retry_after_ms": retry_after.map(|d| d.as_millis() as u64),
+            });
+            write(&line.to_string());
+        };
+        self.options.decision_logger = Some(DecisionLogger(Arc::new(logger)));
+        self
+    }
+
+    /// Cache the rules matched by the last `capacity` distinct queries, so the repeated
+    /// queries are not matched against the regexes again. The cache is disabled if
+    /// `capacity` is 0, and it holds 1024 queries by default.
+    pub fn with_match_cache(
+        mut self,
+        capacity: usize,
+    ) -> Result<ConcurrencyControlLayer, PluginError> {
+        self.options.match_cache = Some(capacity);
+        self.compiled =
+            Arc::new(compile(self.config.as_deref(), &self.options, &self.compiled.stores)?);
+        Ok(self)
+    }
+
+    /// Admit the requests of the rule `rule_index` by the algorithms created by `factory`
+    /// instead of its configured algorithm, each service built from the layer creates its
+    /// own algorithm. It is kept by `reload`, and applies to the reloaded rule of the index.
+    pub fn with_algorithm<F>(
+        mut self,
+        rule_index: usize,
+        factory: F,
+    ) -> Result<ConcurrencyControlLayer, PluginError>
+    where
+        F: Fn() -> Box<dyn AdmitAlgorithm> + Send + Sync + 'static,
+    {
+        self.options.algorithms.insert(rule_index, AlgorithmFactory(Arc::new(factory)));
+        self.compiled =
+            Arc::new(compile(self.config.as_deref(), &self.options, &self.compiled.stores)?);
+        Ok(self)
+    }
+
+    /// Count the requests of the rules with a remote backend in `store` instead of
+    /// connecting to the configured backend, eg: a store shared with other layers.
+    pub fn with_store(
+        mut self,
+        store: Arc<dyn ConcurrencyControlStore>,
+    ) -> Result<ConcurrencyControlLayer, PluginError> {
+        self.options.store = Some(store);
+        self.compiled =
+            Arc::new(compile(self.config.as_deref(), &self.options, &self.compiled.stores)?);
+        Ok(self)
+    }
+
+    /// Set the capacity of the backend, the rules with `max_concurrency_percent` recompute
+    /// their limits from it on the next request. It applies to all services built from the
+    /// layer, and the rules keep using `max_concurrency` until it is set.
+    pub fn set_capacity(&self, capacity: usize) {
+        self.options.capacity.store(capacity, Ordering::Release);
+    }
+
+    /// Evaluate each of `samples` against a fresh copy of the rules, return the rules it
+    /// matches and whether it would be admitted. The services built from the layer are
+    /// not affected, and the samples do not affect each other.
+    pub fn self_test(&self, samples: &[&str]) -> Vec<SampleResult> {
+        samples
+            .iter()
+            .map(|sample| {
+                let instances = self.compiled.instances.iter().map(|c| c.fresh()).collect();
+                let rules =
+                    ConcurrencyControlRules::with_matcher(instances, self.compiled.matcher.clone());
+                let matched_rules = matched_of(&rules.matcher.units(sample, &rules.enabled, None));
+                let decision = rules.evaluate(sample, None, None, RequestPriority::Normal);
+                SampleResult {
+                    sample: sample.to_string(),
+                    matched_rules,
+                    allowed: decision.allowed,
+                    reason: decision.reason,
+                }
+            })
+            .collect()
+    }
+
+    /// Build the instances of all rules, no rule is skipped, the error of the first rule which
+    /// can not be built is returned, eg: its regex is invalid. The instances are built the same
+    /// way when the layer is created, so a bad config is already rejected by `new` and `with_opt`.
+    pub fn try_build_instances(
+        &self,
+    ) -> Result<Option<Vec<ConcurrencyControlInstance>>, PluginError> {
+        build_instances(
+            self.config.as_deref(),
+            &self.options,
+            &self.compiled.stores,
+            &mut Stores::new(),
+        )
+    }
+}
+
+// Return the rules matching any of the statements in ascending order
+fn matched_of(units: &[(&str, Vec<usize>)]) -> Vec<usize> {
+    let mut matched = units.iter().flat_map(|(_, rules)| rules.iter().copied()).collect::<Vec<_>>();
+    matched.sort_unstable();
+    matched.dedup();
+    matched
+}
+
+// Borrow the permits of `input` for the rule `idx` from an idle rule of its pool,
+// the borrowed permits are released to the lender
+fn borrow(
+    instances: &mut [ConcurrencyControlInstance],
+    idx: usize,
+    input: &str,
+) -> Option<Admission> {
+    let borrower = &instances[idx];
+    let pool = borrower.pool.clone()?;
+    let weight = borrower.weight(input);
+    let borrowed = borrower.borrowed.clone();
+    if let Some(max) = borrower.pool_max_borrow {
+        if borrowed.load(Ordering::Acquire) + weight as usize > max as usize {
+            return None;
+        }
+    }
+
+    let permit = instances
+        .iter_mut()
+        .enumerate()
+        .filter(|(i, c)| *i != idx && c.pool.as_ref() == Some(&pool))
+        .find_map(|(_, c)| c.try_lend(weight))?;
+    borrowed.fetch_add(weight as usize, Ordering::AcqRel);
+    Some(Admission::Borrowed(permit, Loan { borrowed, permits: weight as usize }))
+}
+
+// The fallback rule is appended after the sorted rules. The stores of `previous` are reused
+// for the same backends, the stores of the instances are collected in `stores`.
+fn build_instances(
+    config: Option<&[config::ConcurrencyControl]>,
+    options: &BuildOptions,
+    previous: &Stores,
+    stores: &mut Stores,
+) -> Result<Option<Vec<ConcurrencyControlInstance>>, PluginError> {
+    if config.is_none() && options.default.is_none() {
+        return Ok(None);
+    }
+    let mut rng = match options.jitter_seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    // the sort is stable, so the rules with the same priority keep config order
+    let mut config = config.unwrap_or_default().iter().collect::<Vec<_>>();
+    config.sort_by_key(|c| Reverse(c.priority));
+
+    // the rules with the same backend share the store
+    let mut instances = Vec::with_capacity(config.len() + 1);
+    for c in config {
+        let instance = build_instance(c, instances.len(), options, previous, stores, &mut rng)?;
+        instances.push(instance);
+    }
+    // the named rules are evaluated after the rules of the config
+    for (name, c) in options.named_ruleset.iter().flat_map(|n| &n.rules) {
+        let mut instance = build_instance(c, instances.len(), options, previous, stores, &mut rng)?;
+        instance.group = Some(name.clone());
+        instances.push(instance);
+    }
+    if let Some(default) = &options.default {
+        let mut fallback =
+            build_instance(default, instances.len(), options, previous, stores, &mut rng)?;
+        fallback.fallback = true;
+        instances.push(fallback);
+    }
+    Ok(Some(instances))
+}
+
+// Build the instance of the rule `c` at `idx` with the options of the layer
+fn build_instance(
+    c: &config::ConcurrencyControl,
+    idx: usize,
+    options: &BuildOptions,
+    previous: &Stores,
+    stores: &mut Stores,
+    rng: &mut StdRng,
+) -> Result<ConcurrencyControlInstance, PluginError> {
+    let mut instance = ConcurrencyControlInstance::try_new(c)?;
+    instance.store = store_of(&c.backend, options, previous, stores)?;
+    // the resets of the rules are decorrelated by a random extra duration
+    if let Some(jitter) = c.jitter.filter(|j| !j.is_zero()) {
+        instance.duration += rng.gen_range(Duration::ZERO..jitter);
+    }
+    instance.capacity = options.capacity.clone();
+    instance.idle_ttl = options.client_eviction.map(|e| e.idle_ttl);
+    // the built-in algorithm counts in the jittered duration
+    instance.admit = instance.builtin_admit();
+    if let Some(factory) = options.algorithms.get(&idx) {
+        instance.admit = Some(RuleAlgorithm::new(factory.clone(), true));
+    }
+    // the warmup of each rule starts once it is built
+    instance.created_at = Instant::now();
+    Ok(instance)
+}
+
+// Return the store of `backend`, None for the `Local` backend. The store of `previous`
+// is reused, so it is not reconnected when the rules are rebuilt.
+fn store_of(
+    backend: &config::ConcurrencyControlBackend,
+    options: &BuildOptions,
+    previous: &Stores,
+    stores: &mut Stores,
+) -> Result<Option<Arc<dyn ConcurrencyControlStore>>, PluginError> {
+    if *backend == config::ConcurrencyControlBackend::Local {
+        return Ok(None);
+    }
+    if let Some(store) = &options.store {
+        return Ok(Some(store.clone()));
+    }
+    if let Some(store) = stores.get(backend) {
+        return Ok(Some(store.clone()));
+    }
+    if let Some(store) = previous.get(backend) {
+        stores.insert(backend.clone(), store.clone());
+        return Ok(Some(store.clone()));
+    }
+    match backend {
+        #[cfg(feature = "redis")]
+        config::ConcurrencyControlBackend::Redis { url, key_prefix } => {
+            let store: Arc<dyn ConcurrencyControlStore> =
+                Arc::new(store::RedisStore::new(url, key_prefix)?);
+            stores.insert(backend.clone(), store.clone());
+            Ok(Some(store))
+        }
+        _ => Err(PluginError::InvalidConcurrencyControlConfig {
+            errors: vec![String::from("redis backend requires the redis feature")],
+        }),
+    }
+}
+
+fn compile(
+    config: Option<&[config::ConcurrencyControl]>,
+    options: &BuildOptions,
+    previous: &Stores,
+) -> Result<CompiledRules, PluginError> {
+    let mut stores = Stores::new();
+    let instances = build_instances(config, options, previous, &mut stores)?.unwrap_or_default();
+    let matcher = ConcurrencyControlMatcher::new(&instances, options)?;
+    Ok(CompiledRules { instances, matcher: Arc::new(matcher), stores })
+}
+
+// Validate all rules of the config, the problems of all rules are reported at once
+fn validate(config: &[config::ConcurrencyControl]) -> Result<(), PluginError> {
+    let mut errors = vec![];
+    for (idx, c) in config.iter().enumerate() {
+        if let Err(PluginError::InvalidConcurrencyControlConfig { errors: e }) = c.validate() {
+            errors.extend(e.into_iter().map(|e| format!("rule {}: {}", idx, e)));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(PluginError::InvalidConcurrencyControlConfig { errors });
+    }
+    Ok(())
+}
+
+/// All patterns of the rules are compiled into a `RegexSet`,
+/// so the matched rules can be found in a single pass.
+/// The patterns of a literal prefix, eg: `^SELECT`, are matched by an Aho-Corasick automaton.
+#[derive(Debug)]
+struct ConcurrencyControlMatcher {
+    set: RegexSet,
+    // The rule index of each pattern in `set`
+    rules: Vec<usize>,
+    // The patterns of `Prefix` and `Exact` rules, matched without the regex engine
+    literals: Vec<LiteralPattern>,
+    // The regex patterns which only match a literal prefix, eg: `^SELECT`, they are not in `set`
+    prefixes: Option<PrefixMatcher>,
+    // Whether the rule is an `Allow` rule
+    allow_rules: Vec<bool>,
+    // The statement kinds of each rule
+    statement_kinds: Vec<Option<Vec<StatementKind>>>,
+    // Whether the mode of the rule is `AllMatches`
+    all_matches: Vec<bool>,
+    // Whether the mode of the rule is `WeightedOneOf`, and the selection weight of the rule
+    one_of: Vec<bool>,
+    selection_weights: Vec<u32>,
+    // The seed of the selections of the rules built from the matcher
+    selection_seed: Option<u64>,
+    // The max bytes of the queries of each rule
+    max_bytes: Vec<Option<usize>>,
+    require_limit: Vec<Option<u64>>,
+    // Whether the rule only logs the rejections
+    dry_run: Vec<bool>,
+    // The rule applied if no rule is matched
+    fallback: Option<usize>,
+    // Whether the rule is matched against the normalized query
+    normalized: Vec<bool>,
+    // The regex excluding the requests from each rule
+    except: Vec<Option<Regex>>,
+    // The time of day window of each rule, the rule is skipped out of it
+    active_windows: Vec<Option<config::ActiveWindow>>,
+    // Whether the rule matches the statements of a multi-statement query one by one
+    split: Vec<bool>,
+    // The network of the connections each rule applies to
+    source_cidrs: Vec<Option<IpNet>>,
+    // The combined regex of the named ruleset, and the rule of each of its capture groups
+    named: Option<(Regex, Vec<Option<usize>>)>,
+    // The candidates of the recent queries, it is dropped with the matcher on reload
+    cache: Option<Mutex<LruCache<String, Vec<usize>>>>,
+}
+
+/// A pattern compared with the query as plain string, the case insensitive
+/// comparison only ignores the case of ASCII characters.
+#[derive(Debug)]
+struct LiteralPattern {
+    pattern: String,
+    rule: usize,
+    exact: bool,
+    case_insensitive: bool,
+}
+
+impl LiteralPattern {
+    fn is_match(&self, input: &str) -> bool {
+        let input = match (self.exact, input.get(..self.pattern.len())) {
+            (true, _) if input.len() != self.pattern.len() => return false,
+            (_, Some(input)) => input,
+            (_, None) => return false,
+        };
+        if self.case_insensitive {
+            return input.eq_ignore_ascii_case(&self.pattern);
+        }
+        input == self.pattern
+    }
+}
+
+/// The regex patterns which only match a literal prefix of the query, they are matched
+/// by an Aho-Corasick automaton instead of the regex engine.
+#[derive(Debug)]
+struct PrefixMatcher {
+    automaton: AhoCorasick,
+    // The rule index of each pattern of `automaton`
+    rules: Vec<usize>,
+    // The length of the longest pattern, only the prefix of the query of it is searched
+    max_len: usize,
+}
+
+impl PrefixMatcher {
+    fn new(patterns: &[(usize, &str)]) -> Option<Self> {
+        let automaton = AhoCorasick::new(patterns.iter().map(|(_, p)| p)).ok()?;
+        Some(PrefixMatcher {
+            automaton,
+            rules: patterns.iter().map(|(idx, _)| *idx).collect(),
+            max_len: patterns.iter().map(|(_, p)| p.len()).max().unwrap_or_default(),
+        })
+    }
+
+    // Return the rules of the patterns which are a prefix of `input`, not in order
+    fn matches<'a>(&'a self, input: &'a str) -> impl Iterator<Item = usize> + 'a {
+        let input = &input.as_bytes()[..input.len().min(self.max_len)];
+        self.automaton
+            .find_overlapping_iter(input)
+            .filter(|m| m.start() == 0)
+            .map(|m| self.rules[m.pattern().as_usize()])
+    }
+}
+
+// Return the literal of `pattern` if it only matches the literal at the start of the query,
+// eg: `^SELECT`. The escapes and the inline flags are not parsed, such patterns use the regex.
+fn literal_prefix(pattern: &str) -> Option<&str> {
+    let literal = pattern.strip_prefix('^')?;
+    let is_meta = |c: char| r"\.+*?()|[]{}^$".contains(c);
+    (!literal.is_empty() && !literal.contains(is_meta)).then_some(literal)
+}
+
+impl ConcurrencyControlMatcher {
+    fn new(
+        instances: &[ConcurrencyControlInstance],
+        options: &BuildOptions,
+    ) -> Result<Self, PluginError> {
+        let cache_capacity = options.match_cache_capacity();
+        let mut patterns = vec![];
+        let mut rules = vec![];
+        let mut literals = vec![];
+        for (idx, c) in instances.iter().enumerate() {
+            for pattern in &c.literals {
+                literals.push(LiteralPattern {
+                    pattern: pattern.clone(),
+                    rule: idx,
+                    exact: c.match_type == config::ConcurrencyControlMatchType::Exact,
+                    case_insensitive: c.case_insensitive,
+                });
+            }
+        }
+        // the case insensitive patterns use the regex, it also folds the non ASCII characters,
+        // so do the normalized ones, they are matched against the digest
+        let routed = |c: &ConcurrencyControlInstance| !c.case_insensitive && !c.match_normalized;
+        let prefix_patterns: Vec<_> = instances
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.group.is_none() && routed(c))
+            .flat_map(|(idx, c)| {
+                c.regex.iter().filter_map(move |r| Some((idx, literal_prefix(r.as_str())?)))
+            })
+            .collect();
+        // the patterns fall back to the regex if the automaton can not be built
+        let prefixes = match prefix_patterns.is_empty() {
+            true => None,
+            false => PrefixMatcher::new(&prefix_patterns),
+        };
+        for (idx, c) in instances.iter().enumerate() {
+            for r in c.regex.iter().filter(|_| c.group.is_none()) {
+                if prefixes.is_some() && routed(c) && literal_prefix(r.as_str()).is_some() {
+                    continue;
+                }
+                // the flags of `Regex` are not kept by `as_str`
+                if c.case_insensitive {
+                    patterns.push(format!("(?i){}", r.as_str()));
+                } else {
+                    patterns.push(r.as_str().to_string());
+                }
+                rules.push(idx);
+            }
+        }
+
+        let allow_rules =
+            instances.iter().map(|c| c.action == config::ConcurrencyControlAction::Allow).collect();
+
+        let statement_kinds = instances.iter().map(|c| c.statement_kinds.clone()).collect();
+        let all_matches = instances
+            .iter()
+            .map(|c| c.mode == config::ConcurrencyControlMatchMode::AllMatches)
+            .collect();
+        let one_of = instances
+            .iter()
+            .map(|c| c.mode == config::ConcurrencyControlMatchMode::WeightedOneOf)
+            .collect();
+        let selection_weights = instances.iter().map(|c| c.selection_weight).collect();
+
+        let max_bytes = instances.iter().map(|c| c.max_bytes).collect();
+        let require_limit = instances.iter().map(|c| c.require_limit).collect();
+        let dry_run = instances.iter().map(|c| c.dry_run).collect();
+        let normalized = instances.iter().map(|c| c.match_normalized).collect();
+        let except = instances.iter().map(|c| c.except_regex.clone()).collect();
+        let active_windows = instances.iter().map(|c| c.active_window).collect();
+        let split = instances.iter().map(|c| c.split_multi_statement).collect();
+        let source_cidrs = instances.iter().map(|c| c.source_cidr).collect();
+        // the rule of each capture group of the combined regex
+        let named = options.named_ruleset.as_ref().map(|n| {
+            let groups = n
+                .regex
+                .capture_names()
+                .map(|name| {
+                    name.and_then(|name| {
+                        instances.iter().position(|c| c.group.as_deref() == Some(name))
+                    })
+                })
+                .collect();
+            (n.regex.clone(), groups)
+        });
+
+        // the set is compiled with the largest size limits of the rules
+        let mut builder = RegexSetBuilder::new(&patterns);
+        if let Some(limit) = instances.iter().filter_map(|c| c.config.regex_size_limit).max() {
+            builder.size_limit(limit);
+        }
+        if let Some(limit) = instances.iter().filter_map(|c| c.config.dfa_size_limit).max() {
+            builder.dfa_size_limit(limit);
+        }
+        let set = builder.build().map_err(|e| match e {
+            regex::Error::CompiledTooBig(limit) => {
+                PluginError::RegexTooComplex { regex: patterns.join("|"), limit }
+            }
+            e => {
+                PluginError::InvalidConcurrencyControlRegex { regex: patterns.join("|"), source: e }
+            }
+        })?;
+
+        Ok(ConcurrencyControlMatcher {
+            set,
+            rules,
+            literals,
+            prefixes,
+            fallback: instances.iter().position(|c| c.fallback),
+            allow_rules,
+            statement_kinds,
+            all_matches,
+            one_of,
+            selection_weights,
+            selection_seed: options.selection_seed,
+            max_bytes,
+            require_limit,
+            dry_run,
+            normalized,
+            except,
+            active_windows,
+            split,
+            source_cidrs,
+            named,
+            cache: (cache_capacity > 0).then(|| Mutex::new(LruCache::new(cache_capacity))),
+        })
+    }
+
+    // Return why the rule `idx` rejects `input` by its shape, it is checked before
+    // consuming any budget: the query is longer than `max_bytes`, or its LIMIT is
+    // missing or larger than `require_limit`.
+    fn precheck(&self, idx: usize, input: &str) -> Option<RejectReason> {
+        if let Some(max) = self.max_bytes[idx].filter(|max| input.len() > *max) {
+            return Some(RejectReason::QueryTooLarge { bytes: input.len(), max });
+        }
+        let max = self.require_limit[idx]?;
+        match extract_limit(input) {
+            Some(rows) if rows <= max => None,
+            rows => Some(RejectReason::RowLimitExceeded { rows, max }),
+        }
+    }
+
+    // Return the statements of `input` to evaluate, with the rules matching each of them.
+    // The rules with `split_multi_statement` match each statement of a multi-statement
+    // query, the other rules match the whole query.
+    fn units<'a>(
+        &self,
+        input: &'a str,
+        enabled: &[AtomicBool],
+        source: Option<IpAddr>,
+    ) -> Vec<(&'a str, Vec<usize>)> {
+        // the empty queries are not requests, they match no rule even if a pattern matches them
+        if input.trim().is_empty() {
+            return vec![];
+        }
+        if self.split.contains(&true) {
+            let statements = split_statements(input);
+            if statements.len() > 1 {
+                let mut units =
+                    vec![(input, self.matched_rules(input, enabled, source, Some(false)))];
+                units.extend(
+                    statements
+                        .into_iter()
+                        .map(|s| (s, self.matched_rules(s, enabled, source, Some(true)))),
+                );
+                return units;
+            }
+        }
+        vec![(input, self.matched_rules(input, enabled, source, None))]
+    }
+
+    // Return the indexes of matched rules in ascending order, the first matching rule wins
+    // unless its mode is `AllMatches`. Return empty if an `Allow` rule is matched.
+    // The disabled rules are skipped, so are the rules whose `split_multi_statement`
+    // is not `split` if it is set, and the rules whose `source_cidr` does not contain `source`.
+    fn matched_rules(
+        &self,
+        input: &str,
+        enabled: &[AtomicBool],
+        source: Option<IpAddr>,
+        split: Option<bool>,
+    ) -> Vec<usize> {
+        // the clock is read only if a matched rule has an active window
+        let mut now = None;
+        let mut matched = vec![];
+        let in_scope = |idx: usize| {
+            enabled[idx].load(Ordering::Relaxed)
+                && (split.is_none() || split == Some(self.split[idx]))
+                && match self.source_cidrs[idx] {
+                    Some(net) => source.is_some_and(|ip| net.contains(&ip)),
+                    None => true,
+                }
+        };
+        for idx in self.candidates(input) {
+            if !in_scope(idx) {
+                continue;
+            }
+            if let Some(window) = &self.active_windows[idx] {
+                if !window.is_active(*now.get_or_insert_with(SystemTime::now)) {
+                    continue;
+                }
+            }
+            if self.allow_rules[idx] {
+                return vec![];
+            }
+            matched.push(idx);
+        }
+
+        // the rule of `WeightedOneOf` is selected among the matched `WeightedOneOf` rules
+        // when the request is evaluated
+        match matched.first() {
+            Some(idx) if self.one_of[*idx] => matched.retain(|idx| self.one_of[*idx]),
+            Some(idx) if !self.all_matches[*idx] => matched.truncate(1),
+            _ => {}
+        }
+        if let Some(idx) = self.fallback.filter(|idx| matched.is_empty() && in_scope(*idx)) {
+            matched.push(idx);
+        }
+        matched
+    }
+
+    // Return the rules matched by `input` in ascending order, before the filters which may
+    // change between the requests, eg: an enabled flag or an active window. They only depend
+    // on `input`, so they are cached by the query. The long queries are not cached.
+    fn candidates(&self, input: &str) -> Vec<usize> {
+        let cache = match &self.cache {
+            Some(cache) if input.len() <= MATCH_CACHE_MAX_QUERY_BYTES => cache,
+            _ => return self.match_candidates(input),
+        };
+        if let Some(candidates) = cache.lock().get(input) {
+            return candidates.clone();
+        }
+        let candidates = self.match_candidates(input);
+        cache.lock().put(input.to_string(), candidates.clone());
+        candidates
+    }
+
+    fn match_candidates(&self, input: &str) -> Vec<usize> {
+        // the input is classified only if a matched rule has statement kinds
+        let mut kind = None;
+        // the patterns are added in rule order, so the rule indexes are ascending
+        let mut candidates = vec![];
+        // the query is normalized once for all rules with `match_normalized`
+        let digest = self.normalized.contains(&true).then(|| normalize(input));
+        let text = |idx: usize| match &digest {
+            Some(digest) if self.normalized[idx] => digest.as_str(),
+            _ => input,
+        };
+        if !self.set.is_empty() {
+            candidates.extend(
+                self.set
+                    .matches(input)
+                    .iter()
+                    .map(|i| self.rules[i])
+                    .filter(|idx| !self.normalized[*idx]),
+            );
+            if let Some(digest) = &digest {
+                candidates.extend(
+                    self.set
+                        .matches(digest)
+                        .iter()
+                        .map(|i| self.rules[i])
+                        .filter(|idx| self.normalized[*idx]),
+                );
+            }
+        }
+        // the leftmost match of the combined regex identifies a single rule by its group
+        if let Some((regex, groups)) = &self.named {
+            if let Some(caps) = regex.captures(input) {
+                candidates.extend(caps.iter().zip(groups).find_map(|(m, idx)| m.and(*idx)));
+            }
+        }
+        if !self.literals.is_empty() {
+            candidates
+                .extend(self.literals.iter().filter(|l| l.is_match(text(l.rule))).map(|l| l.rule));
+        }
+        if let Some(prefixes) = &self.prefixes {
+            candidates.extend(prefixes.matches(input));
+        }
+        if digest.is_some() || !self.literals.is_empty() || self.prefixes.is_some() {
+            candidates.sort_unstable();
+        }
+        // a rule with several matched patterns is matched once
+        candidates.dedup();
+        candidates.retain(|&idx| {
+            if self.except[idx].as_ref().is_some_and(|r| r.is_match(text(idx))) {
+                return false;
+            }
+            match &self.statement_kinds[idx] {
+                Some(kinds) => kinds.contains(kind.get_or_insert_with(|| classify(input))),
+                None => true,
+            }
+        });
+        candidates
+    }
+}
+
+/// The counters of a rule
+#[derive(Debug)]
+struct RuleCounter {
+    regex: Vec<String>,
+    allowed: AtomicU64,
+    rejected: AtomicU64,
+    would_reject: AtomicU64,
+    overdrawn: AtomicU64,
+    // The inner service time of the admitted requests in microseconds
+    latency: Mutex<Histogram<u64>>,
+}
+
+// The latencies above are recorded as the max
+const LATENCY_MAX_MICROS: u64 = 60_000_000;
+
+impl RuleCounter {
+    fn new(regex: Vec<String>) -> Self {
+        RuleCounter {
+            regex,
+            allowed: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            would_reject: AtomicU64::new(0),
+            overdrawn: AtomicU64::new(0),
+            latency: Mutex::new(
+                Histogram::new_with_max(LATENCY_MAX_MICROS, 3).expect("latency bounds are valid"),
+            ),
+        }
+    }
+
+    fn record_latency(&self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.latency.lock().saturating_record(micros.max(1));
+    }
+
+    // Return the latency at `quantile`, None if no latency is recorded
+    fn latency_at(histogram: &Histogram<u64>, quantile: f64) -> Option<Duration> {
+        (!histogram.is_empty())
+            .then(|| Duration::from_micros(histogram.value_at_quantile(quantile)))
+    }
+
+    fn reset(&self) {
+        self.allowed.store(0, Ordering::Relaxed);
+        self.rejected.store(0, Ordering::Relaxed);
+        self.would_reject.store(0, Ordering::Relaxed);
+        self.overdrawn.store(0, Ordering::Relaxed);
+        self.latency.lock().reset();
+    }
+}
+
+/// The statistics of a rule, returned by `ConcurrencyControl::stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConcurrencyControlRuleStats {
+    pub regex: Vec<String>,
+    pub allowed: u64,
+    pub rejected: u64,
+    // The queries the dry-run rule would have rejected
+    pub would_reject: u64,
+    // The `High` priority queries admitted by overdrawing the used up window,
+    // they are also counted in `allowed`
+    pub overdrawn: u64,
+    // The percentiles of the inner service time of the admitted requests since the stats
+    // were reset, None if no admitted request has completed
+    pub latency_p50: Option<Duration>,
+    pub latency_p95: Option<Duration>,
+    pub latency_p99: Option<Duration>,
+}
+
+/// The current state of a rule, returned by `ConcurrencyControl::snapshot`.
+/// `available_permits` is the remaining tokens for token bucket, and the window
+/// is None if it has not started or has elapsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConcurrencyControlRuleSnapshot {
+    pub regex: Vec<String>,
+    pub max_concurrency: usize,
+    pub available_permits: usize,
+    pub window_started: Option<Instant>,
+    pub window_remaining: Option<Duration>,
+}
+
+/// The windows of the rules, returned by `ConcurrencyControl::export_state`.
+/// Only the fixed windows shared by all clients are exported.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConcurrencyControlState {
+    pub rules: Vec<ConcurrencyControlRuleState>,
+}
+
+/// The window of a rule, it is imported by the rule with the same regexes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConcurrencyControlRuleState {
+    pub regex: Vec<String>,
+    // The time elapsed since the window started, the clock of another process
+    // can not be compared, so it is relative to the export
+    pub elapsed: Duration,
+    // The permits consumed in the window
+    pub consumed: usize,
+}
+
+// Escape the backslashes, double quotes and line feeds of a Prometheus label value
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Resize the total permits of `semaphore` from `from` to `to`, the permits held by
+// in-flight requests can not be removed, so it may shrink less than expected.
+fn resize_semaphore(semaphore: &Semaphore, from: usize, to: usize) {
+    if to > from {
+        semaphore.add_permits(to - from);
+    } else {
+        let n = (from - to).min(semaphore.available_permits());
+        if let Ok(permits) = semaphore.try_acquire_many(n as u32) {
+            permits.forget();
+        }
+    }
+}
+
+/// The rules used by `ConcurrencyControl`, they are swapped as a whole when reloading,
+/// so the rule index of a request always refers to the rules it was matched with.
+#[derive(Debug)]
+struct ConcurrencyControlRules {
+    matcher: Arc<ConcurrencyControlMatcher>,
+    // The instances are shared with the rules swapped in by `update_regex`, so the requests
+    // in flight on the old rules are counted by the same windows
+    instances: Arc<Mutex<Vec<ConcurrencyControlInstance>>>,
+    counters: Vec<Arc<RuleCounter>>,
+    // Whether each rule is enabled, it can be toggled without reloading
+    enabled: Vec<AtomicBool>,
+    // The sampling of the rejection logs of each rule, None if they are not logged
+    log_sampling: Vec<Option<LogSampling>>,
+    // The rng selecting the rules of `WeightedOneOf`
+    selector: Mutex<StdRng>,
+}
+
+/// The evaluation of a request by `ConcurrencyControlRules::evaluate_local`
+enum Evaluation {
+    Decided(ConcurrencyControlDecision),
+    // The request is admitted by the local state, it is decided by the stores
+    Pending(PendingAdmission),
+}
+
+/// The admissions of a request waiting for the stores of the rules with a remote backend
+struct PendingAdmission {
+    guard: ConcurrencyControlGuard,
+    admissions: Vec<(usize, Admission)>,
+}
+
+// The interval of logging the failures of the stores
+const STORE_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+// The failures of the stores of all services, each connection of the proxy has its service
+static STORE_WARNINGS: StoreWarnings = StoreWarnings { state: parking_lot::const_mutex((None, 0)) };
+
+// Log the failure of a store, it is logged once per `STORE_WARN_INTERVAL` with the count
+// of the failures not logged, so an unavailable store does not flood the logs
+fn warn_store_unavailable(e: &PluginError) {
+    if let Some(suppressed) = STORE_WARNINGS.sample(Instant::now()) {
+        tracing::warn!(
+            error = %e,
+            suppressed,
+            "concurrency control store is unavailable, admitted"
+        );
+    }
+}
+
+/// The failures of the stores, the first one is logged, then one per `STORE_WARN_INTERVAL`
+#[derive(Debug)]
+struct StoreWarnings {
+    // The time of the last logged failure and the failures not logged since then
+    state: Mutex<(Option<Instant>, u64)>,
+}
+
+impl StoreWarnings {
+    // Return the count of the failures not logged before this one if it is logged
+    fn sample(&self, now: Instant) -> Option<u64> {
+        let mut state = self.state.lock();
+        match state.0 {
+            Some(at) if now.duration_since(at) < STORE_WARN_INTERVAL => {
+                state.1 += 1;
+                None
+            }
+            _ => {
+                state.0 = Some(now);
+                Some(std::mem::take(&mut state.1))
+            }
+        }
+    }
+}
+
+thread_local! {
+    // The rng deciding which rejections are logged, it is not shared to keep it cheap
+    static LOG_SAMPLER: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+
+/// The rejections of a rule are logged with the probability `rate`, the count of
+/// the rejections not logged is logged once per `window` by the next rejection.
+#[derive(Debug)]
+struct LogSampling {
+    rate: f64,
+    window: Duration,
+    // The start of the current window and the rejections not logged in it
+    state: Mutex<(Instant, u64)>,
+}
+
+impl LogSampling {
+    fn new(rate: f64, window: Duration) -> Self {
+        LogSampling { rate, window, state: Mutex::new((Instant::now(), 0)) }
+    }
+
+    // Return whether the rejection is logged, and the count of the rejections
+    // not logged in the previous window if it has elapsed
+    fn sample(&self) -> (bool, Option<u64>) {
+        let logged = LOG_SAMPLER.with(|rng| rng.borrow_mut().gen_bool(self.rate));
+        let mut state = self.state.lock();
+        let now = Instant::now();
+        let flushed = if now.duration_since(state.0) >= self.window {
+            let suppressed = std::mem::take(&mut state.1);
+            state.0 = now;
+            Some(suppressed).filter(|n| *n > 0)
+        } else {
+            None
+        };
+        if !logged {
+            state.1 += 1;
+        }
+        (logged, flushed)
+    }
+}
+
+#[cfg(test)]
+fn seed_log_sampler(seed: u64) {
+    LOG_SAMPLER.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
+
+impl ConcurrencyControlRules {
+    fn with_matcher(
+        instances: Vec<ConcurrencyControlInstance>,
+        matcher: Arc<ConcurrencyControlMatcher>,
+    ) -> Self {
+        Self::with_shared(Arc::new(Mutex::new(instances)), matcher)
+    }
+
+    // Build the rules counting by the shared `instances`
+    fn with_shared(
+        shared: Arc<Mutex<Vec<ConcurrencyControlInstance>>>,
+        matcher: Arc<ConcurrencyControlMatcher>,
+    ) -> Self {
+        let instances = shared.lock();
+        let counters = instances.iter().map(|c| Arc::new(RuleCounter::new(c.patterns()))).collect();
+
+        let enabled = instances.iter().map(|c| AtomicBool::new(c.enabled)).collect();
+        let log_sampling = instances
+            .iter()
+            .map(|c| c.log_sample_rate.map(|rate| LogSampling::new(rate, c.duration)))
+            .collect();
+        drop(instances);
+
+        let selector = match matcher.selection_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        ConcurrencyControlRules {
+            matcher,
+            instances: shared,
+            counters,
+            enabled,
+            log_sampling,
+            selector: Mutex::new(selector),
+        }
+    }
+
+    // Keep the permits, windows and counters of the rules whose regexes are unchanged
+    fn inherit(mut self, old: &ConcurrencyControlRules) -> Self {
+        let old_instances = old.instances.lock();
+        let ConcurrencyControlRules { instances, counters, .. } = &mut self;
+        for (idx, c) in instances.lock().iter_mut().enumerate() {
+            let old_idx = old.counters.iter().position(|o| o.regex == counters[idx].regex);
+            let old_idx = match old_idx {
+                Some(old_idx)
+                    if old_instances[old_idx].algorithm == c.algorithm
+                        && old_instances[old_idx].match_type == c.match_type
+                        && old_instances[old_idx].fallback == c.fallback
+                        && old_instances[old_idx].case_insensitive == c.case_insensitive =>
+                {
+                    old_idx
+                }
+                _ => continue,
+            };
+
+            let o = &old_instances[old_idx];
+            c.window = o.window.clone();
+            c.created_at = o.created_at;
+            // the windows of the old composite keys do not apply to the new groups
+            if o.key_groups == c.key_groups {
+                c.clients = o.clients.clone();
+                c.last_sweep = o.last_sweep;
+            }
+            if o.key_group == c.key_group {
+                c.seen_keys = o.seen_keys.clone();
+                c.keys_started = o.keys_started;
+            }
+            // the loans of the requests in flight are still counted
+            if o.pool == c.pool {
+                c.borrowed = o.borrowed.clone();
+            }
+            // the same built-in algorithm keeps counting, the window of the sliding window
+            // is part of its state, so it is kept only if the duration is unchanged
+            let builtin = |a: &Option<RuleAlgorithm>| a.as_ref().is_some_and(|a| !a.custom);
+            let tokens =
+                matches!(c.algorithm, config::ConcurrencyControlAlgorithm::TokenBucket { .. });
+            if builtin(&o.admit) && builtin(&c.admit) && (tokens || o.duration == c.duration) {
+                c.admit = o.admit.clone();
+                if let Some(admit) = &c.admit {
+                    admit.algorithm.lock().set_limit(c.max_concurrency);
+                }
+            }
+
+            if o.max_concurrency != c.max_concurrency {
+                resize_semaphore(&c.window.semaphore, o.max_concurrency, c.max_concurrency);
+                for client in c.clients.values() {
+                    resize_semaphore(
+                        &client.window.semaphore,
+                        o.max_concurrency,
+                        c.max_concurrency,
+                    );
+                }
+            }
+
+            counters[idx] = old.counters[old_idx].clone();
+        }
+        drop(old_instances);
+
+        self
+    }
+
+    // Evaluate the query against the matched rules.
+    // The request must be admitted by all matched rules, the admissions of the other
+    // rules are rolled back if one rejects it, and `rule_index` is the rejecting rule.
+    // A rule matching several statements of the query admits each of them.
+    // The sync services wait for the stores of the rules with a remote backend in place.
+    fn evaluate(
+        &self,
+        input: &str,
+        client: Option<&str>,
+        source: Option<IpAddr>,
+        priority: RequestPriority,
+    ) -> ConcurrencyControlDecision {
+        match self.evaluate_local(input, client, source, priority) {
+            Evaluation::Decided(decision) => decision,
+            Evaluation::Pending(pending) => {
+                let rejected = store::block_on(self.acquire_stores(&pending)).unwrap_or_else(|e| {
+                    warn_store_unavailable(&e);
+                    None
+                });
+                self.settle(pending, rejected)
+            }
+        }
+    }
+
+    // Evaluate the query as `evaluate`, the stores are awaited
+    async fn evaluate_async(
+        &self,
+        input: &str,
+        client: Option<&str>,
+        source: Option<IpAddr>,
+        priority: RequestPriority,
+    ) -> ConcurrencyControlDecision {
+        match self.evaluate_local(input, client, source, priority) {
+            Evaluation::Decided(decision) => decision,
+            Evaluation::Pending(pending) => {
+                let rejected = self.acquire_stores(&pending).await;
+                self.settle(pending, rejected)
+            }
+        }
+    }
+
+    // Admit the request by the local state of the matched rules, the requests of the rules
+    // with a remote backend are pending until counted by their stores
+    fn evaluate_local(
+        &self,
+        input: &str,
+        client: Option<&str>,
+        source: Option<IpAddr>,
+        priority: RequestPriority,
+    ) -> Evaluation {
+        let mut units = self.matcher.units(input, &self.enabled, source);
+        for (_, rules) in &mut units {
+            self.select_one_of(rules);
+        }
+        let matched = matched_of(&units);
+        let mut guard = ConcurrencyControlGuard::new(matched.first().copied());
+        if matched.is_empty() {
+            return Evaluation::Decided(ConcurrencyControlDecision::allow(guard));
+        }
+
+        // the rules with the statements they match
+        let pairs = units
+            .iter()
+            .flat_map(|(text, rules)| rules.iter().map(move |idx| (*idx, *text)))
+            .collect::<Vec<_>>();
+        // the oversized queries are rejected before consuming any budget
+        let prechecked =
+            pairs.iter().map(|(idx, text)| self.matcher.precheck(*idx, text)).collect::<Vec<_>>();
+        let rejected = pairs.iter().zip(&prechecked).find_map(|((idx, _), reason)| {
+            let reason = (*reason)?;
+            if self.matcher.dry_run[*idx] {
+                self.would_reject(*idx, reason);
+                return None;
+            }
+            Some((*idx, reason))
+        });
+        if let Some((idx, reason)) = rejected {
+            guard.matched_rules = matched;
+            return Evaluation::Decided(ConcurrencyControlDecision::reject(guard, idx, reason));
+        }
+
+        let mut instances = self.instances.lock();
+        let mut admissions = Vec::with_capacity(pairs.len());
+        for (&(idx, text), reason) in pairs.iter().zip(prechecked) {
+            // the oversized query has been counted by the dry-run rule
+            if reason.is_some() {
+                continue;
+            }
+            // the spare permits of the pool are borrowed before overdrawing
+            let res = instances[idx].try_admit(text, client).or_else(|reason| match reason {
+                RejectReason::PermitsExhausted => borrow(&mut instances, idx, text)
+                    .or_else(|| {
+                        (priority == RequestPriority::High)
+                            .then(|| instances[idx].try_overdraw(text))
+                            .flatten()
+                    })
+                    .ok_or(reason),
+                reason => Err(reason),
+            });
+            match res {
+                Ok(admission) => admissions.push((idx, admission)),
+                Err(reason) if self.matcher.dry_run[idx] => self.would_reject(idx, reason),
+                Err(reason) => {
+                    for (idx, admission) in admissions.into_iter().rev() {
+                        instances[idx].rollback(admission);
+                    }
+                    guard.matched_rules = matched;
+                    return Evaluation::Decided(ConcurrencyControlDecision::reject(
+                        guard, idx, reason,
+                    ));
+                }
+            }
+        }
+        drop(instances);
+
+        guard.matched_rules = matched;
+        let pending = PendingAdmission { guard, admissions };
+        match pending.admissions.iter().any(|(_, a)| matches!(a, Admission::Store(_))) {
+            true => Evaluation::Pending(pending),
+            false => Evaluation::Decided(self.settle(pending, None)),
+        }
+    }
+
+    // Count the request by the stores of the pending admission, return the rule rejecting it.
+    // The requests are admitted if the store is unavailable, so it never blocks the traffic.
+    async fn acquire_stores(&self, pending: &PendingAdmission) -> Option<usize> {
+        for (idx, admission) in &pending.admissions {
+            let check = match admission {
+                Admission::Store(check) => check,
+                _ => continue,
+            };
+            match check.store.try_acquire(&check.key, check.limit, check.window).await {
+                Ok(true) => {}
+                Ok(false) if self.matcher.dry_run[*idx] => {
+                    self.would_reject(*idx, RejectReason::TokensExhausted)
+                }
+                Ok(false) => return Some(*idx),
+                Err(e) => warn_store_unavailable(&e),
+            }
+        }
+        None
+    }
+
+    // Decide the pending admission, all admissions are rolled back if `rejected` is the rule
+    // whose store rejected the request
+    fn settle(
+        &self,
+        pending: PendingAdmission,
+        rejected: Option<usize>,
+    ) -> ConcurrencyControlDecision {
+        let PendingAdmission { mut guard, admissions } = pending;
+        if let Some(idx) = rejected {
+            let mut instances = self.instances.lock();
+            for (idx, admission) in admissions.into_iter().rev() {
+                instances[idx].rollback(admission);
+            }
+            if let Some(quarantine) = instances[idx].quarantine {
+                instances[idx].count_rejection(quarantine, Instant::now());
+            }
+            drop(instances);
+            return ConcurrencyControlDecision::reject(guard, idx, RejectReason::TokensExhausted);
+        }
+
+        for (idx, admission) in admissions {
+            match admission {
+                Admission::Permit(permit, reset, soft_exceeded) => {
+                    guard.window_reset |= reset && guard.rule_idx == Some(idx);
+                    if soft_exceeded {
+                        tracing::info!(
+                            rule_idx = idx,
+                            "concurrency control rule is over soft limit"
+                        );
+                        guard.soft_exceeded = true;
+                    }
+                    guard.permits.push(permit);
+                }
+                Admission::Borrowed(permit, loan) => {
+                    guard.permits.push(permit);
+                    guard.loans.push(loan);
+                }
+                Admission::Overdraft(overdraft) => {
+                    self.counters[idx].overdrawn.fetch_add(1, Ordering::Relaxed);
+                    guard.overdrafts.push(overdraft);
+                }
+                _ => {}
+            }
+        }
+        ConcurrencyControlDecision::allow(guard)
+    }
+
+    // Evict the idle clients of the rules whose clients are evicted by the background task
+    fn evict_idle_clients(&self, now: Instant) {
+        for c in self.instances.lock().iter_mut() {
+            if let Some(ttl) = c.idle_ttl {
+                c.evict_idle_clients(now, ttl);
+            }
+        }
+    }
+
+    // Select one of the matched rules by their selection weights if the mode of the first
+    // rule is `WeightedOneOf`, only the selected rule limits the request. The weights are
+    // summed in u64 so they never overflow, and the first rule is selected if they can not
+    // be drawn, eg: all of them are 0 in the rules built programmatically.
+    fn select_one_of(&self, rules: &mut Vec<usize>) {
+        if rules.len() < 2 || !self.matcher.one_of[rules[0]] {
+            return;
+        }
+        let weights = rules.iter().map(|idx| self.matcher.selection_weights[*idx] as u64);
+        let idx = match WeightedIndex::new(weights) {
+            Ok(selection) => rules[selection.sample(&mut *self.selector.lock())],
+            Err(_) => rules[0],
+        };
+        *rules = vec![idx];
+    }
+
+    // Count and log the rejection of the dry-run rule `idx`, the query is not rejected
+    fn would_reject(&self, idx: usize, reason: RejectReason) {
+        self.counters[idx].would_reject.fetch_add(1, Ordering::Relaxed);
+        match &self.log_sampling[idx] {
+            Some(sampling) => self.log_rejection(idx, reason, sampling),
+            None => {
+                tracing::warn!(
+                    rule_idx = idx,
+                    ?reason,
+                    "concurrency control dry-run rule would reject"
+                )
+            }
+        }
+    }
+
+    // Log the rejection of the rule `idx` if it is sampled
+    fn log_rejection(&self, idx: usize, reason: RejectReason, sampling: &LogSampling) {
+        let (logged, flushed) = sampling.sample();
+        if let Some(suppressed) = flushed {
+            tracing::warn!(
+                rule_idx = idx,
+                suppressed,
+                "concurrency control rejections not logged in the last window"
+            );
+        }
+        if logged {
+            tracing::warn!(rule_idx = idx, ?reason, "concurrency control rule rejected");
+        }
+    }
+
+    // Whether `input` would be admitted by all matched rules, nothing is consumed.
+    // Each statement of a multi-statement query is checked alone.
+    fn would_allow(&self, input: &str) -> bool {
+        let units = self.matcher.units(input, &self.enabled, None);
+        let instances = self.instances.lock();
+        units.iter().all(|(text, rules)| {
+            rules.iter().all(|&idx| {
+                self.matcher.dry_run[idx]
+                    || (self.matcher.precheck(idx, text).is_none()
+                        && instances[idx].would_admit(text))
+            })
+        })
+    }
+
+    // Whether any enabled `Throttle` rule has available permits, or there is no such rule
+    fn has_capacity(&self) -> bool {
+        let now = Instant::now();
+        let instances = self.instances.lock();
+        let mut throttling = instances
+            .iter()
+            .zip(&self.enabled)
+            .filter(|(c, enabled)| {
+                enabled.load(Ordering::Relaxed)
+                    && c.action == config::ConcurrencyControlAction::Throttle
+                    && !c.dry_run
+            })
+            .peekable();
+        throttling.peek().is_none()
+            || throttling.any(|(c, _)| !c.quarantined(now) && c.available(now).0 > 0)
+    }
+
+    // Count the decision of the matched rules, the rejection is counted by the rejecting rule
+    fn record(&self, decision: &ConcurrencyControlDecision) {
+        if !decision.allowed {
+            if let Some(idx) = decision.rule_index {
+                self.counters[idx].rejected.fetch_add(1, Ordering::Relaxed);
+                if let (Some(sampling), Some(reason)) = (&self.log_sampling[idx], decision.reason) {
+                    self.log_rejection(idx, reason, sampling);
+                }
+            }
+            return;
+        }
+
+        for &idx in &decision.guard.matched_rules {
+            self.counters[idx].allowed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Record the inner service time of the request admitted by `guard`
+    fn record_latency(&self, guard: &ConcurrencyControlGuard, latency: Duration) {
+        for &idx in &guard.matched_rules {
+            self.counters[idx].record_latency(latency);
+        }
+    }
+
+    // Return the error of the rejected decision
+    fn reject_error(&self, decision: &ConcurrencyControlDecision) -> PluginError {
+        match decision.reason {
+            Some(RejectReason::QueryTooLarge { bytes, max }) => {
+                return PluginError::QueryTooLarge { bytes, max }
+            }
+            Some(RejectReason::RowLimitExceeded { rows, max }) => {
+                return PluginError::RowLimitExceeded { rows, max }
+            }
+            Some(RejectReason::Draining) => return PluginError::Draining,
+            _ => {}
+        }
+
+        let rule_index = decision.rule_index.unwrap_or_default();
+        if decision.reason == Some(RejectReason::Quarantined) {
+            return PluginError::Quarantined { rule_index };
+        }
+        let regex = self.counters.get(rule_index).map(|c| c.regex.clone()).unwrap_or_default();
+        let retry_after = self.retry_after(rule_index);
+        let message = self.instances.lock().get(rule_index).and_then(|c| c.reject_message.clone());
+        PluginError::ConcurrencyControlPluginReject { rule_index, regex, retry_after, message }
+    }
+
+    // Return the time until the rule `idx` may admit a request
+    fn retry_after(&self, idx: usize) -> Option<Duration> {
+        self.instances.lock().get(idx).and_then(|c| c.retry_after(Instant::now()))
+    }
+
+    // Return the window of the rule `idx`, the permits to wait for `input`
+    // and the time to wait, return None if the rule does not wait
+    fn acquire_timeout(
+        &self,
+        idx: Option<usize>,
+        input: &str,
+    ) -> Option<(FixedWindow, u32, Duration)> {
+        let idx = idx?;
+        let units = self.matcher.units(input, &self.enabled, None);
+        let instances = self.instances.lock();
+        let c = &instances[idx];
+        // the permits of all statements matched by the rule
+        let weight =
+            units.iter().filter(|(_, rules)| rules.contains(&idx)).map(|(t, _)| c.weight(t)).sum();
+        match c.acquire_timeout {
+            Some(timeout) if c.algorithm == config::ConcurrencyControlAlgorithm::FixedWindow => {
+                Some((c.window.clone(), weight, timeout))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Bypassable for ConcurrencyControlLayer {
+    fn with_bypass(mut self, bypass: Bypass) -> Self {
+        self.options.bypass = bypass;
+        self
+    }
+}
+
+impl<S> Layer<S> for ConcurrencyControlLayer {
+    type Service = ConcurrencyControl<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        // The regexes are compiled once when the layer was created
+        let mut instances: Vec<_> = self.compiled.instances.iter().map(|c| c.fresh()).collect();
+        let mut options = self.options.clone();
+        let runtime = options.client_eviction.and_then(|_| Handle::try_current().ok());
+        // the idle clients are evicted when they are looked up if the service is built
+        // outside a runtime, the reloaded rules are built by the options of the service
+        if options.client_eviction.is_some() && runtime.is_none() {
+            tracing::warn!("no tokio runtime to evict the idle clients, evicted when looked up");
+            options.client_eviction = None;
+            for c in &mut instances {
+                c.idle_ttl = None;
+            }
+        }
+        let rules = ConcurrencyControlRules::with_matcher(instances, self.compiled.matcher.clone());
+        let rules = Arc::new(ArcSwap::from_pointee(rules));
+        let eviction = options.client_eviction.zip(runtime).map(|(eviction, runtime)| {
+            Arc::new(spawn_client_eviction(
+                &runtime,
+                Arc::downgrade(&rules),
+                eviction.sweep_interval,
+            ))
+        });
+
+        ConcurrencyControl {
+            inner,
+            rules,
+            options,
+            stores: Arc::new(Mutex::new(self.compiled.stores.clone())),
+            _eviction: eviction,
+            drain: Arc::default(),
+            tracer: Arc::default(),
+            prepared: HashMap::new(),
+        }
+    }
+}
+
+// Sweep the idle clients of the current rules every `sweep_interval` on `runtime`,
+// the task is aborted once all clones of the service are dropped.
+fn spawn_client_eviction(
+    runtime: &Handle,
+    rules: Weak<ArcSwap<ConcurrencyControlRules>>,
+    sweep_interval: Duration,
+) -> EvictionTask {
+    EvictionTask(runtime.spawn(async move {
+        let mut interval = tokio::time::interval(sweep_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let Some(rules) = rules.upgrade() else { break };
+            rules.load().evict_idle_clients(Instant::now());
+        }
+    }))
+}
+
+/// The task evicting the idle clients of a service, it is shared by the clones of the
+/// service and aborted when the last one is dropped
+#[derive(Debug)]
+struct EvictionTask(JoinHandle<()>);
+
+impl Drop for EvictionTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConcurrencyControl<S> {
+    inner: S,
+    rules: Arc<ArcSwap<ConcurrencyControlRules>>,
+    options: BuildOptions,
+    // The stores of the current rules by backend, they are reused by `reload`
+    stores: Arc<Mutex<Stores>>,
+    // The eviction task of the service, only held to abort it with the last clone
+    _eviction: Option<Arc<EvictionTask>>,
+    drain: Arc<DrainState>,
+    tracer: Arc<Tracer>,
+    // The SQL of the prepared statements by id, the ids are per connection,
+    // so they are not shared by the clones of the service
+    prepared: HashMap<u32, String>,
+}
+
+/// The state of draining, it is shared by all clones of the service
+#[derive(Debug, Default)]
+struct DrainState {
+    // No request is admitted once it is closed
+    closed: AtomicBool,
+    in_flight: AtomicUsize,
+    // Notified when the last request in flight finishes
+    idle: Notify,
+}
+
+/// A request in flight, it is counted until dropped
+#[derive(Debug)]
+struct InFlight(Arc<DrainState>);
+
+impl InFlight {
+    fn enter(state: &Arc<DrainState>) -> Self {
+        state.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(state.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// `ConcurrencyControlGuard` holds the permits of the matched rules,
+/// the permits are released when the guard is dropped, even on panic or early return.
+#[derive(Debug, Default)]
+pub struct ConcurrencyControlGuard {
+    rule_idx: Option<usize>,
+    matched_rules: Vec<usize>,
+    permits: Vec<WindowPermit>,
+    // The loans of the permits borrowed from the other rules of the pools
+    loans: Vec<Loan>,
+    // The permits overdrawn by the `High` priority request
+    overdrafts: Vec<Overdraft>,
+    // Whether the request reset the fixed window of `rule_idx`
+    window_reset: bool,
+    // Whether a rule admitted the request beyond its soft limit
+    soft_exceeded: bool,
+    in_flight: Option<InFlight>,
+}
+
+/// How an admitted request was matched, returned by `ConcurrencyControlGuard::outcome`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConcurrencyControlOutcome {
+    // No rule is matched, or an `Allow` rule is matched
+    NotMatched,
+    // The request is admitted by the rule
+    AllowedByRule(usize),
+    // The request is admitted by the rule, after resetting its elapsed fixed window
+    WindowReset(usize),
+}
+
+impl ConcurrencyControlGuard {
+    fn new(rule_idx: Option<usize>) -> Self {
+        ConcurrencyControlGuard {
+            rule_idx,
+            matched_rules: vec![],
+            permits: vec![],
+            loans: vec![],
+            overdrafts: vec![],
+            window_reset: false,
+            soft_exceeded: false,
+            in_flight: None,
+        }
+    }
+
+    /// Return true if a matched rule admitted the request beyond its `soft_limit`
+    pub fn soft_exceeded(&self) -> bool {
+        self.soft_exceeded
+    }
+
+    /// Return how the request was admitted
+    pub fn outcome(&self) -> ConcurrencyControlOutcome {
+        match self.rule_idx {
+            None => ConcurrencyControlOutcome::NotMatched,
+            Some(idx) if self.window_reset => ConcurrencyControlOutcome::WindowReset(idx),
+            Some(idx) => ConcurrencyControlOutcome::AllowedByRule(idx),
+        }
+    }
+
+    /// Return the index of the first matched rule
+    pub fn rule_idx(&self) -> Option<usize> {
+        self.rule_idx
+    }
+
+    /// Return the indexes of all matched rules, there are more than one
+    /// only if the first matched rule is in `AllMatches` mode.
+    pub fn matched_rules(&self) -> &[usize] {
+        &self.matched_rules
+    }
+}
+
+/// The reason why a query is rejected by a rule
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RejectReason {
+    // The permits of the fixed window are used up
+    PermitsExhausted,
+    // `max_concurrency` queries have been admitted in the last `duration`
+    SlidingWindowFull,
+    // The token bucket is empty
+    TokensExhausted,
+    // `max_concurrency` distinct keys have been seen in the window of `key_group`
+    DistinctKeysExhausted,
+    // The query is longer than `max_bytes` of the rule
+    QueryTooLarge { bytes: usize, max: usize },
+    // The LIMIT of the query is missing or larger than `require_limit` of the rule
+    RowLimitExceeded { rows: Option<u64>, max: u64 },
+    // The rule is quarantined after too many rejections
+    Quarantined,
+    // The window was reset less than `post_reset_cooldown` ago
+    CoolingDown,
+    // The algorithm set by `ConcurrencyControlLayer::with_algorithm` rejected the request
+    AlgorithmRejected,
+    // `ConcurrencyControl::drain` has been called, no rule is evaluated
+    Draining,
+}
+
+/// `ConcurrencyControlDecision` is the result of evaluating a query,
+/// `rule_index` is the first matched rule if allowed, otherwise the rejecting rule.
+#[derive(Debug)]
+pub struct ConcurrencyControlDecision {
+    pub allowed: bool,
+    pub reason: Option<RejectReason>,
+    pub rule_index: Option<usize>,
+    // Whether a matched rule admitted the query beyond its `soft_limit`
+    pub soft_exceeded: bool,
+    // The permits of the admitted query are held until the guard is dropped
+    pub guard: ConcurrencyControlGuard,
+}
+
+impl ConcurrencyControlDecision {
+    fn allow(guard: ConcurrencyControlGuard) -> Self {
+        ConcurrencyControlDecision {
+            allowed: true,
+            reason: None,
+            rule_index: guard.rule_idx,
+            soft_exceeded: guard.soft_exceeded,
+            guard,
+        }
+    }
+
+    fn reject(mut guard: ConcurrencyControlGuard, idx: usize, reason: RejectReason) -> Self {
+        guard.rule_idx = Some(idx);
+        ConcurrencyControlDecision {
+            allowed: false,
+            reason: Some(reason),
+            rule_index: Some(idx),
+            soft_exceeded: false,
+            guard,
+        }
+    }
+
+    // The request is rejected by no rule once draining has started
+    fn draining() -> Self {
+        ConcurrencyControlDecision {
+            allowed: false,
+            reason: Some(RejectReason::Draining),
+            rule_index: None,
+            soft_exceeded: false,
+            guard: ConcurrencyControlGuard::default(),
+        }
+    }
+}
+
+impl<S> ConcurrencyControl<S> {
+    /// Evaluate `input` against the rules without calling the inner service,
+    /// the decision is counted into `stats`.
+    pub fn evaluate(&mut self, input: &str) -> ConcurrencyControlDecision {
+        self.evaluate_with_priority(input, RequestPriority::Normal)
+    }
+
+    /// Evaluate `input` as `evaluate` with the priority of the request
+    pub fn evaluate_with_priority(
+        &mut self,
+        input: &str,
+        priority: RequestPriority,
+    ) -> ConcurrencyControlDecision {
+        let rules = self.rules.load();
+        let decision = self.admit(&rules, input, None, None, priority);
+        self.record(&rules, &decision);
+        decision
+    }
+
+    // Evaluate `input` by `rules`, the requests are rejected once draining has started.
+    // The request is in flight until the guard of the decision is dropped.
+    fn admit(
+        &self,
+        rules: &ConcurrencyControlRules,
+        input: &str,
+        client: Option<&str>,
+        source: Option<IpAddr>,
+        priority: RequestPriority,
+    ) -> ConcurrencyControlDecision {
+        let in_flight = match self.enter() {
+            Some(in_flight) => in_flight,
+            None => return ConcurrencyControlDecision::draining(),
+        };
+        let decision = rules.evaluate(input, client, source, priority);
+        self.admitted(rules, input, source, in_flight, decision)
+    }
+
+    // Enter the request in flight, None once draining has started
+    fn enter(&self) -> Option<InFlight> {
+        // entered before checking `closed`, so `drain` never misses the request
+        let in_flight = InFlight::enter(&self.drain);
+        (!self.drain.closed.load(Ordering::SeqCst)).then_some(in_flight)
+    }
+
+    // Capture the trace of the decision of the request in flight
+    fn admitted(
+        &self,
+        rules: &ConcurrencyControlRules,
+        input: &str,
+        source: Option<IpAddr>,
+        in_flight: InFlight,
+        mut decision: ConcurrencyControlDecision,
+    ) -> ConcurrencyControlDecision {
+        decision.guard.in_flight = Some(in_flight);
+        // nothing but the counter is read unless a trace is requested
+        if self.tracer.pending.load(Ordering::Relaxed) > 0 {
+            self.trace(rules, input, source, &decision);
+        }
+        decision
+    }
+
+    /// Capture the evaluation of the next request of the service or its clones, it is
+    /// returned by `take_traces`. Each call traces one more request.
+    pub fn trace_next(&self) {
+        self.tracer.pending.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Return the captured traces in the order they were captured, and clear them. Only the
+    /// last 64 traces are kept.
+    pub fn take_traces(&self) -> Vec<DecisionTrace> {
+        self.tracer.traces.lock().drain(..).collect()
+    }
+
+    // Capture the trace of `decision` if a trace is still pending, the requests evaluated
+    // at the same time take the pending traces once
+    fn trace(
+        &self,
+        rules: &ConcurrencyControlRules,
+        input: &str,
+        source: Option<IpAddr>,
+        decision: &ConcurrencyControlDecision,
+    ) {
+        if self
+            .tracer
+            .pending
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_err()
+        {
+            return;
+        }
+        let matched = matched_of(&rules.matcher.units(input, &rules.enabled, source));
+        let instances = rules.instances.lock();
+        let steps = instances
+            .iter()
+            .enumerate()
+            .map(|(idx, c)| TraceStep {
+                rule_index: idx,
+                regex: rules.counters[idx].regex.clone(),
+                checked: rules.enabled[idx].load(Ordering::Relaxed),
+                matched: matched.contains(&idx),
+                available_permits: c.snapshot().available_permits,
+            })
+            .collect();
+        drop(instances);
+
+        let mut traces = self.tracer.traces.lock();
+        if traces.len() == TRACE_CAPACITY {
+            traces.pop_front();
+        }
+        traces.push_back(DecisionTrace {
+            query: input.to_string(),
+            steps,
+            allowed: decision.allowed,
+            reason: decision.reason,
+            rule_index: decision.rule_index,
+        });
+    }
+
+    // Count the decision, and pass it to the decision logger
+    fn record(&self, rules: &ConcurrencyControlRules, decision: &ConcurrencyControlDecision) {
+        rules.record(decision);
+        if let Some(logger) = &self.options.decision_logger {
+            (logger.0)(decision, rules);
+        }
+    }
+
+    /// Stop admitting the requests, and wait for the admitted requests to finish,
+    /// the new requests are rejected with `PluginError::Draining`. Return
+    /// `PluginError::Timeout` if they are still in flight after `timeout`.
+    pub async fn drain(&self, timeout: Duration) -> Result<(), PluginError> {
+        self.drain.closed.store(true, Ordering::SeqCst);
+        let idle = async {
+            loop {
+                // created before checking, so the notification is not missed
+                let notified = self.drain.idle.notified();
+                if self.drain.in_flight.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        tokio::time::timeout(timeout, idle)
+            .await
+            .map_err(|_| PluginError::Timeout { elapsed: timeout })
+    }
+
+    /// Rebuild the rules from `config` and swap them in atomically, the rules shared by
+    /// all clones of the service are replaced. The permits and windows of the rules whose
+    /// regexes are unchanged are kept, the requests in flight complete normally.
+    /// The default rule and the jitter seed of the layer are kept.
+    pub fn reload(&self, config: Vec<config::ConcurrencyControl>) -> Result<(), PluginError> {
+        validate(&config)?;
+        // the rules are compiled once, the stores of the unchanged backends stay connected
+        let mut stores = self.stores.lock();
+        let compiled = compile(Some(&config), &self.options, &stores)?;
+        let rules = ConcurrencyControlRules::with_matcher(compiled.instances, compiled.matcher)
+            .inherit(&self.rules.load());
+        self.rules.store(Arc::new(rules));
+        *stores = compiled.stores;
+        Ok(())
+    }
+
+    /// Return whether `input` would be admitted now without consuming any permit,
+    /// the result may be stale once the other requests are admitted.
+    pub fn would_allow(&self, input: &str) -> bool {
+        !self.drain.closed.load(Ordering::SeqCst) && self.rules.load().would_allow(input)
+    }
+
+    // Whether a request matching any rule could be admitted now
+    fn is_ready(&self) -> bool {
+        if self.options.bypass.is_set() {
+            return true;
+        }
+        !self.drain.closed.load(Ordering::SeqCst) && self.rules.load().has_capacity()
+    }
+
+    /// Return the allowed and rejected counts of each rule
+    pub fn stats(&self) -> Vec<ConcurrencyControlRuleStats> {
+        self.rules
+            .load()
+            .counters
+            .iter()
+            .map(|c| {
+                let latency = c.latency.lock();
+                ConcurrencyControlRuleStats {
+                    regex: c.regex.clone(),
+                    allowed: c.allowed.load(Ordering::Relaxed),
+                    rejected: c.rejected.load(Ordering::Relaxed),
+                    would_reject: c.would_reject.load(Ordering::Relaxed),
+                    overdrawn: c.overdrawn.load(Ordering::Relaxed),
+                    latency_p50: RuleCounter::latency_at(&latency, 0.5),
+                    latency_p95: RuleCounter::latency_at(&latency, 0.95),
+                    latency_p99: RuleCounter::latency_at(&latency, 0.99),
+                }
+            })
+            .collect()
+    }
+
+    /// Reset the counters and the latencies of the rule `idx`, or of all rules if it is
+    /// None, eg: after a deploy. The permits and windows of the rules are not affected.
+    pub fn reset_stats(&self, idx: Option<usize>) -> Result<(), PluginError> {
+        let rules = self.rules.load();
+        match idx {
+            Some(idx) => rules
+                .counters
+                .get(idx)
+                .ok_or(PluginError::InvalidRuleIndex { rule_index: idx })?
+                .reset(),
+            None => rules.counters.iter().for_each(|c| c.reset()),
+        }
+        Ok(())
+    }
+
+    /// Render the counters and the available permits of each rule in the Prometheus text
+    /// exposition format, the rules are labeled by their index and regexes.
+    pub fn render_prometheus(&self) -> String {
+        let stats = self.stats();
+        let snapshot = self.snapshot();
+        let labels = stats
+            .iter()
+            .enumerate()
+            .map(|(idx, s)| {
+                format!("rule=\"{}\",regex=\"{}\"", idx, escape_label_value(&s.regex.join(",")))
+            })
+            .collect::<Vec<_>>();
+
+        let mut out = String::new();
+        let mut render = |name: &str, kind: &str, help: &str, values: Vec<usize>| {
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+            for (labels, value) in labels.iter().zip(values) {
+                out.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+            }
+        };
+        render(
+            "pisa_concurrency_control_allowed_total",
+            "counter",
+            "The requests admitted by the rule.",
+            stats.iter().map(|s| s.allowed as usize).collect(),
+        );
+        render(
+            "pisa_concurrency_control_rejected_total",
+            "counter",
+            "The requests rejected by the rule.",
+            stats.iter().map(|s| s.rejected as usize).collect(),
+        );
+        render(
+            "pisa_concurrency_control_permits_available",
+            "gauge",
+            "The permits available in the current window of the rule.",
+            snapshot.iter().map(|s| s.available_permits).collect(),
+        );
+        out
+    }
+
+    /// Enable or disable the rule `rule_index` without reloading, the disabled rule
+    /// is skipped as if it did not match. It is reset to the config by `reload`.
+    pub fn set_enabled(&self, rule_index: usize, enabled: bool) {
+        if let Some(e) = self.rules.load().enabled.get(rule_index) {
+            e.store(enabled, Ordering::Relaxed);
+        }
+    }
+
+    /// Return the current state of each rule, the values are copied
+    /// so the rules are only locked briefly.
+    pub fn snapshot(&self) -> Vec<ConcurrencyControlRuleSnapshot> {
+        let rules = self.rules.load();
+        let instances = rules.instances.lock();
+        instances.iter().map(ConcurrencyControlInstance::snapshot).collect()
+    }
+
+    /// Export the current windows, so a new proxy instance can continue them with
+    /// `import_state` instead of starting with the full permits.
+    pub fn export_state(&self) -> ConcurrencyControlState {
+        let now = Instant::now();
+        let rules = self.rules.load();
+        let instances = rules.instances.lock();
+        ConcurrencyControlState {
+            rules: instances.iter().filter_map(|c| c.export_state(now)).collect(),
+        }
+    }
+
+    /// Continue the windows exported by `export_state`, the rules are matched by their
+    /// regexes and the unmatched rules are ignored. It should be imported before serving,
+    /// the permits released by the requests in flight are added back to the window.
+    pub fn import_state(&self, state: ConcurrencyControlState) {
+        let now = Instant::now();
+        let rules = self.rules.load();
+        let mut instances = rules.instances.lock();
+        for rule in &state.rules {
+            let instance = instances.iter_mut().find(|c| {
+                c.patterns() == rule.regex
+                    && c.algorithm == config::ConcurrencyControlAlgorithm::FixedWindow
+                    && c.scope == config::ConcurrencyControlScope::Global
+                    && c.key_group.is_none()
+            });
+            if let Some(instance) = instance {
+                instance.import_state(rule, now);
+            }
+        }
+    }
+
+    fn handle_with_client<Input>(
+        &mut self,
+        client: Option<&str>,
+        source: Option<IpAddr>,
+        priority: RequestPriority,
+        input: Input,
+    ) -> Result<(ConcurrencyControlGuard, S::Output), BoxError>
+    where
+        S: Service<Input>,
+        Input: AsRef<str>,
+        S::Error: Into<BoxError>,
+    {
+        // The bypassed requests are not counted by any rule
+        if self.options.bypass.is_set() {
+            let out = self.inner.handle(input).map_err(Into::into)?;
+            return Ok((ConcurrencyControlGuard::new(None), out));
+        }
+        let rules = self.rules.load_full();
+        let decision = self.admit(&rules, input.as_ref(), client, source, priority);
+        self.record(&rules, &decision);
+        if decision.allowed {
+            let started = Instant::now();
+            let res = self.inner.handle(input).map_err(Into::into);
+            rules.record_latency(&decision.guard, started.elapsed());
+            match res {
+                Ok(out) => return Ok((decision.guard, out)),
+                Err(e) => {
+                    self.record_results(&decision.guard, false);
+                    return Err(e);
+                }
+            }
+        }
+
+        Err(Box::new(rules.reject_error(&decision)))
+    }
+
+    /// Record the result of the inner service for the rule `idx`, the failures shrink
+    /// the limit of the adaptive rule when its window ends. Other rules ignore it.
+    pub fn record_result(&self, idx: usize, ok: bool) {
+        if ok {
+            return;
+        }
+        let rules = self.rules.load();
+        let mut instances = rules.instances.lock();
+        if let Some(c) = instances.get_mut(idx) {
+            if c.adaptive.is_some() {
+                c.window_failed += 1;
+            }
+        }
+    }
+
+    // Record the result for all the rules matched by the admitted request
+    fn record_results(&self, guard: &ConcurrencyControlGuard, ok: bool) {
+        for &idx in guard.matched_rules() {
+            self.record_result(idx, ok)
+        }
+    }
+
+    /// Change the limit of the rule `idx` to `max_concurrency` without resetting its window.
+    /// Growing takes effect at once, shrinking takes effect as the requests in flight release
+    /// their permits. The limit is restored to the config by `reload`.
+    pub fn resize_rule(&self, idx: usize, max_concurrency: usize) -> Result<(), PluginError> {
+        let rules = self.rules.load();
+        let mut instances = rules.instances.lock();
+        let c = instances.get_mut(idx).ok_or(PluginError::InvalidRuleIndex { rule_index: idx })?;
+        let mut errors = vec![];
+        if max_concurrency == 0 {
+            errors.push(String::from("max_concurrency must be greater than 0"));
+        }
+        if matches!(c.algorithm, config::ConcurrencyControlAlgorithm::TokenBucket { .. }) {
+            errors.push(String::from("token bucket can not be resized"));
+        }
+        if !errors.is_empty() {
+            return Err(PluginError::InvalidConcurrencyControlConfig { errors });
+        }
+        c.resize(max_concurrency);
+        Ok(())
+    }
+
+    /// Replace the patterns of the rule `idx` with `patterns` and swap the matcher in atomically,
+    /// without a full reload. The patterns are validated and compiled as the configured rule.
+    /// The instances are shared with the old rules, so the permits, windows and enabled flag
+    /// of all rules are kept, even for the requests in flight, so are the counters of the other
+    /// rules, the rule is counted from zero with its new patterns. The old patterns are kept if
+    /// any of `patterns` is invalid.
+    pub fn update_regex<P: AsRef<str>>(
+        &self,
+        idx: usize,
+        patterns: &[P],
+    ) -> Result<(), PluginError> {
+        let old = self.rules.load();
+        let mut instances = old.instances.lock();
+        let c = instances.get_mut(idx).ok_or(PluginError::InvalidRuleIndex { rule_index: idx })?;
+        let previous = c.config.regex.clone();
+        c.set_patterns(patterns.iter().map(|p| p.as_ref().to_string()).collect())?;
+        let matcher = match ConcurrencyControlMatcher::new(&instances, &self.options) {
+            Ok(matcher) => matcher,
+            Err(e) => {
+                instances[idx].set_patterns(previous).expect("the old patterns are valid");
+                return Err(e);
+            }
+        };
+
+        drop(instances);
+
+        let mut rules =
+            ConcurrencyControlRules::with_shared(old.instances.clone(), Arc::new(matcher));
+        for (i, counter) in old.counters.iter().enumerate().filter(|(i, _)| *i != idx) {
+            rules.counters[i] = counter.clone();
+        }
+        for (enabled, old) in rules.enabled.iter().zip(&old.enabled) {
+            enabled.store(old.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        self.rules.store(Arc::new(rules));
+        Ok(())
+    }
+
+    /// Return a permit to the fixed window of the rule `idx`, eg: the permit of a leaked guard.
+    /// It is written off from the permits in flight, so their guards do not release it again
+    /// and the permits never exceed `max_concurrency`. Calling it on a full window fails.
+    pub fn add_permits(&self, idx: usize) -> Result<(), PluginError> {
+        let rules = self.rules.load();
+        let instances = rules.instances.lock();
+        let c = instances.get(idx).ok_or(PluginError::InvalidRuleIndex { rule_index: idx })?;
+        // The sliding window does not hold any permit
+        if c.algorithm == config::ConcurrencyControlAlgorithm::FixedWindow
+            && !c.window.add_permit(c.max_concurrency)
+        {
+            return Err(PluginError::PermitsFull { rule_index: idx });
+        }
+        Ok(())
+    }
+
+    /// Remember the SQL of the prepared statement `stmt_id`, so its executions can be
+    /// evaluated by the SQL, as the rules never match the binary payload of an execution.
+    pub fn prepare(&mut self, stmt_id: u32, sql: impl Into<String>) {
+        self.prepared.insert(stmt_id, sql.into());
+    }
+
+    /// Forget the prepared statement `stmt_id` when it is closed
+    pub fn close_prepared(&mut self, stmt_id: u32) {
+        self.prepared.remove(&stmt_id);
+    }
+
+    /// Return the SQL of the prepared statement `stmt_id`
+    pub fn resolve_prepared(&self, stmt_id: u32) -> Option<&str> {
+        self.prepared.get(&stmt_id).map(String::as_str)
+    }
+}
+
+impl<S, Input> Service<Input> for ConcurrencyControl<S>
+where
+    S: Service<Input>,
+    Input: AsRef<str>,
+    S::Error: Into<BoxError>,
+{
+    type Output = (ConcurrencyControlGuard, S::Output);
+    type Error = BoxError;
+
+    fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        self.handle_with_client(None, None, RequestPriority::Normal, input)
+    }
+
+    // Not ready if all rules are exhausted, the queries matching no rule are still admitted
+    fn poll_ready(&mut self) -> bool {
+        self.is_ready() && self.inner.poll_ready()
+    }
+}
+
+impl<S, Input> Service<ClientInput<Input>> for ConcurrencyControl<S>
+where
+    S: Service<Input>,
+    Input: AsRef<str>,
+    S::Error: Into<BoxError>,
+{
+    type Output = (ConcurrencyControlGuard, S::Output);
+    type Error = BoxError;
+
+    fn handle(&mut self, input: ClientInput<Input>) -> Result<Self::Output, Self::Error> {
+        self.handle_with_client(Some(&input.client_id), None, RequestPriority::Normal, input.input)
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.is_ready() && self.inner.poll_ready()
+    }
+}
+
+impl<S, Input> Service<SourceInput<Input>> for ConcurrencyControl<S>
+where
+    S: Service<Input>,
+    Input: AsRef<str>,
+    S::Error: Into<BoxError>,
+{
+    type Output = (ConcurrencyControlGuard, S::Output);
+    type Error = BoxError;
+
+    fn handle(&mut self, input: SourceInput<Input>) -> Result<Self::Output, Self::Error> {
+        // the IPv4 clients of a dual-stack socket are matched by their IPv4 address
+        let source = Some(input.source.ip().to_canonical());
+        self.handle_with_client(None, source, RequestPriority::Normal, input.input)
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.is_ready() && self.inner.poll_ready()
+    }
+}
+
+impl<S, Input> Service<PriorityInput<Input>> for ConcurrencyControl<S>
+where
+    S: Service<Input>,
+    Input: AsRef<str>,
+    S::Error: Into<BoxError>,
+{
+    type Output = (ConcurrencyControlGuard, S::Output);
+    type Error = BoxError;
+
+    fn handle(&mut self, input: PriorityInput<Input>) -> Result<Self::Output, Self::Error> {
+        self.handle_with_client(None, None, input.priority, input.input)
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.is_ready() && self.inner.poll_ready()
+    }
+}
+
+// The expired input is rejected before acquiring a permit, it is not counted by any rule
+impl<S, Input> Service<Deadlined<Input>> for ConcurrencyControl<S>
+where
+    S: Service<Input>,
+    Input: AsRef<str>,
+    S::Error: Into<BoxError>,
+{
+    type Output = (ConcurrencyControlGuard, S::Output);
+    type Error = BoxError;
+
+    fn handle(&mut self, input: Deadlined<Input>) -> Result<Self::Output, Self::Error> {
+        if input.is_expired() {
+            return Err(Box::new(PluginError::DeadlineExceeded));
+        }
+        self.handle_with_client(None, None, RequestPriority::Normal, input.input)
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.is_ready() && self.inner.poll_ready()
+    }
+}
+
+// The permits are owned by the guard in the state of the returned future, so they are
+// released if the future is dropped before completing, eg: cancelled by a timeout.
+#[async_trait]
+impl<S, Input> AsyncService<Input> for ConcurrencyControl<S>
+where
+    S: AsyncService<Input> + Send,
+    Input: AsRef<str> + Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Output = (ConcurrencyControlGuard, S::Output);
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        self.handle_async(input, None).await
+    }
+}
+
+// The waiting for a permit ends at the deadline
+#[async_trait]
+impl<S, Input> AsyncService<Deadlined<Input>> for ConcurrencyControl<S>
+where
+    S: AsyncService<Input> + Send,
+    Input: AsRef<str> + Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Output = (ConcurrencyControlGuard, S::Output);
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: Deadlined<Input>) -> Result<Self::Output, Self::Error> {
+        if input.is_expired() {
+            return Err(Box::new(PluginError::DeadlineExceeded));
+        }
+        self.handle_async(input.input, input.deadline).await
+    }
+}
+
+impl<S> ConcurrencyControl<S> {
+    async fn handle_async<Input>(
+        &mut self,
+        input: Input,
+        deadline: Option<Instant>,
+    ) -> Result<(ConcurrencyControlGuard, S::Output), BoxError>
+    where
+        S: AsyncService<Input> + Send,
+        Input: AsRef<str> + Send + 'static,
+        S::Error: Into<BoxError>,
+    {
+        if self.options.bypass.is_set() {
+            let out = self.inner.handle(input).await.map_err(Into::into)?;
+            return Ok((ConcurrencyControlGuard::new(None), out));
+        }
+        let rules = self.rules.load_full();
+        // the stores of the rules with a remote backend are awaited, `self` is not
+        // borrowed across the await as the inner service may not be `Sync`
+        let entered = self.enter();
+        let text: &str = input.as_ref();
+        let mut decision = match entered {
+            Some(in_flight) => {
+                let decision =
+                    rules.evaluate_async(text, None, None, RequestPriority::Normal).await;
+                self.admitted(&rules, text, None, in_flight, decision)
+            }
+            None => ConcurrencyControlDecision::draining(),
+        };
+        // Only wait for a single matched rule, the other rules have been rolled back
+        if decision.reason == Some(RejectReason::PermitsExhausted)
+            && decision.guard.matched_rules.len() == 1
+        {
+            if let Some((window, weight, timeout)) =
+                rules.acquire_timeout(decision.rule_index, input.as_ref())
+            {
+                // the wait ends at the deadline of the input or of the stack
+                let deadline = deadline.into_iter().chain(stack_deadline()).min();
+                let timeout = deadline
+                    .map_or(timeout, |d| timeout.min(d.saturating_duration_since(Instant::now())));
+                if let Ok(Ok(permit)) = tokio::time::timeout(timeout, window.acquire(weight)).await
+                {
+                    decision.guard.permits.push(permit);
+                    decision = ConcurrencyControlDecision::allow(decision.guard);
+                }
+            }
+        }
+
+        self.record(&rules, &decision);
+        if !decision.allowed {
+            return Err(Box::new(rules.reject_error(&decision)));
+        }
+
+        let started = Instant::now();
+        let res = self.inner.handle(input).await.map_err(Into::into);
+        rules.record_latency(&decision.guard, started.elapsed());
+        match res {
+            Ok(out) => Ok((decision.guard, out)),
+            Err(e) => {
+                self.record_results(&decision.guard, false);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        panic::{self, AssertUnwindSafe},
+        sync::{atomic::Ordering, Arc},
+        thread::{self, sleep},
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    };
+
+    use parking_lot::Mutex;
+
+    use super::{
+        algorithm::{FixedWindowAlgorithm, SlidingWindowAlgorithm},
+        literal_prefix, AdmitAlgorithm, AdmitResult, ClientInput, ConcurrencyControl,
+        ConcurrencyControlConfig, ConcurrencyControlDecision, ConcurrencyControlInstance,
+        ConcurrencyControlLayer, ConcurrencyControlOutcome, ConcurrencyControlState, PriorityInput,
+        RejectReason, RequestPriority, SourceInput,
+    };
+    use crate::{
+        config,
+        deadline::Deadlined,
+        err::{BoxError, PluginError},
+        layer::{
+            async_service_fn, service_fn, AsyncService, BoxCloneService, Layer, Service,
+            ServiceBuilder,
+        },
+        sql::StatementKind,
+    };
+    use tracing_test::traced_test;
+
+    fn test_service(input: &str) -> Result<String, PluginError> {
+        sleep(Duration::new(5, 0));
+        Ok(input.to_string())
+    }
+
+    #[test]
+    fn test_concurrency_control() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"[A-Za-z]+$")],
+            max_concurrency: 3,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        }];
+
+        let svc = service_fn(test_service);
+
+        let wrap_svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(svc);
+
+        let mut tasks = vec![];
+        for _ in 0..5 {
+            let mut svc = wrap_svc.clone();
+            //sleep(Duration::new(3, 0));
+            tasks.push(thread::spawn(move || svc.handle("abc")))
+        }
+
+        let mut count = 0;
+        for t in tasks {
+            let res = t.join();
+            println!("{:?}", res);
+            let res = res.unwrap();
+            match res {
+                Ok((guard, _)) => {
+                    assert_eq!(guard.outcome(), ConcurrencyControlOutcome::AllowedByRule(0));
+                    count += 1
+                }
+                Err(e) => {
+                    let e = e.downcast::<PluginError>().unwrap();
+                    assert!(matches!(
+                        *e,
+                        PluginError::ConcurrencyControlPluginReject {
+                            rule_index: 0,
+                            ref regex,
+                            retry_after: Some(_),
+                            message: None,
+                        }
+                            if *regex == vec![String::from(r"[A-Za-z]+$")]
+                    ));
+                }
+            }
+        }
+
+        assert_eq!(count, 3)
+    }
+
+    #[test]
+    fn test_concurrency_control_invalid_regex() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 3,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^INSERT"), String::from(r"^UPDATE ([a-z]+")],
+                max_concurrency: 3,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+        ];
+
+        let err = ConcurrencyControlLayer::new(config).err().unwrap();
+        match err {
+            PluginError::InvalidConcurrencyControlRegex { regex, .. } => {
+                assert_eq!(regex, r"^UPDATE ([a-z]+")
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    fn count_admitted_across_window(algorithm: config::ConcurrencyControlAlgorithm) -> usize {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 3,
+            duration: Duration::from_millis(300),
+            algorithm,
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let mut count = 0;
+        let mut send = |n: usize| {
+            for _ in 0..n {
+                if svc.handle("SELECT 1").is_ok() {
+                    count += 1;
+                }
+            }
+        };
+
+        // start the window, then send the rest of the budget right before the boundary
+        send(1);
+        sleep(Duration::from_millis(250));
+        send(2);
+        // cross the boundary of the fixed window
+        sleep(Duration::from_millis(100));
+        send(3);
+
+        count
+    }
+
+    #[test]
+    fn test_concurrency_control_sliding_window() {
+        let fixed = count_admitted_across_window(config::ConcurrencyControlAlgorithm::FixedWindow);
+        assert_eq!(fixed, 6);
+
+        // only the request admitted at the start of the window has left the trailing duration
+        let sliding =
+            count_admitted_across_window(config::ConcurrencyControlAlgorithm::SlidingWindow);
+        assert_eq!(sliding, 4);
+    }
+
+    #[test]
+    fn test_concurrency_control_token_bucket() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            algorithm: config::ConcurrencyControlAlgorithm::TokenBucket {
+                capacity: 3,
+                refill_per_sec: 10.0,
+            },
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        for _ in 0..3 {
+            assert!(svc.handle("SELECT 1").is_ok());
+        }
+        assert!(svc.handle("SELECT 1").is_err());
+
+        // 10 tokens per second, about 2 tokens are refilled after 200ms
+        sleep(Duration::from_millis(200));
+        let tokens = svc.rules.load().instances.lock()[0].available_tokens();
+        assert!((1.9..2.5).contains(&tokens), "unexpected tokens {}", tokens);
+
+        assert!(svc.handle("SELECT 1").is_ok());
+        assert!(svc.handle("SELECT 1").is_ok());
+        assert!(svc.handle("SELECT 1").is_err());
+
+        // the accumulated tokens are capped by capacity
+        sleep(Duration::from_millis(600));
+        let tokens = svc.rules.load().instances.lock()[0].available_tokens();
+        assert_eq!(tokens, 3.0);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_control_acquire_timeout() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            acquire_timeout: Some(Duration::from_millis(500)),
+            ..Default::default()
+        }];
+
+        let layer = ConcurrencyControlLayer::new(config).unwrap();
+        let mut sync_svc = ServiceBuilder::new()
+            .with_layer(&layer)
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let _guard = Service::handle(&mut sync_svc, "SELECT 1").unwrap();
+        // the sync path rejects immediately
+        assert!(Service::handle(&mut sync_svc, "SELECT 1").is_err());
+
+        let mut svc = ServiceBuilder::new().with_layer(&layer).build(async_service_fn(
+            |input: &'static str| async move { Ok::<_, PluginError>(input.to_string()) },
+        ));
+        let guard = AsyncService::handle(&mut svc, "SELECT 1").await.unwrap();
+
+        // the permit is freed within the timeout
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(guard);
+        });
+        let (guard, _) = AsyncService::handle(&mut svc, "SELECT 1").await.unwrap();
+        assert_eq!(guard.rule_idx(), Some(0));
+
+        // no permit is freed, rejected after the timeout
+        let res = AsyncService::handle(&mut svc, "SELECT 1").await;
+        let e = res.unwrap_err().downcast::<PluginError>().unwrap();
+        assert!(matches!(
+            *e,
+            PluginError::ConcurrencyControlPluginReject {
+                rule_index: 0,
+                ref regex,
+                retry_after: Some(_),
+                message: None,
+            }
+                if *regex == vec![String::from(r"^SELECT")]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_control_async_inner_holds_permit() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(async_service_fn(|input: &'static str| async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                Ok::<_, PluginError>(input.to_string())
+            }));
+
+        // the permit is held while the inner future is pending
+        let mut slow = svc.clone();
+        let pending = tokio::spawn(async move { slow.handle("SELECT 1").await.map(|_| ()) });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(AsyncService::handle(&mut svc, "SELECT 2").await.is_err());
+
+        // and released with the guard once the inner future completes
+        assert!(pending.await.unwrap().is_ok());
+        let (_, out) = AsyncService::handle(&mut svc, "SELECT 3").await.unwrap();
+        assert_eq!(out, "SELECT 3");
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_control_async_cancelled() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            acquire_timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(async_service_fn(|input: &'static str| async move {
+                if input == "SELECT SLEEP" {
+                    std::future::pending::<()>().await;
+                }
+                Ok::<_, PluginError>(input.to_string())
+            }));
+
+        // the future holding the permit is dropped while the inner future is pending
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(20),
+            AsyncService::handle(&mut svc, "SELECT SLEEP"),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        assert_eq!(svc.snapshot()[0].available_permits, 1);
+
+        // so is the future waiting for a permit
+        let (guard, _) = AsyncService::handle(&mut svc, "SELECT 1").await.unwrap();
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(20),
+            AsyncService::handle(&mut svc, "SELECT 2"),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        drop(guard);
+        assert_eq!(svc.snapshot()[0].available_permits, 1);
+        assert!(AsyncService::handle(&mut svc, "SELECT 3").await.is_ok());
+    }
+
+    #[test]
+    fn test_concurrency_control_guard_release_on_panic() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 2,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| -> Result<String, PluginError> {
+                if input.contains("panic") {
+                    panic!("inner service panic");
+                }
+                Ok(input.to_string())
+            }));
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| svc.handle("SELECT panic")));
+        assert!(res.is_err());
+
+        let available = |svc: &ConcurrencyControl<_>| {
+            svc.rules.load().instances.lock()[0].window.semaphore.available_permits()
+        };
+        assert_eq!(available(&svc), 2);
+
+        let (guard, _) = svc.handle("SELECT 1").unwrap();
+        assert_eq!(guard.rule_idx(), Some(0));
+        assert_eq!(available(&svc), 1);
+        drop(guard);
+        assert_eq!(available(&svc), 2);
+    }
+
+    #[test]
+    fn test_concurrency_control_per_client() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::from_millis(100),
+            scope: config::ConcurrencyControlScope::PerClient,
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let input = |client: &str| ClientInput { client_id: client.to_string(), input: "SELECT 1" };
+
+        let guard = svc.handle(input("a")).unwrap();
+        assert!(svc.handle(input("a")).is_err());
+        // the other client has its own permits
+        assert!(svc.handle(input("b")).is_ok());
+
+        let clients =
+            |svc: &ConcurrencyControl<_>| svc.rules.load().instances.lock()[0].clients.len();
+        assert_eq!(clients(&svc), 2);
+
+        // the idle client `b` is evicted, but `a` still holds a permit
+        sleep(Duration::from_millis(150));
+        assert!(svc.handle(input("c")).is_ok());
+        assert_eq!(clients(&svc), 2);
+
+        drop(guard);
+        sleep(Duration::from_millis(150));
+        assert!(svc.handle(input("d")).is_ok());
+        assert_eq!(clients(&svc), 1);
+    }
+
+    #[test]
+    fn test_concurrency_control_stats() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 2,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^INSERT"), String::from(r"^UPDATE")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+        ];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let mut results = vec![];
+        for input in ["SELECT 1", "SELECT 2", "SELECT 3", "INSERT 1", "UPDATE 1", "DELETE 1"] {
+            results.push(svc.handle(input));
+        }
+
+        let stats = svc.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].regex, vec![String::from(r"^SELECT")]);
+        assert_eq!((stats[0].allowed, stats[0].rejected), (2, 1));
+        assert_eq!(stats[1].regex, vec![String::from(r"^INSERT"), String::from(r"^UPDATE")]);
+        assert_eq!((stats[1].allowed, stats[1].rejected), (1, 1));
+
+        // the rejected error names the rule
+        let e = results.remove(4).unwrap_err().downcast::<PluginError>().unwrap();
+        assert!(matches!(
+            *e,
+            PluginError::ConcurrencyControlPluginReject {
+                rule_index: 1,
+                ref regex,
+                retry_after: Some(_),
+                message: None,
+            }
+                if *regex == vec![String::from(r"^INSERT"), String::from(r"^UPDATE")]
+        ));
+    }
+
+    #[test]
+    fn test_concurrency_control_priority() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 10,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT .* FROM t_order")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                priority: 10,
+                ..Default::default()
+            },
+        ];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the tighter rule has higher priority, so it is matched first
+        let (guard, _) = svc.handle("SELECT * FROM t_order").unwrap();
+        assert_eq!(guard.rule_idx(), Some(0));
+        assert!(svc.handle("SELECT * FROM t_order").is_err());
+
+        let (guard, _) = svc.handle("SELECT * FROM t_user").unwrap();
+        assert_eq!(guard.rule_idx(), Some(1));
+    }
+
+    #[test]
+    fn test_concurrency_control_case_insensitive() {
+        let config = |case_insensitive| {
+            vec![config::ConcurrencyControl {
+                regex: vec![String::from(r"^select")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                case_insensitive,
+                ..Default::default()
+            }]
+        };
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config(true)).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let (guard, _) = svc.handle("SELECT * FROM t").unwrap();
+        assert_eq!(guard.rule_idx(), Some(0));
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config(false)).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let (guard, _) = svc.handle("SELECT * FROM t").unwrap();
+        assert_eq!(guard.rule_idx(), None);
+    }
+
+    #[test]
+    fn test_concurrency_control_allow_action() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r".*")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT 1$")],
+                priority: 10,
+                action: config::ConcurrencyControlAction::Allow,
+                ..Default::default()
+            },
+        ];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let _guard = svc.handle("SELECT * FROM t").unwrap();
+        assert!(svc.handle("SELECT * FROM t").is_err());
+        // the health check query bypasses the broad throttle rule
+        let (guard, out) = svc.handle("SELECT 1").unwrap();
+        assert_eq!(guard.rule_idx(), None);
+        assert_eq!(out, "SELECT 1");
+        assert_eq!(svc.stats()[1].rejected, 1);
+    }
+
+    #[test]
+    fn test_concurrency_control_reload() {
+        let config = |max_concurrency| {
+            vec![config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            }]
+        };
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config(3)).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let mut guards = (0..3).map(|_| svc.handle("SELECT 1").unwrap().0).collect::<Vec<_>>();
+        assert!(svc.handle("SELECT 1").is_err());
+
+        // the clones of the service share the reloaded rules
+        let mut cloned = svc.clone();
+        svc.reload(config(5)).unwrap();
+        guards.push(cloned.handle("SELECT 1").unwrap().0);
+        guards.push(svc.handle("SELECT 1").unwrap().0);
+        assert!(svc.handle("SELECT 1").is_err());
+        assert_eq!(svc.stats()[0].allowed, 5);
+        assert_eq!(svc.stats()[0].rejected, 2);
+
+        // the permits held before reloading are still released
+        drop(guards);
+        assert_eq!(svc.rules.load().instances.lock()[0].window.semaphore.available_permits(), 5);
+
+        // the rules are kept on an invalid config
+        assert!(svc
+            .reload(vec![config::ConcurrencyControl {
+                regex: vec![String::from(r"(")],
+                ..Default::default()
+            }])
+            .is_err());
+        assert_eq!(svc.stats()[0].allowed, 5);
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn test_concurrency_control_reload_store() {
+        let config = |regex: &str, url: &str| {
+            vec![config::ConcurrencyControl {
+                regex: vec![String::from(regex)],
+                max_concurrency: 3,
+                duration: Duration::new(50, 0),
+                backend: config::ConcurrencyControlBackend::Redis {
+                    url: String::from(url),
+                    key_prefix: String::new(),
+                },
+                ..Default::default()
+            }]
+        };
+        let store = |svc: &ConcurrencyControl<_>| {
+            svc.rules.load().instances.lock()[0].store.clone().unwrap()
+        };
+
+        let svc = ServiceBuilder::new()
+            .with_layer(
+                ConcurrencyControlLayer::new(config(r"^SELECT", "redis://localhost")).unwrap(),
+            )
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let connected = store(&svc);
+
+        // the store of the same backend is reused by the reloaded rules
+        svc.reload(config(r"^UPDATE", "redis://localhost")).unwrap();
+        assert!(Arc::ptr_eq(&store(&svc), &connected));
+
+        // the store of a removed backend is dropped
+        svc.reload(config(r"^UPDATE", "redis://localhost:6380")).unwrap();
+        assert!(!Arc::ptr_eq(&store(&svc), &connected));
+        assert_eq!(svc.stores.lock().len(), 1);
+    }
+
+    #[test]
+    fn test_concurrency_control_weight() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 4,
+            duration: Duration::new(50, 0),
+            weight_regex: Some(String::from(r"GROUP BY")),
+            weight: Some(5),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the weight-5 query never fits in a limit of 4
+        assert!(svc.handle("SELECT a, count(*) FROM t GROUP BY a").is_err());
+        let guards = (0..4)
+            .map(|_| svc.handle("SELECT * FROM t WHERE id = 1").unwrap().0)
+            .collect::<Vec<_>>();
+        assert!(svc.handle("SELECT * FROM t WHERE id = 1").is_err());
+
+        // the weighted permits are released together
+        drop(guards);
+        assert_eq!(svc.rules.load().instances.lock()[0].window.semaphore.available_permits(), 4);
+    }
+
+    #[test]
+    fn test_concurrency_control_invalid_config() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 0,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::new()],
+                max_concurrency: 3,
+                duration: Duration::ZERO,
+                ..Default::default()
+            },
+        ];
+
+        let err = ConcurrencyControlLayer::new(config).err().unwrap();
+        assert_eq!(
+            err,
+            PluginError::InvalidConcurrencyControlConfig {
+                errors: vec![
+                    String::from("rule 0: max_concurrency must be greater than 0"),
+                    String::from("rule 1: regex must not be empty"),
+                    String::from("rule 1: duration must be greater than 0"),
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn test_concurrency_control_statement_kinds() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"\bt\b")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            statement_kinds: Some(vec![StatementKind::Update, StatementKind::Delete]),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let (guard, _) = svc.handle("/* write */ UPDATE t SET a = 1").unwrap();
+        assert_eq!(guard.rule_idx(), Some(0));
+        assert!(svc.handle("delete from t").is_err());
+
+        // the reads of the same table are not limited
+        let (guard, _) = svc.handle("SELECT * FROM t").unwrap();
+        assert_eq!(guard.rule_idx(), None);
+    }
+
+    #[test]
+    fn test_concurrency_control_from_configs() {
+        let configs = vec![ConcurrencyControlConfig {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 2,
+            duration: Duration::new(50, 0),
+        }];
+
+        let instance = ConcurrencyControlInstance::from_config(&configs[0]).unwrap();
+        assert_eq!(instance.max_concurrency, 2);
+        // the debug output shows the pattern, not the compiled regex and the semaphore
+        let debug = format!("{:?}", instance);
+        assert_eq!(
+            debug,
+            "ConcurrencyControlInstance { regex: [\"^SELECT\"], algorithm: FixedWindow, \
+             max_concurrency: 2, available_permits: 2, window_remaining: None }"
+        );
+        assert!(!debug.contains("Semaphore") && !debug.contains("Regex"));
+        let invalid =
+            ConcurrencyControlConfig { regex: vec![String::from(r"(")], ..configs[0].clone() };
+        assert!(ConcurrencyControlInstance::from_config(&invalid).is_err());
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::from_concurrency_control_configs(configs).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let _guards = (0..2).map(|_| svc.handle("SELECT 1").unwrap().0).collect::<Vec<_>>();
+        assert!(svc.handle("SELECT 1").is_err());
+        assert!(svc.handle("INSERT INTO t VALUES (1)").is_ok());
+    }
+
+    #[test]
+    fn test_concurrency_control_idle_window() {
+        let duration = Duration::from_millis(200);
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 3,
+            duration,
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        drop((0..3).map(|_| svc.handle("SELECT 1").unwrap().0).collect::<Vec<_>>());
+        sleep(Duration::from_millis(500));
+
+        // the full budget is available, and the request resetting the window is counted
+        let guards = (0..3).map(|_| svc.handle("SELECT 1").unwrap().0).collect::<Vec<_>>();
+        assert!(svc.handle("SELECT 1").is_err());
+        assert_eq!(guards[0].outcome(), ConcurrencyControlOutcome::WindowReset(0));
+        assert_eq!(guards[1].outcome(), ConcurrencyControlOutcome::AllowedByRule(0));
+        assert_eq!(
+            svc.handle("DELETE 1").unwrap().0.outcome(),
+            ConcurrencyControlOutcome::NotMatched
+        );
+
+        // the elapsed windows are skipped
+        let start_at = svc.rules.load().instances.lock()[0].window.start_at.unwrap();
+        assert!(start_at.elapsed() < duration);
+    }
+
+    #[test]
+    fn test_concurrency_control_many_windows() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 3,
+            duration: Duration::from_millis(20),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the guards are held, so the budget is only restored by the window resets
+        let mut guards = vec![];
+        let mut admitted = HashMap::<u64, u32>::new();
+        loop {
+            if let Ok((guard, _)) = svc.handle("SELECT 1") {
+                guards.push(guard);
+                let generation =
+                    svc.rules.load().instances.lock()[0].window.generation.load(Ordering::Acquire);
+                *admitted.entry(generation).or_default() += 1;
+                if generation == 10 {
+                    break;
+                }
+            }
+            sleep(Duration::from_millis(1));
+        }
+
+        // the request resetting the window is counted against the new window,
+        // a window may admit fewer if the thread was not scheduled in time
+        for generation in 0..10 {
+            let n = admitted.get(&generation).copied().unwrap_or_default();
+            assert!(n <= 3, "window {} admitted {}", generation, n);
+        }
+        assert_eq!(admitted.values().sum::<u32>() as usize, guards.len());
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_control_queued_across_window() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::from_millis(200),
+            acquire_timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(async_service_fn(|input: &'static str| async move {
+                Ok::<_, PluginError>(input.to_string())
+            }));
+        let held = AsyncService::handle(&mut svc, "SELECT 1").await.unwrap();
+
+        let waiters = (0..3)
+            .map(|_| {
+                let mut svc = svc.clone();
+                tokio::spawn(
+                    async move { AsyncService::handle(&mut svc, "SELECT 1").await.is_ok() },
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // the request crossing the window boundary resets the window,
+        // the restored permit is handed to the waiters first
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(AsyncService::handle(&mut svc, "SELECT 1").await.is_ok());
+        for waiter in waiters {
+            assert!(waiter.await.unwrap());
+        }
+
+        // the permit acquired in the previous window is not released twice
+        drop(held);
+        assert_eq!(svc.rules.load().instances.lock()[0].window.semaphore.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_concurrency_control_all_matches() {
+        let rule = |regex: &str, priority, algorithm| config::ConcurrencyControl {
+            regex: vec![String::from(regex)],
+            max_concurrency: 3,
+            duration: Duration::new(50, 0),
+            algorithm,
+            priority,
+            ..Default::default()
+        };
+        let config = vec![
+            config::ConcurrencyControl {
+                mode: config::ConcurrencyControlMatchMode::AllMatches,
+                ..rule(r"^SELECT", 30, config::ConcurrencyControlAlgorithm::FixedWindow)
+            },
+            rule(
+                r"^SELECT",
+                20,
+                config::ConcurrencyControlAlgorithm::TokenBucket {
+                    capacity: 5,
+                    refill_per_sec: 0.001,
+                },
+            ),
+            rule(r"^SELECT", 10, config::ConcurrencyControlAlgorithm::SlidingWindow),
+            config::ConcurrencyControl {
+                max_concurrency: 1,
+                ..rule(r"\bt1\b", 0, config::ConcurrencyControlAlgorithm::FixedWindow)
+            },
+        ];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let (held, _) = svc.handle("SELECT * FROM t1").unwrap();
+        assert_eq!(held.matched_rules(), [0, 1, 2, 3]);
+
+        // the last rule rejects, the admissions of the other rules are rolled back
+        let e = svc.handle("SELECT * FROM t1").unwrap_err().downcast::<PluginError>().unwrap();
+        assert!(matches!(
+            *e,
+            PluginError::ConcurrencyControlPluginReject {
+                rule_index: 3,
+                ref regex,
+                retry_after: Some(_),
+                message: None,
+            }
+                if *regex == vec![String::from(r"\bt1\b")]
+        ));
+        {
+            let rules = svc.rules.load();
+            let instances = rules.instances.lock();
+            assert_eq!(instances[0].window.semaphore.available_permits(), 2);
+            assert!(instances[1].available_tokens() < 4.1);
+            assert!(instances[1].available_tokens() >= 4.0);
+            assert_eq!(instances[2].available(Instant::now()).0, 2);
+        }
+
+        let (guard, _) = svc.handle("SELECT * FROM t2").unwrap();
+        assert_eq!(guard.rule_idx(), Some(0));
+        assert_eq!(guard.matched_rules(), [0, 1, 2]);
+
+        let stats = svc.stats();
+        assert_eq!(
+            stats.iter().map(|s| (s.allowed, s.rejected)).collect::<Vec<_>>(),
+            [(2, 0), (2, 0), (2, 0), (1, 1)]
+        );
+    }
+
+    #[test]
+    fn test_concurrency_control_first_match() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 3,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"\bt1\b")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+        ];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the second rule is not applied
+        let guards = (0..3).map(|_| svc.handle("SELECT * FROM t1").unwrap().0).collect::<Vec<_>>();
+        assert!(guards.iter().all(|g| g.matched_rules() == [0]));
+    }
+
+    #[test]
+    fn test_concurrency_control_max_bytes() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^INSERT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            max_bytes: Some(16),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // 17 bytes
+        let e = svc.handle("INSERT INTO t 1,2").unwrap_err().downcast::<PluginError>().unwrap();
+        assert_eq!(*e, PluginError::QueryTooLarge { bytes: 17, max: 16 });
+        assert_eq!(svc.stats()[0].rejected, 1);
+
+        // the oversized query does not consume the permit, 16 bytes
+        let (_guard, out) = svc.handle("INSERT INTO t 1,").unwrap();
+        assert_eq!(out.len(), 16);
+        let e = svc.handle("INSERT INTO t 1,").unwrap_err().downcast::<PluginError>().unwrap();
+        assert!(matches!(*e, PluginError::ConcurrencyControlPluginReject { .. }));
+    }
+
+    #[test]
+    fn test_concurrency_control_snapshot() {
+        let duration = Duration::from_millis(200);
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 3,
+                duration,
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^INSERT")],
+                max_concurrency: 2,
+                duration,
+                algorithm: config::ConcurrencyControlAlgorithm::SlidingWindow,
+                ..Default::default()
+            },
+        ];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let snapshot = svc.snapshot();
+        assert_eq!(snapshot[0].regex, vec![String::from(r"^SELECT")]);
+        assert_eq!((snapshot[0].max_concurrency, snapshot[0].available_permits), (3, 3));
+        assert_eq!(snapshot[0].window_started, None);
+
+        let _guards = (0..2).map(|_| svc.handle("SELECT 1").unwrap().0).collect::<Vec<_>>();
+        assert!(svc.handle("INSERT INTO t VALUES (1)").is_ok());
+
+        let snapshot = svc.snapshot();
+        assert_eq!(snapshot[0].available_permits, 1);
+        assert!(snapshot[0].window_started.is_some());
+        assert!(snapshot[0].window_remaining.unwrap() <= duration);
+        assert_eq!(snapshot[1].available_permits, 1);
+        assert!(snapshot[1].window_started.is_some());
+
+        // the elapsed windows report the full budget
+        sleep(Duration::from_millis(250));
+        let snapshot = svc.snapshot();
+        assert_eq!((snapshot[0].available_permits, snapshot[0].window_started), (3, None));
+        assert_eq!((snapshot[1].available_permits, snapshot[1].window_remaining), (2, None));
+    }
+
+    #[test]
+    fn test_concurrency_control_layer_shares_regexes() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        }];
+
+        let layer = ConcurrencyControlLayer::new(config).unwrap();
+        let inner = || service_fn(|input: &str| Ok::<_, PluginError>(input.to_string()));
+        let mut svc1 = ServiceBuilder::new().with_layer(layer.clone()).build(inner());
+        let mut svc2 = ServiceBuilder::new().with_layer(layer).build(inner());
+
+        // the compiled regexes are shared, the state is not
+        assert!(Arc::ptr_eq(&svc1.rules.load().matcher, &svc2.rules.load().matcher));
+        let _guard = svc1.handle("SELECT 1").unwrap();
+        assert!(svc1.handle("SELECT 1").is_err());
+        assert!(svc2.handle("SELECT 1").is_ok());
+    }
+
+    #[test]
+    fn test_concurrency_control_match_type() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from("SELECT 1")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                match_type: config::ConcurrencyControlMatchType::Exact,
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from("select * from")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                case_insensitive: true,
+                match_type: config::ConcurrencyControlMatchType::Prefix,
+                ..Default::default()
+            },
+        ];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the patterns are not regexes
+        assert_eq!(svc.evaluate("SELECT 10").rule_index, None);
+        let held = svc.evaluate("SELECT * FROM t");
+        assert_eq!(held.rule_index, Some(1));
+        assert_eq!(svc.evaluate("SELECT 1").rule_index, Some(0));
+        assert_eq!(svc.evaluate("Select * From (t)").reason, Some(RejectReason::PermitsExhausted));
+        assert_eq!(svc.evaluate("SELECT *").rule_index, None);
+        assert_eq!(svc.stats()[1].regex, vec![String::from("select * from")]);
+    }
+
+    #[test]
+    fn test_concurrency_control_match_normalized() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT \* FROM t WHERE id=\?$")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                match_normalized: true,
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from("SELECT a FROM t WHERE b IN (?, ?)")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                match_type: config::ConcurrencyControlMatchType::Exact,
+                match_normalized: true,
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"id=1$")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+        ];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the queries with different literals hit the same rule
+        let held = svc.evaluate("SELECT * FROM t WHERE id=1");
+        assert_eq!(held.rule_index, Some(0));
+        let decision = svc.evaluate("SELECT  *  FROM t WHERE id=2");
+        assert_eq!(
+            (decision.rule_index, decision.reason),
+            (Some(0), Some(RejectReason::PermitsExhausted))
+        );
+
+        assert_eq!(svc.evaluate("SELECT a FROM t WHERE b IN ('x, y', 0x1F)").rule_index, Some(1));
+        // the other rules are matched against the original query
+        assert_eq!(svc.evaluate("SELECT b FROM t WHERE id=1").rule_index, Some(2));
+    }
+
+    #[test]
+    fn test_concurrency_control_key_group() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"(?i)^SELECT .* FROM (\w+)")],
+            max_concurrency: 3,
+            duration: Duration::new(50, 0),
+            key_group: Some(1),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let admitted: Vec<usize> =
+            (1..=10).filter(|i| svc.evaluate(&format!("SELECT * FROM t{}", i)).allowed).collect();
+        assert_eq!(admitted, vec![1, 2, 3]);
+        assert_eq!(svc.snapshot()[0].available_permits, 0);
+
+        // the seen keys are always admitted
+        let decisions: Vec<_> = (0..5).map(|_| svc.evaluate("SELECT a FROM t2")).collect();
+        assert!(decisions.iter().all(|d| d.allowed));
+        assert_eq!(
+            svc.evaluate("SELECT * FROM t4").reason,
+            Some(RejectReason::DistinctKeysExhausted)
+        );
+
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 3,
+            duration: Duration::new(50, 0),
+            key_group: Some(1),
+            ..Default::default()
+        }];
+        assert!(matches!(
+            ConcurrencyControlLayer::new(config),
+            Err(PluginError::InvalidConcurrencyControlConfig { .. })
+        ));
+    }
+
+    #[test]
+    fn test_concurrency_control_poll_ready() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 2,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // checking does not consume a permit
+        assert!(Service::<&str>::poll_ready(&mut svc));
+        assert!(svc.would_allow("SELECT 1"));
+        assert_eq!(svc.snapshot()[0].available_permits, 2);
+
+        let first = svc.handle("SELECT 1").unwrap();
+        assert!(Service::<&str>::poll_ready(&mut svc));
+        let _second = svc.handle("SELECT 2").unwrap();
+        assert!(!Service::<&str>::poll_ready(&mut svc));
+        assert!(!svc.would_allow("SELECT 3"));
+        assert!(svc.would_allow("UPDATE t SET a = 1"));
+        assert!(!BoxCloneService::<&str, _, _>::new(svc.clone()).poll_ready());
+
+        drop(first);
+        assert!(Service::<&str>::poll_ready(&mut svc));
+        assert!(svc.would_allow("SELECT 3"));
+    }
+
+    #[test]
+    fn test_concurrency_control_capacity() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            max_concurrency_percent: Some(50),
+            ..Default::default()
+        }];
+
+        let layer = ConcurrencyControlLayer::new(config).unwrap();
+        let mut svc = ServiceBuilder::new()
+            .with_layer(&layer)
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // `max_concurrency` is used until the capacity is set
+        drop(svc.handle("SELECT 1").unwrap());
+        assert_eq!(svc.snapshot()[0].max_concurrency, 1);
+
+        layer.set_capacity(10);
+        let mut guards: Vec<_> = (0..4).map(|_| svc.handle("SELECT 1").unwrap()).collect();
+        assert_eq!(
+            (svc.snapshot()[0].max_concurrency, svc.snapshot()[0].available_permits),
+            (5, 1)
+        );
+
+        // 4 requests are in flight, more than the new limit
+        layer.set_capacity(4);
+        assert!(svc.handle("SELECT 1").is_err());
+        assert_eq!(
+            (svc.snapshot()[0].max_concurrency, svc.snapshot()[0].available_permits),
+            (2, 0)
+        );
+
+        // the released permits beyond the limit are forgotten
+        guards.truncate(1);
+        assert!(svc.handle("SELECT 1").is_ok());
+        assert_eq!(svc.snapshot()[0].available_permits, 1);
+        guards.clear();
+        let held = svc.handle("SELECT 1").unwrap();
+        let _held = svc.handle("SELECT 1").unwrap();
+        assert!(svc.handle("SELECT 1").is_err());
+
+        drop(held);
+        layer.set_capacity(8);
+        let _more: Vec<_> = (0..3).map(|_| svc.handle("SELECT 1").unwrap()).collect();
+        assert_eq!(
+            (svc.snapshot()[0].max_concurrency, svc.snapshot()[0].available_permits),
+            (4, 0)
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_concurrency_control_dry_run() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            max_bytes: Some(16),
+            dry_run: true,
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the guards are held, so the rule would reject the later queries
+        let _guards: Vec<_> = ["SELECT 1", "SELECT 2", "SELECT 3", "SELECT * FROM too_large"]
+            .into_iter()
+            .map(|query| svc.handle(query).unwrap())
+            .collect();
+
+        let stats = &svc.stats()[0];
+        assert_eq!((stats.allowed, stats.rejected, stats.would_reject), (4, 0, 3));
+        assert!(logs_contain("rule_idx=0 reason=PermitsExhausted"));
+        assert!(logs_contain("reason=QueryTooLarge"));
+    }
+
+    #[test]
+    fn test_concurrency_control_regex_size_limit() {
+        // the set of all patterns is compiled with the limits of the rules
+        let huge = config::ConcurrencyControl {
+            regex: vec![String::from(r"\w{600}")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            regex_size_limit: Some(1 << 30),
+            dfa_size_limit: Some(1 << 30),
+            ..Default::default()
+        };
+        assert!(ConcurrencyControlLayer::new(vec![huge]).is_ok());
+
+        let config = |regex: &str| config::ConcurrencyControl {
+            regex: vec![String::from(regex)],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            regex_size_limit: Some(100 * 1024),
+            dfa_size_limit: Some(64 * 1024),
+            ..Default::default()
+        };
+
+        assert!(ConcurrencyControlLayer::new(vec![config(r"^SELECT \w+ FROM t")]).is_ok());
+        // the repetitions of unicode classes compile into a huge program
+        assert_eq!(
+            ConcurrencyControlLayer::new(vec![config(r"\w{100}")]).err(),
+            Some(PluginError::RegexTooComplex {
+                regex: String::from(r"\w{100}"),
+                limit: 100 * 1024
+            })
+        );
+    }
+
+    #[test]
+    fn test_concurrency_control_quarantine() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            quarantine: Some(config::Quarantine {
+                after_rejections: 2,
+                for_duration: Duration::from_millis(100),
+            }),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let held = svc.handle("SELECT 1").unwrap();
+        for _ in 0..2 {
+            let err = svc.handle("SELECT 1").unwrap_err().downcast::<PluginError>().unwrap();
+            assert!(matches!(*err, PluginError::ConcurrencyControlPluginReject { .. }));
+        }
+
+        // the permit is available, but the rule is quarantined
+        drop(held);
+        assert_eq!(svc.snapshot()[0].available_permits, 1);
+        let err = svc.handle("SELECT 1").unwrap_err().downcast::<PluginError>().unwrap();
+        assert_eq!(*err, PluginError::Quarantined { rule_index: 0 });
+        assert_eq!(svc.evaluate("SELECT 1").reason, Some(RejectReason::Quarantined));
+        assert!(!svc.would_allow("SELECT 1"));
+        // the other queries are not affected
+        assert!(svc.handle("UPDATE t SET a = 1").is_ok());
+
+        sleep(Duration::from_millis(120));
+        assert!(svc.handle("SELECT 1").is_ok());
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_concurrency_control_log_sample_rate() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::from_millis(100),
+            log_sample_rate: Some(0.1),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        super::seed_log_sampler(42);
+        let _held = svc.handle("SELECT 1").unwrap();
+        for _ in 0..1000 {
+            assert!(svc.handle("SELECT 1").is_err());
+        }
+        logs_assert(|lines| {
+            let logged = lines.iter().filter(|l| l.contains("rule rejected")).count();
+            match logged {
+                70..=130 => Ok(()),
+                n => Err(format!("{} of 1000 rejections are logged", n)),
+            }
+        });
+
+        // the rejections not logged are counted by the first rejection in the next window
+        sleep(Duration::from_millis(120));
+        let _next = svc.handle("SELECT 1");
+        assert!(svc.handle("SELECT 1").is_err());
+        assert!(logs_contain("rule_idx=0 suppressed="));
+    }
+
+    #[test]
+    fn test_concurrency_control_retry_after() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 1,
+                duration: Duration::new(5, 0),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^INSERT")],
+                duration: Duration::new(5, 0),
+                algorithm: config::ConcurrencyControlAlgorithm::TokenBucket {
+                    capacity: 1,
+                    refill_per_sec: 2.0,
+                },
+                ..Default::default()
+            },
+        ];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let retry_after = |res: Result<_, BoxError>| match *res.unwrap_err().downcast().unwrap() {
+            PluginError::ConcurrencyControlPluginReject { retry_after, .. } => retry_after.unwrap(),
+            e => panic!("unexpected error {:?}", e),
+        };
+
+        // the rest of the fixed window
+        let _held = svc.handle("SELECT 1").unwrap();
+        let wait = retry_after(svc.handle("SELECT 1"));
+        assert!(wait > Duration::from_millis(4500) && wait <= Duration::new(5, 0), "{:?}", wait);
+
+        // one token is refilled in 500ms
+        let _ = svc.handle("INSERT 1").unwrap();
+        let wait = retry_after(svc.handle("INSERT 1"));
+        assert!(
+            wait > Duration::from_millis(400) && wait <= Duration::from_millis(500),
+            "{:?}",
+            wait
+        );
+    }
+
+    #[test]
+    fn test_concurrency_control_active_window() {
+        // the windows are around the current time of day in UTC, they may wrap around midnight
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        let at = |offset: u32| {
+            let t = (secs + offset) % 86400;
+            config::TimeOfDay::new(t / 3600, t / 60 % 60, t % 60).unwrap()
+        };
+        let rule = |regex: &str, start, end| config::ConcurrencyControl {
+            regex: vec![String::from(regex)],
+            max_concurrency: 1,
+            duration: Duration::new(5, 0),
+            active_window: Some(config::ActiveWindow { start, end, utc_offset_minutes: 0 }),
+            ..Default::default()
+        };
+        let config = vec![
+            // in the window
+            rule(r"^SELECT", at(86400 - 3600), at(3600)),
+            // out of the window
+            rule(r"^INSERT", at(3600), at(7200)),
+            // the window starts later than it ends, it excludes only the next hour
+            rule(r"^UPDATE", at(7200), at(3600)),
+        ];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let _held = svc.handle("SELECT 1").unwrap();
+        assert!(svc.handle("SELECT 1").is_err());
+        for _ in 0..3 {
+            let (guard, _) = svc.handle("INSERT 1").unwrap();
+            assert_eq!(guard.rule_idx(), None);
+        }
+        let _held = svc.handle("UPDATE 1").unwrap();
+        assert!(svc.handle("UPDATE 1").is_err());
+    }
+
+    #[test]
+    fn test_concurrency_control_key_groups() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^/\* user=(\w+) \*/ SELECT .* FROM (\w+)")],
+            max_concurrency: 1,
+            duration: Duration::new(5, 0),
+            key_groups: vec![1, 2],
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // each (user, table) pair has its own permit
+        let _u1_t1 = svc.handle("/* user=u1 */ SELECT * FROM t1").unwrap();
+        assert!(svc.handle("/* user=u1 */ SELECT a FROM t1").is_err());
+        let _u1_t2 = svc.handle("/* user=u1 */ SELECT * FROM t2").unwrap();
+        let _u2_t1 = svc.handle("/* user=u2 */ SELECT * FROM t1").unwrap();
+        assert!(svc.handle("/* user=u1 */ SELECT * FROM t2").is_err());
+
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT .* FROM (\w+)")],
+            max_concurrency: 1,
+            duration: Duration::new(5, 0),
+            key_groups: vec![1, 2],
+            ..Default::default()
+        }];
+        assert!(matches!(
+            ConcurrencyControlLayer::new(config),
+            Err(PluginError::InvalidConcurrencyControlConfig { .. })
+        ));
+    }
+
+    #[test]
+    fn test_concurrency_control_pool() {
+        let rule = |regex: &str| config::ConcurrencyControl {
+            regex: vec![String::from(regex)],
+            max_concurrency: 2,
+            duration: Duration::new(5, 0),
+            pool: Some(String::from("reads")),
+            pool_max_borrow: Some(1),
+            ..Default::default()
+        };
+        let config = vec![rule(r"^SELECT"), rule(r"^SHOW")];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the saturated rule borrows one permit from the idle rule
+        let mut held = (0..3).map(|_| svc.handle("SELECT 1").unwrap()).collect::<Vec<_>>();
+        assert!(svc.handle("SELECT 1").is_err());
+        // the lender has one permit left, the pool never exceeds its total
+        held.push(svc.handle("SHOW TABLES").unwrap());
+        assert!(svc.handle("SHOW TABLES").is_err());
+        assert!(svc.handle("SELECT 1").is_err());
+
+        // the borrowed permit is returned to the lender on release
+        held.remove(2);
+        let _show = svc.handle("SHOW TABLES").unwrap();
+        assert!(svc.handle("SHOW TABLES").is_err());
+        {
+            let rules = svc.rules.load();
+            let instances = rules.instances.lock();
+            assert_eq!(instances[0].borrowed.load(Ordering::Acquire), 0);
+            assert_eq!(instances[0].window.semaphore.available_permits(), 0);
+            assert_eq!(instances[1].window.semaphore.available_permits(), 0);
+        }
+    }
+
+    #[test]
+    fn test_concurrency_control_decision_logger() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(5, 0),
+            ..Default::default()
+        }];
+        let decisions = Arc::new(Mutex::new(vec![]));
+        let logged = decisions.clone();
+        let layer = ConcurrencyControlLayer::new(config).unwrap().with_decision_logger(
+            move |d: &ConcurrencyControlDecision| {
+                logged.lock().push((d.allowed, d.rule_index, d.reason));
+            },
+        );
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(&layer)
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let _held = svc.handle("SELECT 1").unwrap();
+        assert!(svc.handle("SELECT 1").is_err());
+        let _ = svc.handle("INSERT 1").unwrap();
+        assert_eq!(
+            *decisions.lock(),
+            [
+                (true, Some(0), None),
+                (false, Some(0), Some(RejectReason::PermitsExhausted)),
+                (true, None, None)
+            ]
+        );
+
+        // the json logger looks up the rules, the instances are not locked by the caller
+        let lines = Arc::new(Mutex::new(vec![]));
+        let written = lines.clone();
+        let layer =
+            layer.with_json_decision_logger(move |line| written.lock().push(line.to_string()));
+        let mut svc = ServiceBuilder::new()
+            .with_layer(&layer)
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let _held = svc.handle("SELECT 1").unwrap();
+        assert!(svc.handle("SELECT 1").is_err());
+
+        let lines = lines.lock();
+        let json = lines.iter().map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap());
+        let json = json.collect::<Vec<_>>();
+        assert_eq!(json[0]["decision"], "allowed");
+        assert_eq!(json[0]["retry_after_ms"], serde_json::Value::Null);
+        assert_eq!(json[1]["decision"], "rejected");
+        assert_eq!(json[1]["rule_index"], 0);
+        assert_eq!(json[1]["regex"], serde_json::json!(["^SELECT"]));
+        assert!(json[1]["ts"].as_u64().unwrap() > 0);
+        assert!(json[1]["retry_after_ms"].as_u64().unwrap() <= 5000);
+    }
+
+    #[test]
+    fn test_concurrency_control_require_limit() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 100,
+            duration: Duration::new(5, 0),
+            require_limit: Some(1000),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let err = |res: Result<_, BoxError>| *res.unwrap_err().downcast::<PluginError>().unwrap();
+        assert_eq!(
+            err(svc.handle("SELECT * FROM t LIMIT 1000000")),
+            PluginError::RowLimitExceeded { rows: Some(1000000), max: 1000 }
+        );
+        assert_eq!(
+            err(svc.handle("SELECT * FROM t")),
+            PluginError::RowLimitExceeded { rows: None, max: 1000 }
+        );
+        assert!(svc.handle("SELECT * FROM t LIMIT 100").is_ok());
+        assert!(svc.handle("SELECT * FROM t LIMIT 5000, 1000").is_ok());
+        // the statements matching no rule are not checked
+        assert!(svc.handle("DELETE FROM t").is_ok());
+
+        let stats = svc.stats();
+        assert_eq!((stats[0].allowed, stats[0].rejected), (2, 2));
+    }
+
+    #[test]
+    fn test_concurrency_control_resize_rule() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 3,
+            duration: Duration::new(5, 0),
+            ..Default::default()
+        }];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let start_at =
+            |svc: &ConcurrencyControl<_>| svc.rules.load().instances.lock()[0].window.start_at;
+
+        // growing adds the permits at once
+        let mut held = (0..3).map(|_| svc.handle("SELECT 1").unwrap()).collect::<Vec<_>>();
+        let started = start_at(&svc);
+        svc.resize_rule(0, 5).unwrap();
+        held.extend((0..2).map(|_| svc.handle("SELECT 1").unwrap()));
+        assert!(svc.handle("SELECT 1").is_err());
+
+        // shrinking waits for the held permits, the window is kept
+        svc.resize_rule(0, 2).unwrap();
+        for _ in 0..3 {
+            held.pop();
+            assert!(svc.handle("SELECT 1").is_err());
+        }
+        held.pop();
+        held.push(svc.handle("SELECT 1").unwrap());
+        assert!(svc.handle("SELECT 1").is_err());
+        assert_eq!(start_at(&svc), started);
+
+        assert_eq!(
+            svc.resize_rule(1, 2).unwrap_err(),
+            PluginError::InvalidRuleIndex { rule_index: 1 }
+        );
+        assert!(svc.resize_rule(0, 0).is_err());
+    }
+
+    #[test]
+    fn test_concurrency_control_prepared() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(5, 0),
+            ..Default::default()
+        }];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: String| Ok::<_, PluginError>(input)));
+
+        // the execution is evaluated by the SQL of its statement
+        svc.prepare(7, "SELECT * FROM t WHERE id = ?");
+        let sql = svc.resolve_prepared(7).unwrap().to_string();
+        let _held = svc.handle(sql.clone()).unwrap();
+        let err = svc.handle(sql).unwrap_err().downcast::<PluginError>().unwrap();
+        assert!(matches!(*err, PluginError::ConcurrencyControlPluginReject { rule_index: 0, .. }));
+
+        // a clone copies the statements, closing them in the clone does not affect the others
+        assert_eq!(svc.clone().resolve_prepared(7), Some("SELECT * FROM t WHERE id = ?"));
+        let mut other = svc.clone();
+        other.close_prepared(7);
+        assert_eq!(other.resolve_prepared(7), None);
+        assert!(svc.resolve_prepared(7).is_some());
+    }
+
+    #[test]
+    fn test_concurrency_control_split_multi_statement() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 3,
+                duration: Duration::new(5, 0),
+                split_multi_statement: true,
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^UPDATE")],
+                max_concurrency: 1,
+                duration: Duration::new(5, 0),
+                ..Default::default()
+            },
+        ];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // each matching statement consumes a permit, the rule without
+        // `split_multi_statement` matches the whole query
+        let (guard, _) = svc.handle("UPDATE t SET a = 1; SELECT 1; SELECT 2").unwrap();
+        assert_eq!(guard.matched_rules(), [0, 1]);
+        assert_eq!(svc.snapshot()[0].available_permits, 1);
+
+        // the batch is rejected as a whole, the admitted statements are rolled back
+        let err = svc.handle("SELECT 3; SELECT 4").unwrap_err().downcast::<PluginError>().unwrap();
+        assert!(matches!(*err, PluginError::ConcurrencyControlPluginReject { rule_index: 0, .. }));
+        assert_eq!(svc.snapshot()[0].available_permits, 1);
+        assert_eq!(svc.stats()[0].allowed, 1);
+        drop(guard);
+        assert_eq!(svc.snapshot()[0].available_permits, 3);
+    }
+
+    #[test]
+    fn test_concurrency_control_split_quoted_semicolon() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 2,
+            duration: Duration::new(5, 0),
+            split_multi_statement: true,
+            ..Default::default()
+        }];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the quoted semicolon does not split the query
+        let _held = svc.handle("SELECT * FROM t WHERE a = ';SELECT 1' ;").unwrap();
+        assert_eq!(svc.snapshot()[0].available_permits, 1);
+        let _held = svc.handle("SELECT `a;b` FROM t").unwrap();
+        assert!(svc.handle("SELECT 1").is_err());
+    }
+
+    #[test]
+    fn test_concurrency_control_match_cache() {
+        let config = |regex: &str| {
+            vec![
+                config::ConcurrencyControl {
+                    regex: vec![String::from(regex)],
+                    max_concurrency: 1,
+                    duration: Duration::new(50, 0),
+                    ..Default::default()
+                },
+                config::ConcurrencyControl {
+                    regex: vec![String::from(r"^SELECT")],
+                    max_concurrency: 1,
+                    duration: Duration::new(50, 0),
+                    ..Default::default()
+                },
+            ]
+        };
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config(r"FROM t1")).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        assert_eq!(svc.handle("SELECT * FROM t1").unwrap().0.rule_idx(), Some(0));
+        assert_eq!(
+            svc.rules.load().matcher.cache.as_ref().unwrap().lock().peek("SELECT * FROM t1"),
+            Some(&vec![0, 1])
+        );
+
+        // the cached candidates are still filtered by the enabled rules
+        svc.set_enabled(0, false);
+        assert_eq!(svc.handle("SELECT * FROM t1").unwrap().0.rule_idx(), Some(1));
+        svc.set_enabled(0, true);
+
+        // the cache is dropped on reload
+        svc.reload(config(r"FROM t2")).unwrap();
+        assert!(svc.rules.load().matcher.cache.as_ref().unwrap().lock().is_empty());
+        assert_eq!(svc.handle("SELECT * FROM t1").unwrap().0.rule_idx(), Some(1));
+
+        // the cache is disabled with a capacity of 0
+        let layer =
+            ConcurrencyControlLayer::new(config(r"FROM t1")).unwrap().with_match_cache(0).unwrap();
+        let mut svc = ServiceBuilder::new()
+            .with_layer(layer)
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        assert_eq!(svc.handle("SELECT * FROM t1").unwrap().0.rule_idx(), Some(0));
+        assert!(svc.rules.load().matcher.cache.is_none());
+    }
+
+    #[test]
+    fn test_concurrency_control_state() {
+        let config = || {
+            vec![
+                config::ConcurrencyControl {
+                    regex: vec![String::from(r"^SELECT")],
+                    max_concurrency: 3,
+                    duration: Duration::new(50, 0),
+                    ..Default::default()
+                },
+                config::ConcurrencyControl {
+                    regex: vec![String::from(r"^INSERT")],
+                    max_concurrency: 3,
+                    duration: Duration::new(50, 0),
+                    ..Default::default()
+                },
+            ]
+        };
+        let build = || {
+            ServiceBuilder::new()
+                .with_layer(ConcurrencyControlLayer::new(config()).unwrap())
+                .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())))
+        };
+
+        let mut old = build();
+        let guards = (0..2).map(|_| old.handle("SELECT 1").unwrap().0).collect::<Vec<_>>();
+        let state = old.export_state();
+        // the window of INSERT has not started
+        assert_eq!(state.rules.len(), 1);
+        assert_eq!(state.rules[0].consumed, 2);
+
+        let json = serde_json::to_string(&state).unwrap();
+        let state = serde_json::from_str::<ConcurrencyControlState>(&json).unwrap();
+
+        let mut new = build();
+        new.import_state(state.clone());
+        let snapshot = &new.snapshot()[0];
+        assert_eq!(snapshot.available_permits, 1);
+        assert!(
+            snapshot.window_remaining.unwrap() <= Duration::new(50, 0) - state.rules[0].elapsed
+        );
+        let _guard = new.handle("SELECT 1").unwrap();
+        assert!(new.handle("SELECT 1").is_err());
+        assert_eq!(new.snapshot()[1].window_started, None);
+
+        // the exported state round trips
+        let exported = new.export_state();
+        assert_eq!(exported.rules[0].regex, state.rules[0].regex);
+        assert_eq!(exported.rules[0].consumed, 3);
+        assert!(exported.rules[0].elapsed >= state.rules[0].elapsed);
+        drop(guards);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_control_deadline() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        }];
+        let layer = ConcurrencyControlLayer::new(config).unwrap();
+        let mut svc = ServiceBuilder::new()
+            .with_layer(layer.clone())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the expired query does not consume the permit
+        let expired = Deadlined::new("SELECT 1", Some(Instant::now()));
+        let err = svc.handle(expired.clone()).unwrap_err();
+        assert_eq!(err.downcast_ref::<PluginError>(), Some(&PluginError::DeadlineExceeded));
+        assert_eq!(svc.stats()[0].allowed, 0);
+        assert_eq!(svc.snapshot()[0].available_permits, 1);
+
+        let deadline = Some(Instant::now() + Duration::from_secs(10));
+        let (guard, out) = svc.handle(Deadlined::new("SELECT 1", deadline)).unwrap();
+        assert_eq!(out, "SELECT 1");
+        drop(guard);
+
+        let mut svc = ServiceBuilder::new().with_layer(layer).build(async_service_fn(
+            |input: &'static str| async move { Ok::<_, PluginError>(input) },
+        ));
+        let err = svc.handle(expired).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<PluginError>(), Some(&PluginError::DeadlineExceeded));
+        assert_eq!(svc.stats()[0].allowed, 0);
+    }
+
+    #[test]
+    fn test_concurrency_control_render_prometheus() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r#"^SELECT .* WHERE name = "a\d""#)],
+                max_concurrency: 2,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^INSERT"), String::from(r"^UPDATE")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+        ];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let _guard = svc.handle(r#"SELECT * FROM t WHERE name = "a1""#).unwrap();
+        let _guard = svc.handle("INSERT INTO t VALUES (1)").unwrap();
+        assert!(svc.handle("UPDATE t SET a = 1").is_err());
+
+        let text = svc.render_prometheus();
+        let sample =
+            regex::Regex::new(r#"^[a-z_]+\{([a-z_]+="([^"\\\n]|\\[\\"n])*",?)*\} \d+$"#).unwrap();
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            assert!(sample.is_match(line), "{}", line);
+        }
+        for line in [
+            r#"pisa_concurrency_control_allowed_total{rule="0",regex="^SELECT .* WHERE name = \"a\\d\""} 1"#,
+            r#"pisa_concurrency_control_allowed_total{rule="1",regex="^INSERT,^UPDATE"} 1"#,
+            r#"pisa_concurrency_control_rejected_total{rule="1",regex="^INSERT,^UPDATE"} 1"#,
+            r#"pisa_concurrency_control_permits_available{rule="0",regex="^SELECT .* WHERE name = \"a\\d\""} 1"#,
+            "# TYPE pisa_concurrency_control_permits_available gauge",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_concurrency_control_post_reset_cooldown() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 2,
+            duration: Duration::from_millis(300),
+            post_reset_cooldown: Some(Duration::from_millis(150)),
+            ..Default::default()
+        }];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the first window has no cooldown
+        let guards = (0..2).map(|_| svc.handle("SELECT 1").unwrap().0).collect::<Vec<_>>();
+        assert_eq!(svc.evaluate("SELECT 1").reason, Some(RejectReason::PermitsExhausted));
+
+        // the reset window rejects in the cooldown
+        sleep(Duration::from_millis(320));
+        assert_eq!(svc.evaluate("SELECT 1").reason, Some(RejectReason::CoolingDown));
+        let err = svc.handle("SELECT 1").unwrap_err();
+        match err.downcast_ref::<PluginError>() {
+            Some(PluginError::ConcurrencyControlPluginReject { retry_after, .. }) => {
+                assert!(retry_after.unwrap() <= Duration::from_millis(150))
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        sleep(Duration::from_millis(150));
+        let _guard = svc.handle("SELECT 1").unwrap();
+        drop(guards);
+
+        let err = ConcurrencyControlLayer::new(vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 2,
+            duration: Duration::from_millis(100),
+            post_reset_cooldown: Some(Duration::from_millis(100)),
+            ..Default::default()
+        }])
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("post_reset_cooldown must be less than duration"));
+    }
+
+    #[test]
+    fn test_concurrency_control_request_priority() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            high_priority_overdraft: Some(1),
+            ..Default::default()
+        }];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let high = || PriorityInput { priority: RequestPriority::High, input: "SELECT 1" };
+
+        let _guard = svc.handle("SELECT 1").unwrap();
+        assert!(svc.handle("SELECT 1").is_err());
+
+        // the high priority request overdraws the saturated rule
+        let overdraft = svc.handle(high()).unwrap();
+        assert!(svc.handle("SELECT 1").is_err());
+        // the overdraft is used up
+        assert!(svc.handle(high()).is_err());
+        assert_eq!(svc.stats()[0].overdrawn, 1);
+        assert_eq!(svc.stats()[0].allowed, 2);
+
+        // the overdraft is returned when released, the window stays used up
+        drop(overdraft);
+        assert_eq!(svc.snapshot()[0].available_permits, 0);
+        assert!(svc.evaluate_with_priority("SELECT 1", RequestPriority::High).allowed);
+        assert!(!svc.evaluate_with_priority("SELECT 1", RequestPriority::Normal).allowed);
+        assert_eq!(svc.stats()[0].overdrawn, 2);
+    }
+
+    // the redis backend of the config requires the feature, even if the store is mocked
+    #[cfg(feature = "redis")]
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrency_control_store() {
+        use async_trait::async_trait;
+
+        use super::ConcurrencyControlStore;
+
+        // Admit `limit` requests of each key, fail if the key is "^UPDATE"
+        #[derive(Debug, Default)]
+        struct MockStore(Mutex<HashMap<String, u64>>);
+
+        #[async_trait]
+        impl ConcurrencyControlStore for MockStore {
+            async fn try_acquire(
+                &self,
+                key: &str,
+                limit: u64,
+                _: Duration,
+            ) -> Result<bool, PluginError> {
+                tokio::task::yield_now().await;
+                if key == "^UPDATE" {
+                    return Err(PluginError::StoreUnavailable { reason: String::from("down") });
+                }
+                let mut admitted = self.0.lock();
+                let n = admitted.entry(key.to_string()).or_default();
+                *n += 1;
+                Ok(*n <= limit)
+            }
+        }
+
+        let backend = config::ConcurrencyControlBackend::Redis {
+            url: String::from("redis://localhost"),
+            key_prefix: String::new(),
+        };
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 2,
+                duration: Duration::new(50, 0),
+                backend: backend.clone(),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^UPDATE")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                backend: backend.clone(),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 3,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+        ];
+        let store = Arc::new(MockStore::default());
+        let layer =
+            ConcurrencyControlLayer::new(config).unwrap().with_store(store.clone()).unwrap();
+        let mut svc = ServiceBuilder::new()
+            .with_layer(layer.clone())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        // the services built from the layer share the store
+        let layer_async = layer.clone();
+        let mut other = ServiceBuilder::new()
+            .with_layer(layer)
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the store admits the requests, no local permit is held by the store rule
+        let guard = svc.handle("SELECT 1").unwrap();
+        drop(guard);
+        let _guard = other.handle("SELECT 1").unwrap();
+        // the rejection of the store rolls back the permit of the local rule
+        let decision = svc.evaluate("SELECT 1");
+        assert_eq!(
+            (decision.rule_index, decision.reason),
+            (Some(0), Some(RejectReason::TokensExhausted))
+        );
+        assert_eq!(store.0.lock()["^SELECT"], 3);
+        assert_eq!(svc.snapshot()[2].available_permits, 3);
+
+        // the async services await the store
+        let mut async_svc = ServiceBuilder::new().with_layer(layer_async).build(async_service_fn(
+            |input: &'static str| async move { Ok::<_, PluginError>(input.to_string()) },
+        ));
+        let res = AsyncService::handle(&mut async_svc, "SELECT 1").await;
+        assert!(res.is_err());
+        assert_eq!(store.0.lock()["^SELECT"], 4);
+
+        // the requests are admitted while the store is unavailable, the failures are logged once
+        for _ in 0..3 {
+            assert!(svc.handle("UPDATE t SET a = 1").is_ok());
+        }
+        assert!(AsyncService::handle(&mut async_svc, "UPDATE t SET a = 1").await.is_ok());
+
+        // the named rules are counted by the store as the rules of the config
+        let named_rule = config::ConcurrencyControl {
+            regex: vec![String::from(r"^DELETE")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            backend,
+            ..Default::default()
+        };
+        let layer = ConcurrencyControlLayer::new(vec![])
+            .unwrap()
+            .with_named_ruleset(vec![(String::from("deletes"), named_rule)])
+            .unwrap()
+            .with_store(store.clone())
+            .unwrap();
+        let mut named = ServiceBuilder::new()
+            .with_layer(layer)
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        assert!(named.handle("DELETE FROM t").is_ok());
+        assert_eq!(named.evaluate("DELETE FROM t").reason, Some(RejectReason::TokensExhausted));
+        assert_eq!(store.0.lock()["^DELETE"], 2);
+        logs_assert(|lines| {
+            match lines.iter().filter(|l| l.contains("store is unavailable")).count() {
+                1 => Ok(()),
+                n => Err(format!("{} store failures are logged", n)),
+            }
+        });
+    }
+
+    #[test]
+    fn test_concurrency_control_custom_algorithm() {
+        struct RejectAll;
+
+        impl AdmitAlgorithm for RejectAll {
+            fn try_admit(&mut self, _: Instant, _: u32) -> AdmitResult {
+                AdmitResult::Rejected
+            }
+        }
+
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 10,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^INSERT")],
+                max_concurrency: 10,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+        ];
+        let layer = ConcurrencyControlLayer::new(config.clone())
+            .unwrap()
+            .with_algorithm(0, || Box::new(RejectAll))
+            .unwrap()
+            .with_algorithm(1, || Box::new(SlidingWindowAlgorithm::new(1, Duration::new(50, 0))))
+            .unwrap();
+        let mut svc = ServiceBuilder::new()
+            .with_layer(layer.clone())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        assert_eq!(svc.evaluate("SELECT 1").reason, Some(RejectReason::AlgorithmRejected));
+        assert!(svc.handle("SELECT 1").is_err());
+        assert_eq!(svc.stats()[0].rejected, 2);
+
+        // the algorithm counts the requests until its window is reset
+        drop(svc.handle("INSERT INTO t VALUES (1)").unwrap());
+        assert!(svc.handle("INSERT INTO t VALUES (1)").is_err());
+
+        // the fixed window counting by the permits can be replaced by the built-in algorithm,
+        // the weights are not returned when the requests complete
+        let fixed_layer = ConcurrencyControlLayer::new(config.clone())
+            .unwrap()
+            .with_algorithm(0, || Box::new(FixedWindowAlgorithm::new(2, Duration::new(50, 0))))
+            .unwrap();
+        let mut fixed = ServiceBuilder::new()
+            .with_layer(fixed_layer)
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        for _ in 0..2 {
+            drop(fixed.handle("SELECT 1").unwrap());
+        }
+        assert_eq!(fixed.evaluate("SELECT 1").reason, Some(RejectReason::AlgorithmRejected));
+        assert_eq!(fixed.snapshot()[0].available_permits, 0);
+
+        // each service has its own algorithm, kept by reload
+        let mut other = ServiceBuilder::new()
+            .with_layer(layer)
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        assert!(other.handle("INSERT INTO t VALUES (1)").is_ok());
+        other.reload(config).unwrap();
+        assert_eq!(other.evaluate("SELECT 1").reason, Some(RejectReason::AlgorithmRejected));
+    }
+
+    #[test]
+    fn test_concurrency_control_weighted_one_of() {
+        let rule = |selection_weight| config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 10,
+            duration: Duration::new(50, 0),
+            mode: config::ConcurrencyControlMatchMode::WeightedOneOf,
+            selection_weight: Some(selection_weight),
+            ..Default::default()
+        };
+        let layer = ConcurrencyControlLayer::new(vec![rule(3), rule(1)])
+            .unwrap()
+            .with_selection_seed(7)
+            .unwrap();
+        let mut svc = ServiceBuilder::new()
+            .with_layer(layer.clone())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let mut charged = [0; 2];
+        let mut selected = vec![];
+        for _ in 0..4000 {
+            let (guard, _) = svc.handle("SELECT 1").unwrap();
+            // only the selected rule is charged
+            assert_eq!(guard.matched_rules().len(), 1);
+            let idx = guard.rule_idx().unwrap();
+            charged[idx] += 1;
+            selected.push(idx);
+        }
+        assert!((2850..=3150).contains(&charged[0]), "{:?}", charged);
+        assert_eq!(svc.stats()[0].allowed + svc.stats()[1].allowed, 4000);
+
+        // the same seed selects the same rules
+        let mut other = ServiceBuilder::new()
+            .with_layer(layer)
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        for idx in selected.iter().take(100) {
+            assert_eq!(other.handle("SELECT 1").unwrap().0.rule_idx(), Some(*idx));
+        }
+
+        // only the `WeightedOneOf` rules are selected when the first matched rule is one,
+        // and the largest weights do not overflow
+        let first_match = config::ConcurrencyControl {
+            mode: config::ConcurrencyControlMatchMode::FirstMatch,
+            ..rule(u32::MAX)
+        };
+        let layer = ConcurrencyControlLayer::new(vec![rule(u32::MAX), first_match, rule(u32::MAX)])
+            .unwrap();
+        let mut svc = ServiceBuilder::new()
+            .with_layer(layer)
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        for _ in 0..100 {
+            let (guard, _) = svc.handle("SELECT 1").unwrap();
+            assert_ne!(guard.rule_idx(), Some(1));
+        }
+        assert_eq!(svc.stats()[1].allowed, 0);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_control_client_eviction() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            scope: config::ConcurrencyControlScope::PerClient,
+            ..Default::default()
+        }];
+        // the builder returns the error instead of panicking
+        let err = ConcurrencyControlLayer::new(config.clone())
+            .unwrap()
+            .with_client_eviction(Duration::ZERO, Duration::from_millis(50))
+            .err();
+        assert!(matches!(err, Some(PluginError::InvalidConcurrencyControlConfig { .. })));
+
+        let layer = ConcurrencyControlLayer::new(config)
+            .unwrap()
+            .with_client_eviction(Duration::from_millis(20), Duration::from_millis(50))
+            .unwrap();
+        let mut svc = ServiceBuilder::new()
+            .with_layer(layer.clone())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let input = |client: String| ClientInput { client_id: client, input: "SELECT 1" };
+        let clients =
+            |svc: &ConcurrencyControl<_>| svc.rules.load().instances.lock()[0].clients.len();
+
+        let guard = svc.handle(input(String::from("busy"))).unwrap();
+        for i in 0..100 {
+            drop(svc.handle(input(format!("client-{}", i))).unwrap());
+        }
+        assert_eq!(clients(&svc), 101);
+
+        // the idle clients are evicted without any request, but `busy` still holds a permit
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(clients(&svc), 1);
+
+        drop(guard);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(clients(&svc), 0);
+
+        // the task is aborted once the last clone of the service is dropped
+        let task = svc._eviction.as_ref().unwrap().0.abort_handle();
+        let other = svc.clone();
+        drop(svc);
+        tokio::task::yield_now().await;
+        assert!(!task.is_finished());
+        drop(other);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(task.is_finished());
+
+        // the clients are evicted when looked up outside a runtime
+        let svc = thread::spawn(move || ServiceBuilder::new().with_layer(layer).build(()))
+            .join()
+            .unwrap();
+        assert!(svc._eviction.is_none());
+        assert_eq!(svc.rules.load().instances.lock()[0].idle_ttl, None);
+    }
+
+    #[test]
+    fn test_concurrency_control_empty_input() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r".*")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        }];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the empty queries are allowed without consuming a permit
+        let (empty, _) = svc.handle("").unwrap();
+        let (blank, _) = svc.handle(" \t\n ").unwrap();
+        assert_eq!(empty.rule_idx(), None);
+        assert_eq!(blank.rule_idx(), None);
+        assert_eq!(svc.stats()[0].allowed, 0);
+
+        let (guard, _) = svc.handle("SELECT 1").unwrap();
+        assert_eq!(guard.rule_idx(), Some(0));
+        assert!(svc.handle("SELECT 1").is_err());
+    }
+
+    #[test]
+    fn test_concurrency_control_reject_message() {
+        let rule = |regex: &str, reject_message: Option<&str>| config::ConcurrencyControl {
+            regex: vec![String::from(regex)],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            reject_message: reject_message.map(String::from),
+            ..Default::default()
+        };
+        let config = vec![
+            rule(r"^SELECT", Some("too many reports, contact the dba")),
+            rule(r"^UPDATE", None),
+        ];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let _guards = (svc.handle("SELECT 1").unwrap(), svc.handle("UPDATE t SET a = 1").unwrap());
+        let err = svc.handle("SELECT 1").unwrap_err();
+        let err = err.downcast_ref::<PluginError>().unwrap();
+        assert!(matches!(
+            err,
+            PluginError::ConcurrencyControlPluginReject { rule_index: 0, message: Some(_), .. }
+        ));
+        assert_eq!(err.client_message(), "too many reports, contact the dba");
+
+        // the message of the error is sent without `reject_message`
+        let err = svc.handle("UPDATE t SET a = 1").unwrap_err();
+        let err = err.downcast_ref::<PluginError>().unwrap();
+        assert_eq!(err.client_message(), err.to_string());
+    }
+
+    #[test]
+    fn test_concurrency_control_latency_stats() {
+        let rule = |regex: &str| config::ConcurrencyControl {
+            regex: vec![String::from(regex)],
+            max_concurrency: 100,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        };
+        let config = vec![rule(r"^SELECT"), rule(r"^UPDATE")];
+        // the inner service sleeps for the millis of the query
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| {
+                let millis = input.trim_start_matches("SELECT ").parse().unwrap_or(0);
+                sleep(Duration::from_millis(millis));
+                Ok::<_, PluginError>(())
+            }));
+
+        for _ in 0..19 {
+            drop(svc.handle("SELECT 5").unwrap());
+        }
+        drop(svc.handle("SELECT 40").unwrap());
+
+        let stats = svc.stats();
+        let ms = Duration::from_millis;
+        assert!((ms(5)..ms(15)).contains(&stats[0].latency_p50.unwrap()), "{:?}", stats[0]);
+        assert!((ms(5)..ms(15)).contains(&stats[0].latency_p95.unwrap()), "{:?}", stats[0]);
+        assert!((ms(40)..ms(60)).contains(&stats[0].latency_p99.unwrap()), "{:?}", stats[0]);
+        assert_eq!(stats[1].latency_p50, None);
+
+        // the permits are kept, but the counters and latencies are zeroed
+        let guard = svc.handle("SELECT 0").unwrap();
+        svc.reset_stats(Some(0)).unwrap();
+        let stats = svc.stats();
+        assert_eq!((stats[0].allowed, stats[0].latency_p50, stats[0].latency_p99), (0, None, None));
+        drop(guard);
+        assert_eq!(svc.snapshot()[0].available_permits, 100);
+
+        drop(svc.handle("SELECT 0").unwrap());
+        drop(svc.handle("UPDATE t").unwrap());
+        assert_eq!((svc.stats()[0].allowed, svc.stats()[1].allowed), (1, 1));
+        svc.reset_stats(None).unwrap();
+        assert!(svc.stats().iter().all(|s| s.allowed == 0 && s.latency_p50.is_none()));
+        assert!(matches!(
+            svc.reset_stats(Some(2)),
+            Err(PluginError::InvalidRuleIndex { rule_index: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_concurrency_control_named_ruleset() {
+        let rule = |regex: &str, max_concurrency| config::ConcurrencyControl {
+            regex: vec![String::from(regex)],
+            max_concurrency,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        };
+        let layer = ConcurrencyControlLayer::new(vec![rule(r"^UPDATE", 1)])
+            .unwrap()
+            .with_named_ruleset(vec![
+                (String::from("reports"), rule(r"^SELECT .* FROM reports", 1)),
+                (String::from("orders"), rule(r"^SELECT .* FROM orders", 2)),
+            ])
+            .unwrap();
+        let mut svc = ServiceBuilder::new()
+            .with_layer(layer)
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the group of the match resolves to the rule, the named rules follow the config
+        let (reports, _) = svc.handle("SELECT * FROM reports").unwrap();
+        assert_eq!(reports.rule_idx(), Some(1));
+        let err = svc.handle("SELECT a FROM reports").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PluginError>(),
+            Some(PluginError::ConcurrencyControlPluginReject { rule_index: 1, .. })
+        ));
+
+        let orders =
+            (0..2).map(|_| svc.handle("SELECT * FROM orders").unwrap().0).collect::<Vec<_>>();
+        assert!(orders.iter().all(|g| g.rule_idx() == Some(2)));
+        assert!(svc.handle("SELECT * FROM orders").is_err());
+
+        // the rules of the config and the queries matching no rule are not affected
+        assert_eq!(svc.handle("UPDATE t SET a = 1").unwrap().0.rule_idx(), Some(0));
+        assert_eq!(svc.handle("SELECT * FROM users").unwrap().0.rule_idx(), None);
+        assert_eq!(svc.stats()[1].regex, vec![String::from(r"^SELECT .* FROM reports")]);
+
+        let err = ConcurrencyControlLayer::new(vec![])
+            .unwrap()
+            .with_named_ruleset(vec![(String::from("not-a-name"), rule(r"^SELECT", 1))])
+            .err()
+            .unwrap();
+        assert!(matches!(err, PluginError::InvalidConcurrencyControlRegex { .. }));
+    }
+
+    #[test]
+    fn test_concurrency_control_warmup() {
+        let config = config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 10,
+            duration: Duration::new(50, 0),
+            warmup: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let layer = ConcurrencyControlLayer::new(vec![config.clone()]).unwrap();
+        let mut svc = ServiceBuilder::new()
+            .with_layer(layer.clone())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let mut guards = vec![];
+
+        // the limit starts from 1
+        guards.push(svc.handle("SELECT 1").unwrap());
+        assert!(svc.handle("SELECT 1").is_err());
+
+        // the limit is 5 in the middle of the warmup
+        sleep(Duration::from_millis(500));
+        for _ in 0..4 {
+            guards.push(svc.handle("SELECT 1").unwrap());
+        }
+        assert!(svc.handle("SELECT 1").is_err());
+
+        // the full limit applies after the warmup
+        sleep(Duration::from_millis(600));
+        for _ in 0..5 {
+            guards.push(svc.handle("SELECT 1").unwrap());
+        }
+        assert!(svc.handle("SELECT 1").is_err());
+        assert_eq!(svc.stats()[0].allowed, 10);
+
+        // the warmup of a service built later starts when its rules are built
+        let mut later = ServiceBuilder::new()
+            .with_layer(layer)
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let _later = later.handle("SELECT 1").unwrap();
+        assert!(later.handle("SELECT 1").is_err());
+
+        let config = config::ConcurrencyControl {
+            algorithm: config::ConcurrencyControlAlgorithm::SlidingWindow,
+            ..config
+        };
+        let err = ConcurrencyControlLayer::new(vec![config]).err().unwrap();
+        assert!(err.to_string().contains("warmup only works with fixed window"));
+    }
+
+    #[test]
+    fn test_concurrency_control_trace() {
+        let rule = |regex: &str| config::ConcurrencyControl {
+            regex: vec![String::from(regex)],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        };
+        let config = vec![rule(r"^SELECT"), rule(r"^UPDATE"), rule(r"^DELETE")];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        svc.set_enabled(2, false);
+
+        // nothing is captured until a trace is requested
+        let _guard = svc.handle("SELECT 1").unwrap();
+        assert!(svc.take_traces().is_empty());
+
+        svc.trace_next();
+        assert!(svc.handle("SELECT 2").is_err());
+        svc.handle("UPDATE t SET a = 1").unwrap();
+        let traces = svc.take_traces();
+        assert_eq!(traces.len(), 1);
+        let trace = &traces[0];
+        assert_eq!(trace.query, "SELECT 2");
+        assert_eq!(
+            (trace.allowed, trace.reason, trace.rule_index),
+            (false, Some(RejectReason::PermitsExhausted), Some(0))
+        );
+        let steps = trace
+            .steps
+            .iter()
+            .map(|s| (s.checked, s.matched, s.available_permits))
+            .collect::<Vec<_>>();
+        assert_eq!(steps, [(true, true, 0), (true, false, 1), (false, false, 1)]);
+        assert_eq!(trace.steps[1].regex, vec![String::from(r"^UPDATE")]);
+
+        // the traces are taken once
+        assert!(svc.take_traces().is_empty());
+    }
+
+    #[test]
+    fn test_concurrency_control_source_cidr() {
+        let rule = |regex: &str, source_cidr: &str| config::ConcurrencyControl {
+            regex: vec![String::from(regex)],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            source_cidr: Some(source_cidr.parse().unwrap()),
+            ..Default::default()
+        };
+        let config = vec![rule(r"^SELECT", "10.0.0.0/8"), rule(r"^SELECT", "2001:db8::/32")];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let input =
+            |source: &str| SourceInput { source: source.parse().unwrap(), input: "SELECT 1" };
+
+        // the connections in the network are limited by the rule
+        let (guard, _) = svc.handle(input("10.1.2.3:3306")).unwrap();
+        assert_eq!(guard.rule_idx(), Some(0));
+        assert!(svc.handle(input("10.4.5.6:3306")).is_err());
+        let (v6, _) = svc.handle(input("[2001:db8::1]:3306")).unwrap();
+        assert_eq!(v6.rule_idx(), Some(1));
+        assert!(svc.handle(input("[2001:db8:ffff::2]:3306")).is_err());
+        assert!(svc.handle(input("[::ffff:10.1.2.3]:3306")).is_err());
+
+        // the connections outside are not limited, nor are the requests without a source
+        for source in ["192.168.1.1:3306", "[2001:db9::1]:3306", "[::ffff:192.168.1.1]:3306"] {
+            assert_eq!(svc.handle(input(source)).unwrap().0.rule_idx(), None);
+        }
+        assert_eq!(svc.handle("SELECT 1").unwrap().0.rule_idx(), None);
+
+        let config: config::ConcurrencyControl = serde_json::from_str(
+            r#"{"regex": ["^SELECT"], "max_concurrency": 1, "duration": 1000, "source_cidr": "10.0.0.0/8"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.source_cidr, Some("10.0.0.0/8".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_concurrency_control_literal_prefix() {
+        assert_eq!(literal_prefix(r"^SELECT a"), Some("SELECT a"));
+        for pattern in [r"SELECT", r"^", r"^SELECT .*", r"^SELECT \*", r"^(?i)SELECT", r"^a|b"] {
+            assert_eq!(literal_prefix(pattern), None, "{}", pattern);
+        }
+
+        let patterns = [
+            r"^UPDATE t_order SET .* WHERE",
+            r"^SELECT a FROM",
+            r"^SELECT",
+            r"^SELECT a",
+            r"^DELETE FROM t_order",
+            r"^DELETE FROM t_order WHERE",
+            r"^INSERT",
+            r"^INSERТ",
+        ];
+        let mut config = patterns
+            .iter()
+            .map(|r| config::ConcurrencyControl {
+                regex: vec![String::from(*r)],
+                max_concurrency: 100,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        // the case insensitive rules keep using the regex
+        config[6].case_insensitive = true;
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the first matching rule is the same as the one of the regexes
+        let mut regexes =
+            patterns.iter().map(|r| regex::Regex::new(r).unwrap()).collect::<Vec<_>>();
+        regexes[6] = regex::Regex::new(r"(?i)^INSERT").unwrap();
+        for query in [
+            "UPDATE t_order SET a = 1 WHERE id = 1",
+            "SELECT a FROM t_order",
+            "SELECT b FROM t_order",
+            "SELECT",
+            "SELEC",
+            " SELECT a",
+            "DELETE FROM t_order WHERE id = 1",
+            "insert into t_order values (1)",
+            "INSERТ",
+            "",
+        ] {
+            let expected = match query.is_empty() {
+                true => None,
+                false => regexes.iter().position(|r| r.is_match(query)),
+            };
+            let rule_idx = svc.handle(query).map(|(g, _)| g.rule_idx()).unwrap();
+            assert_eq!(rule_idx, expected, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_concurrency_control_soft_limit() {
+        let config = config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 4,
+            duration: Duration::new(50, 0),
+            soft_limit: Some(2),
+            ..Default::default()
+        };
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(vec![config.clone()]).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the requests within the soft limit are not marked
+        let mut decisions = (0..2).map(|_| svc.evaluate("SELECT 1")).collect::<Vec<_>>();
+        assert!(decisions.iter().all(|d| d.allowed && !d.soft_exceeded));
+
+        // the requests over the soft limit are still admitted until the hard limit
+        decisions.push(svc.evaluate("SELECT 1"));
+        assert!(decisions[2].allowed && decisions[2].soft_exceeded);
+        let (guard, _) = svc.handle("SELECT 1").unwrap();
+        assert!(guard.soft_exceeded());
+        let rejected = svc.evaluate("SELECT 1");
+        assert!(!rejected.allowed && !rejected.soft_exceeded);
+
+        // the mark follows the permits in use
+        drop(guard);
+        decisions.truncate(1);
+        assert!(!svc.handle("SELECT 1").unwrap().0.soft_exceeded());
+        assert!(!svc.evaluate("UPDATE t SET a = 1").soft_exceeded);
+
+        let config = config::ConcurrencyControl {
+            algorithm: config::ConcurrencyControlAlgorithm::SlidingWindow,
+            ..config
+        };
+        let err = ConcurrencyControlLayer::new(vec![config]).err().unwrap();
+        assert!(err.to_string().contains("soft_limit only works with fixed window"));
+    }
+
+    #[test]
+    fn test_concurrency_control_update_regex() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 2,
+            duration: Duration::new(50, 0),
+            regex_size_limit: Some(100 * 1024),
+            ..Default::default()
+        }];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let (held, _) = svc.handle("SELECT 1").unwrap();
+
+        // the new pattern applies at once, the permit in flight is still counted
+        svc.update_regex(0, &[r"^UPDATE"]).unwrap();
+        assert_eq!(svc.handle("SELECT 1").unwrap().0.rule_idx(), None);
+        let (updated, _) = svc.handle("UPDATE t SET a = 1").unwrap();
+        assert_eq!(updated.rule_idx(), Some(0));
+        assert!(svc.handle("UPDATE t SET a = 1").is_err());
+        assert_eq!(svc.stats()[0].regex, vec![String::from(r"^UPDATE")]);
+
+        // the permit acquired before the update is released to the same window
+        drop(held);
+        let (_guard, _) = svc.handle("UPDATE t SET a = 1").unwrap();
+
+        // an invalid pattern leaves the rule intact
+        let err = svc.update_regex(0, &["(UPDATE"]).unwrap_err();
+        assert!(matches!(err, PluginError::InvalidConcurrencyControlRegex { .. }));
+        // the patterns are compiled with the size limit of the rule
+        assert_eq!(
+            svc.update_regex(0, &[r"^UPDATE", r"\w{100}"]),
+            Err(PluginError::RegexTooComplex {
+                regex: String::from(r"\w{100}"),
+                limit: 100 * 1024
+            })
+        );
+        assert_eq!(
+            svc.update_regex(1, &[r"^DELETE"]),
+            Err(PluginError::InvalidRuleIndex { rule_index: 1 })
+        );
+        assert!(svc.handle("UPDATE t SET a = 1").is_err());
+        assert_eq!(svc.snapshot()[0].available_permits, 0);
+    }
+
+    #[test]
+    fn test_concurrency_control_update_regex_in_flight() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT"), String::from(r"^INSERT")],
+            max_concurrency: 400,
+            duration: Duration::new(50, 0),
+            algorithm: config::ConcurrencyControlAlgorithm::SlidingWindow,
+            ..Default::default()
+        }];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let mut svc = svc.clone();
+                thread::spawn(move || (0..100).filter(|_| svc.handle("SELECT 1").is_ok()).count())
+            })
+            .collect();
+        // the rule is updated until all requests are handled, both patterns match them
+        let mut updates = 0;
+        while !tasks.iter().all(|t| t.is_finished()) {
+            let select = if updates % 2 == 0 { r"(?i)^select" } else { r"^SELECT" };
+            svc.update_regex(0, &[select, r"^INSERT"]).unwrap();
+            updates += 1;
+        }
+        svc.update_regex(0, &[r"^SELECT", r"^INSERT"]).unwrap();
+        let admitted: usize = tasks.into_iter().map(|t| t.join().unwrap()).sum();
+
+        // the admissions on the old rules are counted by the window of the new rules
+        assert_eq!(admitted, 400);
+        assert_eq!(svc.snapshot()[0].available_permits, 0);
+        assert_eq!(svc.stats()[0].regex, vec![String::from(r"^SELECT"), String::from(r"^INSERT")]);
+        assert!(svc.handle("INSERT INTO t VALUES (1)").is_err());
+    }
+
+    #[test]
+    fn test_concurrency_control_except_regex() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            except_regex: Some(String::from(r"\baudit\b")),
+            max_concurrency: 1,
+            duration: Duration::new(5, 0),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let _held = svc.handle("SELECT * FROM users").unwrap();
+        assert!(svc.handle("SELECT * FROM users").is_err());
+        // the excluded queries match no rule
+        for _ in 0..3 {
+            let (guard, _) = svc.handle("SELECT * FROM audit WHERE id = 1").unwrap();
+            assert_eq!(guard.rule_idx(), None);
+        }
+        assert!(svc.handle("SELECT * FROM audits").is_err());
+    }
+
+    #[test]
+    fn test_concurrency_control_self_test() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 1,
+                duration: Duration::new(5, 0),
+                mode: config::ConcurrencyControlMatchMode::AllMatches,
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^UPDATE"), String::from(r"FROM t$")],
+                max_concurrency: 1,
+                duration: Duration::new(5, 0),
+                max_bytes: Some(16),
+                ..Default::default()
+            },
+        ];
+        let layer = ConcurrencyControlLayer::new(config).unwrap();
+
+        // the live permit is not seen by the self test
+        let mut svc = ServiceBuilder::new()
+            .with_layer(&layer)
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+        let _held = svc.handle("SELECT 1").unwrap();
+
+        let results =
+            layer.self_test(&["SELECT a FROM t", "SELECT 1", "UPDATE t SET a = 1", "INSERT"]);
+        let summary = results
+            .iter()
+            .map(|r| (r.matched_rules.clone(), r.allowed, r.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (vec![0, 1], true, None),
+                (vec![0], true, None),
+                (vec![1], false, Some(RejectReason::QueryTooLarge { bytes: 18, max: 16 })),
+                (vec![], true, None),
+            ]
+        );
+        assert_eq!(results[0].sample, "SELECT a FROM t");
+        assert!(svc.handle("SELECT 1").is_err());
+    }
+
+    #[test]
+    fn test_concurrency_control_set_enabled() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT .* FROM t1")],
+                max_concurrency: 2,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^INSERT")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                enabled: false,
+                ..Default::default()
+            },
+        ];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let _guard = svc.handle("SELECT * FROM t1").unwrap();
+        assert!(svc.handle("SELECT * FROM t1").is_err());
+
+        // the next matched rule applies
+        svc.set_enabled(0, false);
+        let guard = svc.handle("SELECT * FROM t1").unwrap().0;
+        assert_eq!(guard.rule_idx(), Some(1));
+        assert!(svc.handle("SELECT 1").is_ok());
+
+        svc.set_enabled(0, true);
+        assert!(svc.handle("SELECT 1").is_err());
+
+        // the rule disabled by config does not limit until enabled
+        let _guards = (0..3).map(|_| svc.handle("INSERT INTO t").unwrap().0).collect::<Vec<_>>();
+        svc.set_enabled(2, true);
+        let _guard = svc.handle("INSERT INTO t").unwrap();
+        assert!(svc.handle("INSERT INTO t").is_err());
+    }
+
+    #[test]
+    fn test_concurrency_control_default_rule() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 3,
+                duration: Duration::new(50, 0),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SHOW")],
+                action: config::ConcurrencyControlAction::Allow,
+                ..Default::default()
+            },
+        ];
+
+        let layer = ConcurrencyControlLayer::new(config.clone()).unwrap();
+        assert!(matches!(
+            layer.clone().with_default(0, Duration::new(50, 0)),
+            Err(PluginError::InvalidConcurrencyControlConfig { .. })
+        ));
+        let mut svc = ServiceBuilder::new()
+            .with_layer(layer.with_default(1, Duration::new(50, 0)).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        // the query matching no rule is limited by the default rule
+        let guard = svc.handle("DELETE FROM t").unwrap().0;
+        assert_eq!(guard.rule_idx(), Some(2));
+        assert!(svc.handle("INSERT INTO t").is_err());
+
+        // the default rule does not shadow the specific rules
+        let _guards = (0..3).map(|_| svc.handle("SELECT 1").unwrap().0).collect::<Vec<_>>();
+        assert!(svc.handle("SHOW TABLES").is_ok());
+
+        // the default rule is kept by reload
+        svc.reload(config).unwrap();
+        assert!(svc.handle("DELETE FROM t").is_err());
+        drop(guard);
+        assert!(svc.handle("DELETE FROM t").is_ok());
+    }
+
+    #[test]
+    fn test_concurrency_control_default_rule_options() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 3,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        }];
+        // the default rule is built with the options of the layer as the other rules
+        let layer = ConcurrencyControlLayer::new(config)
+            .unwrap()
+            .with_default(1, Duration::new(50, 0))
+            .unwrap()
+            .with_client_eviction(Duration::from_millis(20), Duration::from_millis(50))
+            .unwrap()
+            .with_algorithm(1, || Box::new(SlidingWindowAlgorithm::new(0, Duration::new(50, 0))))
+            .unwrap();
+        let instances = layer.try_build_instances().unwrap().unwrap();
+        assert!(instances[1].fallback);
+        assert_eq!(instances[1].idle_ttl, Some(Duration::from_millis(50)));
+        assert!(instances[1].admit.as_ref().is_some_and(|a| a.custom));
+    }
+
+    #[test]
+    fn test_concurrency_control_jitter() {
+        let duration = Duration::from_millis(100);
+        let rule = |regex: &str| config::ConcurrencyControl {
+            regex: vec![String::from(regex)],
+            max_concurrency: 1,
+            duration,
+            jitter: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let config = vec![rule(r"^SELECT"), rule(r"^INSERT")];
+        let durations = |layer: ConcurrencyControlLayer| {
+            let svc = layer.layer(service_fn(|input: &str| Ok::<_, PluginError>(input.len())));
+            let rules = svc.rules.load();
+            let durations = rules.instances.lock().iter().map(|c| c.duration).collect::<Vec<_>>();
+            (svc, durations)
+        };
+
+        // the same seed generates the same jitter
+        let layer =
+            ConcurrencyControlLayer::new(config.clone()).unwrap().with_jitter_seed(7).unwrap();
+        let (mut svc, jittered) = durations(layer.clone());
+        assert_eq!(durations(layer).1, jittered);
+        assert!(jittered.iter().all(|d| (duration..duration * 3).contains(d)));
+        assert!(
+            jittered[0].max(jittered[1]) - jittered[0].min(jittered[1])
+                >= Duration::from_millis(50)
+        );
+
+        // the windows started together are reset at different times
+        let _guards = [svc.handle("SELECT 1").unwrap().0, svc.handle("INSERT INTO t").unwrap().0];
+        let (mut first, mut second) = ("SELECT 1", "INSERT INTO t");
+        if jittered[0] > jittered[1] {
+            (first, second) = (second, first);
+        }
+        sleep(jittered[0].min(jittered[1]) + Duration::from_millis(5));
+        assert!(svc.handle(first).is_ok());
+        assert!(svc.handle(second).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_control_drain() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 3,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        }];
+
+        let svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(async_service_fn(|input: &'static str| async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                Ok::<_, PluginError>(input.to_string())
+            }));
+
+        let mut long_svc = svc.clone();
+        let long = tokio::spawn(async move { long_svc.handle("SELECT 1").await.map(|_| ()) });
+        while svc.drain.in_flight.load(Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // the request in flight is not finished in time
+        assert_eq!(
+            svc.drain(Duration::from_millis(10)).await,
+            Err(PluginError::Timeout { elapsed: Duration::from_millis(10) })
+        );
+        let mut new_svc = svc.clone();
+        let e = new_svc.handle("SELECT 2").await.unwrap_err().downcast::<PluginError>().unwrap();
+        assert_eq!(*e, PluginError::Draining);
+        assert_eq!(new_svc.evaluate("DELETE 1").reason, Some(RejectReason::Draining));
+
+        assert_eq!(svc.drain(Duration::from_secs(1)).await, Ok(()));
+        assert!(long.await.unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_concurrency_control_add_permits() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 2,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        assert_eq!(svc.add_permits(1), Err(PluginError::InvalidRuleIndex { rule_index: 1 }));
+
+        // the full window is not inflated
+        assert_eq!(svc.add_permits(0), Err(PluginError::PermitsFull { rule_index: 0 }));
+        assert_eq!(svc.snapshot()[0].available_permits, 2);
+
+        // the permit of a forgotten guard is returned
+        std::mem::forget(svc.handle("SELECT 1").unwrap().0);
+        assert_eq!(svc.snapshot()[0].available_permits, 1);
+        svc.add_permits(0).unwrap();
+        assert!(svc.add_permits(0).is_err());
+        assert_eq!(svc.snapshot()[0].available_permits, 2);
+
+        // the permit returned for a guard in flight is not released again by the guard
+        let (held, _) = svc.handle("SELECT 1").unwrap();
+        svc.add_permits(0).unwrap();
+        assert!(svc.add_permits(0).is_err());
+        drop(held);
+        assert_eq!(svc.snapshot()[0].available_permits, 2);
+        let guards = (0..2).map(|_| svc.handle("SELECT 1").unwrap().0).collect::<Vec<_>>();
+        assert!(svc.handle("SELECT 1").is_err());
+        drop(guards);
+        assert_eq!(svc.snapshot()[0].available_permits, 2);
+    }
+
+    #[test]
+    fn test_concurrency_control_adaptive() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 4,
+            duration: Duration::from_millis(50),
+            adaptive: Some(config::AdaptiveLimit { min: 1, max: 8 }),
+            ..Default::default()
+        }];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| match input {
+                "SELECT ERR" => Err(PluginError::Draining),
+                _ => Ok(input.to_string()),
+            }));
+
+        // the limit halves on the windows with errors
+        for expected in [2, 1, 1] {
+            assert!(svc.handle("SELECT ERR").is_err());
+            std::thread::sleep(Duration::from_millis(60));
+            let _ = svc.handle("SELECT 1").unwrap();
+            assert_eq!(svc.snapshot()[0].max_concurrency, expected);
+        }
+
+        // and recovers by 1 on the windows without rejections
+        for expected in [2, 3] {
+            std::thread::sleep(Duration::from_millis(60));
+            let _ = svc.handle("SELECT 1").unwrap();
+            assert_eq!(svc.snapshot()[0].max_concurrency, expected);
+        }
+    }
+
+    #[test]
+    fn test_concurrency_control_evaluate() {
+        let config = vec![
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^SELECT")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                max_bytes: Some(16),
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^INSERT")],
+                max_concurrency: 1,
+                duration: Duration::new(50, 0),
+                algorithm: config::ConcurrencyControlAlgorithm::SlidingWindow,
+                ..Default::default()
+            },
+            config::ConcurrencyControl {
+                regex: vec![String::from(r"^UPDATE")],
+                algorithm: config::ConcurrencyControlAlgorithm::TokenBucket {
+                    capacity: 1,
+                    refill_per_sec: 0.1,
+                },
+                ..Default::default()
+            },
+        ];
+
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let decision = svc.evaluate("DELETE FROM t");
+        assert!(decision.allowed);
+        assert_eq!((decision.reason, decision.rule_index), (None, None));
+
+        let decision = svc.evaluate("SELECT * FROM t1 WHERE id = 1");
+        assert!(!decision.allowed);
+        assert_eq!(decision.reason, Some(RejectReason::QueryTooLarge { bytes: 29, max: 16 }));
+
+        // the permit is held by the allowed decision
+        let allowed = svc.evaluate("SELECT 1");
+        assert!(allowed.allowed);
+        assert_eq!(allowed.rule_index, Some(0));
+        let decision = svc.evaluate("SELECT 1");
+        assert_eq!(decision.reason, Some(RejectReason::PermitsExhausted));
+        assert_eq!(decision.rule_index, Some(0));
+
+        assert!(svc.evaluate("INSERT INTO t VALUES (1)").allowed);
+        let decision = svc.evaluate("INSERT INTO t VALUES (1)");
+        assert_eq!(decision.reason, Some(RejectReason::SlidingWindowFull));
+        assert_eq!(decision.rule_index, Some(1));
+
+        assert!(svc.evaluate("UPDATE t SET a = 1").allowed);
+        let decision = svc.evaluate("UPDATE t SET a = 1");
+        assert_eq!(decision.reason, Some(RejectReason::TokensExhausted));
+        assert_eq!(decision.rule_index, Some(2));
+
+        // the decisions are counted, and `handle` maps the reason to the error
+        assert_eq!(svc.stats()[0].rejected, 2);
+        let e = svc.handle("SELECT 1").unwrap_err().downcast::<PluginError>().unwrap();
+        assert!(matches!(*e, PluginError::ConcurrencyControlPluginReject { rule_index: 0, .. }));
     }
 }
@@ -0,0 +1,368 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{future::Future, sync::OnceLock, time::Duration};
+
+use async_trait::async_trait;
+use tokio::runtime::{Builder, Handle, Runtime, RuntimeFlavor};
+
+use crate::err::PluginError;
+
+/// The store counting the requests of the rules with a remote backend, so the limits are
+/// enforced across all proxy instances sharing the store. `try_acquire` admits a request
+/// of `key` if fewer than `limit` requests of it were admitted in the trailing `window`.
+/// It is called once the rules are unlocked, so a slow store never blocks other requests.
+#[async_trait]
+pub trait ConcurrencyControlStore: std::fmt::Debug + Send + Sync {
+    async fn try_acquire(
+        &self,
+        key: &str,
+        limit: u64,
+        window: Duration,
+    ) -> Result<bool, PluginError>;
+}
+
+// The runtime waiting for the stores of the sync services outside a multi-thread runtime
+static STORE_RUNTIME: OnceLock<Result<Runtime, String>> = OnceLock::new();
+
+// Wait for `future` of the stores on the sync path. The worker of a multi-thread runtime
+// waits in place, a current-thread runtime can not be blocked by its only thread, so the
+// future is driven by a thread of its own.
+pub(crate) fn block_on<F>(future: F) -> Result<F::Output, PluginError>
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    let runtime = || {
+        STORE_RUNTIME
+            .get_or_init(|| {
+                Builder::new_current_thread().enable_all().build().map_err(|e| e.to_string())
+            })
+            .as_ref()
+            .map_err(|reason| PluginError::StoreUnavailable { reason: reason.clone() })
+    };
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            Ok(tokio::task::block_in_place(|| handle.block_on(future)))
+        }
+        Ok(_) => std::thread::scope(|s| {
+            s.spawn(|| runtime().map(|runtime| runtime.block_on(future)))
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e))
+        }),
+        Err(_) => runtime().map(|runtime| runtime.block_on(future)),
+    }
+}
+
+#[cfg(feature = "redis")]
+pub use self::redis::RedisStore;
+
+#[cfg(feature = "redis")]
+mod redis {
+    use std::{fmt, io, time::Duration};
+
+    use async_trait::async_trait;
+    use parking_lot::Mutex;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpStream,
+    };
+
+    use super::ConcurrencyControlStore;
+    use crate::err::PluginError;
+
+    // The GCRA of the requests of KEYS[1], ARGV[1] requests are admitted per ARGV[2]
+    // microseconds. The key holds the theoretical arrival time by the clock of Redis,
+    // so the clocks of the proxy instances do not need to be synchronized.
+    const GCRA_SCRIPT: &str = r#"
+local limit = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+local tat = math.max(tonumber(redis.call('GET', KEYS[1])) or now, now)
+local next_tat = tat + window / limit
+if next_tat - now > window then
+    return 0
+end
+redis.call('SET', KEYS[1], string.format('%d', next_tat), 'PX', math.ceil((next_tat - now) / 1000))
+return 1
+"#;
+
+    // The timeout of a request, including connecting
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    // The idle connections kept for the next requests
+    const MAX_IDLE: usize = 8;
+
+    /// `RedisStore` admits the requests by a GCRA script in Redis, the url is
+    /// `redis://[[user]:password@]host[:port][/db]`. The connections are opened by the
+    /// requests finding no idle one, and a connection is closed after an error.
+    pub struct RedisStore {
+        addr: String,
+        user: Option<String>,
+        password: Option<String>,
+        db: u32,
+        key_prefix: String,
+        idle: Mutex<Vec<BufReader<TcpStream>>>,
+    }
+
+    // The password is not printed, the configs are logged
+    impl fmt::Debug for RedisStore {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("RedisStore")
+                .field("addr", &self.addr)
+                .field("user", &self.user)
+                .field("password", &self.password.as_ref().map(|_| "***"))
+                .field("db", &self.db)
+                .field("key_prefix", &self.key_prefix)
+                .finish_non_exhaustive()
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Reply {
+        Status(String),
+        Integer(i64),
+        Bulk(Option<Vec<u8>>),
+    }
+
+    impl RedisStore {
+        pub fn new(url: &str, key_prefix: &str) -> Result<RedisStore, PluginError> {
+            let invalid = || PluginError::InvalidConcurrencyControlConfig {
+                errors: vec![format!("invalid redis url {:?}", url)],
+            };
+            let rest = url.strip_prefix("redis://").ok_or_else(invalid)?;
+            // the user is sent by `AUTH user password` of the ACL, the password alone
+            // authenticates the default user
+            let (user, password, rest) = match rest.rsplit_once('@') {
+                Some((auth, rest)) => {
+                    let (user, password) = auth.split_once(':').ok_or_else(invalid)?;
+                    let user = Some(user.to_string()).filter(|user| !user.is_empty());
+                    (user, Some(password.to_string()), rest)
+                }
+                None => (None, None, rest),
+            };
+            let (host, db) = match rest.split_once('/') {
+                Some((host, "")) => (host, 0),
+                Some((host, db)) => (host, db.parse().map_err(|_| invalid())?),
+                None => (rest, 0),
+            };
+            if host.is_empty() {
+                return Err(invalid());
+            }
+            let addr = match host.contains(':') {
+                true => host.to_string(),
+                false => format!("{}:6379", host),
+            };
+            Ok(RedisStore {
+                addr,
+                user,
+                password,
+                db,
+                key_prefix: key_prefix.to_string(),
+                idle: Mutex::new(vec![]),
+            })
+        }
+
+        async fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+            let stream = TcpStream::connect(&self.addr).await?;
+            stream.set_nodelay(true)?;
+            let mut conn = BufReader::new(stream);
+            match (&self.user, &self.password) {
+                (Some(user), Some(password)) => {
+                    command(&mut conn, &["AUTH", user, password]).await?;
+                }
+                (None, Some(password)) => {
+                    command(&mut conn, &["AUTH", password]).await?;
+                }
+                _ => {}
+            }
+            if self.db != 0 {
+                command(&mut conn, &["SELECT", &self.db.to_string()]).await?;
+            }
+            Ok(conn)
+        }
+
+        // Run the script on an idle connection or a new one, the connection is kept
+        // only if the reply is read completely
+        async fn eval(&self, key: &str, limit: &str, window: &str) -> io::Result<Reply> {
+            let idle = self.idle.lock().pop();
+            let mut conn = match idle {
+                Some(conn) => conn,
+                None => self.connect().await?,
+            };
+            let reply = command(&mut conn, &["EVAL", GCRA_SCRIPT, "1", key, limit, window]).await?;
+            let mut idle = self.idle.lock();
+            if idle.len() < MAX_IDLE {
+                idle.push(conn);
+            }
+            Ok(reply)
+        }
+    }
+
+    #[async_trait]
+    impl ConcurrencyControlStore for RedisStore {
+        async fn try_acquire(
+            &self,
+            key: &str,
+            limit: u64,
+            window: Duration,
+        ) -> Result<bool, PluginError> {
+            let key = format!("{}{}", self.key_prefix, key);
+            let (limit, window) = (limit.max(1).to_string(), window.as_micros().max(1).to_string());
+            let res = tokio::time::timeout(TIMEOUT, self.eval(&key, &limit, &window)).await;
+            let reason = match res {
+                Ok(Ok(Reply::Integer(admitted))) => return Ok(admitted == 1),
+                Ok(Ok(reply)) => format!("unexpected reply {:?}", reply),
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => format!("no reply in {:?}", TIMEOUT),
+            };
+            Err(PluginError::StoreUnavailable { reason })
+        }
+    }
+
+    // Send the command and read its reply, an error reply is returned as an error
+    async fn command(conn: &mut BufReader<TcpStream>, args: &[&str]) -> io::Result<Reply> {
+        let mut buf = format!("*{}\r\n", args.len());
+        for arg in args {
+            buf.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        conn.get_mut().write_all(buf.as_bytes()).await?;
+
+        let line = read_line(conn).await?;
+        let invalid =
+            || io::Error::new(io::ErrorKind::InvalidData, format!("invalid reply {:?}", line));
+        let (kind, value) = (line.as_bytes().first().copied(), line.get(1..).unwrap_or_default());
+        match kind {
+            Some(b'+') => Ok(Reply::Status(value.to_string())),
+            Some(b'-') => Err(io::Error::other(value.to_string())),
+            Some(b':') => value.parse().map(Reply::Integer).map_err(|_| invalid()),
+            Some(b'$') => match value.parse::<i64>().map_err(|_| invalid())? {
+                len if len < 0 => Ok(Reply::Bulk(None)),
+                len => {
+                    let mut data = vec![0; len as usize + 2];
+                    conn.read_exact(&mut data).await?;
+                    data.truncate(len as usize);
+                    Ok(Reply::Bulk(Some(data)))
+                }
+            },
+            _ => Err(invalid()),
+        }
+    }
+
+    // Read a line of the reply without the trailing CRLF
+    async fn read_line(conn: &mut BufReader<TcpStream>) -> io::Result<String> {
+        let mut line = String::new();
+        if conn.read_line(&mut line).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        line.truncate(line.trim_end_matches(['\r', '\n']).len());
+        Ok(line)
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::{
+            io::{BufRead, BufReader, Read, Write},
+            net::TcpListener,
+            thread,
+        };
+
+        use super::*;
+
+        // Read a line of the command without the trailing CRLF
+        fn read_command_line(conn: &mut impl BufRead) -> String {
+            let mut line = String::new();
+            conn.read_line(&mut line).unwrap();
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+
+        // Serve the replies to the commands in order, and return the received commands
+        fn serve(replies: Vec<&'static str>) -> (String, thread::JoinHandle<Vec<Vec<String>>>) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let handle = thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let mut conn = BufReader::new(stream);
+                let mut commands = vec![];
+                for reply in replies {
+                    let n = read_command_line(&mut conn)[1..].parse::<usize>().unwrap();
+                    let mut args = vec![];
+                    for _ in 0..n {
+                        let len = read_command_line(&mut conn)[1..].parse::<usize>().unwrap();
+                        let mut arg = vec![0; len + 2];
+                        conn.read_exact(&mut arg).unwrap();
+                        arg.truncate(len);
+                        args.push(String::from_utf8(arg).unwrap());
+                    }
+                    commands.push(args);
+                    conn.get_mut().write_all(reply.as_bytes()).unwrap();
+                }
+                commands
+            });
+            (addr, handle)
+        }
+
+        #[tokio::test]
+        async fn test_redis_store() {
+            let (addr, handle) = serve(vec!["+OK\r\n", "+OK\r\n", ":1\r\n", ":0\r\n"]);
+            let url = format!("redis://:secret@{}/2", addr);
+            let store = RedisStore::new(&url, "pisa:").unwrap();
+            assert!(store.try_acquire("^SELECT", 10, Duration::from_secs(1)).await.unwrap());
+            assert!(!store.try_acquire("^SELECT", 10, Duration::from_secs(1)).await.unwrap());
+
+            let commands = handle.join().unwrap();
+            assert_eq!(commands[0], vec!["AUTH", "secret"]);
+            assert_eq!(commands[1], vec!["SELECT", "2"]);
+            assert!(!format!("{:?}", store).contains("secret"));
+            assert_eq!(commands[2][0], "EVAL");
+            assert_eq!(commands[2][3..], ["pisa:^SELECT", "10", "1000000"]);
+
+            // the connection is closed after an error
+            let err = store.try_acquire("^SELECT", 10, Duration::from_secs(1)).await.unwrap_err();
+            assert!(matches!(err, PluginError::StoreUnavailable { .. }));
+            assert!(store.idle.lock().is_empty());
+        }
+
+        #[tokio::test]
+        async fn test_redis_store_acl_user() {
+            let (addr, handle) = serve(vec!["+OK\r\n", ":1\r\n"]);
+            let url = format!("redis://pisa:secret@{}", addr);
+            let store = RedisStore::new(&url, "").unwrap();
+            assert!(store.try_acquire("^SELECT", 10, Duration::from_secs(1)).await.unwrap());
+
+            let commands = handle.join().unwrap();
+            assert_eq!(commands[0], vec!["AUTH", "pisa", "secret"]);
+            assert_eq!(commands[1][0], "EVAL");
+            assert!(!format!("{:?}", store).contains("secret"));
+        }
+
+        #[test]
+        fn test_redis_store_url() {
+            let store = RedisStore::new("redis://localhost", "").unwrap();
+            assert_eq!(
+                (store.addr.as_str(), store.db, store.user, store.password),
+                ("localhost:6379", 0, None, None)
+            );
+            let store = RedisStore::new("redis://pisa:p:w@localhost:6380/1", "").unwrap();
+            assert_eq!(
+                (store.addr.as_str(), store.db, store.user.as_deref(), store.password.as_deref()),
+                ("localhost:6380", 1, Some("pisa"), Some("p:w"))
+            );
+            assert!(RedisStore::new("http://localhost", "").is_err());
+            assert!(RedisStore::new("redis://localhost/db", "").is_err());
+            assert!(RedisStore::new("redis://pisa@localhost", "").is_err());
+        }
+    }
+}
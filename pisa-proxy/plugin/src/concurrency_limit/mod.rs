@@ -0,0 +1,159 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::Semaphore;
+
+use crate::{
+    err::{BoxError, PluginError},
+    layer::{AsyncService, Bypass, Bypassable, Layer, Service},
+};
+
+/// `ConcurrencyLimitLayer` limits the max number of requests in flight,
+/// unlike `ConcurrencyControlLayer`, the permits are never reset by time.
+#[derive(Clone)]
+pub struct ConcurrencyLimitLayer {
+    max_concurrency: usize,
+    bypass: Bypass,
+}
+
+impl ConcurrencyLimitLayer {
+    pub fn new(max_concurrency: usize) -> ConcurrencyLimitLayer {
+        ConcurrencyLimitLayer { max_concurrency, bypass: Bypass::default() }
+    }
+}
+
+impl Bypassable for ConcurrencyLimitLayer {
+    fn with_bypass(self, bypass: Bypass) -> Self {
+        ConcurrencyLimitLayer { bypass, ..self }
+    }
+}
+
+impl<S> Layer<S> for ConcurrencyLimitLayer {
+    type Service = ConcurrencyLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConcurrencyLimit {
+            inner,
+            semaphore: Arc::new(Semaphore::new(self.max_concurrency)),
+            bypass: self.bypass.clone(),
+        }
+    }
+}
+
+/// The clones of `ConcurrencyLimit` share the same permits.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit<S> {
+    inner: S,
+    semaphore: Arc<Semaphore>,
+    bypass: Bypass,
+}
+
+impl<S, Input> Service<Input> for ConcurrencyLimit<S>
+where
+    S: Service<Input>,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        if self.bypass.is_set() {
+            return self.inner.handle(input).map_err(Into::into);
+        }
+        // The permit is released when the inner service returns, even on panic.
+        let _permit = self
+            .semaphore
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| BoxError::from(PluginError::ConcurrencyLimitReached))?;
+        self.inner.handle(input).map_err(Into::into)
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        (self.bypass.is_set() || self.semaphore.available_permits() > 0) && self.inner.poll_ready()
+    }
+}
+
+#[async_trait]
+impl<S, Input> AsyncService<Input> for ConcurrencyLimit<S>
+where
+    S: AsyncService<Input> + Send,
+    Input: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        if self.bypass.is_set() {
+            return self.inner.handle(input).await.map_err(Into::into);
+        }
+        // The permit is held until the inner future completes or is dropped.
+        let _permit = self
+            .semaphore
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| BoxError::from(PluginError::ConcurrencyLimitReached))?;
+        self.inner.handle(input).await.map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{mpsc, Arc, Barrier},
+        thread,
+    };
+
+    use super::*;
+    use crate::layer::{service_fn, ServiceBuilder};
+
+    #[test]
+    fn test_concurrency_limit() {
+        const N: usize = 4;
+        // the admitted handlers and the test thread
+        let release = Arc::new(Barrier::new(N));
+        let inner_release = release.clone();
+        let svc = ServiceBuilder::new().with_layer(ConcurrencyLimitLayer::new(N - 1)).build(
+            service_fn(move |input: &str| {
+                inner_release.wait();
+                Ok::<_, PluginError>(input.to_string())
+            }),
+        );
+
+        let (tx, rx) = mpsc::channel();
+        let tasks = (0..N)
+            .map(|_| {
+                let mut svc = svc.clone();
+                let tx = tx.clone();
+                thread::spawn(move || tx.send(svc.handle("SELECT 1").map_err(|e| e.to_string())))
+            })
+            .collect::<Vec<_>>();
+
+        // the admitted handlers are blocked, so the first result is the rejected one
+        assert_eq!(rx.recv().unwrap(), Err(PluginError::ConcurrencyLimitReached.to_string()));
+        release.wait();
+        for _ in 1..N {
+            assert_eq!(rx.recv().unwrap(), Ok(String::from("SELECT 1")));
+        }
+
+        for t in tasks {
+            t.join().unwrap().unwrap();
+        }
+        assert_eq!(svc.semaphore.available_permits(), N - 1);
+    }
+}
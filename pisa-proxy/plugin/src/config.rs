@@ -12,32 +12,961 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::time::Duration;
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use serde::{Deserialize, Serialize};
+use ipnet::IpNet;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::{
+    err::{ConfigError, PluginError},
+    sql::StatementKind,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Plugin {
     pub concurrency_control: Option<Vec<ConcurrencyControl>>,
     pub circuit_break: Option<Vec<CircuitBreak>>,
+    #[serde(default)]
+    pub firewall: Option<Vec<Firewall>>,
 }
 
 #[serde_with::serde_as]
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ConcurrencyControl {
     pub regex: Vec<String>,
     pub max_concurrency: u32,
-    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(
+        deserialize_with = "humantime_duration",
+        serialize_with = "serialize_humantime_duration"
+    )]
     pub duration: Duration,
+    #[serde(default)]
+    pub algorithm: ConcurrencyControlAlgorithm,
+    // The max time in milliseconds to wait for a permit by `AsyncService`,
+    // the request is rejected immediately if it is not set. Only works with fixed window.
+    #[serde_as(as = "Option<serde_with::DurationMilliSeconds<u64>>")]
+    #[serde(default)]
+    pub acquire_timeout: Option<Duration>,
+    #[serde(default)]
+    pub scope: ConcurrencyControlScope,
+    // Where the requests of the rule are counted
+    #[serde(default)]
+    pub backend: ConcurrencyControlBackend,
+    // The rules with higher priority are matched first,
+    // the rules with the same priority are matched in config order.
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default = "default_as_false")]
+    pub case_insensitive: bool,
+    #[serde(default)]
+    pub action: ConcurrencyControlAction,
+    // The requests matching `weight_regex` consume `weight` permits,
+    // the other requests consume 1 permit. Only works with fixed window.
+    #[serde(default)]
+    pub weight_regex: Option<String>,
+    // The rule does not apply to the requests matching `except_regex`,
+    // even if they match `regex`.
+    #[serde(default)]
+    pub except_regex: Option<String>,
+    #[serde(default)]
+    pub weight: Option<u32>,
+    // The rule only applies to these kinds of statements even if the regex matches,
+    // it applies to all statements if it is not set.
+    #[serde(default)]
+    pub statement_kinds: Option<Vec<StatementKind>>,
+    #[serde(default)]
+    pub mode: ConcurrencyControlMatchMode,
+    // The weight of the rule when it is selected by `WeightedOneOf`, 1 if not set
+    #[serde(default)]
+    pub selection_weight: Option<u32>,
+    // The matched queries longer than `max_bytes` bytes are rejected
+    // before acquiring a permit.
+    #[serde(default)]
+    pub max_bytes: Option<usize>,
+    // The matched queries without a LIMIT clause, or with a LIMIT of more rows,
+    // are rejected, see `sql::extract_limit`
+    #[serde(default)]
+    pub require_limit: Option<u64>,
+    #[serde(default)]
+    pub match_type: ConcurrencyControlMatchType,
+    // A random duration in [0, jitter) in milliseconds is added to `duration` of each rule,
+    // so the windows of the rules and the proxies are not reset at the same time.
+    #[serde_as(as = "Option<serde_with::DurationMilliSeconds<u64>>")]
+    #[serde(default)]
+    pub jitter: Option<Duration>,
+    // The limit of fixed window is adjusted in [min, max] when the window is reset, it
+    // increases by 1 after a window without rejections, and halves after a window with
+    // failed requests. `max_concurrency` is the initial limit.
+    #[serde(default)]
+    pub adaptive: Option<AdaptiveLimit>,
+    // The patterns are matched against the normalized query, the literals
+    // are replaced with `?`, see `sql::normalize`.
+    #[serde(default = "default_as_false")]
+    pub match_normalized: bool,
+    // The rule admits the requests of up to `max_concurrency` distinct keys in each
+    // `duration`, the key is the capture group `key_group` of the matched regex.
+    // The requests whose key has been seen in the window are always admitted.
+    #[serde(default)]
+    pub key_group: Option<usize>,
+    // Each composite key of the capture groups `key_groups` of the matched regex has
+    // its own `max_concurrency` permits, eg: the (user, table) pairs. The requests
+    // missing any group share the window of the rule. Only works with fixed window.
+    #[serde(default)]
+    pub key_groups: Vec<usize>,
+    // The limit in percent of the capacity set by `ConcurrencyControlLayer::set_capacity`,
+    // `max_concurrency` is used until the capacity is set. Only works with fixed window.
+    #[serde(default)]
+    pub max_concurrency_percent: Option<u32>,
+    // The dry-run rule counts and logs the queries it would reject, but admits them
+    #[serde(default = "default_as_false")]
+    pub dry_run: bool,
+    // The size limits in bytes of the compiled regexes and of the cache of the lazy DFA,
+    // the default limits of `regex` are used if they are not set. A regex exceeding
+    // `regex_size_limit` is rejected, the lazy DFA falls back to a slower engine instead.
+    #[serde(default)]
+    pub regex_size_limit: Option<usize>,
+    #[serde(default)]
+    pub dfa_size_limit: Option<usize>,
+    // The rule rejects all matched queries for a while after too many rejections
+    #[serde(default)]
+    pub quarantine: Option<Quarantine>,
+    // The time in milliseconds the rule rejects all matched queries after its window is
+    // reset, so the backend can recover before the next burst. The first window has no
+    // cooldown. Only works with fixed window.
+    #[serde_as(as = "Option<serde_with::DurationMilliSeconds<u64>>")]
+    #[serde(default)]
+    pub post_reset_cooldown: Option<Duration>,
+    // The limit ramps linearly from 1 to `max_concurrency` in `warmup` after the rules are
+    // built, so the cold backend is not flooded on startup. Only works with fixed window.
+    #[serde_as(as = "Option<serde_with::DurationMilliSeconds<u64>>")]
+    #[serde(default)]
+    pub warmup: Option<Duration>,
+    // The rule only applies to the connections from the network, eg: "10.0.0.0/8", see
+    // `concurrency_control::SourceInput`. The requests without a source are not limited.
+    #[serde(default)]
+    pub source_cidr: Option<IpNet>,
+    // The rejections of the rule are logged with this probability, and the count of the
+    // rejections not logged is logged once per `duration`. They are not logged if not set.
+    #[serde(default)]
+    pub log_sample_rate: Option<f64>,
+    // The rule only applies in the time of day window, it applies all day if not set
+    #[serde(default)]
+    pub active_window: Option<ActiveWindow>,
+    // The rules of the same pool lend their spare permits to each other, a rule whose
+    // window is used up borrows the permits from an idle rule of the pool, at most
+    // `pool_max_borrow` permits at a time. No limit if it is not set.
+    // Only works with fixed window.
+    #[serde(default)]
+    pub pool: Option<String>,
+    #[serde(default)]
+    pub pool_max_borrow: Option<u32>,
+    // The permits the `High` priority requests may take beyond `max_concurrency` when the
+    // window is used up, see `concurrency_control::RequestPriority`. The `High` requests
+    // are limited as the others if it is not set. Only works with fixed window.
+    #[serde(default)]
+    pub high_priority_overdraft: Option<u32>,
+    // The requests admitted while more than `soft_limit` permits of the window are in use
+    // are marked as over the soft limit, eg: to scale out before the rejections begin.
+    // It must be less than `max_concurrency`. Only works with fixed window.
+    #[serde(default)]
+    pub soft_limit: Option<u32>,
+    // The message sent to the client when the rule rejects a request, eg: the contact of
+    // the operator. The message of `PluginError` is sent if it is not set.
+    #[serde(default)]
+    pub reject_message: Option<String>,
+    // The statements of a multi-statement query are matched by the rule one by one, each
+    // matching statement consumes a permit, and the query is rejected if any is rejected.
+    // The rule matches the whole query if it is not set, see `sql::split_statements`.
+    #[serde(default = "default_as_false")]
+    pub split_multi_statement: bool,
+    // The disabled rule is skipped as if it did not match
+    #[serde(default = "default_as_true")]
+    pub enabled: bool,
+}
+
+impl Default for ConcurrencyControl {
+    fn default() -> Self {
+        ConcurrencyControl {
+            regex: vec![],
+            max_concurrency: 0,
+            duration: Duration::ZERO,
+            algorithm: ConcurrencyControlAlgorithm::default(),
+            acquire_timeout: None,
+            scope: ConcurrencyControlScope::default(),
+            backend: ConcurrencyControlBackend::default(),
+            priority: 0,
+            case_insensitive: false,
+            action: ConcurrencyControlAction::default(),
+            weight_regex: None,
+            except_regex: None,
+            weight: None,
+            statement_kinds: None,
+            mode: ConcurrencyControlMatchMode::default(),
+            selection_weight: None,
+            max_bytes: None,
+            require_limit: None,
+            match_type: ConcurrencyControlMatchType::default(),
+            jitter: None,
+            adaptive: None,
+            match_normalized: false,
+            key_group: None,
+            key_groups: vec![],
+            max_concurrency_percent: None,
+            dry_run: false,
+            regex_size_limit: None,
+            dfa_size_limit: None,
+            quarantine: None,
+            post_reset_cooldown: None,
+            warmup: None,
+            source_cidr: None,
+            log_sample_rate: None,
+            active_window: None,
+            pool: None,
+            pool_max_borrow: None,
+            high_priority_overdraft: None,
+            soft_limit: None,
+            reject_message: None,
+            split_multi_statement: false,
+            enabled: true,
+        }
+    }
+}
+
+impl ConcurrencyControl {
+    /// Check the values which make the rule reject or match everything,
+    /// all problems of the rule are reported in one error.
+    pub fn validate(&self) -> Result<(), PluginError> {
+        let mut errors = vec![];
+        if self.regex.iter().any(|r| r.is_empty()) {
+            errors.push(String::from("regex must not be empty"));
+        }
+
+        // The `Allow` rules never consume a permit
+        if self.action == ConcurrencyControlAction::Throttle {
+            match self.algorithm {
+                ConcurrencyControlAlgorithm::TokenBucket { capacity, .. } => {
+                    if capacity == 0 {
+                        errors.push(String::from("capacity must be greater than 0"));
+                    }
+                }
+                ConcurrencyControlAlgorithm::FixedWindow
+                | ConcurrencyControlAlgorithm::SlidingWindow => {
+                    if self.max_concurrency == 0 {
+                        errors.push(String::from("max_concurrency must be greater than 0"));
+                    }
+                    if self.duration.is_zero() {
+                        errors.push(String::from("duration must be greater than 0"));
+                    }
+                }
+            }
+        }
+
+        if let Some(adaptive) = &self.adaptive {
+            if adaptive.min == 0 {
+                errors.push(String::from("adaptive min must be greater than 0"));
+            }
+            if adaptive.min > adaptive.max {
+                errors.push(String::from("adaptive min must not be greater than max"));
+            }
+        }
+
+        if let Some(percent) = self.max_concurrency_percent {
+            if percent == 0 || percent > 100 {
+                errors.push(String::from("max_concurrency_percent must be in [1, 100]"));
+            }
+            if self.adaptive.is_some() {
+                errors.push(String::from("max_concurrency_percent does not work with adaptive"));
+            }
+        }
+
+        if self.log_sample_rate.is_some_and(|r| !(r > 0.0 && r <= 1.0)) {
+            errors.push(String::from("log_sample_rate must be in (0, 1]"));
+        }
+
+        if self.pool.is_some() {
+            if self.algorithm != ConcurrencyControlAlgorithm::FixedWindow {
+                errors.push(String::from("pool only works with fixed window"));
+            }
+            // the rejections would be counted even if the permits are borrowed
+            if self.quarantine.is_some() {
+                errors.push(String::from("pool does not work with quarantine"));
+            }
+        }
+
+        if self.backend != ConcurrencyControlBackend::Local {
+            if self.algorithm != ConcurrencyControlAlgorithm::FixedWindow {
+                errors.push(String::from("redis backend does not work with algorithm"));
+            }
+            if self.pool.is_some() || self.adaptive.is_some() {
+                errors.push(String::from("redis backend does not work with pool or adaptive"));
+            }
+            if self.key_group.is_some() || !self.key_groups.is_empty() {
+                errors.push(String::from("redis backend does not work with key groups"));
+            }
+        }
+
+        if self.selection_weight == Some(0) {
+            errors.push(String::from("selection_weight must be greater than 0"));
+        }
+
+        if self.high_priority_overdraft.is_some()
+            && self.algorithm != ConcurrencyControlAlgorithm::FixedWindow
+        {
+            errors.push(String::from("high_priority_overdraft only works with fixed window"));
+        }
+
+        if let Some(soft_limit) = self.soft_limit {
+            if self.algorithm != ConcurrencyControlAlgorithm::FixedWindow {
+                errors.push(String::from("soft_limit only works with fixed window"));
+            }
+            if soft_limit >= self.max_concurrency {
+                errors.push(String::from("soft_limit must be less than max_concurrency"));
+            }
+        }
+
+        if self.warmup.is_some() && self.algorithm != ConcurrencyControlAlgorithm::FixedWindow {
+            errors.push(String::from("warmup only works with fixed window"));
+        }
+
+        if let Some(cooldown) = self.post_reset_cooldown {
+            if self.algorithm != ConcurrencyControlAlgorithm::FixedWindow {
+                errors.push(String::from("post_reset_cooldown only works with fixed window"));
+            }
+            if cooldown >= self.duration {
+                errors.push(String::from("post_reset_cooldown must be less than duration"));
+            }
+        }
+
+        if self.active_window.is_some_and(|w| w.start == w.end) {
+            errors.push(String::from("active_window start and end must differ"));
+        }
+
+        if self.quarantine.is_some_and(|q| q.after_rejections == 0) {
+            errors.push(String::from("quarantine after_rejections must be greater than 0"));
+        }
+
+        if self.key_group.is_some() && self.match_type != ConcurrencyControlMatchType::Regex {
+            errors.push(String::from("key_group only works with regex match type"));
+        }
+
+        if !self.key_groups.is_empty() {
+            if self.match_type != ConcurrencyControlMatchType::Regex {
+                errors.push(String::from("key_groups only works with regex match type"));
+            }
+            if self.algorithm != ConcurrencyControlAlgorithm::FixedWindow {
+                errors.push(String::from("key_groups only works with fixed window"));
+            }
+            if self.key_group.is_some() {
+                errors.push(String::from("key_groups does not work with key_group"));
+            }
+            if self.scope == ConcurrencyControlScope::PerClient {
+                errors.push(String::from("key_groups does not work with per client scope"));
+            }
+        }
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(PluginError::InvalidConcurrencyControlConfig { errors })
+    }
+}
+
+/// The bounds of the adaptive limit
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct AdaptiveLimit {
+    pub min: u32,
+    pub max: u32,
+}
+
+/// The rule is quarantined for `for_duration` after `after_rejections` rejections
+/// within `duration` of the rule.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Quarantine {
+    pub after_rejections: usize,
+    #[serde(
+        deserialize_with = "humantime_duration",
+        serialize_with = "serialize_humantime_duration"
+    )]
+    pub for_duration: Duration,
 }
 
+/// The time of day window from `start` to `end`, it wraps around midnight if `start` is
+/// later than `end`, eg: 22:00 to 06:00. The wall clock is UTC shifted by `utc_offset_minutes`,
+/// as the local time zone of the system is not known.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct ActiveWindow {
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+}
+
+impl ActiveWindow {
+    /// Return whether `time` is in the window, `start` is included and `end` is excluded.
+    pub fn contains(&self, time: TimeOfDay) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// Return whether the wall clock time of `now` is in the window.
+    pub fn is_active(&self, now: SystemTime) -> bool {
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let local = secs + i64::from(self.utc_offset_minutes) * 60;
+        self.contains(TimeOfDay(local.rem_euclid(TimeOfDay::DAY as i64) as u32))
+    }
+}
+
+/// The seconds since midnight, it is written as "HH:MM" or "HH:MM:SS".
+#[derive(Debug, SerializeDisplay, DeserializeFromStr, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay(u32);
+
+impl TimeOfDay {
+    const DAY: u32 = 24 * 60 * 60;
+
+    pub fn new(hour: u32, minute: u32, second: u32) -> Option<TimeOfDay> {
+        (hour < 24 && minute < 60 && second < 60)
+            .then(|| TimeOfDay(hour * 3600 + minute * 60 + second))
+    }
+}
+
+impl std::str::FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid time of day {:?}, expect HH:MM or HH:MM:SS", s);
+        let parts = s
+            .split(':')
+            .map(|p| p.parse::<u32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        match parts[..] {
+            [hour, minute] => TimeOfDay::new(hour, minute, 0),
+            [hour, minute, second] => TimeOfDay::new(hour, minute, second),
+            _ => None,
+        }
+        .ok_or_else(invalid)
+    }
+}
+
+impl std::fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (hour, minute, second) = (self.0 / 3600, self.0 / 60 % 60, self.0 % 60);
+        if second == 0 {
+            write!(f, "{:02}:{:02}", hour, minute)
+        } else {
+            write!(f, "{:02}:{:02}:{:02}", hour, minute, second)
+        }
+    }
+}
+
+/// The mode decides which matched rules limit the request, it is taken from
+/// the first matched rule.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConcurrencyControlMatchMode {
+    // Only the first matched rule limits the request
+    #[default]
+    FirstMatch,
+    // The request must be admitted by all matched rules
+    AllMatches,
+    // One of the matched `WeightedOneOf` rules is selected randomly by `selection_weight`
+    // to limit the request, so the requests are spread across the budgets of the rules.
+    // The matched rules of the other modes are not selected.
+    WeightedOneOf,
+}
+
+/// How the patterns of `regex` are matched against the query
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConcurrencyControlMatchType {
+    #[default]
+    Regex,
+    // The query starts with the pattern, compared as plain string
+    Prefix,
+    // The query equals the pattern, compared as plain string
+    Exact,
+}
+
+/// The action of a matched rule
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConcurrencyControlAction {
+    // Limit the matched requests
+    #[default]
+    Throttle,
+    // The matched requests bypass all `Throttle` rules whatever their priority,
+    // and never consume a permit.
+    Allow,
+}
+
+/// The scope of the permits of a rule
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConcurrencyControlScope {
+    // All clients share the permits
+    #[default]
+    Global,
+    // Each client has its own permits, the client is identified by `ClientInput`.
+    // Only works with fixed window.
+    PerClient,
+}
+
+/// The backend counting the requests of a rule
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConcurrencyControlBackend {
+    // The permits of the rule are counted by the proxy instance
+    #[default]
+    Local,
+    // The requests of all proxy instances sharing the Redis are counted by GCRA in it,
+    // at most `max_concurrency` requests are admitted per `duration`. The key of the rule
+    // is its patterns prefixed by `key_prefix`. It requires the `redis` feature, or a
+    // store set by `ConcurrencyControlLayer::with_store`.
+    Redis {
+        url: String,
+        #[serde(default)]
+        key_prefix: String,
+    },
+}
+
+// The password of the redis url is not printed, the configs are logged
+impl std::fmt::Debug for ConcurrencyControlBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConcurrencyControlBackend::Local => f.write_str("Local"),
+            ConcurrencyControlBackend::Redis { url, key_prefix } => {
+                let url = match (url.split_once("://"), url.rsplit_once('@')) {
+                    (Some((scheme, _)), Some((_, host))) => format!("{}://***@{}", scheme, host),
+                    _ => url.clone(),
+                };
+                f.debug_struct("Redis").field("url", &url).field("key_prefix", key_prefix).finish()
+            }
+        }
+    }
+}
+
+/// The algorithm used to count the matched requests in `duration`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConcurrencyControlAlgorithm {
+    // The permits are reset when `duration` has elapsed since the window started,
+    // so the bursts of `2 * max_concurrency` can be admitted across a window boundary.
+    #[default]
+    FixedWindow,
+    // Only admits a request if fewer than `max_concurrency` requests were admitted
+    // in the trailing `duration`.
+    SlidingWindow,
+    // Each request consumes a token, the tokens are refilled `refill_per_sec` per second
+    // and at most `capacity` tokens can be accumulated.
+    TokenBucket {
+        capacity: u32,
+        refill_per_sec: f64,
+    },
+}
+
+/// The config of `CircuitBreakerLayer`, it opens after `failure_threshold` consecutive
+/// failures of the inner service and lets a probe request through after `cool_down`.
+#[serde_with::serde_as]
 #[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CircuitBreaker {
+    pub failure_threshold: u32,
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub cool_down: Duration,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct CircuitBreak {
     pub regex: Vec<String>,
     #[serde(default = "default_as_false")]
     pub case_insensitive: bool,
 }
 
+/// The query matching `regex` is blocked, `message` is returned to the client.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Firewall {
+    pub regex: String,
+    pub message: String,
+    #[serde(default = "default_as_false")]
+    pub case_insensitive: bool,
+}
+
+/// The results of the queries matching `regex` are cached for `ttl`,
+/// at most `capacity` results are kept, the least recently used one is evicted first.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Cache {
+    pub regex: String,
+    #[serde(
+        deserialize_with = "humantime_duration",
+        serialize_with = "serialize_humantime_duration"
+    )]
+    pub ttl: Duration,
+    pub capacity: usize,
+    #[serde(default = "default_as_false")]
+    pub case_insensitive: bool,
+}
+
+/// The requests matching `regex` are a class of `FairQueueLayer`, the classes share
+/// the concurrency in proportion to `weight`, a weight of 0 is treated as 1.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FairQueueClass {
+    pub regex: String,
+    pub weight: u32,
+    #[serde(default = "default_as_false")]
+    pub case_insensitive: bool,
+}
+
+/// Load the plugin config from `path`, the format is detected by the extension,
+/// `toml`, `json`, `yaml` or `yml`. The concurrency control rules are validated, the errors
+/// of all invalid rules are reported, a single invalid rule is reported as `Invalid`.
+pub fn load_from_path(path: &Path) -> Result<Plugin, ConfigError> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| ConfigError::Io { path: path.to_path_buf(), source: e })?;
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+    let plugin: Plugin = match extension.to_ascii_lowercase().as_str() {
+        "toml" => toml::from_str(&content)?,
+        "json" => serde_json::from_str(&content)?,
+        "yaml" | "yml" => serde_yaml::from_str(&content)?,
+        _ => return Err(ConfigError::UnsupportedFormat { extension: extension.to_string() }),
+    };
+
+    let mut errors = plugin
+        .concurrency_control
+        .iter()
+        .flatten()
+        .enumerate()
+        .filter_map(|(idx, rule)| {
+            rule.validate().err().map(|e| ConfigError::Invalid { rule_index: idx, source: e })
+        })
+        .collect::<Vec<_>>();
+    match errors.len() {
+        0 => Ok(plugin),
+        1 => Err(errors.remove(0)),
+        _ => Err(ConfigError::InvalidRules { errors }),
+    }
+}
+
 fn default_as_false() -> bool {
     false
-}
\ No newline at end of file
+}
+
+fn default_as_true() -> bool {
+    true
+}
+
+// Deserialize a human readable duration, eg: "50s", "500ms", "2m".
+// A bare integer is accepted as seconds, so the existing config files keep working.
+pub fn humantime_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct DurationVisitor;
+
+    impl<'de> de::Visitor<'de> for DurationVisitor {
+        type Value = Duration;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a duration like \"50s\", \"500ms\" or an integer in seconds")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Duration, E> {
+            humantime::parse_duration(v)
+                .map_err(|e| E::custom(format!("invalid duration {:?}: {}", v, e)))
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<Duration, E> {
+            Ok(Duration::from_secs(v))
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<Duration, E> {
+            u64::try_from(v)
+                .map(Duration::from_secs)
+                .map_err(|_| E::custom(format!("invalid duration {:?}: must not be negative", v)))
+        }
+    }
+
+    deserializer.deserialize_any(DurationVisitor)
+}
+
+pub fn serialize_humantime_duration<S>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&humantime::format_duration(*duration).to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Config {
+        #[serde(
+            deserialize_with = "humantime_duration",
+            serialize_with = "serialize_humantime_duration"
+        )]
+        duration: Duration,
+    }
+
+    #[test]
+    fn test_humantime_duration() {
+        let cases = [
+            ("50s", Duration::from_secs(50)),
+            ("500ms", Duration::from_millis(500)),
+            ("2m", Duration::from_secs(120)),
+            ("1h 30m", Duration::from_secs(5400)),
+        ];
+        for (s, duration) in cases {
+            let config: Config = toml::from_str(&format!("duration = {:?}", s)).unwrap();
+            assert_eq!(config.duration, duration);
+
+            // round trip
+            let config: Config = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+            assert_eq!(config.duration, duration);
+        }
+
+        // the bare integer is seconds
+        let config: Config = toml::from_str("duration = 333").unwrap();
+        assert_eq!(config.duration, Duration::from_secs(333));
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = ConcurrencyControl {
+            regex: vec![String::from("^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::from_secs(1),
+            ..Default::default()
+        };
+        assert_eq!(valid.validate(), Ok(()));
+
+        let errors = |c: ConcurrencyControl| match c.validate() {
+            Err(PluginError::InvalidConcurrencyControlConfig { errors }) => errors,
+            res => panic!("unexpected result {:?}", res),
+        };
+
+        let c = ConcurrencyControl { regex: vec![String::new()], ..valid.clone() };
+        assert_eq!(errors(c), ["regex must not be empty"]);
+
+        let c = ConcurrencyControl { max_concurrency: 0, ..valid.clone() };
+        assert_eq!(errors(c), ["max_concurrency must be greater than 0"]);
+
+        let c = ConcurrencyControl {
+            duration: Duration::ZERO,
+            algorithm: ConcurrencyControlAlgorithm::SlidingWindow,
+            ..valid.clone()
+        };
+        assert_eq!(errors(c), ["duration must be greater than 0"]);
+
+        // the token bucket does not use `max_concurrency` and `duration`
+        let c = ConcurrencyControl {
+            max_concurrency: 0,
+            duration: Duration::ZERO,
+            algorithm: ConcurrencyControlAlgorithm::TokenBucket {
+                capacity: 0,
+                refill_per_sec: 1.0,
+            },
+            ..valid.clone()
+        };
+        assert_eq!(errors(c), ["capacity must be greater than 0"]);
+
+        let c = ConcurrencyControl { soft_limit: Some(1), ..valid.clone() };
+        assert_eq!(errors(c), ["soft_limit must be less than max_concurrency"]);
+
+        // all problems are reported
+        let c = ConcurrencyControl { regex: vec![String::new()], ..Default::default() };
+        assert_eq!(errors(c).len(), 3);
+
+        let c =
+            ConcurrencyControl { action: ConcurrencyControlAction::Allow, ..Default::default() };
+        assert_eq!(c.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_backend_debug() {
+        let backend = ConcurrencyControlBackend::Redis {
+            url: String::from("redis://:secret@localhost:6379/2"),
+            key_prefix: String::from("pisa:"),
+        };
+        assert_eq!(
+            format!("{:?}", backend),
+            r#"Redis { url: "redis://***@localhost:6379/2", key_prefix: "pisa:" }"#
+        );
+        assert_eq!(format!("{:?}", ConcurrencyControlBackend::Local), "Local");
+    }
+
+    #[test]
+    fn test_humantime_duration_invalid() {
+        let err = toml::from_str::<Config>(r#"duration = "50 parsecs""#).unwrap_err();
+        assert!(err.to_string().contains(r#"invalid duration "50 parsecs""#), "{}", err);
+
+        assert!(toml::from_str::<Config>("duration = -1").is_err());
+    }
+
+    // Write `content` into the file `name` in the temp dir, return its path
+    fn write_config(name: &str, content: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("pisa-plugin-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_active_window() {
+        let at = |s: &str| s.parse::<TimeOfDay>().unwrap();
+        let window: ActiveWindow = toml::from_str("start = \"09:00\"\nend = \"18:30\"").unwrap();
+        assert_eq!((window.start, window.end), (at("09:00"), at("18:30")));
+        assert!(window.contains(at("09:00")) && window.contains(at("18:29:59")));
+        assert!(!window.contains(at("18:30")) && !window.contains(at("08:59:59")));
+
+        // the window wraps around midnight
+        let window = ActiveWindow { start: at("22:00"), end: at("06:00"), utc_offset_minutes: 0 };
+        assert!(window.contains(at("23:00")) && window.contains(at("00:00")));
+        assert!(window.contains(at("05:59")) && !window.contains(at("06:00")));
+        assert!(!window.contains(at("12:00")));
+
+        // 1970-01-01 23:00 UTC is 01:00 in UTC+2 and 13:00 in UTC-10
+        let now = UNIX_EPOCH + Duration::from_secs(23 * 3600);
+        assert!(window.is_active(now));
+        assert!(ActiveWindow { utc_offset_minutes: 120, ..window }.is_active(now));
+        assert!(!ActiveWindow { utc_offset_minutes: -600, ..window }.is_active(now));
+
+        assert_eq!(at("07:05:09").to_string(), "07:05:09");
+        assert!("24:00".parse::<TimeOfDay>().is_err());
+        assert!("9".parse::<TimeOfDay>().is_err());
+    }
+
+    #[test]
+    fn test_load_from_path() {
+        let toml = r#"
+            [[concurrency_control]]
+            regex = ["^SELECT"]
+            max_concurrency = 10
+            duration = "5s"
+            jitter = 200
+            statement_kinds = ["select"]
+
+            [[concurrency_control]]
+            regex = ["^insert"]
+            max_concurrency = 2
+            duration = "1m"
+            algorithm = "slidingwindow"
+            case_insensitive = true
+
+            [[circuit_break]]
+            regex = ["DROP"]
+        "#;
+        let json = r#"{
+            "concurrency_control": [
+                {
+                    "regex": ["^SELECT"],
+                    "max_concurrency": 10,
+                    "duration": "5s",
+                    "jitter": 200,
+                    "statement_kinds": ["select"]
+                },
+                {
+                    "regex": ["^insert"],
+                    "max_concurrency": 2,
+                    "duration": "1m",
+                    "algorithm": "slidingwindow",
+                    "case_insensitive": true
+                }
+            ],
+            "circuit_break": [{ "regex": ["DROP"] }]
+        }"#;
+        let yaml = r#"
+concurrency_control:
+  - regex: ["^SELECT"]
+    max_concurrency: 10
+    duration: 5s
+    jitter: 200
+    statement_kinds: [select]
+  - regex: ["^insert"]
+    max_concurrency: 2
+    duration: 1m
+    algorithm: slidingwindow
+    case_insensitive: true
+circuit_break:
+  - regex: [DROP]
+"#;
+
+        let from_toml = load_from_path(&write_config("load.toml", toml)).unwrap();
+        let from_json = load_from_path(&write_config("load.JSON", json)).unwrap();
+        assert_eq!(from_toml, from_json);
+        for name in ["load.yaml", "load.yml"] {
+            assert_eq!(load_from_path(&write_config(name, yaml)).unwrap(), from_toml);
+        }
+
+        let rules = from_toml.concurrency_control.unwrap();
+        assert_eq!(rules[0].jitter, Some(Duration::from_millis(200)));
+        assert_eq!(rules[1].algorithm, ConcurrencyControlAlgorithm::SlidingWindow);
+    }
+
+    #[test]
+    fn test_load_from_path_errors() {
+        let err = load_from_path(Path::new("/nonexistent/plugin.toml")).unwrap_err();
+        assert!(matches!(err, ConfigError::Io { .. }), "{}", err);
+
+        let err = load_from_path(&write_config("errors.ini", "")).unwrap_err();
+        assert!(matches!(err, ConfigError::UnsupportedFormat { .. }), "{}", err);
+
+        let err =
+            load_from_path(&write_config("errors.toml", "concurrency_control = 1")).unwrap_err();
+        assert!(matches!(err, ConfigError::Toml(_)), "{}", err);
+
+        let err = load_from_path(&write_config("errors.json", "{")).unwrap_err();
+        assert!(matches!(err, ConfigError::Json(_)), "{}", err);
+
+        let err =
+            load_from_path(&write_config("errors.yaml", "concurrency_control: 1")).unwrap_err();
+        assert!(matches!(err, ConfigError::Yaml(_)), "{}", err);
+
+        let invalid = r#"
+            [[concurrency_control]]
+            regex = ["^SELECT"]
+            max_concurrency = 1
+            duration = "5s"
+
+            [[concurrency_control]]
+            regex = ["^SELECT"]
+            max_concurrency = 0
+            duration = "5s"
+        "#;
+        let err = load_from_path(&write_config("invalid.toml", invalid)).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { rule_index: 1, .. }), "{}", err);
+
+        // the errors of all invalid rules are reported
+        let invalid = format!(
+            r#"{}
+            [[concurrency_control]]
+            regex = [""]
+            max_concurrency = 1
+            duration = "5s"
+        "#,
+            invalid
+        );
+        let err = load_from_path(&write_config("invalid_rules.toml", &invalid)).unwrap_err();
+        let errors = match &err {
+            ConfigError::InvalidRules { errors } => errors,
+            err => panic!("unexpected error {}", err),
+        };
+        let indexes = errors
+            .iter()
+            .map(|e| match e {
+                ConfigError::Invalid { rule_index, .. } => *rule_index,
+                e => panic!("unexpected error {}", e),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(indexes, [1, 2]);
+        assert!(err.to_string().contains("invalid concurrency control rule 2: "), "{}", err);
+    }
+}
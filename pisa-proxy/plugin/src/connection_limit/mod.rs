@@ -0,0 +1,203 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::Mutex;
+
+use crate::{
+    err::{BoxError, PluginError},
+    layer::{Layer, Service},
+};
+
+/// The metadata of a connection, it is the input of `ConnectionLimit`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionMeta {
+    pub user: String,
+    pub host: String,
+}
+
+/// The part of `ConnectionMeta` the connections are counted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionKey {
+    User,
+    Host,
+    UserHost,
+}
+
+impl ConnectionKey {
+    fn of(&self, meta: &ConnectionMeta) -> String {
+        match self {
+            ConnectionKey::User => meta.user.clone(),
+            ConnectionKey::Host => meta.host.clone(),
+            ConnectionKey::UserHost => format!("{}@{}", meta.user, meta.host),
+        }
+    }
+}
+
+/// `ConnectionLimitLayer` limits the open connections of each key to `max_connections`.
+/// It is evaluated when a connection is accepted, unlike `ConcurrencyControlLayer` which
+/// evaluates each query. The returned `ConnectionPermit` must be kept for the lifetime of
+/// the connection, the connection is no longer counted once it is dropped.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimitLayer {
+    max_connections: usize,
+    key: ConnectionKey,
+}
+
+impl ConnectionLimitLayer {
+    pub fn new(max_connections: usize, key: ConnectionKey) -> ConnectionLimitLayer {
+        ConnectionLimitLayer { max_connections, key }
+    }
+}
+
+impl<S> Layer<S> for ConnectionLimitLayer {
+    type Service = ConnectionLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConnectionLimit {
+            inner,
+            max_connections: self.max_connections,
+            key: self.key,
+            connections: Arc::default(),
+        }
+    }
+}
+
+/// The clones of `ConnectionLimit` share the same counts.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit<S> {
+    inner: S,
+    max_connections: usize,
+    key: ConnectionKey,
+    // The open connections of each key, the key is removed when it has none
+    connections: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl<S> ConnectionLimit<S> {
+    /// Return the open connections of `key`
+    pub fn connections(&self, key: &str) -> usize {
+        self.connections.lock().get(key).copied().unwrap_or_default()
+    }
+
+    fn try_acquire(&self, meta: &ConnectionMeta) -> Result<ConnectionPermit, PluginError> {
+        let key = self.key.of(meta);
+        let mut connections = self.connections.lock();
+        let count = connections.entry(key.clone()).or_default();
+        if *count >= self.max_connections {
+            if *count == 0 {
+                connections.remove(&key);
+            }
+            return Err(PluginError::ConnectionLimitReached { key });
+        }
+        *count += 1;
+        Ok(ConnectionPermit { connections: self.connections.clone(), key })
+    }
+}
+
+/// The permit of an open connection, it is released when dropped
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    connections: Arc<Mutex<HashMap<String, usize>>>,
+    key: String,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock();
+        if let Some(count) = connections.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl<S> Service<ConnectionMeta> for ConnectionLimit<S>
+where
+    S: Service<ConnectionMeta>,
+    S::Error: Into<BoxError>,
+{
+    type Output = (ConnectionPermit, S::Output);
+    type Error = BoxError;
+
+    fn handle(&mut self, input: ConnectionMeta) -> Result<Self::Output, Self::Error> {
+        let permit = self.try_acquire(&input)?;
+        let out = self.inner.handle(input).map_err(Into::into)?;
+        Ok((permit, out))
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.inner.poll_ready()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::layer::{service_fn, ServiceBuilder};
+
+    fn meta(user: &str, host: &str) -> ConnectionMeta {
+        ConnectionMeta { user: user.to_string(), host: host.to_string() }
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let n = 4;
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConnectionLimitLayer::new(n - 1, ConnectionKey::User))
+            .build(service_fn(|meta: ConnectionMeta| Ok::<_, PluginError>(meta.host)));
+
+        let mut permits = vec![];
+        for i in 0..n {
+            match svc.handle(meta("app", &format!("10.0.0.{}", i))) {
+                Ok((permit, _)) => permits.push(permit),
+                Err(err) => {
+                    assert_eq!(i, n - 1);
+                    assert_eq!(
+                        err.downcast_ref::<PluginError>(),
+                        Some(&PluginError::ConnectionLimitReached { key: String::from("app") })
+                    );
+                }
+            }
+        }
+        assert_eq!(permits.len(), n - 1);
+        assert_eq!(svc.connections("app"), n - 1);
+
+        // the other users are counted separately
+        let _other = svc.handle(meta("admin", "10.0.0.1")).unwrap();
+
+        // the disconnected connection releases its permit
+        permits.pop();
+        assert_eq!(svc.connections("app"), n - 2);
+        permits.push(svc.handle(meta("app", "10.0.0.9")).unwrap().0);
+
+        drop(permits);
+        assert_eq!(svc.connections("app"), 0);
+        assert!(svc.connections.lock().get("app").is_none());
+    }
+
+    #[test]
+    fn test_connection_limit_key() {
+        let mut svc = ServiceBuilder::new()
+            .with_layer(ConnectionLimitLayer::new(1, ConnectionKey::UserHost))
+            .build(service_fn(|_: ConnectionMeta| Ok::<_, PluginError>(())));
+
+        let _a = svc.handle(meta("app", "10.0.0.1")).unwrap();
+        let _b = svc.handle(meta("app", "10.0.0.2")).unwrap();
+        assert!(svc.handle(meta("app", "10.0.0.1")).is_err());
+        assert_eq!(svc.connections("app@10.0.0.1"), 1);
+    }
+}
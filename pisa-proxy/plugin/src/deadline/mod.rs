@@ -0,0 +1,359 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::{
+    err::{BoxError, PluginError},
+    layer::{AsyncService, Layer, Service},
+};
+
+/// The input with the deadline of the caller, the layers reject it once the deadline
+/// has passed instead of doing work the caller no longer waits for.
+#[derive(Debug, Clone)]
+pub struct Deadlined<I> {
+    pub deadline: Option<Instant>,
+    pub input: I,
+}
+
+impl<I> Deadlined<I> {
+    pub fn new(input: I, deadline: Option<Instant>) -> Self {
+        Deadlined { deadline, input }
+    }
+
+    /// Return true if the deadline has passed
+    pub fn is_expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= Instant::now())
+    }
+}
+
+tokio::task_local! {
+    // The deadline of the `StackContext` handled by the async stack, the layers which are
+    // generic over their input can not read it from the input
+    static STACK_DEADLINE: Option<Instant>;
+}
+
+/// Return the deadline of the stack of the request handled by the current task, None if it
+/// is not handled by an async `StackContextLayer` stack or the stack has no budget, eg: the
+/// wait of `ConcurrencyControl` for a permit ends at it.
+pub fn stack_deadline() -> Option<Instant> {
+    STACK_DEADLINE.try_with(|deadline| *deadline).ok().flatten()
+}
+
+/// The context of a request passed through all layers of a stack, it is created by
+/// `StackContextLayer` when the request enters the stack, so the time spent in a layer,
+/// eg: waiting for a permit of `ConcurrencyControl`, is taken from the budget of the later
+/// layers. It is matched by the query of its input, so it passes through the layers unchanged.
+#[derive(Debug, Clone)]
+pub struct StackContext<I> {
+    pub started: Instant,
+    // The end of the budget of the whole stack, None if it has no budget
+    pub deadline: Option<Instant>,
+    pub input: I,
+}
+
+impl<I> StackContext<I> {
+    pub fn new(input: I, budget: Option<Duration>) -> Self {
+        let started = Instant::now();
+        StackContext { started, deadline: budget.map(|budget| started + budget), input }
+    }
+
+    /// Return the budget left, None if it has no budget
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Return true if the budget is used up
+    pub fn is_exhausted(&self) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= Instant::now())
+    }
+}
+
+impl<I: AsRef<str>> AsRef<str> for StackContext<I> {
+    fn as_ref(&self) -> &str {
+        self.input.as_ref()
+    }
+}
+
+/// `StackContextLayer` is the entry of a stack, it passes the input to the inner service
+/// in a `StackContext` with the deadline of `budget` from now. The later layers consult
+/// the context, eg: `DeadlineLayer` short-circuits once the budget is used up.
+#[derive(Debug, Clone)]
+pub struct StackContextLayer {
+    budget: Option<Duration>,
+}
+
+impl StackContextLayer {
+    pub fn new(budget: Option<Duration>) -> StackContextLayer {
+        StackContextLayer { budget }
+    }
+}
+
+impl<S> Layer<S> for StackContextLayer {
+    type Service = StackEntry<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        StackEntry { inner, budget: self.budget }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StackEntry<S> {
+    inner: S,
+    budget: Option<Duration>,
+}
+
+impl<S, Input> Service<Input> for StackEntry<S>
+where
+    S: Service<StackContext<Input>>,
+{
+    type Output = S::Output;
+    type Error = S::Error;
+
+    fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        self.inner.handle(StackContext::new(input, self.budget))
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.inner.poll_ready()
+    }
+}
+
+#[async_trait]
+impl<S, Input> AsyncService<Input> for StackEntry<S>
+where
+    S: AsyncService<StackContext<Input>> + Send,
+    Input: Send + 'static,
+{
+    type Output = S::Output;
+    type Error = S::Error;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        let input = StackContext::new(input, self.budget);
+        STACK_DEADLINE.scope(input.deadline, self.inner.handle(input)).await
+    }
+}
+
+/// `DeadlineLayer` rejects a `Deadlined` input with `PluginError::DeadlineExceeded` if its
+/// deadline has passed, otherwise the input is passed to the inner service with the
+/// deadline, so the inner layers can check it again, eg: `ConcurrencyControl` checks it
+/// before acquiring a permit. An `AsyncService` is also aborted when the deadline passes.
+/// A `StackContext` input is handled the same by the deadline of the stack.
+#[derive(Debug, Clone, Default)]
+pub struct DeadlineLayer;
+
+impl DeadlineLayer {
+    pub fn new() -> DeadlineLayer {
+        DeadlineLayer
+    }
+}
+
+impl<S> Layer<S> for DeadlineLayer {
+    type Service = Deadline<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Deadline { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Deadline<S> {
+    inner: S,
+}
+
+impl<S, Input> Service<Deadlined<Input>> for Deadline<S>
+where
+    S: Service<Deadlined<Input>>,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    fn handle(&mut self, input: Deadlined<Input>) -> Result<Self::Output, Self::Error> {
+        if input.is_expired() {
+            return Err(Box::new(PluginError::DeadlineExceeded));
+        }
+        self.inner.handle(input).map_err(Into::into)
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.inner.poll_ready()
+    }
+}
+
+#[async_trait]
+impl<S, Input> AsyncService<Deadlined<Input>> for Deadline<S>
+where
+    S: AsyncService<Deadlined<Input>> + Send,
+    Input: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: Deadlined<Input>) -> Result<Self::Output, Self::Error> {
+        let deadline = input.deadline;
+        self.handle_by(input, deadline).await
+    }
+}
+
+impl<S, Input> Service<StackContext<Input>> for Deadline<S>
+where
+    S: Service<StackContext<Input>>,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    fn handle(&mut self, input: StackContext<Input>) -> Result<Self::Output, Self::Error> {
+        if input.is_exhausted() {
+            return Err(Box::new(PluginError::DeadlineExceeded));
+        }
+        self.inner.handle(input).map_err(Into::into)
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.inner.poll_ready()
+    }
+}
+
+#[async_trait]
+impl<S, Input> AsyncService<StackContext<Input>> for Deadline<S>
+where
+    S: AsyncService<StackContext<Input>> + Send,
+    Input: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: StackContext<Input>) -> Result<Self::Output, Self::Error> {
+        let deadline = input.deadline;
+        self.handle_by(input, deadline).await
+    }
+}
+
+impl<S> Deadline<S> {
+    // Reject the input if `deadline` has passed, otherwise the inner future is dropped
+    // when it passes
+    async fn handle_by<Input>(
+        &mut self,
+        input: Input,
+        deadline: Option<Instant>,
+    ) -> Result<S::Output, BoxError>
+    where
+        S: AsyncService<Input> + Send,
+        Input: Send + 'static,
+        S::Error: Into<BoxError>,
+    {
+        match deadline {
+            Some(deadline) if deadline <= Instant::now() => {
+                Err(Box::new(PluginError::DeadlineExceeded))
+            }
+            Some(deadline) => {
+                match tokio::time::timeout_at(deadline.into(), self.inner.handle(input)).await {
+                    Ok(res) => res.map_err(Into::into),
+                    Err(_) => Err(Box::new(PluginError::DeadlineExceeded)),
+                }
+            }
+            None => self.inner.handle(input).await.map_err(Into::into),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::{
+        concurrency_control::ConcurrencyControlLayer,
+        config,
+        layer::{async_service_fn, service_fn, ServiceBuilder},
+    };
+
+    #[test]
+    fn test_deadline_expired() {
+        let mut svc = ServiceBuilder::new()
+            .with_layer(DeadlineLayer::new())
+            .build(service_fn(|input: Deadlined<&'static str>| Ok::<_, PluginError>(input.input)));
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        assert_eq!(svc.handle(Deadlined::new("SELECT 1", Some(deadline))).unwrap(), "SELECT 1");
+        assert_eq!(svc.handle(Deadlined::new("SELECT 1", None)).unwrap(), "SELECT 1");
+
+        let err = svc.handle(Deadlined::new("SELECT 1", Some(Instant::now()))).unwrap_err();
+        assert_eq!(err.downcast_ref::<PluginError>(), Some(&PluginError::DeadlineExceeded));
+    }
+
+    #[tokio::test]
+    async fn test_deadline_async() {
+        let mut svc = ServiceBuilder::new().with_layer(DeadlineLayer::new()).build(
+            async_service_fn(|input: Deadlined<u64>| async move {
+                tokio::time::sleep(Duration::from_millis(input.input)).await;
+                Ok::<_, PluginError>(input.input)
+            }),
+        );
+
+        let deadline = || Some(Instant::now() + Duration::from_millis(100));
+        assert_eq!(svc.handle(Deadlined::new(10, deadline())).await.unwrap(), 10);
+        let err = svc.handle(Deadlined::new(500, deadline())).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<PluginError>(), Some(&PluginError::DeadlineExceeded));
+    }
+
+    #[tokio::test]
+    async fn test_stack_budget() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            acquire_timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        }];
+        let calls = Arc::new(AtomicUsize::new(0));
+        let count = calls.clone();
+        let mut svc = ServiceBuilder::new()
+            .with_layer(StackContextLayer::new(Some(Duration::from_millis(50))))
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .with_layer(DeadlineLayer::new())
+            .build(async_service_fn(move |input: StackContext<&'static str>| {
+                count.fetch_add(1, Ordering::SeqCst);
+                async move { Ok::<_, PluginError>(input.input) }
+            }));
+
+        let (guard, out) = svc.handle("SELECT 1").await.unwrap();
+        assert_eq!(out, "SELECT 1");
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            drop(guard);
+        });
+
+        // the wait for the permit ends once the budget is used up, before the permit is
+        // released and the acquire timeout
+        let start = Instant::now();
+        let err = svc.handle("SELECT 1").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PluginError>(),
+            Some(PluginError::ConcurrencyControlPluginReject { rule_index: 0, .. })
+        ));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_millis(150));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -18,11 +18,260 @@ pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
 #[derive(thiserror::Error, Debug, PartialEq)]
 pub enum PluginError {
-    #[error("concurrency control plugin rejected")]
-    ConcurrencyControlPluginReject,
+    #[error("concurrency control plugin rejected by rule {rule_index} {regex:?}")]
+    ConcurrencyControlPluginReject {
+        rule_index: usize,
+        regex: Vec<String>,
+        // The time until the rule may admit a request again, if it can be estimated
+        retry_after: Option<std::time::Duration>,
+        // The `reject_message` of the rule
+        message: Option<String>,
+    },
+    #[error("concurrency control plugin rejected query of {bytes} bytes, max {max} bytes")]
+    QueryTooLarge { bytes: usize, max: usize },
+    #[error(
+        "concurrency control plugin rejected query {}, max {max} rows",
+        rows.map_or(String::from("without LIMIT"), |rows| format!("with LIMIT {}", rows))
+    )]
+    RowLimitExceeded { rows: Option<u64>, max: u64 },
+    #[error("concurrency control plugin rule {rule_index} is quarantined")]
+    Quarantined { rule_index: usize },
+    #[error("concurrency limit reached")]
+    ConcurrencyLimitReached,
+    #[error("connection limit plugin reached the limit of {key:?}")]
+    ConnectionLimitReached { key: String },
+    #[error("load shed plugin overloaded")]
+    Overloaded,
+    #[error("buffer plugin is full")]
+    BufferFull,
+    #[error("buffer plugin worker has stopped")]
+    BufferClosed,
     #[error("audit plugin rejected")]
     CircuitBreakPluginReject,
+    #[error("circuit breaker is open")]
+    CircuitOpen,
+    #[error("firewall plugin blocked by rule {rule}: {message}")]
+    FirewallBlocked { rule: usize, message: String },
+
+    #[error("concurrency control plugin invalid regex {regex:?}: {source}")]
+    InvalidConcurrencyControlRegex {
+        regex: String,
+        #[source]
+        source: regex::Error,
+    },
+
+    #[error("concurrency control plugin regex {regex:?} exceeds the size limit of {limit} bytes")]
+    RegexTooComplex { regex: String, limit: usize },
+
+    #[error("firewall plugin invalid regex {regex:?}: {source}")]
+    InvalidFirewallRegex {
+        regex: String,
+        #[source]
+        source: regex::Error,
+    },
+
+    #[error("cache plugin invalid regex {regex:?}: {source}")]
+    InvalidCacheRegex {
+        regex: String,
+        #[source]
+        source: regex::Error,
+    },
+
+    #[error("fair queue plugin invalid regex {regex:?}: {source}")]
+    InvalidFairQueueRegex {
+        regex: String,
+        #[source]
+        source: regex::Error,
+    },
+
+    #[error("fair queue plugin invalid config: {reason}")]
+    InvalidFairQueueConfig { reason: String },
+
+    #[error("latency injection plugin invalid regex {regex:?}: {source}")]
+    InvalidLatencyInjectionRegex {
+        regex: String,
+        #[source]
+        source: regex::Error,
+    },
+
+    #[error("mirror plugin invalid regex {regex:?}: {source}")]
+    InvalidMirrorRegex {
+        regex: String,
+        #[source]
+        source: regex::Error,
+    },
+
+    #[error("single flight plugin invalid regex {regex:?}: {source}")]
+    InvalidSingleFlightRegex {
+        regex: String,
+        #[source]
+        source: regex::Error,
+    },
+
+    #[error("concurrency control plugin invalid config: {}", errors.join("; "))]
+    InvalidConcurrencyControlConfig { errors: Vec<String> },
+
+    #[error("concurrency control plugin invalid rule index {rule_index}")]
+    InvalidRuleIndex { rule_index: usize },
+
+    #[error("concurrency control plugin rule {rule_index} has all its permits")]
+    PermitsFull { rule_index: usize },
+
+    #[error("concurrency control store unavailable: {reason}")]
+    StoreUnavailable { reason: String },
+
+    #[error("concurrency control plugin is draining")]
+    Draining,
+
+    #[error("deadline exceeded")]
+    DeadlineExceeded,
+
+    #[error("timeout plugin elapsed {elapsed:?}")]
+    Timeout { elapsed: std::time::Duration },
 
     #[error("unknown error")]
     Unknown,
 }
+
+/// The errors of loading the plugin config, see `config::load_from_path`
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read config {path:?}: {source}")]
+    Io {
+        path: std::path::PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("failed to parse toml config: {0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("failed to parse json config: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("failed to parse yaml config: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[error("unsupported config format {extension:?}, expect toml, json or yaml")]
+    UnsupportedFormat { extension: String },
+
+    #[error("invalid concurrency control rule {rule_index}: {source}")]
+    Invalid {
+        rule_index: usize,
+        #[source]
+        source: PluginError,
+    },
+
+    // More than one rule is invalid, each error is an `Invalid`
+    #[error("{}", errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+    InvalidRules { errors: Vec<ConfigError> },
+}
+
+impl PluginError {
+    /// Return the name of the variant, it is used as the value of log fields
+    pub fn name(&self) -> &'static str {
+        match self {
+            PluginError::ConcurrencyControlPluginReject { .. } => "ConcurrencyControlPluginReject",
+            PluginError::QueryTooLarge { .. } => "QueryTooLarge",
+            PluginError::RowLimitExceeded { .. } => "RowLimitExceeded",
+            PluginError::Quarantined { .. } => "Quarantined",
+            PluginError::ConcurrencyLimitReached => "ConcurrencyLimitReached",
+            PluginError::ConnectionLimitReached { .. } => "ConnectionLimitReached",
+            PluginError::Overloaded => "Overloaded",
+            PluginError::BufferFull => "BufferFull",
+            PluginError::BufferClosed => "BufferClosed",
+            PluginError::CircuitBreakPluginReject => "CircuitBreakPluginReject",
+            PluginError::CircuitOpen => "CircuitOpen",
+            PluginError::FirewallBlocked { .. } => "FirewallBlocked",
+            PluginError::InvalidFirewallRegex { .. } => "InvalidFirewallRegex",
+            PluginError::InvalidCacheRegex { .. } => "InvalidCacheRegex",
+            PluginError::InvalidFairQueueRegex { .. } => "InvalidFairQueueRegex",
+            PluginError::InvalidFairQueueConfig { .. } => "InvalidFairQueueConfig",
+            PluginError::InvalidLatencyInjectionRegex { .. } => "InvalidLatencyInjectionRegex",
+            PluginError::InvalidMirrorRegex { .. } => "InvalidMirrorRegex",
+            PluginError::InvalidSingleFlightRegex { .. } => "InvalidSingleFlightRegex",
+            PluginError::InvalidConcurrencyControlRegex { .. } => "InvalidConcurrencyControlRegex",
+            PluginError::RegexTooComplex { .. } => "RegexTooComplex",
+            PluginError::InvalidConcurrencyControlConfig { .. } => {
+                "InvalidConcurrencyControlConfig"
+            }
+            PluginError::InvalidRuleIndex { .. } => "InvalidRuleIndex",
+            PluginError::PermitsFull { .. } => "PermitsFull",
+            PluginError::StoreUnavailable { .. } => "StoreUnavailable",
+            PluginError::Draining => "Draining",
+            PluginError::DeadlineExceeded => "DeadlineExceeded",
+            PluginError::Timeout { .. } => "Timeout",
+            PluginError::Unknown => "Unknown",
+        }
+    }
+
+    /// Return the message sent to the client, it is the `reject_message` of the rule
+    /// which rejected the request if it is set, otherwise the message of the error
+    pub fn client_message(&self) -> String {
+        match self {
+            PluginError::ConcurrencyControlPluginReject { message: Some(message), .. } => {
+                message.clone()
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    /// Return true if the request is rejected by a limiting plugin
+    pub fn is_limited(&self) -> bool {
+        matches!(
+            self,
+            PluginError::ConcurrencyControlPluginReject { .. }
+                | PluginError::QueryTooLarge { .. }
+                | PluginError::RowLimitExceeded { .. }
+                | PluginError::Quarantined { .. }
+                | PluginError::ConcurrencyLimitReached
+                | PluginError::ConnectionLimitReached { .. }
+                | PluginError::Overloaded
+                | PluginError::BufferFull
+                | PluginError::CircuitOpen
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use super::*;
+    use crate::concurrency_control::ConcurrencyControlLayer;
+
+    #[test]
+    fn test_source_chain() {
+        let config = vec![crate::config::ConcurrencyControl {
+            regex: vec![String::from("(SELECT")],
+            max_concurrency: 1,
+            duration: std::time::Duration::from_secs(1),
+            ..Default::default()
+        }];
+        let err = ConcurrencyControlLayer::new(config).err().unwrap();
+        assert!(matches!(err, PluginError::InvalidConcurrencyControlRegex { .. }));
+        assert!(err
+            .to_string()
+            .starts_with("concurrency control plugin invalid regex \"(SELECT\""));
+
+        // the regex error is the source, it has no source itself
+        let source = err.source().unwrap();
+        assert!(source.downcast_ref::<regex::Error>().is_some());
+        assert!(source.source().is_none());
+        let cause = source.to_string();
+
+        // the chain is kept when wrapped and boxed
+        let err: BoxError = Box::new(ConfigError::Invalid { rule_index: 0, source: err });
+        let mut chain = vec![];
+        let mut next: Option<&(dyn Error + 'static)> = Some(err.as_ref());
+        while let Some(e) = next {
+            chain.push(e.to_string());
+            next = e.source();
+        }
+        assert_eq!(chain.len(), 3);
+        assert!(chain[0].starts_with("invalid concurrency control rule 0: "));
+        assert_eq!(chain[2], cause);
+
+        assert!(PluginError::Draining.source().is_none());
+    }
+}
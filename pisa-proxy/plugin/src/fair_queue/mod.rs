@@ -0,0 +1,337 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::VecDeque, sync::Arc};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use regex::{Regex, RegexSet};
+use tokio::sync::oneshot;
+
+use crate::{
+    config,
+    err::{BoxError, PluginError},
+    layer::{AsyncService, Bypass, Bypassable, Layer},
+};
+
+/// `FairQueueLayer` shares `max_concurrency` among the classes of requests in proportion
+/// to their weights, the requests wait in the queue of their class until they are admitted,
+/// so a heavy class can not starve a light one. The requests matching no class are in an
+/// extra class of weight 1. Only `AsyncService` is supported, as the requests are queued.
+#[derive(Clone)]
+pub struct FairQueueLayer {
+    max_concurrency: usize,
+    classes: Arc<FairQueueClasses>,
+    bypass: Bypass,
+}
+
+/// All class regexes are compiled into a `RegexSet`, the first matching class wins.
+#[derive(Debug)]
+struct FairQueueClasses {
+    set: RegexSet,
+    // The weight of each class, the last one is the class of the unmatched requests
+    weights: Vec<u32>,
+}
+
+impl FairQueueClasses {
+    fn classify(&self, input: &str) -> usize {
+        self.set.matches(input).iter().next().unwrap_or(self.weights.len() - 1)
+    }
+}
+
+impl FairQueueLayer {
+    pub fn new(
+        max_concurrency: usize,
+        classes: Vec<config::FairQueueClass>,
+    ) -> Result<FairQueueLayer, PluginError> {
+        // no request would ever be dispatched
+        if max_concurrency == 0 {
+            return Err(PluginError::InvalidFairQueueConfig {
+                reason: String::from("max_concurrency must be greater than 0"),
+            });
+        }
+        let mut patterns = Vec::with_capacity(classes.len());
+        for c in &classes {
+            // check each regex, so the error names the bad one
+            Regex::new(&c.regex).map_err(|e| PluginError::InvalidFairQueueRegex {
+                regex: c.regex.clone(),
+                source: e,
+            })?;
+            if c.case_insensitive {
+                patterns.push(format!("(?i){}", c.regex));
+            } else {
+                patterns.push(c.regex.clone());
+            }
+        }
+
+        let set = RegexSet::new(patterns).expect("fair queue regexes are validated");
+        let weights = classes.iter().map(|c| c.weight.max(1)).chain([1]).collect();
+        Ok(FairQueueLayer {
+            max_concurrency,
+            classes: Arc::new(FairQueueClasses { set, weights }),
+            bypass: Bypass::default(),
+        })
+    }
+}
+
+impl Bypassable for FairQueueLayer {
+    fn with_bypass(self, bypass: Bypass) -> Self {
+        FairQueueLayer { bypass, ..self }
+    }
+}
+
+impl<S> Layer<S> for FairQueueLayer {
+    type Service = FairQueue<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        let scheduler = Scheduler {
+            max_concurrency: self.max_concurrency,
+            in_flight: 0,
+            weights: self.classes.weights.clone(),
+            queues: self.classes.weights.iter().map(|_| VecDeque::new()).collect(),
+            deficits: vec![0; self.classes.weights.len()],
+            current: 0,
+        };
+        FairQueue {
+            inner,
+            classes: self.classes.clone(),
+            scheduler: Arc::new(Mutex::new(scheduler)),
+            bypass: self.bypass.clone(),
+        }
+    }
+}
+
+/// A deficit round robin scheduler of the queued requests, each class earns its weight
+/// when it is visited, and every admitted request costs 1.
+#[derive(Debug)]
+struct Scheduler {
+    max_concurrency: usize,
+    in_flight: usize,
+    weights: Vec<u32>,
+    // The waiters of each class, they are admitted by sending on the channel
+    queues: Vec<VecDeque<oneshot::Sender<()>>>,
+    deficits: Vec<u32>,
+    // The class visited by the round robin
+    current: usize,
+}
+
+impl Scheduler {
+    // Admit the request now if the concurrency is not used up and nobody is waiting
+    fn try_admit(&mut self) -> bool {
+        if self.in_flight < self.max_concurrency && self.queues.iter().all(VecDeque::is_empty) {
+            self.in_flight += 1;
+            return true;
+        }
+        false
+    }
+
+    fn release(&mut self) {
+        self.in_flight -= 1;
+        while self.in_flight < self.max_concurrency {
+            match self.next_waiter() {
+                // the waiter may have been cancelled, then the next one is admitted
+                Some(tx) => {
+                    if tx.send(()).is_ok() {
+                        self.in_flight += 1;
+                    }
+                }
+                None => return,
+            }
+        }
+    }
+
+    fn next_waiter(&mut self) -> Option<oneshot::Sender<()>> {
+        if self.queues.iter().all(VecDeque::is_empty) {
+            return None;
+        }
+        loop {
+            let class = self.current;
+            if self.queues[class].is_empty() {
+                // an idle class does not save its deficit
+                self.deficits[class] = 0;
+                self.current = (class + 1) % self.queues.len();
+                continue;
+            }
+            if self.deficits[class] == 0 {
+                self.deficits[class] = self.weights[class];
+            }
+            self.deficits[class] -= 1;
+            let tx = self.queues[class].pop_front();
+            if self.deficits[class] == 0 {
+                self.current = (class + 1) % self.queues.len();
+            }
+            return tx;
+        }
+    }
+}
+
+/// The concurrency is released when the request completes or is dropped
+struct Permit(Arc<Mutex<Scheduler>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.lock().release();
+    }
+}
+
+/// A queued request, the concurrency handed to it is released if it is cancelled
+struct Queued {
+    rx: Option<oneshot::Receiver<()>>,
+    scheduler: Arc<Mutex<Scheduler>>,
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        if let Some(mut rx) = self.rx.take() {
+            rx.close();
+            if rx.try_recv().is_ok() {
+                self.scheduler.lock().release();
+            }
+        }
+    }
+}
+
+/// The clones of `FairQueue` share the same concurrency and queues.
+#[derive(Debug, Clone)]
+pub struct FairQueue<S> {
+    inner: S,
+    classes: Arc<FairQueueClasses>,
+    scheduler: Arc<Mutex<Scheduler>>,
+    bypass: Bypass,
+}
+
+// Wait until the request of `class` is admitted
+async fn acquire(scheduler: Arc<Mutex<Scheduler>>, class: usize) -> Permit {
+    let rx = {
+        let mut locked = scheduler.lock();
+        if locked.try_admit() {
+            drop(locked);
+            return Permit(scheduler);
+        }
+        let (tx, rx) = oneshot::channel();
+        locked.queues[class].push_back(tx);
+        rx
+    };
+
+    let mut queued = Queued { rx: Some(rx), scheduler: scheduler.clone() };
+    // the senders are only dropped after sending, as the queues are alive
+    let _ = queued.rx.as_mut().expect("the receiver is taken once").await;
+    queued.rx = None;
+    Permit(scheduler)
+}
+
+#[async_trait]
+impl<S, Input> AsyncService<Input> for FairQueue<S>
+where
+    S: AsyncService<Input> + Send,
+    Input: AsRef<str> + Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        // The requests bypassing the queue are not counted in the concurrency
+        if self.bypass.is_set() {
+            return self.inner.handle(input).await.map_err(Into::into);
+        }
+        let class = self.classes.classify(input.as_ref());
+        let _permit = acquire(self.scheduler.clone(), class).await;
+        self.inner.handle(input).await.map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::sync::Notify;
+
+    use super::*;
+    use crate::layer::{async_service_fn, ServiceBuilder};
+
+    #[tokio::test]
+    async fn test_fair_queue() {
+        let classes = vec![
+            config::FairQueueClass {
+                regex: String::from("^A"),
+                weight: 3,
+                case_insensitive: false,
+            },
+            config::FairQueueClass {
+                regex: String::from("^B"),
+                weight: 1,
+                case_insensitive: false,
+            },
+        ];
+        let order = Arc::new(Mutex::new(vec![]));
+        let gate = Arc::new(Notify::new());
+        let (inner_order, inner_gate) = (order.clone(), gate.clone());
+        let svc = ServiceBuilder::new().with_layer(FairQueueLayer::new(1, classes).unwrap()).build(
+            async_service_fn(move |input: &'static str| {
+                let (order, gate) = (inner_order.clone(), inner_gate.clone());
+                async move {
+                    if input == "BLOCK" {
+                        gate.notified().await;
+                    } else {
+                        order.lock().push(input);
+                    }
+                    Ok::<_, PluginError>(input)
+                }
+            }),
+        );
+
+        // the blocking request holds the only concurrency, so the others are queued
+        let mut blocker = svc.clone();
+        let blocked = tokio::spawn(async move { blocker.handle("BLOCK").await.is_ok() });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let tasks = (0..40)
+            .flat_map(|_| ["A", "B"])
+            .map(|input| {
+                let mut svc = svc.clone();
+                tokio::spawn(async move { svc.handle(input).await.is_ok() })
+            })
+            .collect::<Vec<_>>();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        gate.notify_one();
+        assert!(blocked.await.unwrap());
+        for t in tasks {
+            assert!(t.await.unwrap());
+        }
+
+        // both classes are saturated, so they are admitted 3:1
+        let order = order.lock();
+        let heavy = order[..40].iter().filter(|i| **i == "A").count();
+        assert_eq!((heavy, 40 - heavy), (30, 10));
+        assert_eq!(order.len(), 80);
+    }
+
+    #[test]
+    fn test_fair_queue_invalid_regex() {
+        let classes = vec![config::FairQueueClass {
+            regex: String::from("("),
+            weight: 1,
+            case_insensitive: false,
+        }];
+        let err = FairQueueLayer::new(1, classes).err().unwrap();
+        assert!(matches!(err, PluginError::InvalidFairQueueRegex { .. }));
+    }
+
+    #[test]
+    fn test_fair_queue_zero_concurrency() {
+        let err = FairQueueLayer::new(0, vec![]).err().unwrap();
+        assert!(matches!(err, PluginError::InvalidFairQueueConfig { .. }));
+    }
+}
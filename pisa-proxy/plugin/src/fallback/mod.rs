@@ -0,0 +1,184 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+
+use crate::{
+    err::{BoxError, PluginError},
+    layer::{AsyncService, Layer, Service},
+};
+
+/// Return true if the request is rejected by concurrency control
+pub fn is_rejected(err: &BoxError) -> bool {
+    matches!(
+        err.downcast_ref::<PluginError>(),
+        Some(PluginError::ConcurrencyControlPluginReject { .. })
+    )
+}
+
+/// `FallbackLayer` calls the fallback service `F` with the cloned input when the inner
+/// service fails with an error accepted by the predicate, eg: to serve a canned response
+/// or to route the request to a replica. The predicate is `is_rejected` by default.
+#[derive(Clone)]
+pub struct FallbackLayer<F> {
+    fallback: F,
+    predicate: fn(&BoxError) -> bool,
+}
+
+impl<F> FallbackLayer<F> {
+    pub fn new(fallback: F) -> FallbackLayer<F> {
+        FallbackLayer { fallback, predicate: is_rejected }
+    }
+
+    // Fall back on the errors accepted by `predicate` instead
+    pub fn with_predicate(mut self, predicate: fn(&BoxError) -> bool) -> Self {
+        self.predicate = predicate;
+        self
+    }
+}
+
+impl<S, F: Clone> Layer<S> for FallbackLayer<F> {
+    type Service = Fallback<S, F>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Fallback { inner, fallback: self.fallback.clone(), predicate: self.predicate }
+    }
+}
+
+#[derive(Clone)]
+pub struct Fallback<S, F> {
+    inner: S,
+    fallback: F,
+    predicate: fn(&BoxError) -> bool,
+}
+
+impl<S, F, Input> Service<Input> for Fallback<S, F>
+where
+    S: Service<Input>,
+    F: Service<Input, Output = S::Output>,
+    Input: Clone,
+    S::Error: Into<BoxError>,
+    F::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        match self.inner.handle(input.clone()).map_err(Into::into) {
+            Err(err) if (self.predicate)(&err) => self.fallback.handle(input).map_err(Into::into),
+            res => res,
+        }
+    }
+
+    // Ready if either service can handle the request
+    fn poll_ready(&mut self) -> bool {
+        self.inner.poll_ready() || self.fallback.poll_ready()
+    }
+}
+
+#[async_trait]
+impl<S, F, Input> AsyncService<Input> for Fallback<S, F>
+where
+    S: AsyncService<Input> + Send,
+    F: AsyncService<Input, Output = S::Output> + Send,
+    Input: Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    F::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        // the output is not held across the await of the fallback
+        match self.inner.handle(input.clone()).await.map_err(Into::into) {
+            Err(err) if (self.predicate)(&err) => {}
+            res => return res,
+        }
+        self.fallback.handle(input).await.map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        concurrency_control::{ConcurrencyControlGuard, ConcurrencyControlLayer},
+        config,
+        layer::{async_service_fn, service_fn, ServiceBuilder},
+    };
+
+    fn config() -> Vec<config::ConcurrencyControl> {
+        vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        }]
+    }
+
+    #[test]
+    fn test_fallback() {
+        let replica = service_fn(|_: &str| {
+            Ok::<_, BoxError>((ConcurrencyControlGuard::default(), String::from("replica")))
+        });
+        let mut svc = ServiceBuilder::new()
+            .with_layer(FallbackLayer::new(replica))
+            .with_layer(ConcurrencyControlLayer::new(config()).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let (_guard, out) = svc.handle("SELECT 1").unwrap();
+        assert_eq!(out, "SELECT 1");
+        // the primary is saturated
+        let (_, out) = svc.handle("SELECT 2").unwrap();
+        assert_eq!(out, "replica");
+    }
+
+    #[test]
+    fn test_fallback_predicate() {
+        let canned = service_fn(|_: &str| Ok::<_, PluginError>(String::from("canned")));
+        let mut svc = ServiceBuilder::new()
+            .with_layer(FallbackLayer::new(canned).with_predicate(|err| {
+                matches!(err.downcast_ref::<PluginError>(), Some(PluginError::CircuitOpen))
+            }))
+            .build(service_fn(|input: &str| match input {
+                "open" => Err(PluginError::CircuitOpen),
+                _ => Err(PluginError::Unknown),
+            }));
+
+        assert_eq!(svc.handle("open").unwrap(), "canned");
+        // the other errors are returned
+        let err = svc.handle("SELECT 1").unwrap_err();
+        assert_eq!(err.downcast_ref::<PluginError>(), Some(&PluginError::Unknown));
+    }
+
+    #[tokio::test]
+    async fn test_fallback_async() {
+        let replica = async_service_fn(|_: &'static str| async move {
+            Ok::<_, BoxError>((ConcurrencyControlGuard::default(), String::from("replica")))
+        });
+        let mut svc = ServiceBuilder::new()
+            .with_layer(FallbackLayer::new(replica))
+            .with_layer(ConcurrencyControlLayer::new(config()).unwrap())
+            .build(async_service_fn(|input: &'static str| async move {
+                Ok::<_, PluginError>(input.to_string())
+            }));
+
+        let (_guard, out) = svc.handle("SELECT 1").await.unwrap();
+        assert_eq!(out, "SELECT 1");
+        let (_, out) = svc.handle("SELECT 2").await.unwrap();
+        assert_eq!(out, "replica");
+    }
+}
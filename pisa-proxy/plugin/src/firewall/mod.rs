@@ -0,0 +1,187 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use regex::{Regex, RegexSet};
+
+use crate::{
+    config,
+    err::{BoxError, PluginError},
+    layer::{Bypass, Bypassable, Layer, Service},
+};
+
+/// `FirewallLayer` blocks the queries matching any rule, the inner service is not called.
+#[derive(Clone)]
+pub struct FirewallLayer {
+    config: Option<Vec<config::Firewall>>,
+    bypass: Bypass,
+}
+
+impl FirewallLayer {
+    pub fn new(config: Vec<config::Firewall>) -> Result<FirewallLayer, PluginError> {
+        Self::with_opt(Some(config))
+    }
+
+    pub fn with_opt(config: Option<Vec<config::Firewall>>) -> Result<FirewallLayer, PluginError> {
+        let layer = FirewallLayer { config, bypass: Bypass::default() };
+        layer.try_build_rules()?;
+        Ok(layer)
+    }
+
+    fn try_build_rules(&self) -> Result<Option<FirewallRules>, PluginError> {
+        if let Some(config) = &self.config {
+            let mut patterns = Vec::with_capacity(config.len());
+            for c in config {
+                // check each regex, so the error names the bad one
+                Regex::new(&c.regex).map_err(|e| PluginError::InvalidFirewallRegex {
+                    regex: c.regex.clone(),
+                    source: e,
+                })?;
+                if c.case_insensitive {
+                    patterns.push(format!("(?i){}", c.regex));
+                } else {
+                    patterns.push(c.regex.clone());
+                }
+            }
+
+            let set = RegexSet::new(patterns).expect("firewall regexes are validated");
+            let messages = config.iter().map(|c| c.message.clone()).collect();
+            return Ok(Some(FirewallRules { set, messages }));
+        }
+
+        Ok(None)
+    }
+}
+
+impl Bypassable for FirewallLayer {
+    fn with_bypass(self, bypass: Bypass) -> Self {
+        FirewallLayer { bypass, ..self }
+    }
+}
+
+impl<S> Layer<S> for FirewallLayer {
+    type Service = Firewall<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        let rules = self
+            .try_build_rules()
+            .expect("firewall config is validated in `FirewallLayer::with_opt`");
+        Firewall { inner, rules, bypass: self.bypass.clone() }
+    }
+}
+
+/// All rules are compiled into a `RegexSet`, so the query is matched in a single pass.
+#[derive(Debug, Clone)]
+struct FirewallRules {
+    set: RegexSet,
+    messages: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Firewall<S> {
+    inner: S,
+    rules: Option<FirewallRules>,
+    bypass: Bypass,
+}
+
+impl<S> Firewall<S> {
+    // Return the error of the first matched rule, return None if no rule is matched
+    fn check(&self, input: &str) -> Option<PluginError> {
+        let rules = self.rules.as_ref()?;
+        let rule = rules.set.matches(input).iter().next()?;
+        Some(PluginError::FirewallBlocked { rule, message: rules.messages[rule].clone() })
+    }
+}
+
+impl<S, Input> Service<Input> for Firewall<S>
+where
+    S: Service<Input>,
+    Input: AsRef<str>,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        if self.bypass.is_set() {
+            return self.inner.handle(input).map_err(Into::into);
+        }
+        if let Some(err) = self.check(input.as_ref()) {
+            return Err(Box::new(err));
+        }
+
+        self.inner.handle(input).map_err(Into::into)
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.inner.poll_ready()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::layer::{service_fn, ServiceBuilder};
+
+    fn config() -> Vec<config::Firewall> {
+        vec![
+            config::Firewall {
+                regex: String::from(r"^DELETE FROM \w+\s*$"),
+                message: String::from("delete without where"),
+                case_insensitive: true,
+            },
+            config::Firewall {
+                regex: String::from(r"^DROP TABLE"),
+                message: String::from("drop table is not allowed"),
+                case_insensitive: true,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_firewall() {
+        let mut svc = ServiceBuilder::new()
+            .with_layer(FirewallLayer::new(config()).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let err = svc.handle("drop table users").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PluginError>(),
+            Some(&PluginError::FirewallBlocked {
+                rule: 1,
+                message: String::from("drop table is not allowed")
+            })
+        );
+
+        assert!(svc.handle("DELETE FROM users").is_err());
+        assert_eq!(
+            svc.handle("DELETE FROM users WHERE id = 1").unwrap(),
+            "DELETE FROM users WHERE id = 1"
+        );
+        assert_eq!(svc.handle("SELECT * FROM users").unwrap(), "SELECT * FROM users");
+    }
+
+    #[test]
+    fn test_firewall_invalid_regex() {
+        let config = vec![config::Firewall {
+            regex: String::from(r"^DROP (TABLE"),
+            message: String::new(),
+            case_insensitive: false,
+        }];
+
+        match FirewallLayer::new(config).err().unwrap() {
+            PluginError::InvalidFirewallRegex { regex, .. } => assert_eq!(regex, r"^DROP (TABLE"),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use regex::Regex;
+
+use crate::{
+    err::PluginError,
+    layer::{AsyncService, Layer},
+};
+
+/// The delay injected before the matching requests
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delay {
+    Fixed(Duration),
+    // The delay is sampled uniformly between `min` and `max`
+    Uniform { min: Duration, max: Duration },
+}
+
+/// `LatencyInjectionLayer` sleeps before calling the inner service for the requests matching
+/// `regex`, so the timeouts and circuit breakers can be tested against a slow backend.
+/// It is only built with the `chaos` feature, and only `AsyncService` is supported.
+#[derive(Clone)]
+pub struct LatencyInjectionLayer {
+    regex: Regex,
+    delay: Delay,
+    // The delays sampled by all services built from the layer
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl LatencyInjectionLayer {
+    pub fn new(regex: &str, delay: Delay) -> Result<LatencyInjectionLayer, PluginError> {
+        let regex = Regex::new(regex).map_err(|e| PluginError::InvalidLatencyInjectionRegex {
+            regex: regex.to_string(),
+            source: e,
+        })?;
+        Ok(LatencyInjectionLayer {
+            regex,
+            delay,
+            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
+        })
+    }
+
+    /// Sample the delays from `seed`, so the injected delays are reproducible
+    pub fn with_seed(self, seed: u64) -> LatencyInjectionLayer {
+        LatencyInjectionLayer { rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))), ..self }
+    }
+}
+
+impl<S> Layer<S> for LatencyInjectionLayer {
+    type Service = LatencyInjection<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LatencyInjection {
+            inner,
+            regex: self.regex.clone(),
+            delay: self.delay,
+            rng: self.rng.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LatencyInjection<S> {
+    inner: S,
+    regex: Regex,
+    delay: Delay,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl<S> LatencyInjection<S> {
+    // Return the delay of `input`, return None if it does not match
+    fn delay(&self, input: &str) -> Option<Duration> {
+        if !self.regex.is_match(input) {
+            return None;
+        }
+        match self.delay {
+            Delay::Fixed(delay) => Some(delay),
+            Delay::Uniform { min, max } => {
+                Some(self.rng.lock().gen_range(min.min(max)..=min.max(max)))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S, Input> AsyncService<Input> for LatencyInjection<S>
+where
+    S: AsyncService<Input> + Send,
+    Input: AsRef<str> + Send + 'static,
+{
+    type Output = S::Output;
+    type Error = S::Error;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        if let Some(delay) = self.delay(input.as_ref()) {
+            tokio::time::sleep(delay).await;
+        }
+        self.inner.handle(input).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::layer::{async_service_fn, ServiceBuilder};
+
+    #[tokio::test]
+    async fn test_latency_injection() {
+        let layer =
+            LatencyInjectionLayer::new(r"^SELECT", Delay::Fixed(Duration::from_millis(100)))
+                .unwrap();
+        let mut svc = ServiceBuilder::new().with_layer(layer).build(async_service_fn(
+            |input: &'static str| async move { Ok::<_, PluginError>(input) },
+        ));
+
+        let start = Instant::now();
+        assert_eq!(svc.handle("SELECT 1").await.unwrap(), "SELECT 1");
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        let start = Instant::now();
+        assert_eq!(svc.handle("UPDATE t SET a = 1").await.unwrap(), "UPDATE t SET a = 1");
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_latency_injection_uniform() {
+        let (min, max) = (Duration::from_millis(10), Duration::from_millis(20));
+        let layer = LatencyInjectionLayer::new(r"^SELECT", Delay::Uniform { min, max }).unwrap();
+        let svc = layer.clone().with_seed(7).layer(());
+
+        let delays = (0..100).map(|_| svc.delay("SELECT 1").unwrap()).collect::<Vec<_>>();
+        assert!(delays.iter().all(|d| (min..=max).contains(d)));
+        assert!(delays.iter().any(|d| *d != delays[0]));
+        assert_eq!(svc.delay("INSERT"), None);
+
+        // the same seed samples the same delays
+        let again = layer.with_seed(7).layer(());
+        assert_eq!((0..100).map(|_| again.delay("SELECT 1").unwrap()).collect::<Vec<_>>(), delays);
+    }
+
+    #[test]
+    fn test_latency_injection_invalid_regex() {
+        let err = LatencyInjectionLayer::new("(", Delay::Fixed(Duration::ZERO)).err().unwrap();
+        assert!(matches!(err, PluginError::InvalidLatencyInjectionRegex { .. }));
+    }
+}
@@ -14,6 +14,18 @@
 
 // Thanks to <https://github.com/tower-rs/tower>
 
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use async_trait::async_trait;
+
+use crate::err::BoxError;
+
 /// Layer is a wrapper for service, which can have multiple different plugins
 /// `S` can be of any type
 pub trait Layer<S> {
@@ -33,6 +45,24 @@ pub trait Service<Input> {
     type Error;
 
     fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error>;
+
+    // Return false if the service can not handle a request now, so the caller can avoid
+    // constructing the request. No capacity is reserved, `handle` may still reject.
+    fn poll_ready(&mut self) -> bool {
+        true
+    }
+}
+
+/// `AsyncService` is the async version of `Service`, it is used when
+/// the service needs to wait, eg: waiting for a permit or doing I/O
+#[async_trait]
+pub trait AsyncService<Input> {
+    // the service output
+    type Output;
+    // the service error
+    type Error;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error>;
 }
 
 #[derive(Clone)]
@@ -67,21 +97,64 @@ impl<S> Layer<S> for Empty {
     }
 }
 
+/// The kill switch shared by the layers of a `ServiceBuilder`, when it is set
+/// the layers forward the requests to their inner services without evaluating them.
+#[derive(Debug, Clone, Default)]
+pub struct Bypass(Arc<AtomicBool>);
+
+impl Bypass {
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, bypass: bool) {
+        self.0.store(bypass, Ordering::Relaxed)
+    }
+}
+
+/// `Bypassable` is implemented by the layers which evaluate the requests, so they can
+/// be taken out of the path by `ServiceBuilder::set_bypass` during an incident.
+pub trait Bypassable {
+    // Share `bypass` with the services built from the layer
+    fn with_bypass(self, bypass: Bypass) -> Self;
+}
+
 #[derive(Clone)]
 pub struct ServiceBuilder<L> {
     pub layer: L,
+    bypass: Bypass,
 }
 
 impl ServiceBuilder<Empty> {
     pub fn new() -> Self {
-        ServiceBuilder { layer: Empty }
+        ServiceBuilder { layer: Empty, bypass: Bypass::default() }
     }
 }
 
 impl<L> ServiceBuilder<L> {
     // Add a wrapd service
     pub fn with_layer<T>(self, s: T) -> ServiceBuilder<LayerTrans<T, L>> {
-        ServiceBuilder { layer: LayerTrans { input: s, output: self.layer } }
+        ServiceBuilder { layer: LayerTrans { input: s, output: self.layer }, bypass: self.bypass }
+    }
+
+    // Add a layer which is bypassed together with the other bypassable layers of the builder
+    pub fn with_bypassable_layer<T>(self, s: T) -> ServiceBuilder<LayerTrans<T, L>>
+    where
+        T: Bypassable,
+    {
+        let s = s.with_bypass(self.bypass.clone());
+        self.with_layer(s)
+    }
+
+    // Bypass all the bypassable layers of the services built by the builder at once,
+    // including the services built before
+    pub fn set_bypass(&self, bypass: bool) {
+        self.bypass.set(bypass)
+    }
+
+    // Return the kill switch of the builder, eg: to wire a layer before boxing it
+    pub fn bypass(&self) -> Bypass {
+        self.bypass.clone()
     }
 
     // wrap the service by ServiceBuilder's layers, return a new service
@@ -92,6 +165,30 @@ impl<L> ServiceBuilder<L> {
         self.layer.layer(s)
     }
 
+    // Map the output of the service by `f`, eg: drop the guard of `ConcurrencyControl`
+    pub fn map_output<F>(self, f: F) -> ServiceBuilder<LayerTrans<MapOutputLayer<F>, L>> {
+        self.with_layer(MapOutputLayer::new(f))
+    }
+
+    // wrap the service by the layers and box it, the error is converted into `BoxError`
+    pub fn build_boxed<S, I>(&self, s: S) -> BoxService<I, <L::Service as Service<I>>::Output>
+    where
+        L: Layer<S>,
+        L::Service: Service<I> + Send + 'static,
+        <L::Service as Service<I>>::Error: Into<BoxError>,
+    {
+        BoxService::new(self.build(s))
+    }
+
+    // wrap the async service by the layers and box it
+    pub fn build_async<S, I>(&self, s: S) -> BoxLayeredAsyncService<L, S, I>
+    where
+        L: Layer<S>,
+        L::Service: AsyncService<I> + Send + 'static,
+    {
+        BoxAsyncService::new(self.build(s))
+    }
+
     // return inner service by `BoxCloneService::layer()`
     pub fn boxed_clone<S, I>(
         self,
@@ -117,6 +214,124 @@ impl<L> ServiceBuilder<L> {
     }
 }
 
+/// The named layers stored in a `Vec`, so the layers can be inspected, inserted
+/// or removed after construction. The first layer is the outermost one,
+/// the same as the layers added by `ServiceBuilder::with_layer`.
+pub struct NamedLayers<S> {
+    names: Vec<&'static str>,
+    layers: Vec<BoxLayer<S>>,
+}
+
+impl<S> Clone for NamedLayers<S> {
+    fn clone(&self) -> Self {
+        NamedLayers { names: self.names.clone(), layers: self.layers.clone() }
+    }
+}
+
+impl<S> Layer<S> for NamedLayers<S> {
+    type Service = S;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        self.layers.iter().rev().fold(inner, |s, l| l.layer(s))
+    }
+}
+
+impl<S> ServiceBuilder<NamedLayers<S>> {
+    pub fn named() -> Self {
+        ServiceBuilder {
+            layer: NamedLayers { names: vec![], layers: vec![] },
+            bypass: Bypass::default(),
+        }
+    }
+
+    // Add a named layer inside the existing layers
+    pub fn with_named_layer(mut self, name: &'static str, layer: BoxLayer<S>) -> Self {
+        self.layer.names.push(name);
+        self.layer.layers.push(layer);
+        self
+    }
+
+    // Insert a named layer outside the layer `name`, return false if `name` is not found
+    pub fn insert_before(
+        &mut self,
+        name: &str,
+        new_name: &'static str,
+        layer: BoxLayer<S>,
+    ) -> bool {
+        match self.layer.names.iter().position(|n| *n == name) {
+            Some(idx) => {
+                self.layer.names.insert(idx, new_name);
+                self.layer.layers.insert(idx, layer);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Remove the layer `name`, return None if `name` is not found
+    pub fn remove(&mut self, name: &str) -> Option<BoxLayer<S>> {
+        let idx = self.layer.names.iter().position(|n| *n == name)?;
+        self.layer.names.remove(idx);
+        Some(self.layer.layers.remove(idx))
+    }
+
+    // Return the names of the layers, from the outermost to the innermost
+    pub fn layers(&self) -> &[&'static str] {
+        &self.layer.names
+    }
+}
+
+/// A `Layer` wrapping `S` into `S`, so the layers can be stored together
+pub struct BoxLayer<S>(Arc<dyn Layer<S, Service = S> + Send + Sync>);
+
+impl<S> BoxLayer<S> {
+    pub fn new<L>(layer: L) -> Self
+    where
+        L: Layer<S, Service = S> + Send + Sync + 'static,
+    {
+        BoxLayer(Arc::new(layer))
+    }
+}
+
+impl<T, O, E> BoxLayer<BoxCloneService<T, O, E>> {
+    /// Wrap `layer` whose service is boxed into `BoxCloneService`
+    pub fn boxed_clone<L>(layer: L) -> Self
+    where
+        L: Layer<BoxCloneService<T, O, E>> + Send + Sync + 'static,
+        L::Service: Service<T, Output = O, Error = E> + Clone + Send + 'static,
+    {
+        BoxLayer::new(layer_fn(move |s| BoxCloneService::new(layer.layer(s))))
+    }
+}
+
+impl<T, O, E> BoxLayer<BoxAsyncService<T, O, E>>
+where
+    T: Send + 'static,
+{
+    /// Wrap `layer` whose service is boxed into `BoxAsyncService`
+    pub fn boxed_async<L>(layer: L) -> Self
+    where
+        L: Layer<BoxAsyncService<T, O, E>> + Send + Sync + 'static,
+        L::Service: AsyncService<T, Output = O, Error = E> + Send + 'static,
+    {
+        BoxLayer::new(layer_fn(move |s| BoxAsyncService::new(layer.layer(s))))
+    }
+}
+
+impl<S> Layer<S> for BoxLayer<S> {
+    type Service = S;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        self.0.layer(inner)
+    }
+}
+
+impl<S> Clone for BoxLayer<S> {
+    fn clone(&self) -> Self {
+        BoxLayer(self.0.clone())
+    }
+}
+
 /// A `Layer` implement by closure
 pub fn layer_fn<T>(f: T) -> LayerFn<T> {
     LayerFn { f }
@@ -138,6 +353,65 @@ where
     }
 }
 
+/// A `Layer` mapping the output of the inner service by `f`, the errors are unchanged
+#[derive(Clone)]
+pub struct MapOutputLayer<F> {
+    f: F,
+}
+
+impl<F> MapOutputLayer<F> {
+    pub fn new(f: F) -> Self {
+        MapOutputLayer { f }
+    }
+}
+
+impl<S, F: Clone> Layer<S> for MapOutputLayer<F> {
+    type Service = MapOutput<S, F>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MapOutput { inner, f: self.f.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct MapOutput<S, F> {
+    inner: S,
+    f: F,
+}
+
+impl<S, F, O, Input> Service<Input> for MapOutput<S, F>
+where
+    S: Service<Input>,
+    F: FnMut(S::Output) -> O,
+{
+    type Output = O;
+    type Error = S::Error;
+
+    fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        self.inner.handle(input).map(&mut self.f)
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.inner.poll_ready()
+    }
+}
+
+#[async_trait]
+impl<S, F, O, Input> AsyncService<Input> for MapOutput<S, F>
+where
+    S: AsyncService<Input> + Send,
+    F: FnMut(S::Output) -> O + Send,
+    Input: Send + 'static,
+{
+    type Output = O;
+    type Error = S::Error;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        let out = self.inner.handle(input).await?;
+        Ok((self.f)(out))
+    }
+}
+
 /// A `Service` implement by closure
 pub fn service_fn<T>(f: T) -> ServiceFn<T> {
     ServiceFn { f }
@@ -160,6 +434,31 @@ where
     }
 }
 
+/// A `AsyncService` implement by closure which returns a future
+pub fn async_service_fn<T>(f: T) -> AsyncServiceFn<T> {
+    AsyncServiceFn { f }
+}
+
+#[derive(Clone)]
+pub struct AsyncServiceFn<T> {
+    f: T,
+}
+
+#[async_trait]
+impl<T, F, R, E, Input> AsyncService<Input> for AsyncServiceFn<T>
+where
+    T: FnMut(Input) -> F + Send,
+    F: Future<Output = Result<R, E>> + Send,
+    Input: Send + 'static,
+{
+    type Output = R;
+    type Error = E;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        (self.f)(input).await
+    }
+}
+
 impl<'a, T, S> Layer<S> for &'a T
 where
     T: ?Sized + Layer<S>,
@@ -198,6 +497,10 @@ impl<T, O, E> Service<T> for BoxCloneService<T, O, E> {
     fn handle(&mut self, input: T) -> Result<O, E> {
         self.0.handle(input)
     }
+
+    fn poll_ready(&mut self) -> bool {
+        self.0.poll_ready()
+    }
 }
 
 impl<T, O, E> Clone for BoxCloneService<T, O, E> {
@@ -220,3 +523,83 @@ where
         Box::new(self.clone())
     }
 }
+
+/// A boxed `Service` whose error is `BoxError`, so the services of different types
+/// can be stored together, eg: the service stacks of each backend in a `HashMap`.
+pub struct BoxService<T, O>(Box<dyn Service<T, Output = O, Error = BoxError> + Send>);
+
+impl<T, O> BoxService<T, O> {
+    pub fn new<S>(inner: S) -> Self
+    where
+        S: Service<T, Output = O> + Send + 'static,
+        S::Error: Into<BoxError>,
+    {
+        BoxService(Box::new(ErrInto(inner)))
+    }
+}
+
+impl<T, O> Service<T> for BoxService<T, O> {
+    type Output = O;
+    type Error = BoxError;
+
+    fn handle(&mut self, input: T) -> Result<O, BoxError> {
+        self.0.handle(input)
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.0.poll_ready()
+    }
+}
+
+// Convert the error of the service into `BoxError`
+struct ErrInto<S>(S);
+
+impl<S, T> Service<T> for ErrInto<S>
+where
+    S: Service<T>,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    fn handle(&mut self, input: T) -> Result<Self::Output, BoxError> {
+        self.0.handle(input).map_err(Into::into)
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.0.poll_ready()
+    }
+}
+
+pub struct BoxAsyncService<T, O, E>(Box<dyn AsyncService<T, Output = O, Error = E> + Send>);
+
+/// The boxed async service built by `ServiceBuilder::build_async` from the layers `L`
+/// wrapping the service `S`
+pub type BoxLayeredAsyncService<L, S, I> = BoxAsyncService<
+    I,
+    <<L as Layer<S>>::Service as AsyncService<I>>::Output,
+    <<L as Layer<S>>::Service as AsyncService<I>>::Error,
+>;
+
+impl<T, O, E> BoxAsyncService<T, O, E> {
+    pub fn new<S>(inner: S) -> Self
+    where
+        S: AsyncService<T, Output = O, Error = E> + Send + 'static,
+    {
+        BoxAsyncService(Box::new(inner))
+    }
+}
+
+// implement `AsyncService` for `BoxAsyncService`
+#[async_trait]
+impl<T, O, E> AsyncService<T> for BoxAsyncService<T, O, E>
+where
+    T: Send + 'static,
+{
+    type Output = O;
+    type Error = E;
+
+    async fn handle(&mut self, input: T) -> Result<O, E> {
+        self.0.handle(input).await
+    }
+}
@@ -12,12 +12,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod buffer;
 pub mod build_phase;
+pub mod cache;
 pub mod circuit_break;
+pub mod circuit_breaker;
 pub mod concurrency_control;
+pub mod concurrency_limit;
 pub mod config;
+pub mod connection_limit;
+pub mod deadline;
 pub mod err;
+pub mod fair_queue;
+pub mod fallback;
+pub mod firewall;
+#[cfg(feature = "chaos")]
+pub mod latency_injection;
 pub mod layer;
+pub mod load_shed;
+pub mod metrics;
+pub mod mirror;
+pub mod retry;
+pub mod single_flight;
+pub mod sql;
+pub mod timeout;
+pub mod trace;
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,192 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    err::{BoxError, PluginError},
+    layer::{AsyncService, Bypass, Bypassable, Layer, Service},
+};
+
+/// `LoadShedLayer` rejects the requests without calling the inner service
+/// once `ceiling` requests are in flight, instead of queueing them.
+#[derive(Clone)]
+pub struct LoadShedLayer {
+    ceiling: usize,
+    bypass: Bypass,
+}
+
+impl LoadShedLayer {
+    pub fn new(ceiling: usize) -> LoadShedLayer {
+        LoadShedLayer { ceiling, bypass: Bypass::default() }
+    }
+}
+
+impl Bypassable for LoadShedLayer {
+    fn with_bypass(self, bypass: Bypass) -> Self {
+        LoadShedLayer { bypass, ..self }
+    }
+}
+
+impl<S> Layer<S> for LoadShedLayer {
+    type Service = LoadShed<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LoadShed {
+            inner,
+            ceiling: self.ceiling,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            bypass: self.bypass.clone(),
+        }
+    }
+}
+
+/// The clones of `LoadShed` share the count of in-flight requests.
+#[derive(Debug, Clone)]
+pub struct LoadShed<S> {
+    inner: S,
+    ceiling: usize,
+    in_flight: Arc<AtomicUsize>,
+    bypass: Bypass,
+}
+
+// Decrease the in-flight count when the request finishes, even on panic or cancellation
+struct InFlightGuard {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl<S> LoadShed<S> {
+    /// Return the count of in-flight requests
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    fn enter(&self) -> Result<InFlightGuard, PluginError> {
+        self.in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                if n < self.ceiling {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .map_err(|_| PluginError::Overloaded)?;
+
+        Ok(InFlightGuard { in_flight: self.in_flight.clone() })
+    }
+}
+
+impl<S, Input> Service<Input> for LoadShed<S>
+where
+    S: Service<Input>,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        if self.bypass.is_set() {
+            return self.inner.handle(input).map_err(Into::into);
+        }
+        let _guard = self.enter().map_err(BoxError::from)?;
+        self.inner.handle(input).map_err(Into::into)
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.inner.poll_ready()
+    }
+}
+
+#[async_trait]
+impl<S, Input> AsyncService<Input> for LoadShed<S>
+where
+    S: AsyncService<Input> + Send,
+    Input: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        if self.bypass.is_set() {
+            return self.inner.handle(input).await.map_err(Into::into);
+        }
+        let _guard = self.enter().map_err(BoxError::from)?;
+        self.inner.handle(input).await.map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{mpsc, Barrier},
+        thread,
+    };
+
+    use super::*;
+    use crate::layer::{service_fn, ServiceBuilder};
+
+    #[test]
+    fn test_load_shed() {
+        const CEILING: usize = 3;
+        const N: usize = 8;
+        // the admitted handlers and the test thread
+        let release = Arc::new(Barrier::new(CEILING + 1));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (inner_release, inner_peak) = (release.clone(), peak.clone());
+        let svc = ServiceBuilder::new().with_layer(LoadShedLayer::new(CEILING)).build(service_fn(
+            move |_: &str| {
+                inner_peak.fetch_add(1, Ordering::AcqRel);
+                inner_release.wait();
+                Ok::<_, PluginError>(())
+            },
+        ));
+
+        let (tx, rx) = mpsc::channel();
+        let tasks = (0..N)
+            .map(|_| {
+                let mut svc = svc.clone();
+                let tx = tx.clone();
+                thread::spawn(move || tx.send(svc.handle("SELECT 1").is_ok()))
+            })
+            .collect::<Vec<_>>();
+
+        // the admitted handlers are blocked, so all the others are rejected first
+        for _ in CEILING..N {
+            assert!(!rx.recv().unwrap());
+        }
+        assert_eq!(svc.in_flight(), CEILING);
+        assert_eq!(peak.load(Ordering::Acquire), CEILING);
+
+        release.wait();
+        for _ in 0..CEILING {
+            assert!(rx.recv().unwrap());
+        }
+        for t in tasks {
+            t.join().unwrap().unwrap();
+        }
+        assert_eq!(svc.in_flight(), 0);
+    }
+}
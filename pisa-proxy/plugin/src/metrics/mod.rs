@@ -0,0 +1,177 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Instant;
+
+use async_trait::async_trait;
+
+use crate::{
+    err::{BoxError, PluginError},
+    layer::{AsyncService, Layer, Service},
+};
+
+/// `MetricsLayer` records the requests of the inner service to the `metrics` facade,
+/// so they can be exported by any installed recorder. The rejections are labeled by
+/// the name of `PluginError`.
+#[derive(Clone, Default)]
+pub struct MetricsLayer;
+
+impl MetricsLayer {
+    pub fn new() -> MetricsLayer {
+        MetricsLayer
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = Metrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Metrics { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Metrics<S> {
+    inner: S,
+}
+
+fn record<T, E>(start: Instant, res: &Result<T, E>)
+where
+    E: AsRef<dyn std::error::Error + Send + Sync>,
+{
+    ::metrics::increment_counter!("pisa_requests_total");
+    ::metrics::histogram!("pisa_request_duration_seconds", start.elapsed());
+    // The errors of the inner service are not rejections
+    if let Some(err) = res.as_ref().err().and_then(|e| e.as_ref().downcast_ref::<PluginError>()) {
+        ::metrics::increment_counter!("pisa_requests_rejected_total", "reason" => err.name());
+    }
+}
+
+impl<S, Input> Service<Input> for Metrics<S>
+where
+    S: Service<Input>,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        let start = Instant::now();
+        let res = self.inner.handle(input).map_err(Into::into);
+        record(start, &res);
+        res
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.inner.poll_ready()
+    }
+}
+
+#[async_trait]
+impl<S, Input> AsyncService<Input> for Metrics<S>
+where
+    S: AsyncService<Input> + Send,
+    Input: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        let start = Instant::now();
+        let res = self.inner.handle(input).await.map_err(Into::into);
+        record(start, &res);
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use ::metrics::{SharedString, Unit};
+    use metrics_util::{
+        debugging::{DebugValue, DebuggingRecorder, Snapshotter},
+        CompositeKey, MetricKind,
+    };
+
+    use super::*;
+    use crate::{
+        concurrency_control::ConcurrencyControlLayer,
+        config,
+        layer::{service_fn, ServiceBuilder},
+    };
+
+    type Metric = (CompositeKey, Option<Unit>, Option<SharedString>, DebugValue);
+
+    fn value<'a>(
+        metrics: &'a [Metric],
+        kind: MetricKind,
+        name: &str,
+        labels: &[(&str, &str)],
+    ) -> Option<&'a DebugValue> {
+        metrics.iter().find_map(|(key, _, _, value)| {
+            let matched = key.kind() == kind
+                && key.key().name() == name
+                && key.key().labels().map(|l| (l.key(), l.value())).eq(labels.iter().copied());
+            matched.then_some(value)
+        })
+    }
+
+    #[test]
+    fn test_metrics() {
+        DebuggingRecorder::per_thread().install().unwrap();
+
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        }];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(MetricsLayer::new())
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| match input {
+                "ERROR" => Err(BoxError::from("failed")),
+                _ => Ok(input.to_string()),
+            }));
+
+        let _guard = svc.handle("SELECT 1").unwrap();
+        assert!(svc.handle("SELECT 1").is_err());
+        assert!(svc.handle("ERROR").is_err());
+
+        // the histograms are drained by the snapshot, so it is taken once
+        let metrics = Snapshotter::current_thread_snapshot().unwrap().into_vec();
+        let total = value(&metrics, MetricKind::Counter, "pisa_requests_total", &[]);
+        assert_eq!(total, Some(&DebugValue::Counter(3)));
+        let rejected = value(
+            &metrics,
+            MetricKind::Counter,
+            "pisa_requests_rejected_total",
+            &[(
+                "reason",
+                PluginError::ConcurrencyControlPluginReject {
+                    rule_index: 0,
+                    regex: vec![],
+                    retry_after: None,
+                    message: None,
+                }
+                .name(),
+            )],
+        );
+        assert_eq!(rejected, Some(&DebugValue::Counter(1)));
+        let duration = value(&metrics, MetricKind::Histogram, "pisa_request_duration_seconds", &[]);
+        assert!(matches!(duration, Some(DebugValue::Histogram(v)) if v.len() == 3));
+    }
+}
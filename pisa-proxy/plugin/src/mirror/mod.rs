@@ -0,0 +1,154 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use regex::Regex;
+
+use crate::{
+    err::PluginError,
+    layer::{AsyncService, Layer},
+};
+
+/// `MirrorLayer` sends a copy of `sample_rate` of the requests matching `regex` to the
+/// mirror service `M`, eg: a canary backend. The mirrored request runs in a spawned task,
+/// its result and errors are ignored, and the client is always served by the inner service.
+/// It must be used in a tokio runtime, and only `AsyncService` is supported.
+#[derive(Clone)]
+pub struct MirrorLayer<M> {
+    mirror: M,
+    regex: Regex,
+    sample_rate: f64,
+    // The samples drawn by all services built from the layer
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl<M> MirrorLayer<M> {
+    /// Panics if `sample_rate` is not in [0, 1].
+    pub fn new(mirror: M, regex: &str, sample_rate: f64) -> Result<MirrorLayer<M>, PluginError> {
+        assert!((0.0..=1.0).contains(&sample_rate), "mirror plugin sample rate must be in [0, 1]");
+        let regex = Regex::new(regex)
+            .map_err(|e| PluginError::InvalidMirrorRegex { regex: regex.to_string(), source: e })?;
+        Ok(MirrorLayer {
+            mirror,
+            regex,
+            sample_rate,
+            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
+        })
+    }
+
+    /// Draw the samples from `seed`, so the mirrored requests are reproducible
+    pub fn with_seed(self, seed: u64) -> MirrorLayer<M> {
+        MirrorLayer { rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))), ..self }
+    }
+}
+
+impl<S, M: Clone> Layer<S> for MirrorLayer<M> {
+    type Service = Mirror<S, M>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Mirror {
+            inner,
+            mirror: self.mirror.clone(),
+            regex: self.regex.clone(),
+            sample_rate: self.sample_rate,
+            rng: self.rng.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Mirror<S, M> {
+    inner: S,
+    mirror: M,
+    regex: Regex,
+    sample_rate: f64,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl<S, M> Mirror<S, M> {
+    // Whether `input` is mirrored, the unmatched requests do not draw a sample
+    fn sampled(&self, input: &str) -> bool {
+        self.regex.is_match(input) && self.rng.lock().gen_bool(self.sample_rate)
+    }
+}
+
+#[async_trait]
+impl<S, M, Input> AsyncService<Input> for Mirror<S, M>
+where
+    S: AsyncService<Input> + Send,
+    M: AsyncService<Input> + Clone + Send + 'static,
+    Input: AsRef<str> + Clone + Send + 'static,
+{
+    type Output = S::Output;
+    type Error = S::Error;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        if self.sampled(input.as_ref()) {
+            let mut mirror = self.mirror.clone();
+            let mirrored = input.clone();
+            tokio::spawn(async move {
+                let _ = mirror.handle(mirrored).await;
+            });
+        }
+        self.inner.handle(input).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::layer::{async_service_fn, ServiceBuilder};
+
+    #[tokio::test]
+    async fn test_mirror() {
+        let mirrored = Arc::new(AtomicUsize::new(0));
+        let count = mirrored.clone();
+        let mirror = async_service_fn(move |_: &'static str| {
+            let count = count.clone();
+            async move {
+                count.fetch_add(1, Ordering::SeqCst);
+                Err::<(), _>(PluginError::Unknown)
+            }
+        });
+        let layer = MirrorLayer::new(mirror, r"^SELECT", 0.2).unwrap().with_seed(7);
+        let mut svc = ServiceBuilder::new().with_layer(layer).build(async_service_fn(
+            |input: &'static str| async move { Ok::<_, PluginError>(input.len()) },
+        ));
+
+        // the errors of the mirror do not affect the results
+        for _ in 0..1000 {
+            assert_eq!(svc.handle("SELECT 1").await.unwrap(), 8);
+            assert_eq!(svc.handle("UPDATE t SET a = 1").await.unwrap(), 18);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mirrored = mirrored.load(Ordering::SeqCst);
+        assert!((150..=250).contains(&mirrored), "{}", mirrored);
+    }
+
+    #[test]
+    fn test_mirror_invalid_regex() {
+        let err = MirrorLayer::new((), "(", 0.5).err().unwrap();
+        assert!(matches!(err, PluginError::InvalidMirrorRegex { .. }));
+    }
+}
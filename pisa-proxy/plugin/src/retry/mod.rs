@@ -0,0 +1,194 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::{
+    err::BoxError,
+    layer::{AsyncService, Layer, Service},
+};
+
+#[derive(Clone)]
+pub struct RetryConfig {
+    // The max times to retry, the inner service is called at most `max_retries + 1` times
+    pub max_retries: usize,
+    // The time to wait before each retry
+    pub backoff: Duration,
+    // Return true if the error is transient and the request can be retried
+    pub retryable: fn(&BoxError) -> bool,
+}
+
+/// `RetryLayer` calls the inner service again with the cloned input on a retryable error,
+/// so it should only wrap the services handling idempotent queries.
+#[derive(Clone)]
+pub struct RetryLayer {
+    config: RetryConfig,
+}
+
+impl RetryLayer {
+    pub fn new(config: RetryConfig) -> RetryLayer {
+        RetryLayer { config }
+    }
+}
+
+impl<S> Layer<S> for RetryLayer {
+    type Service = Retry<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Retry { inner, config: self.config.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct Retry<S> {
+    inner: S,
+    config: RetryConfig,
+}
+
+impl<S> Retry<S> {
+    // Return true if the request should be retried after the `attempt`th retries
+    fn should_retry(&self, attempt: usize, err: &BoxError) -> bool {
+        attempt < self.config.max_retries && (self.config.retryable)(err)
+    }
+}
+
+impl<S, Input> Service<Input> for Retry<S>
+where
+    S: Service<Input>,
+    Input: Clone + AsRef<str>,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        let mut attempt = 0;
+        loop {
+            let err = match self.inner.handle(input.clone()).map_err(Into::into) {
+                Ok(out) => return Ok(out),
+                Err(e) => e,
+            };
+            if !self.should_retry(attempt, &err) {
+                return Err(err);
+            }
+
+            attempt += 1;
+            std::thread::sleep(self.config.backoff);
+        }
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.inner.poll_ready()
+    }
+}
+
+#[async_trait]
+impl<S, Input> AsyncService<Input> for Retry<S>
+where
+    S: AsyncService<Input> + Send,
+    Input: Clone + AsRef<str> + Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        let mut attempt = 0;
+        loop {
+            let err = match self.inner.handle(input.clone()).await.map_err(Into::into) {
+                Ok(out) => return Ok(out),
+                Err(e) => e,
+            };
+            if !self.should_retry(attempt, &err) {
+                return Err(err);
+            }
+
+            attempt += 1;
+            tokio::time::sleep(self.config.backoff).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::{
+        err::PluginError,
+        layer::{service_fn, ServiceBuilder},
+    };
+
+    fn is_unknown(err: &BoxError) -> bool {
+        err.downcast_ref::<PluginError>() == Some(&PluginError::Unknown)
+    }
+
+    fn config() -> RetryConfig {
+        RetryConfig { max_retries: 3, backoff: Duration::from_millis(10), retryable: is_unknown }
+    }
+
+    #[test]
+    fn test_retry() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let inner_calls = calls.clone();
+        let mut svc = ServiceBuilder::new().with_layer(RetryLayer::new(config())).build(
+            service_fn(move |input: &str| {
+                // fail twice then succeed
+                if inner_calls.fetch_add(1, Ordering::Relaxed) < 2 {
+                    return Err(PluginError::Unknown);
+                }
+                Ok(input.to_string())
+            }),
+        );
+
+        assert_eq!(svc.handle("SELECT 1").unwrap(), "SELECT 1");
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_retry_exhausted() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let inner_calls = calls.clone();
+        let mut svc = ServiceBuilder::new().with_layer(RetryLayer::new(config())).build(
+            service_fn(move |_: &str| {
+                inner_calls.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(PluginError::Unknown)
+            }),
+        );
+
+        assert!(is_unknown(&svc.handle("SELECT 1").unwrap_err()));
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn test_retry_not_retryable() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let inner_calls = calls.clone();
+        let mut svc = ServiceBuilder::new().with_layer(RetryLayer::new(config())).build(
+            service_fn(move |_: &str| {
+                inner_calls.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(PluginError::CircuitOpen)
+            }),
+        );
+
+        let err = svc.handle("SELECT 1").unwrap_err();
+        assert_eq!(err.downcast_ref::<PluginError>(), Some(&PluginError::CircuitOpen));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+}
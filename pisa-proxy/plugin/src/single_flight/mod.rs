@@ -0,0 +1,203 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{any::Any, collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use regex::Regex;
+use tokio::sync::broadcast;
+
+use crate::{
+    err::PluginError,
+    layer::{AsyncService, Layer},
+};
+
+/// `SingleFlightLayer` coalesces the concurrent identical queries matching `regex`, only the
+/// first one calls the inner service and the others wait for its output. The waiters call
+/// the inner service themselves if the first one fails or is cancelled, since the error
+/// can not be cloned. Only `AsyncService` is supported.
+#[derive(Clone)]
+pub struct SingleFlightLayer {
+    regex: Regex,
+}
+
+impl SingleFlightLayer {
+    pub fn new(regex: &str) -> Result<SingleFlightLayer, PluginError> {
+        let regex = Regex::new(regex).map_err(|e| PluginError::InvalidSingleFlightRegex {
+            regex: regex.to_string(),
+            source: e,
+        })?;
+        Ok(SingleFlightLayer { regex })
+    }
+}
+
+impl<S> Layer<S> for SingleFlightLayer {
+    type Service = SingleFlight<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SingleFlight { inner, regex: self.regex.clone(), flights: Arc::default() }
+    }
+}
+
+// The sender of the output of each query in flight,
+// the output type is only known by the `AsyncService` impl
+type Flights = Arc<Mutex<HashMap<String, Box<dyn Any + Send>>>>;
+
+/// The clones of `SingleFlight` share the queries in flight.
+#[derive(Clone)]
+pub struct SingleFlight<S> {
+    inner: S,
+    regex: Regex,
+    flights: Flights,
+}
+
+// The query in flight is removed once its output is sent or it is dropped
+struct Flight<O> {
+    query: String,
+    flights: Flights,
+    tx: Option<broadcast::Sender<O>>,
+}
+
+impl<O> Flight<O> {
+    fn finish(mut self, out: &O)
+    where
+        O: Clone,
+    {
+        // removed before sending, so every waiter has subscribed
+        self.flights.lock().remove(&self.query);
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(out.clone());
+        }
+    }
+}
+
+impl<O> Drop for Flight<O> {
+    fn drop(&mut self) {
+        if self.tx.is_some() {
+            self.flights.lock().remove(&self.query);
+        }
+    }
+}
+
+enum Role<O> {
+    Leader(Flight<O>),
+    Waiter(broadcast::Receiver<O>),
+}
+
+impl<S> SingleFlight<S> {
+    // Wait for the query in flight, or start a flight if there is none
+    fn join<O: Clone + Send + 'static>(&self, query: &str) -> Role<O> {
+        let mut flights = self.flights.lock();
+        if let Some(tx) = flights.get(query).and_then(|f| f.downcast_ref::<broadcast::Sender<O>>())
+        {
+            return Role::Waiter(tx.subscribe());
+        }
+        let (tx, _) = broadcast::channel(1);
+        flights.insert(query.to_string(), Box::new(tx.clone()));
+        Role::Leader(Flight {
+            query: query.to_string(),
+            flights: self.flights.clone(),
+            tx: Some(tx),
+        })
+    }
+
+    /// Return the number of the queries in flight
+    pub fn in_flight(&self) -> usize {
+        self.flights.lock().len()
+    }
+}
+
+#[async_trait]
+impl<S, Input> AsyncService<Input> for SingleFlight<S>
+where
+    S: AsyncService<Input> + Send,
+    S::Output: Clone + Send + 'static,
+    Input: AsRef<str> + Send + 'static,
+{
+    type Output = S::Output;
+    type Error = S::Error;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        if !self.regex.is_match(input.as_ref()) {
+            return self.inner.handle(input).await;
+        }
+        match self.join(input.as_ref()) {
+            Role::Leader(flight) => {
+                let out = self.inner.handle(input).await?;
+                flight.finish(&out);
+                Ok(out)
+            }
+            Role::Waiter(mut rx) => match rx.recv().await {
+                Ok(out) => Ok(out),
+                // the first query has failed or been cancelled
+                Err(_) => self.inner.handle(input).await,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::layer::{async_service_fn, ServiceBuilder};
+
+    #[tokio::test]
+    async fn test_single_flight() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let count = calls.clone();
+        let svc = ServiceBuilder::new()
+            .with_layer(SingleFlightLayer::new(r"^SELECT").unwrap())
+            .build(async_service_fn(move |input: &'static str| {
+                let calls = count.fetch_add(1, Ordering::SeqCst) + 1;
+                async move {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Ok::<_, PluginError>((input, calls))
+                }
+            }));
+
+        let tasks = (0..10)
+            .map(|_| {
+                let mut svc = svc.clone();
+                tokio::spawn(async move { svc.handle("SELECT * FROM t").await.unwrap() })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), ("SELECT * FROM t", 1));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(svc.in_flight(), 0);
+
+        // the query is called again once the flight has finished
+        let mut svc = svc.clone();
+        assert_eq!(svc.handle("SELECT * FROM t").await.unwrap(), ("SELECT * FROM t", 2));
+        // the queries not matching are not coalesced
+        let (mut a, mut b) = (svc.clone(), svc.clone());
+        let (a, b) = tokio::join!(a.handle("UPDATE t SET a = 1"), b.handle("UPDATE t SET a = 1"));
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_ne!(a.1, b.1);
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_single_flight_invalid_regex() {
+        let err = SingleFlightLayer::new("(").err().unwrap();
+        assert!(matches!(err, PluginError::InvalidSingleFlightRegex { .. }));
+    }
+}
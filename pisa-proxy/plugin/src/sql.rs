@@ -0,0 +1,440 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::{Deserialize, Serialize};
+
+/// The kind of a SQL statement, decided by its leading keyword
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatementKind {
+    Select,
+    Insert,
+    Update,
+    Delete,
+    Replace,
+    Other,
+}
+
+impl StatementKind {
+    fn from_keyword(word: &str) -> StatementKind {
+        const KEYWORDS: [(&str, StatementKind); 5] = [
+            ("SELECT", StatementKind::Select),
+            ("INSERT", StatementKind::Insert),
+            ("UPDATE", StatementKind::Update),
+            ("DELETE", StatementKind::Delete),
+            ("REPLACE", StatementKind::Replace),
+        ];
+
+        KEYWORDS
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(word))
+            .map_or(StatementKind::Other, |(_, kind)| *kind)
+    }
+}
+
+/// Classify `query` by its leading keyword, the comments, whitespaces and parentheses
+/// before it are skipped. For `WITH`, the statement after the common table expressions
+/// decides the kind, eg: `WITH t AS (...) SELECT ...` is `Select`.
+pub fn classify(query: &str) -> StatementKind {
+    let mut words = Words { query: query.as_bytes(), pos: 0, depth: 0 };
+    let first = match words.next() {
+        Some((_, word)) => word,
+        None => return StatementKind::Other,
+    };
+
+    if !first.eq_ignore_ascii_case("WITH") {
+        return StatementKind::from_keyword(first);
+    }
+
+    // The bodies of the common table expressions are in parentheses
+    words
+        .filter(|(depth, _)| *depth == 0)
+        .map(|(_, word)| StatementKind::from_keyword(word))
+        .find(|kind| *kind != StatementKind::Other)
+        .unwrap_or(StatementKind::Other)
+}
+
+/// Return the rows of the `LIMIT` clause of `query`, eg: 10 of `LIMIT 10`, `LIMIT 5, 10`
+/// and `LIMIT 10 OFFSET 5`. The clauses in parentheses, eg: of the subqueries, are skipped,
+/// the last clause is used. Return None if there is no clause or the rows are not a number,
+/// eg: a placeholder.
+pub fn extract_limit(query: &str) -> Option<u64> {
+    let mut words = Words { query: query.as_bytes(), pos: 0, depth: 0 }.peekable();
+    let number = |word: Option<(usize, &str)>| match word {
+        Some((0, word)) => word.parse::<u64>().ok(),
+        _ => None,
+    };
+    let mut rows = None;
+    while let Some((depth, word)) = words.next() {
+        if depth != 0 || !word.eq_ignore_ascii_case("LIMIT") {
+            continue;
+        }
+        // the comma is skipped, so the second number is the rows of `LIMIT offset, rows`
+        let first = number(words.next());
+        rows = match (first, number(words.peek().copied())) {
+            (Some(_), Some(second)) => {
+                words.next();
+                Some(second)
+            }
+            _ => first,
+        };
+    }
+    rows
+}
+
+/// Split the multi-statement `query` on the semicolons, the semicolons in the quoted strings,
+/// the quoted identifiers and the comments are skipped. The statements are trimmed and the
+/// empty ones are dropped, eg: `SELECT ';'; SELECT 2;` is `SELECT ';'` and `SELECT 2`.
+pub fn split_statements(query: &str) -> Vec<&str> {
+    let bytes = query.as_bytes();
+    let mut statements = vec![];
+    let (mut start, mut pos) = (0, 0);
+    while pos < bytes.len() {
+        let rest = &bytes[pos..];
+        pos += match rest[0] {
+            b'/' if rest.starts_with(b"/*") => 2 + len_past(&rest[2..], b"*/"),
+            b'-' if rest.starts_with(b"--") => len_past(rest, b"\n"),
+            b'#' => len_past(rest, b"\n"),
+            b'\'' | b'"' | b'`' => quoted_len(rest),
+            b';' => {
+                // the semicolon is ascii, so the statements are on char boundaries
+                statements.push(&query[start..pos]);
+                start = pos + 1;
+                1
+            }
+            _ => 1,
+        };
+    }
+    statements.push(&query[start..]);
+    statements.into_iter().map(str::trim).filter(|s| !s.is_empty()).collect()
+}
+
+/// Normalize `query` into its digest, the numeric, hex and string literals are replaced
+/// with `?`, the comments are removed and the whitespaces are collapsed into one space,
+/// eg: `SELECT * FROM t WHERE id=1` and `... id=2` are both `SELECT * FROM t WHERE id=?`.
+/// The identifiers, including the quoted ones, are kept.
+pub fn normalize(query: &str) -> String {
+    let bytes = query.as_bytes();
+    let mut digest = String::with_capacity(query.len());
+    let mut pos = 0;
+    // the skipped whitespaces and comments are written as a space before the next token
+    let mut space = false;
+    while pos < bytes.len() {
+        let rest = &bytes[pos..];
+        let (len, token) = match rest[0] {
+            c if c.is_ascii_whitespace() => (1, Token::Skipped),
+            b'/' if rest.starts_with(b"/*") => (2 + len_past(&rest[2..], b"*/"), Token::Skipped),
+            b'-' if rest.starts_with(b"--") => (len_past(rest, b"\n"), Token::Skipped),
+            b'#' => (len_past(rest, b"\n"), Token::Skipped),
+            b'\'' | b'"' => (quoted_len(rest), Token::Literal),
+            b'`' => (quoted_len(rest), Token::Kept),
+            // the hex and bit strings, eg: X'1F', b'101'
+            b'x' | b'X' | b'b' | b'B' if rest.get(1) == Some(&b'\'') => {
+                (1 + quoted_len(&rest[1..]), Token::Literal)
+            }
+            b'0'..=b'9' => (number_len(rest), Token::Literal),
+            b'.' if rest.get(1).is_some_and(u8::is_ascii_digit) => {
+                (number_len(rest), Token::Literal)
+            }
+            c if is_word_byte(c) => {
+                (rest.iter().position(|c| !is_word_byte(*c)).unwrap_or(rest.len()), Token::Kept)
+            }
+            _ => (1, Token::Kept),
+        };
+
+        if token == Token::Skipped {
+            space = true;
+        } else {
+            if std::mem::take(&mut space) && !digest.is_empty() {
+                digest.push(' ');
+            }
+            match token {
+                Token::Literal => digest.push('?'),
+                // the tokens end before an ascii byte, so they are on char boundaries
+                _ => digest.push_str(&query[pos..pos + len]),
+            }
+        }
+        pos += len;
+    }
+
+    digest
+}
+
+// How a token is written into the digest
+#[derive(PartialEq)]
+enum Token {
+    // The whitespaces and comments
+    Skipped,
+    // Replaced with `?`
+    Literal,
+    Kept,
+}
+
+// Whether `c` is a byte of a word, the non-ascii bytes are kept in words
+fn is_word_byte(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'$' || !c.is_ascii()
+}
+
+// Return the length of `query` up to and including the first `end`
+fn len_past(query: &[u8], end: &[u8]) -> usize {
+    match query.windows(end.len()).position(|w| w == end) {
+        Some(i) => i + end.len(),
+        None => query.len(),
+    }
+}
+
+// Return the length of the quoted string at the start of `query`, including the quotes.
+// The quote is escaped by doubling it, or by a backslash in the string literals.
+fn quoted_len(query: &[u8]) -> usize {
+    let quote = query[0];
+    let mut i = 1;
+    while i < query.len() {
+        match query[i] {
+            b'\\' if quote != b'`' => i += 2,
+            c if c == quote && query.get(i + 1) == Some(&quote) => i += 2,
+            c if c == quote => return i + 1,
+            _ => i += 1,
+        }
+    }
+    query.len()
+}
+
+// Return the length of the number at the start of `query`,
+// eg: `1`, `1.5`, `.5`, `1e-3`, `0x1F`, `0b101`
+fn number_len(query: &[u8]) -> usize {
+    let digits = |from: usize, f: fn(&u8) -> bool| {
+        from + query[from..].iter().position(|c| !f(c)).unwrap_or(query.len() - from)
+    };
+    if query.len() > 2 && query[0] == b'0' {
+        match query[1] {
+            b'x' | b'X' if query[2].is_ascii_hexdigit() => return digits(2, u8::is_ascii_hexdigit),
+            b'b' | b'B' if matches!(query[2], b'0' | b'1') => {
+                return digits(2, |c| matches!(c, b'0' | b'1'))
+            }
+            _ => {}
+        }
+    }
+
+    let mut len = digits(0, u8::is_ascii_digit);
+    if query.get(len) == Some(&b'.') {
+        len = digits(len + 1, u8::is_ascii_digit);
+    }
+    if matches!(query.get(len), Some(b'e' | b'E')) {
+        let sign = matches!(query.get(len + 1), Some(b'+' | b'-')) as usize;
+        if query.get(len + 1 + sign).is_some_and(u8::is_ascii_digit) {
+            len = digits(len + 1 + sign, u8::is_ascii_digit);
+        }
+    }
+    len
+}
+
+// The words of a query with their parenthesis depth, comments and quoted strings are skipped
+struct Words<'a> {
+    query: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Words<'a> {
+    // Skip to the byte after the first `end` from `pos`
+    fn skip_past(&mut self, end: &[u8]) {
+        match self.query[self.pos..].windows(end.len()).position(|w| w == end) {
+            Some(i) => self.pos += i + end.len(),
+            None => self.pos = self.query.len(),
+        }
+    }
+}
+
+impl<'a> Iterator for Words<'a> {
+    type Item = (usize, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pos < self.query.len() {
+            let rest = &self.query[self.pos..];
+            match rest[0] {
+                b'/' if rest.starts_with(b"/*") => {
+                    self.pos += 2;
+                    self.skip_past(b"*/");
+                }
+                b'-' if rest.starts_with(b"--") => self.skip_past(b"\n"),
+                b'#' => self.skip_past(b"\n"),
+                quote @ (b'\'' | b'"' | b'`') => {
+                    self.pos += 1;
+                    self.skip_past(&[quote]);
+                }
+                b'(' => {
+                    self.depth += 1;
+                    self.pos += 1;
+                }
+                b')' => {
+                    self.depth = self.depth.saturating_sub(1);
+                    self.pos += 1;
+                }
+                c if c.is_ascii_alphanumeric() || c == b'_' => {
+                    let len = rest
+                        .iter()
+                        .position(|c| !(c.is_ascii_alphanumeric() || *c == b'_' || *c == b'$'))
+                        .unwrap_or(rest.len());
+                    self.pos += len;
+                    // the word only contains ascii bytes
+                    let word = std::str::from_utf8(&rest[..len]).unwrap_or_default();
+                    return Some((self.depth, word));
+                }
+                _ => self.pos += 1,
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let cases = [
+            ("SELECT * FROM t", StatementKind::Select),
+            ("INSERT INTO t VALUES (1)", StatementKind::Insert),
+            ("UPDATE t SET a = 1", StatementKind::Update),
+            ("DELETE FROM t WHERE id = 1", StatementKind::Delete),
+            ("REPLACE INTO t VALUES (1)", StatementKind::Replace),
+            ("SET autocommit = 1", StatementKind::Other),
+            ("", StatementKind::Other),
+            ("  \n\tselect 1", StatementKind::Select),
+            ("uPdAtE t SET a = 1", StatementKind::Update),
+            ("(SELECT 1) UNION (SELECT 2)", StatementKind::Select),
+        ];
+        for (query, kind) in cases {
+            assert_eq!(classify(query), kind, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_classify_comments() {
+        let cases = [
+            ("/* SELECT */ DELETE FROM t", StatementKind::Delete),
+            ("/* multi\n line */ /*+ hint */INSERT INTO t VALUES (1)", StatementKind::Insert),
+            ("-- SELECT\nUPDATE t SET a = 1", StatementKind::Update),
+            ("# SELECT\nDELETE FROM t", StatementKind::Delete),
+            ("/* unterminated SELECT", StatementKind::Other),
+        ];
+        for (query, kind) in cases {
+            assert_eq!(classify(query), kind, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_classify_cte() {
+        let cases = [
+            ("WITH t AS (SELECT 1) SELECT * FROM t", StatementKind::Select),
+            (
+                "with recursive t (n) as (select 1 union all select n + 1 from t) select * from t",
+                StatementKind::Select,
+            ),
+            ("WITH a AS (SELECT 1), b AS (SELECT ')') SELECT * FROM a, b", StatementKind::Select),
+            (
+                "WITH t AS (SELECT id FROM u) DELETE FROM v WHERE id IN (SELECT id FROM t)",
+                StatementKind::Delete,
+            ),
+            ("WITH t AS (SELECT 1)", StatementKind::Other),
+        ];
+        for (query, kind) in cases {
+            assert_eq!(classify(query), kind, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_extract_limit() {
+        let cases = [
+            ("SELECT * FROM t LIMIT 10", Some(10)),
+            ("select * from t limit 5, 10", Some(10)),
+            ("SELECT * FROM t LIMIT 10 OFFSET 5", Some(10)),
+            ("SELECT * FROM t LIMIT 18446744073709551615", Some(u64::MAX)),
+            ("SELECT * FROM t", None),
+            ("SELECT * FROM t LIMIT ?", None),
+            ("SELECT * FROM (SELECT * FROM t LIMIT 10) s", None),
+            ("SELECT * FROM (SELECT * FROM t LIMIT 10) s LIMIT 20", Some(20)),
+            ("SELECT 'LIMIT 10' FROM t /* LIMIT 10 */", None),
+        ];
+        for (query, rows) in cases {
+            assert_eq!(extract_limit(query), rows, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        let cases = [
+            ("SELECT * FROM t WHERE id=1", "SELECT * FROM t WHERE id=?"),
+            ("SELECT * FROM t WHERE id=2", "SELECT * FROM t WHERE id=?"),
+            ("  SELECT\n\t*   FROM t1  ", "SELECT * FROM t1"),
+            ("INSERT INTO t VALUES (1, 'a', \"b\")", "INSERT INTO t VALUES (?, ?, ?)"),
+            ("SELECT `id 1`, `a``b` FROM `t`", "SELECT `id 1`, `a``b` FROM `t`"),
+            ("SELECT /* hint */ 1 -- comment\nFROM dual", "SELECT ? FROM dual"),
+            (
+                "SELECT a$b, c_1, 名字 FROM t WHERE x='名字'",
+                "SELECT a$b, c_1, 名字 FROM t WHERE x=?",
+            ),
+            ("", ""),
+        ];
+        for (query, digest) in cases {
+            assert_eq!(normalize(query), digest, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_normalize_strings() {
+        let cases = [
+            ("SELECT 'a, b', 'c'", "SELECT ?, ?"),
+            ("SELECT 'it\\'s', 1", "SELECT ?, ?"),
+            ("SELECT 'it''s', 1", "SELECT ?, ?"),
+            ("SELECT \"say \\\"hi\\\"\", 1", "SELECT ?, ?"),
+            ("SELECT 'a\\\\', 'b'", "SELECT ?, ?"),
+            ("SELECT 'unterminated, 1", "SELECT ?"),
+        ];
+        for (query, digest) in cases {
+            assert_eq!(normalize(query), digest, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_split_statements() {
+        let cases: [(&str, &[&str]); 5] = [
+            ("SELECT 1", &["SELECT 1"]),
+            ("SELECT 1; UPDATE t SET a = 1;", &["SELECT 1", "UPDATE t SET a = 1"]),
+            ("SELECT ';'; SELECT \"a;b\", `c;d`", &["SELECT ';'", "SELECT \"a;b\", `c;d`"]),
+            ("SELECT 'it\\'s;'; SELECT 2 -- x;y\n", &["SELECT 'it\\'s;'", "SELECT 2 -- x;y"]),
+            ("SELECT 1 /* ; */;; ", &["SELECT 1 /* ; */"]),
+        ];
+        for (query, statements) in cases {
+            assert_eq!(split_statements(query), statements, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_normalize_numbers() {
+        let cases = [
+            ("SELECT 0x1F, 0XaB", "SELECT ?, ?"),
+            ("SELECT X'1F', x'ab', b'101', 0b101", "SELECT ?, ?, ?, ?"),
+            ("SELECT 1.5, .5, 1., 10", "SELECT ?, ?, ?, ?"),
+            ("SELECT 1e10, 1.5E-3, 2e+5", "SELECT ?, ?, ?"),
+            ("SELECT a FROM t WHERE b>-1.5", "SELECT a FROM t WHERE b>-?"),
+            ("SELECT t.a FROM t1 t WHERE t.b=1", "SELECT t.a FROM t1 t WHERE t.b=?"),
+        ];
+        for (query, digest) in cases {
+            assert_eq!(normalize(query), digest, "{}", query);
+        }
+    }
+}
@@ -12,14 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{io::Error, time::Duration};
+use std::{collections::HashMap, io::Error, time::Duration};
 
 use crate::{
+    build_phase::PluginPhase,
     circuit_break::CircuitBreakLayer,
-    concurrency_control::ConcurrencyControlLayer,
+    concurrency_control::{ConcurrencyControlGuard, ConcurrencyControlLayer},
+    concurrency_limit::ConcurrencyLimitLayer,
     config,
-    err::PluginError,
-    layer::{service_fn, Service, ServiceBuilder},
+    err::{BoxError, PluginError},
+    layer::{
+        async_service_fn, service_fn, AsyncService, BoxAsyncService, BoxLayer, BoxService, Service,
+        ServiceBuilder,
+    },
+    timeout::TimeoutLayer,
 };
 
 fn test_service(input: &str) -> Result<String, Error> {
@@ -30,22 +36,201 @@ fn test_service(input: &str) -> Result<String, Error> {
 fn test_chain_concurrency_control_and_circuit_break() {
     let concurrency_control_config = vec![config::ConcurrencyControl {
         regex: vec![String::from(r"[A-Za-z]+$")],
-        max_concurrency: 0,
+        max_concurrency: 1,
         duration: Duration::new(5, 0),
+        algorithm: config::ConcurrencyControlAlgorithm::SlidingWindow,
+        ..Default::default()
     }];
 
-    let circuit_break_config =
-        vec![config::CircuitBreak { regex: vec![String::from(r"[A-Za-z]+")], case_insensitive: false }];
+    let circuit_break_config = vec![config::CircuitBreak {
+        regex: vec![String::from(r"[A-Za-z]+")],
+        case_insensitive: false,
+    }];
 
     let mut wrap_svc = ServiceBuilder::new()
-        .with_layer(ConcurrencyControlLayer::new(concurrency_control_config))
+        .with_layer(ConcurrencyControlLayer::new(concurrency_control_config).unwrap())
         .with_layer(CircuitBreakLayer::new(circuit_break_config))
         .build(service_fn(test_service));
 
+    // the only admission in the window is rejected by circuit break
+    let _ = wrap_svc.handle("abc");
     let res = wrap_svc.handle("abc");
     println!("{:?}", res);
     if let Err(e) = res {
         let e = e.downcast::<PluginError>().unwrap();
-        assert_eq!(*e, PluginError::ConcurrencyControlPluginReject)
+        assert!(matches!(
+            *e,
+            PluginError::ConcurrencyControlPluginReject {
+                rule_index: 0,
+                ref regex,
+                retry_after: Some(_),
+                message: None,
+            }
+                if *regex == [r"[A-Za-z]+$"]
+        ))
     }
 }
+
+#[test]
+fn test_bypass() {
+    let circuit_break_config = vec![config::CircuitBreak {
+        regex: vec![String::from(r"^DELETE")],
+        case_insensitive: false,
+    }];
+    let builder = ServiceBuilder::new()
+        .with_bypassable_layer(ConcurrencyLimitLayer::new(0))
+        .with_bypassable_layer(CircuitBreakLayer::new(circuit_break_config));
+    let mut svc = builder.build(service_fn(test_service));
+
+    let err = svc.handle("DELETE FROM t").unwrap_err();
+    assert_eq!(err.downcast_ref::<PluginError>(), Some(&PluginError::ConcurrencyLimitReached));
+    assert!(!svc.poll_ready());
+
+    // the services built before are bypassed too
+    builder.set_bypass(true);
+    assert_eq!(svc.handle("DELETE FROM t").unwrap(), "DELETE FROM t");
+    assert!(svc.poll_ready());
+
+    builder.set_bypass(false);
+    assert!(svc.handle("DELETE FROM t").is_err());
+}
+
+#[tokio::test]
+async fn test_named_layers() {
+    let mut builder = ServiceBuilder::named().with_named_layer(
+        "timeout",
+        BoxLayer::boxed_async(TimeoutLayer::new(Duration::from_millis(50))),
+    );
+    assert!(builder.insert_before(
+        "timeout",
+        "concurrency_limit",
+        BoxLayer::boxed_async(ConcurrencyLimitLayer::new(0))
+    ));
+    assert!(!builder.insert_before(
+        "unknown",
+        "timeout",
+        BoxLayer::boxed_async(TimeoutLayer::new(Duration::ZERO))
+    ));
+    assert_eq!(builder.layers(), ["concurrency_limit", "timeout"]);
+
+    let inner = || {
+        BoxAsyncService::new(async_service_fn(|input: &'static str| async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            Ok::<_, BoxError>(input.to_string())
+        }))
+    };
+
+    // the concurrency limit rejects before the slow service is called
+    let mut svc = builder.build(inner());
+    let err = svc.handle("SELECT 1").await.unwrap_err();
+    assert_eq!(err.downcast_ref::<PluginError>(), Some(&PluginError::ConcurrencyLimitReached));
+
+    assert!(builder.remove("concurrency_limit").is_some());
+    assert!(builder.remove("concurrency_limit").is_none());
+    assert_eq!(builder.layers(), ["timeout"]);
+
+    let mut svc = builder.build(inner());
+    let err = svc.handle("SELECT 1").await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<PluginError>(),
+        Some(&PluginError::Timeout { elapsed: Duration::from_millis(50) })
+    );
+}
+
+#[tokio::test]
+async fn test_map_output() {
+    let config = vec![config::ConcurrencyControl {
+        regex: vec![String::from(r"^SELECT")],
+        max_concurrency: 1,
+        duration: Duration::new(5, 0),
+        ..Default::default()
+    }];
+    let layer = ConcurrencyControlLayer::new(config).unwrap();
+    let strip = |(_, out): (ConcurrencyControlGuard, String)| out;
+
+    // the guard is dropped when the inner service returns, so the permit is released
+    let mut svc =
+        ServiceBuilder::new().map_output(strip).with_layer(&layer).build(service_fn(test_service));
+    let out: String = svc.handle("SELECT 1").unwrap();
+    assert_eq!(out, "SELECT 1");
+    assert_eq!(svc.handle("SELECT 2").unwrap(), "SELECT 2");
+
+    let mut svc = ServiceBuilder::new().map_output(strip).with_layer(&layer).build(
+        async_service_fn(|input: &'static str| async move { Ok::<_, Error>(input.to_string()) }),
+    );
+    assert_eq!(svc.handle("SELECT 3").await.unwrap(), "SELECT 3");
+}
+
+#[test]
+fn test_build_boxed() {
+    let concurrency_control_config = vec![config::ConcurrencyControl {
+        regex: vec![String::from(r"^SELECT")],
+        max_concurrency: 1,
+        duration: Duration::new(5, 0),
+        ..Default::default()
+    }];
+    let circuit_break_config = vec![config::CircuitBreak {
+        regex: vec![String::from(r"^DELETE")],
+        case_insensitive: false,
+    }];
+
+    let mut backends: HashMap<String, BoxService<&str, String>> = HashMap::new();
+    backends.insert(
+        String::from("primary"),
+        ServiceBuilder::new()
+            .map_output(|(_, out): (ConcurrencyControlGuard, String)| out)
+            .with_layer(ConcurrencyControlLayer::new(concurrency_control_config).unwrap())
+            .build_boxed(service_fn(test_service)),
+    );
+    backends.insert(
+        String::from("replica"),
+        ServiceBuilder::new()
+            .with_layer(CircuitBreakLayer::new(circuit_break_config))
+            .build_boxed(service_fn(test_service)),
+    );
+
+    let mut dispatch = |backend: &str, query| backends.get_mut(backend).unwrap().handle(query);
+    assert_eq!(dispatch("primary", "DELETE FROM t").unwrap(), "DELETE FROM t");
+    assert!(dispatch("replica", "DELETE FROM t").is_err());
+    assert_eq!(dispatch("replica", "SELECT 1").unwrap(), "SELECT 1");
+}
+
+#[tokio::test]
+async fn test_build_async() {
+    let mut svc = ServiceBuilder::new()
+        .with_layer(TimeoutLayer::new(Duration::from_millis(50)))
+        .build_async(async_service_fn(|input: &'static str| async move {
+            Ok::<_, BoxError>(input.to_string())
+        }));
+    assert_eq!(svc.handle("SELECT 1").await.unwrap(), "SELECT 1");
+}
+
+#[test]
+fn test_plugin_phase_bypass() {
+    let config = config::Plugin {
+        concurrency_control: Some(vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        }]),
+        circuit_break: Some(vec![config::CircuitBreak {
+            regex: vec![String::from(r"^DELETE")],
+            case_insensitive: false,
+        }]),
+        firewall: None,
+    };
+    let mut phase = PluginPhase::new(config).unwrap();
+    let _held = phase.concurrency_control.handle(String::from("SELECT 1")).unwrap();
+    assert!(phase.concurrency_control.handle(String::from("SELECT 1")).is_err());
+    assert!(phase.circuit_break.handle(String::from("DELETE FROM t")).is_err());
+
+    // the limited requests pass straight through the plugins of the clones
+    let mut other = phase.clone();
+    phase.set_bypass(true);
+    assert!(other.concurrency_control.handle(String::from("SELECT 1")).is_ok());
+    assert!(other.circuit_break.handle(String::from("DELETE FROM t")).is_ok());
+
+    phase.set_bypass(false);
+    assert!(phase.concurrency_control.handle(String::from("SELECT 1")).is_err());
+}
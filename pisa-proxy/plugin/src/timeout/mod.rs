@@ -0,0 +1,103 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::{
+    err::{BoxError, PluginError},
+    layer::{AsyncService, Bypass, Bypassable, Layer},
+};
+
+/// `TimeoutLayer` fails the request if the inner service does not complete in `timeout`.
+/// Only `AsyncService` is supported, a synchronous `Service::handle` can not be aborted.
+#[derive(Clone)]
+pub struct TimeoutLayer {
+    timeout: Duration,
+    bypass: Bypass,
+}
+
+impl TimeoutLayer {
+    pub fn new(timeout: Duration) -> TimeoutLayer {
+        TimeoutLayer { timeout, bypass: Bypass::default() }
+    }
+}
+
+impl Bypassable for TimeoutLayer {
+    fn with_bypass(self, bypass: Bypass) -> Self {
+        TimeoutLayer { bypass, ..self }
+    }
+}
+
+impl<S> Layer<S> for TimeoutLayer {
+    type Service = Timeout<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Timeout { inner, timeout: self.timeout, bypass: self.bypass.clone() }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Timeout<S> {
+    inner: S,
+    timeout: Duration,
+    bypass: Bypass,
+}
+
+#[async_trait]
+impl<S, Input> AsyncService<Input> for Timeout<S>
+where
+    S: AsyncService<Input> + Send,
+    Input: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        if self.bypass.is_set() {
+            return self.inner.handle(input).await.map_err(Into::into);
+        }
+        // The inner future is dropped when the timeout elapses.
+        match tokio::time::timeout(self.timeout, self.inner.handle(input)).await {
+            Ok(res) => res.map_err(Into::into),
+            Err(_) => Err(Box::new(PluginError::Timeout { elapsed: self.timeout })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::layer::{async_service_fn, ServiceBuilder};
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let mut svc = ServiceBuilder::new()
+            .with_layer(TimeoutLayer::new(Duration::from_millis(100)))
+            .build(async_service_fn(|sleep: u64| async move {
+                tokio::time::sleep(Duration::from_millis(sleep)).await;
+                Ok::<_, PluginError>(sleep)
+            }));
+
+        assert_eq!(svc.handle(10).await.unwrap(), 10);
+
+        let err = svc.handle(500).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PluginError>(),
+            Some(&PluginError::Timeout { elapsed: Duration::from_millis(100) })
+        );
+    }
+}
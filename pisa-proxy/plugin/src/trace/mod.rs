@@ -0,0 +1,164 @@
+// Copyright 2022 SphereEx Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Instant;
+
+use async_trait::async_trait;
+use tracing::{field, Instrument, Span};
+
+use crate::{
+    err::{BoxError, PluginError},
+    layer::{AsyncService, Layer, Service},
+};
+
+/// `TraceLayer` opens a `plugin` span around the inner service, and records
+/// the elapsed time, the result and the matched rule of the request.
+#[derive(Clone, Default)]
+pub struct TraceLayer;
+
+impl TraceLayer {
+    pub fn new() -> TraceLayer {
+        TraceLayer
+    }
+}
+
+impl<S> Layer<S> for TraceLayer {
+    type Service = Trace<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Trace { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Trace<S> {
+    inner: S,
+}
+
+// The fields are recorded when the request finishes
+fn new_span() -> Span {
+    tracing::debug_span!(
+        "plugin",
+        elapsed = field::Empty,
+        result = field::Empty,
+        limited = field::Empty,
+        rule_idx = field::Empty,
+        error = field::Empty,
+    )
+}
+
+fn record<T, E>(span: &Span, start: Instant, res: &Result<T, E>)
+where
+    E: AsRef<dyn std::error::Error + Send + Sync>,
+{
+    // The span is disabled if there is no subscriber
+    if span.is_disabled() {
+        return;
+    }
+
+    span.record("elapsed", field::debug(start.elapsed()));
+    match res {
+        Ok(_) => {
+            span.record("result", "ok");
+            span.record("limited", false);
+        }
+        Err(e) => {
+            span.record("result", "err");
+            let err = e.as_ref().downcast_ref::<PluginError>();
+            if let Some(PluginError::ConcurrencyControlPluginReject { rule_index, .. }) = err {
+                span.record("rule_idx", rule_index);
+            }
+            span.record("limited", err.is_some_and(PluginError::is_limited));
+            span.record("error", err.map_or("other", PluginError::name));
+        }
+    }
+    span.in_scope(|| tracing::debug!("plugin request finished"));
+}
+
+impl<S, Input> Service<Input> for Trace<S>
+where
+    S: Service<Input>,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        let span = new_span();
+        let start = Instant::now();
+        let res = span.in_scope(|| self.inner.handle(input).map_err(Into::into));
+        record(&span, start, &res);
+        res
+    }
+
+    fn poll_ready(&mut self) -> bool {
+        self.inner.poll_ready()
+    }
+}
+
+#[async_trait]
+impl<S, Input> AsyncService<Input> for Trace<S>
+where
+    S: AsyncService<Input> + Send,
+    Input: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Output = S::Output;
+    type Error = BoxError;
+
+    async fn handle(&mut self, input: Input) -> Result<Self::Output, Self::Error> {
+        let span = new_span();
+        let start = Instant::now();
+        let res = self.inner.handle(input).instrument(span.clone()).await.map_err(Into::into);
+        record(&span, start, &res);
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::{
+        concurrency_control::ConcurrencyControlLayer,
+        config,
+        layer::{service_fn, ServiceBuilder},
+    };
+
+    #[traced_test]
+    #[test]
+    fn test_trace() {
+        let config = vec![config::ConcurrencyControl {
+            regex: vec![String::from(r"^SELECT")],
+            max_concurrency: 1,
+            duration: Duration::new(50, 0),
+            ..Default::default()
+        }];
+        let mut svc = ServiceBuilder::new()
+            .with_layer(TraceLayer::new())
+            .with_layer(ConcurrencyControlLayer::new(config).unwrap())
+            .build(service_fn(|input: &str| Ok::<_, PluginError>(input.to_string())));
+
+        let _guard = svc.handle("SELECT 1").unwrap();
+        assert!(logs_contain("result=\"ok\" limited=false"));
+
+        assert!(svc.handle("SELECT 1").is_err());
+        assert!(logs_contain("result=\"err\""));
+        assert!(logs_contain("rule_idx=0 limited=true error=\"ConcurrencyControlPluginReject\""));
+        assert!(logs_contain("elapsed="));
+    }
+}
@@ -19,6 +19,7 @@ use std::{
 };
 
 use async_trait::async_trait;
+use byteorder::{ByteOrder, LittleEndian};
 use bytes::{Buf, BytesMut};
 use common::ast_cache::ParserAstCache;
 use conn_pool::Pool;
@@ -41,7 +42,12 @@ use mysql_protocol::{
 };
 use parking_lot::Mutex;
 use pisa_error::error::{Error, ErrorKind};
-use plugin::{build_phase::PluginPhase, err::BoxError, layer::Service};
+use plugin::{
+    build_phase::PluginPhase,
+    concurrency_control::ConcurrencyControlGuard,
+    err::{BoxError, PluginError},
+    layer::Service,
+};
 use proxy::{
     listener::Listener,
     proxy::{MySQLNode, Proxy, ProxyConfig},
@@ -189,7 +195,10 @@ impl proxy::factory::Proxy for MySQLProxy {
 
         let mut plugin: Option<PluginPhase> = None;
         if let Some(config) = &self.proxy_config.plugin {
-            plugin = Some(PluginPhase::new(config.clone()))
+            plugin = Some(
+                PluginPhase::new(config.clone())
+                    .map_err(|e| Error::new(ErrorKind::Runtime(e.into())))?,
+            )
         };
 
         let parser = Arc::new(Parser::new());
@@ -243,7 +252,7 @@ impl proxy::factory::Proxy for MySQLProxy {
                     ast_cache,
                     plugin,
                     metrics_collector: MySQLServerMetricsCollector,
-                    concurrency_control_rule_idx: None,
+                    concurrency_control_guard: None,
                     framed,
                     name: proxy_name,
                     mysql_parser: parser,
@@ -272,10 +281,9 @@ pub struct ReqContext<T, C> {
     pub ast_cache: Arc<Mutex<ParserAstCache>>,
     pub plugin: Option<PluginPhase>,
     pub metrics_collector: MySQLServerMetricsCollector,
-    // `concurrency_control_rule_idx` is index of concurrency_control rules
-    // `concurrency_control_rule_idx` is required to add permits when the
-    //  concurrency_control layer service is enabled
-    pub concurrency_control_rule_idx: Option<usize>,
+    // `concurrency_control_guard` holds the permit of concurrency_control rules
+    // while the command is running, the permit is released when the guard is dropped
+    pub concurrency_control_guard: Option<ConcurrencyControlGuard>,
     // The codc for MySQL Protocol
     pub framed: Framed<T, C>,
     pub rewriter: Option<ShardingRewrite>,
@@ -356,10 +364,8 @@ where
 
                     cx.framed.codec_mut().reset_seq();
 
-                    if let Some(idx) = &cx.concurrency_control_rule_idx {
-                        cx.plugin.as_mut().unwrap().concurrency_control.add_permits(*idx);
-                        cx.concurrency_control_rule_idx = None;
-                    }
+                    // release the permit of concurrency_control rules
+                    cx.concurrency_control_guard = None;
 
                     if self.is_quit {
                         return Ok(());
@@ -382,12 +388,11 @@ where
         let com = data.get_u8();
         let payload = data.split();
 
-        if let Err(err) = self.plugin_run(cx, &payload) {
-            let err_info = make_err_packet(MySQLError::new(
-                1047,
-                "08S01".as_bytes().to_vec(),
-                err.to_string(),
-            ));
+        if let Err(err) = self.plugin_run(cx, com, &payload) {
+            let err_info = make_err_packet(match err.downcast_ref::<PluginError>() {
+                Some(err) => error_packet_for(err),
+                None => MySQLError::new(1047, "08S01".as_bytes().to_vec(), err.to_string()),
+            });
             cx.framed
                 .send(PacketSend::Encode(err_info[4..].into()))
                 .await
@@ -435,9 +440,27 @@ where
         }
     }
 
-    fn plugin_run(&mut self, cx: &mut ReqContext<T, C>, payload: &[u8]) -> Result<(), BoxError> {
+    fn plugin_run(
+        &mut self,
+        cx: &mut ReqContext<T, C>,
+        com: u8,
+        payload: &[u8],
+    ) -> Result<(), BoxError> {
         if let Some(plugin) = cx.plugin.as_mut() {
-            let input = unsafe { std::str::from_utf8_unchecked(payload).to_string() };
+            // The execution of a prepared statement is evaluated by the SQL of the statement
+            let prepared = match ComType::from(com) {
+                ComType::STMT_EXECUTE if payload.len() >= 4 => plugin
+                    .concurrency_control
+                    .resolve_prepared(LittleEndian::read_u32(payload))
+                    .map(str::to_string),
+                _ => None,
+            };
+            let input = match prepared {
+                Some(sql) => sql,
+                None => unsafe { std::str::from_utf8_unchecked(payload).to_string() },
+            };
+
+            plugin.firewall.handle(input.clone())?;
 
             plugin.circuit_break.handle(input.clone())?;
 
@@ -445,7 +468,7 @@ where
 
             match res {
                 Ok(data) => {
-                    cx.concurrency_control_rule_idx = data.0;
+                    cx.concurrency_control_guard = Some(data.0);
                     return Ok(());
                 }
 
@@ -456,3 +479,87 @@ where
         Ok(())
     }
 }
+
+/// Map the error of the plugins to the error packet sent to the client. The limited requests
+/// fail with `ER_CON_COUNT_ERROR`, the blocked ones with `ER_OPTION_PREVENTS_STATEMENT`.
+pub fn error_packet_for(err: &PluginError) -> MySQLError {
+    let (code, state) = match err {
+        PluginError::ConcurrencyControlPluginReject { .. }
+        | PluginError::Quarantined { .. }
+        | PluginError::ConcurrencyLimitReached
+        | PluginError::ConnectionLimitReached { .. }
+        | PluginError::Overloaded
+        | PluginError::BufferFull => (1040, "08004"),
+        // ER_NET_PACKET_TOO_LARGE
+        PluginError::QueryTooLarge { .. } => (1153, "08S01"),
+        PluginError::RowLimitExceeded { .. }
+        | PluginError::CircuitBreakPluginReject
+        | PluginError::FirewallBlocked { .. } => (1290, "HY000"),
+        // CR_CONNECTION_ERROR, the backend is unhealthy
+        PluginError::CircuitOpen => (2002, "HY000"),
+        // ER_QUERY_TIMEOUT
+        PluginError::Timeout { .. } | PluginError::DeadlineExceeded => (3024, "HY000"),
+        // ER_SERVER_SHUTDOWN
+        PluginError::Draining | PluginError::BufferClosed => (1053, "08S01"),
+        PluginError::InvalidConcurrencyControlRegex { .. }
+        | PluginError::RegexTooComplex { .. }
+        | PluginError::InvalidFirewallRegex { .. }
+        | PluginError::InvalidCacheRegex { .. }
+        | PluginError::InvalidFairQueueRegex { .. }
+        | PluginError::InvalidFairQueueConfig { .. }
+        | PluginError::InvalidLatencyInjectionRegex { .. }
+        | PluginError::InvalidMirrorRegex { .. }
+        | PluginError::InvalidSingleFlightRegex { .. }
+        | PluginError::InvalidConcurrencyControlConfig { .. }
+        | PluginError::InvalidRuleIndex { .. }
+        | PluginError::PermitsFull { .. }
+        | PluginError::StoreUnavailable { .. }
+        | PluginError::Unknown => (1105, "HY000"),
+    };
+    MySQLError::new(code, state.as_bytes().to_vec(), err.client_message())
+}
+
+#[cfg(test)]
+mod test {
+    use mysql_protocol::server::codec::make_err_packet;
+    use plugin::err::PluginError;
+
+    use super::error_packet_for;
+
+    #[test]
+    fn test_error_packet_for() {
+        let err = PluginError::ConcurrencyControlPluginReject {
+            rule_index: 0,
+            regex: vec![String::from("^SELECT")],
+            retry_after: None,
+            message: None,
+        };
+        let packet = error_packet_for(&err);
+        assert_eq!(packet.code, 1040);
+        assert_eq!(packet.state, b"08004");
+        assert_eq!(packet.msg, err.to_string());
+
+        // the header is filled by the codec, the error code is little endian
+        let data = make_err_packet(packet);
+        assert_eq!(&data[4..10], &[0xff, 0x10, 0x04, b'#', b'0', b'8']);
+
+        let packet = error_packet_for(&PluginError::Draining);
+        assert_eq!((packet.code, packet.state.as_slice()), (1053, b"08S01".as_slice()));
+    }
+
+    #[test]
+    fn test_error_packet_reject_message() {
+        let err = PluginError::ConcurrencyControlPluginReject {
+            rule_index: 0,
+            regex: vec![String::from("^SELECT")],
+            retry_after: None,
+            message: Some(String::from("too many reports, contact the dba")),
+        };
+        // the message of the rule is sent verbatim
+        let packet = error_packet_for(&err);
+        assert_eq!(packet.code, 1040);
+        assert_eq!(packet.msg, "too many reports, contact the dba");
+        let data = make_err_packet(packet);
+        assert!(data.ends_with(b"too many reports, contact the dba"));
+    }
+}
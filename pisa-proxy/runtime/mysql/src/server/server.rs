@@ -177,7 +177,7 @@ where
         stmt.stmt_id = stmt_id;
 
         req.stmt_cache.put_sharding_column(stmt_id, sharding_column);
-        Self::prepare_stmt(req, stmt).await?;
+        Self::prepare_stmt(req, stmt, raw_sql).await?;
 
         Ok(())
     }
@@ -188,12 +188,18 @@ where
         payload: &[u8],
     ) -> Result<(), Error> {
         let stmt = client_conn.send_prepare(payload).await.map_err(ErrorKind::from)?;
-        Self::prepare_stmt(req, stmt).await?;
+        let sql = std::str::from_utf8(payload).unwrap().trim_matches(char::from(0));
+        Self::prepare_stmt(req, stmt, sql).await?;
 
         Ok(())
     }
 
-    async fn prepare_stmt(req: &mut ReqContext<T, C>, stmt: Stmt) -> Result<(), Error> {
+    async fn prepare_stmt(req: &mut ReqContext<T, C>, stmt: Stmt, sql: &str) -> Result<(), Error> {
+        // the executions of the statement are evaluated by its SQL
+        if let Some(plugin) = req.plugin.as_mut() {
+            plugin.concurrency_control.prepare(stmt.stmt_id, sql);
+        }
+
         let mut buf = BytesMut::with_capacity(128);
         let mut data = vec![0];
         data.extend_from_slice(&u32::to_le_bytes(stmt.stmt_id));
@@ -757,6 +763,9 @@ where
         let now = Instant::now();
         let stmt_id = LittleEndian::read_u32(payload);
         cx.stmt_cache.remove(stmt_id);
+        if let Some(plugin) = cx.plugin.as_mut() {
+            plugin.concurrency_control.close_prepared(stmt_id);
+        }
         debug!("stmt close {:?}", stmt_id);
 
         Ok(RespContext { ep: None, duration: now.elapsed() })